@@ -1,6 +1,15 @@
-use clap::{self, Parser};
+use clap::{self, Args, Parser, Subcommand, ValueEnum};
+use glob::Pattern;
+
+use crux_torrent::metainfo::FilePriority;
+use crux_torrent::peer_protocol::mse::EncryptionPolicy;
+use crux_torrent::rpc::DEFAULT_RPC_PORT;
+use crux_torrent::InfoHash;
+use reqwest::Url;
 
 use std::ffi::OsStr;
+use std::io::IsTerminal;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -43,16 +52,413 @@ impl AsRef<Path> for MetainfoFilePath {
     }
 }
 
+/// parses a rate in bytes per second, with an optional binary K, M or G suffix, e.g `512K`.
+pub fn parse_byte_rate(s: &str) -> Result<u64, anyhow::Error> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+
+    let rate: u64 = digits
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid rate {:?}, expected e.g 1048576, 512K or 2M", s))?;
+    rate.checked_mul(multiplier)
+        .ok_or_else(|| anyhow::anyhow!("rate {:?} is too large", s))
+}
+
 #[derive(Parser, Debug)]
-#[command(author, about, long_about = None)]
+#[command(
+    author,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 /// a cli bittorrent (v1) client written in rust.
 pub struct Cli {
-    #[arg(required = true)]
-    /// the source for the torrent information, i.e a torrent file.
-    /// torrent files must have the .torrent extention
-    pub source: MetainfoFilePath,
-
-    #[arg(short, long, default_value = "8860")]
-    /// the port on which to listen to incoming messages.
-    pub port: u16,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[arg(required = true, num_args = 1..)]
+    /// the sources for the torrent information, i.e torrent files, all downloaded in the same
+    /// session. torrent files must have the .torrent extention
+    pub sources: Vec<MetainfoFilePath>,
+
+    #[arg(long, value_enum, default_value_t)]
+    /// how progress is shown while downloading.
+    pub ui: UiMode,
+
+    #[arg(long)]
+    /// write the logs to this file, the progress ui would otherwise hide them.
+    pub log_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub files: FileFilter,
+
+    #[arg(long)]
+    /// download the pieces in order rather than the rarest ones first, e.g to preview files
+    /// while they download.
+    pub sequential: bool,
+
+    #[command(flatten)]
+    pub session: SessionArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct FileFilter {
+    #[arg(long, value_name = "GLOB")]
+    /// only download the files of multi file torrents whose path within the torrent matches,
+    /// can be given more than once.
+    pub only: Vec<Pattern>,
+
+    #[arg(long, value_name = "GLOB")]
+    /// skip the files of multi file torrents whose path within the torrent matches, can be given
+    /// more than once.
+    pub skip: Vec<Pattern>,
+}
+
+impl FileFilter {
+    /// the priority a file starts out with, by its path within the torrent. skipping wins over
+    /// `--only`.
+    pub fn priority(&self, path: &Path) -> FilePriority {
+        let matches = |patterns: &[Pattern]| patterns.iter().any(|p| p.matches_path(path));
+        if matches(&self.skip) || (!self.only.is_empty() && !matches(&self.only)) {
+            FilePriority::Skip
+        } else {
+            FilePriority::Normal
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UiMode {
+    /// the full screen ui on a terminal, the progress bar otherwise.
+    #[default]
+    Auto,
+    /// a full screen ui with the peers, files, pieces and trackers of each torrent.
+    Tui,
+    /// a single line of progress, for terminals which can't take the full screen ui.
+    Bar,
+    /// only the logs.
+    Log,
+}
+
+impl UiMode {
+    /// picks the ui `auto` stands for.
+    pub fn resolve(self) -> Self {
+        let is_terminal = std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+        match self {
+            Self::Auto if is_terminal => Self::Tui,
+            Self::Auto => Self::Bar,
+            ui => ui,
+        }
+    }
+}
+
+/// options of the session which downloads the torrents. they take precedence over the config
+/// file, and the ones which are left out fall back to it.
+#[derive(Args, Debug)]
+pub struct SessionArgs {
+    #[arg(long, env = "CRUX_TORRENT_CONFIG")]
+    /// the config file to read, instead of crux-torrent/config.toml in the xdg config directory.
+    pub config: Option<PathBuf>,
+
+    #[arg(short, long, env = "CRUX_TORRENT_PORT")]
+    /// the port on which to listen to incoming messages, 8860 by default.
+    pub port: Option<u16>,
+
+    #[arg(long, value_enum, env = "CRUX_TORRENT_ENCRYPTION")]
+    /// whether peer connections should be obfuscated with message stream encryption, enabled by
+    /// default.
+    pub encryption: Option<EncryptionPolicy>,
+
+    #[arg(short = 'o', long, env = "CRUX_TORRENT_DOWNLOAD_DIR")]
+    /// where the torrents are saved, the current directory by default.
+    pub download_dir: Option<PathBuf>,
+
+    #[arg(long, env = "CRUX_TORRENT_UNCHOKE_SLOTS")]
+    /// the number of peers which are unchoked at once, including the optimistic unchoke.
+    pub unchoke_slots: Option<usize>,
+
+    #[arg(
+        long,
+        env = "CRUX_TORRENT_SMART_BAN",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    /// ban a peer which sent a corrupt piece as soon as a copy downloaded from another peer shows
+    /// its blocks were wrong, without waiting for it to fail repeatedly. peers are banned after
+    /// repeated hash failures either way.
+    pub smart_ban: Option<bool>,
+
+    #[arg(long, env = "CRUX_TORRENT_MAX_DOWNLOAD_RATE", value_parser = parse_byte_rate)]
+    /// cap on the total download rate in bytes per second, accepts K, M and G suffixes.
+    pub max_download_rate: Option<u64>,
+
+    #[arg(long, env = "CRUX_TORRENT_MAX_UPLOAD_RATE", value_parser = parse_byte_rate)]
+    /// cap on the total upload rate in bytes per second, accepts K, M and G suffixes.
+    pub max_upload_rate: Option<u64>,
+
+    #[arg(long, env = "CRUX_TORRENT_MAX_PEER_DOWNLOAD_RATE", value_parser = parse_byte_rate)]
+    /// cap on the download rate from each individual peer.
+    pub max_peer_download_rate: Option<u64>,
+
+    #[arg(long, env = "CRUX_TORRENT_MAX_PEER_UPLOAD_RATE", value_parser = parse_byte_rate)]
+    /// cap on the upload rate to each individual peer.
+    pub max_peer_upload_rate: Option<u64>,
+
+    #[arg(long, env = "CRUX_TORRENT_MAX_CONNECTIONS")]
+    /// the most peer connections open at once, across all torrents.
+    pub max_connections: Option<usize>,
+
+    #[arg(long, env = "CRUX_TORRENT_MAX_CONNECTIONS_PER_TORRENT")]
+    /// the most peer connections open at once for a single torrent.
+    pub max_connections_per_torrent: Option<usize>,
+
+    #[arg(long, env = "CRUX_TORRENT_MAX_HALF_OPEN")]
+    /// the most outgoing connections which may be in the middle of connecting at once.
+    pub max_half_open: Option<usize>,
+
+    #[arg(long, env = "CRUX_TORRENT_CONNECT_TIMEOUT")]
+    /// seconds a peer gets to finish connecting and handshaking before it's given up on.
+    pub connect_timeout: Option<u64>,
+
+    #[arg(long, env = "CRUX_TORRENT_REQUEST_TIMEOUT")]
+    /// seconds a block request may stay outstanding before it's cancelled.
+    pub request_timeout: Option<u64>,
+
+    #[arg(long, env = "CRUX_TORRENT_SNUB_TIMEOUT")]
+    /// seconds a peer may sit on our requests before it's considered to be snubbing us.
+    pub snub_timeout: Option<u64>,
+
+    #[arg(long, env = "CRUX_TORRENT_INACTIVITY_TIMEOUT")]
+    /// seconds a peer may stay silent before it's disconnected.
+    pub inactivity_timeout: Option<u64>,
+
+    #[arg(long, env = "CRUX_TORRENT_KEEPALIVE_INTERVAL")]
+    /// seconds between keepalives sent to a peer we have nothing else to say to.
+    pub keepalive_interval: Option<u64>,
+
+    #[arg(long, env = "CRUX_TORRENT_ANNOUNCE_ATTEMPTS")]
+    /// attempts at a tracker announce, including the first one.
+    pub announce_attempts: Option<u32>,
+
+    #[arg(long, env = "CRUX_TORRENT_ANNOUNCE_RETRY_DELAY")]
+    /// seconds between attempts at a tracker announce.
+    pub announce_retry_delay: Option<u64>,
+
+    #[arg(long, env = "CRUX_TORRENT_ALERTS_BUFFER_SIZE")]
+    /// alerts from the peers which can queue up for the engine of a torrent.
+    pub alerts_buffer_size: Option<usize>,
+
+    #[arg(long, env = "CRUX_TORRENT_COMMAND_BUFFER_SIZE")]
+    /// commands from the engine which can queue up for a peer.
+    pub command_buffer_size: Option<usize>,
+
+    #[arg(long, env = "CRUX_TORRENT_MAX_FRAME_SIZE")]
+    /// the longest message accepted from a peer, in bytes.
+    pub max_frame_size: Option<usize>,
+
+    #[arg(long, env = "CRUX_TORRENT_MAX_ACTIVE_TORRENTS")]
+    /// the most torrents downloading at once, the rest wait their turn in the order given.
+    /// complete torrents keep seeding without taking up a turn.
+    pub max_active_torrents: Option<usize>,
+
+    #[arg(
+        long,
+        env = "CRUX_TORRENT_DHT",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    /// find peers through the dht as well as the trackers, on by default. private torrents never
+    /// use it.
+    pub dht: Option<bool>,
+
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        env = "CRUX_TORRENT_DHT_BOOTSTRAP_NODES",
+        value_delimiter = ','
+    )]
+    /// comma separated dht nodes the routing table is filled from.
+    pub dht_bootstrap_nodes: Option<Vec<String>>,
+
+    #[arg(
+        long,
+        value_name = "ADDR",
+        env = "CRUX_TORRENT_METRICS",
+        num_args = 0..=1,
+        default_missing_value = "127.0.0.1:8862",
+    )]
+    /// serve prometheus metrics on /metrics, on 127.0.0.1:8862 unless given an address.
+    pub metrics: Option<SocketAddr>,
+
+    #[arg(long, value_name = "ADDR", env = "CRUX_TORRENT_RPC_ADDR")]
+    /// the address the daemon serves rpc requests on, 127.0.0.1:8861 by default.
+    pub rpc_addr: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        value_name = "ADDR",
+        env = "CRUX_TORRENT_TRANSMISSION",
+        num_args = 0..=1,
+        default_missing_value = "127.0.0.1:9091",
+    )]
+    /// have the daemon also serve the transmission rpc protocol, for frontends built for
+    /// transmission, on 127.0.0.1:9091 unless given an address.
+    pub transmission: Option<SocketAddr>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// runs a long lived session which is controlled over json-rpc, e.g with `remote`.
+    Daemon {
+        #[command(flatten)]
+        session: SessionArgs,
+    },
+
+    /// downloads a torrent while serving its files over http, ranges included, so that they can
+    /// be played or read before the download is done. reading a file that was skipped with
+    /// --only or --skip downloads it after all, HEAD requests don't.
+    Serve {
+        source: MetainfoFilePath,
+
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8080")]
+        /// the address to serve the files on.
+        http: SocketAddr,
+
+        #[command(flatten)]
+        files: FileFilter,
+
+        #[command(flatten)]
+        session: SessionArgs,
+    },
+
+    /// controls a running daemon.
+    Remote {
+        #[arg(long, default_value_t = default_rpc_url())]
+        /// the rpc endpoint of the daemon.
+        rpc_url: Url,
+
+        #[command(subcommand)]
+        command: RemoteCommand,
+    },
+
+    /// inspects the configuration.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// prints the settings in effect after the config file, environment variables and flags are
+    /// applied, in the config file format.
+    Show {
+        #[command(flatten)]
+        session: SessionArgs,
+    },
+}
+
+fn default_rpc_url() -> Url {
+    format!("http://{}:{}/rpc", Ipv4Addr::LOCALHOST, DEFAULT_RPC_PORT)
+        .parse()
+        .expect("the default rpc url is valid")
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RemoteCommand {
+    /// adds a torrent from a local torrent file, a url or a magnet link.
+    Add { source: String },
+    /// lists every torrent of the daemon.
+    List,
+    /// shows how a torrent is doing.
+    Status { info_hash: InfoHash },
+    /// disconnects from the peers of a torrent, keeping its progress.
+    Pause { info_hash: InfoHash },
+    /// puts a paused torrent back in the queue.
+    Resume { info_hash: InfoHash },
+    /// removes a torrent from the daemon.
+    Remove {
+        info_hash: InfoHash,
+
+        #[arg(long)]
+        /// delete the downloaded files as well.
+        delete_data: bool,
+    },
+    /// shows the limits on the total rates, or on the rates of a single torrent.
+    Limits {
+        #[arg(long)]
+        torrent: Option<InfoHash>,
+    },
+    /// sets the limits on the total rates, or on the rates of a single torrent. a limit which is
+    /// left out is lifted.
+    SetLimits {
+        #[arg(long)]
+        torrent: Option<InfoHash>,
+
+        #[arg(long, value_parser = parse_byte_rate)]
+        download: Option<u64>,
+
+        #[arg(long, value_parser = parse_byte_rate)]
+        upload: Option<u64>,
+    },
+    /// lists the peers a torrent is connected to.
+    Peers { info_hash: InfoHash },
+    /// shows how the last announces of a torrent went.
+    Trackers { info_hash: InfoHash },
+    /// lists the files of a torrent, along with their priorities.
+    Files { info_hash: InfoHash },
+    /// turns sequential downloading of a torrent on or off.
+    Sequential {
+        info_hash: InfoHash,
+        #[arg(action = clap::ArgAction::Set)]
+        enabled: bool,
+    },
+    /// changes the priority of a file of a torrent, by its index in `files`.
+    Priority {
+        info_hash: InfoHash,
+        file: usize,
+        #[arg(value_enum)]
+        priority: FilePriority,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(&[], &[], "video/a.mkv", FilePriority::Normal)]
+    #[case(&["*.mkv"], &[], "a.mkv", FilePriority::Normal)]
+    #[case(&["*.mkv"], &[], "a.nfo", FilePriority::Skip)]
+    #[case(&["video/*"], &["*.nfo"], "video/a.nfo", FilePriority::Skip)]
+    #[case(&[], &["samples/*"], "samples/a.mkv", FilePriority::Skip)]
+    fn test_file_priority(
+        #[case] only: &[&str],
+        #[case] skip: &[&str],
+        #[case] path: &str,
+        #[case] expected: FilePriority,
+    ) {
+        #[derive(Parser)]
+        struct Args {
+            #[command(flatten)]
+            files: FileFilter,
+        }
+
+        let mut args = vec!["crux-torrent"];
+        for pattern in only {
+            args.extend(["--only", pattern]);
+        }
+        for pattern in skip {
+            args.extend(["--skip", pattern]);
+        }
+        let filter = Args::parse_from(args).files;
+        assert_eq!(filter.priority(Path::new(path)), expected);
+    }
 }
@@ -0,0 +1,429 @@
+//! the settings of the client. they're layered from the defaults, to the config file, to
+//! environment variables and then command line flags, each one overriding the ones before.
+use crate::cli::{parse_byte_rate, SessionArgs};
+use anyhow::Context;
+use crux_torrent::connection_manager::ConnectionLimits;
+use crux_torrent::dht::DhtConfig;
+use crux_torrent::peer_protocol::mse::EncryptionPolicy;
+use crux_torrent::peers::download_worker::WorkerConfig;
+use crux_torrent::rate_limit::RateLimits;
+use crux_torrent::rpc::DEFAULT_RPC_PORT;
+use crux_torrent::tracker::TrackerConfig;
+use crux_torrent::SessionConfig;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub directories: Directories,
+    pub network: Network,
+    pub limits: Limits,
+    pub timeouts: Timeouts,
+    pub tracker: Tracker,
+    pub peers: Peers,
+    pub dht: Dht,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Directories {
+    /// where the torrents are saved.
+    pub download: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Network {
+    pub port: u16,
+    pub encryption: EncryptionPolicy,
+    /// where the daemon serves rpc requests.
+    pub rpc_addr: SocketAddr,
+    /// where the daemon serves the transmission rpc protocol, if at all.
+    pub transmission_addr: Option<SocketAddr>,
+    /// where prometheus metrics are served, if at all.
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+/// rates are in bytes per second, and may be written as strings with a K, M or G suffix.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub max_download_rate: Option<u64>,
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub max_upload_rate: Option<u64>,
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub max_peer_download_rate: Option<u64>,
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub max_peer_upload_rate: Option<u64>,
+    pub max_connections: usize,
+    pub max_connections_per_torrent: usize,
+    pub max_half_open: usize,
+    pub max_active_torrents: usize,
+    pub unchoke_slots: usize,
+}
+
+/// all in seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// time a peer gets to finish connecting and handshaking.
+    pub connect: u64,
+    /// time a block request may stay outstanding before it's cancelled.
+    pub request: u64,
+    /// time a peer may sit on our requests before it's considered to be snubbing us.
+    pub snub: u64,
+    /// time a peer may stay silent before it's disconnected.
+    pub inactivity: u64,
+    pub keepalive_interval: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tracker {
+    /// attempts at an announce, including the first one.
+    pub announce_attempts: u32,
+    /// seconds between attempts.
+    pub retry_delay: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Peers {
+    pub smart_ban: bool,
+    /// alerts from the workers which can queue up for the engine of a torrent.
+    pub alerts_buffer_size: usize,
+    /// commands from the engine which can queue up for a worker.
+    pub command_buffer_size: usize,
+    /// the longest message accepted from a peer, in bytes.
+    pub max_frame_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Dht {
+    pub enabled: bool,
+    /// host:port of the nodes an empty routing table is filled from.
+    pub bootstrap_nodes: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let session = SessionConfig::default();
+        let rate_limits = &session.rate_limits;
+        Self {
+            directories: Directories {
+                download: session.download_dir,
+            },
+            network: Network {
+                port: session.port,
+                encryption: session.encryption,
+                rpc_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, DEFAULT_RPC_PORT)),
+                transmission_addr: None,
+                metrics_addr: None,
+            },
+            limits: Limits {
+                max_download_rate: rate_limits.download.rate(),
+                max_upload_rate: rate_limits.upload.rate(),
+                max_peer_download_rate: session.peer_rate_limits.0,
+                max_peer_upload_rate: session.peer_rate_limits.1,
+                max_connections: session.connection_limits.max_connections,
+                max_connections_per_torrent: session.connection_limits.max_connections_per_torrent,
+                max_half_open: session.connection_limits.max_half_open,
+                max_active_torrents: session.max_active_torrents,
+                unchoke_slots: session.unchoke_slots,
+            },
+            timeouts: Timeouts {
+                connect: session.connection_limits.connect_timeout.as_secs(),
+                request: session.worker.request_timeout.as_secs(),
+                snub: session.worker.snub_timeout.as_secs(),
+                inactivity: session.worker.inactivity_timeout.as_secs(),
+                keepalive_interval: session.worker.keepalive_interval.as_secs(),
+            },
+            tracker: Tracker {
+                announce_attempts: session.tracker.announce_attempts,
+                retry_delay: session.tracker.retry_delay.as_secs(),
+            },
+            peers: Peers {
+                smart_ban: session.smart_ban,
+                alerts_buffer_size: session.alerts_buffer_size,
+                command_buffer_size: session.worker.command_buffer_size,
+                max_frame_size: session.worker.max_frame_size,
+            },
+            dht: Dht {
+                enabled: session.dht.enabled,
+                bootstrap_nodes: session.dht.bootstrap_nodes,
+            },
+        }
+    }
+}
+
+// the sections fall back to the defaults of the whole config, so they're only spelled out once.
+impl Default for Directories {
+    fn default() -> Self {
+        Config::default().directories
+    }
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Config::default().network
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Config::default().limits
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Config::default().timeouts
+    }
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Config::default().tracker
+    }
+}
+
+impl Default for Peers {
+    fn default() -> Self {
+        Config::default().peers
+    }
+}
+
+impl Default for Dht {
+    fn default() -> Self {
+        Config::default().dht
+    }
+}
+
+impl Config {
+    /// the config file which is read when none is given, it's fine for it not to exist.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            // relative paths are to be ignored as per the xdg spec.
+            .filter(|path| path.is_absolute())
+            .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".config")))?;
+        Some(config_home.join("crux-torrent").join("config.toml"))
+    }
+
+    /// reads the config file at `path`, or the default one if there is one.
+    pub fn load(path: Option<&Path>) -> Result<Self, anyhow::Error> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Self::default_path() {
+                Some(path) if path.is_file() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("could not read config file {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// reads the config file the args point to, then applies the args over it.
+    pub fn from_args(args: &SessionArgs) -> Result<Self, anyhow::Error> {
+        let mut config = Self::load(args.config.as_deref())?;
+        config.apply(args);
+        Ok(config)
+    }
+
+    /// overrides the settings which were given as flags or environment variables.
+    pub fn apply(&mut self, args: &SessionArgs) {
+        fn set<T: Clone>(setting: &mut T, arg: &Option<T>) {
+            if let Some(arg) = arg {
+                *setting = arg.clone();
+            }
+        }
+        // limits which are left out stay as they are, rather than being lifted.
+        fn set_limit(setting: &mut Option<u64>, arg: Option<u64>) {
+            if arg.is_some() {
+                *setting = arg;
+            }
+        }
+
+        set(&mut self.directories.download, &args.download_dir);
+        set(&mut self.network.port, &args.port);
+        set(&mut self.network.encryption, &args.encryption);
+        if args.metrics.is_some() {
+            self.network.metrics_addr = args.metrics;
+        }
+        set(&mut self.network.rpc_addr, &args.rpc_addr);
+        if args.transmission.is_some() {
+            self.network.transmission_addr = args.transmission;
+        }
+        set_limit(&mut self.limits.max_download_rate, args.max_download_rate);
+        set_limit(&mut self.limits.max_upload_rate, args.max_upload_rate);
+        set_limit(
+            &mut self.limits.max_peer_download_rate,
+            args.max_peer_download_rate,
+        );
+        set_limit(
+            &mut self.limits.max_peer_upload_rate,
+            args.max_peer_upload_rate,
+        );
+        set(&mut self.limits.max_connections, &args.max_connections);
+        set(
+            &mut self.limits.max_connections_per_torrent,
+            &args.max_connections_per_torrent,
+        );
+        set(&mut self.limits.max_half_open, &args.max_half_open);
+        set(
+            &mut self.limits.max_active_torrents,
+            &args.max_active_torrents,
+        );
+        set(&mut self.limits.unchoke_slots, &args.unchoke_slots);
+        set(&mut self.timeouts.connect, &args.connect_timeout);
+        set(&mut self.timeouts.request, &args.request_timeout);
+        set(&mut self.timeouts.snub, &args.snub_timeout);
+        set(&mut self.timeouts.inactivity, &args.inactivity_timeout);
+        set(
+            &mut self.timeouts.keepalive_interval,
+            &args.keepalive_interval,
+        );
+        set(&mut self.tracker.announce_attempts, &args.announce_attempts);
+        set(&mut self.tracker.retry_delay, &args.announce_retry_delay);
+        set(&mut self.peers.smart_ban, &args.smart_ban);
+        set(&mut self.peers.alerts_buffer_size, &args.alerts_buffer_size);
+        set(
+            &mut self.peers.command_buffer_size,
+            &args.command_buffer_size,
+        );
+        set(&mut self.peers.max_frame_size, &args.max_frame_size);
+        set(&mut self.dht.enabled, &args.dht);
+        set(&mut self.dht.bootstrap_nodes, &args.dht_bootstrap_nodes);
+    }
+
+    pub fn session_config(&self) -> SessionConfig {
+        let Self {
+            directories,
+            network,
+            limits,
+            timeouts,
+            tracker,
+            peers,
+            dht,
+        } = self;
+        SessionConfig {
+            port: network.port,
+            encryption: network.encryption,
+            rate_limits: RateLimits::new(limits.max_download_rate, limits.max_upload_rate),
+            peer_rate_limits: (limits.max_peer_download_rate, limits.max_peer_upload_rate),
+            connection_limits: ConnectionLimits {
+                max_connections: limits.max_connections,
+                max_connections_per_torrent: limits.max_connections_per_torrent,
+                max_half_open: limits.max_half_open,
+                connect_timeout: Duration::from_secs(timeouts.connect),
+            },
+            unchoke_slots: limits.unchoke_slots,
+            smart_ban: peers.smart_ban,
+            max_active_torrents: limits.max_active_torrents,
+            download_dir: directories.download.clone(),
+            worker: WorkerConfig {
+                keepalive_interval: Duration::from_secs(timeouts.keepalive_interval),
+                inactivity_timeout: Duration::from_secs(timeouts.inactivity),
+                request_timeout: Duration::from_secs(timeouts.request),
+                snub_timeout: Duration::from_secs(timeouts.snub),
+                command_buffer_size: peers.command_buffer_size,
+                max_frame_size: peers.max_frame_size,
+            },
+            alerts_buffer_size: peers.alerts_buffer_size,
+            tracker: TrackerConfig {
+                announce_attempts: tracker.announce_attempts,
+                retry_delay: Duration::from_secs(tracker.retry_delay),
+            },
+            dht: DhtConfig {
+                enabled: dht.enabled,
+                bootstrap_nodes: dht.bootstrap_nodes.clone(),
+            },
+        }
+    }
+}
+
+fn deserialize_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Rate {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Rate::deserialize(deserializer)? {
+        Rate::Bytes(rate) => Ok(Some(rate)),
+        Rate::Text(rate) => parse_byte_rate(&rate)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Args {
+        #[command(flatten)]
+        session: SessionArgs,
+    }
+
+    #[test]
+    fn test_flags_override_the_file() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [network]
+            port = 7000
+            encryption = "forced"
+
+            [limits]
+            max_download_rate = "2M"
+            max_upload_rate = 1000
+            "#,
+        )
+        .unwrap();
+        // whatever the file leaves out stays at the default.
+        assert_eq!(config.limits.max_connections, 200);
+        assert_eq!(config.limits.max_download_rate, Some(2 << 20));
+
+        let args = Args::parse_from(["crux-torrent", "--port", "7001", "--max-upload-rate", "2K"]);
+        config.apply(&args.session);
+        assert_eq!(config.network.port, 7001);
+        assert_eq!(config.network.encryption, EncryptionPolicy::Forced);
+        assert_eq!(config.limits.max_download_rate, Some(2 << 20));
+        assert_eq!(config.limits.max_upload_rate, Some(2 << 10));
+    }
+
+    #[test]
+    fn test_env_overrides_the_file() {
+        let mut config: Config = toml::from_str("[timeouts]\nrequest = 30\n").unwrap();
+        // nothing else reads this variable, so setting it doesn't race with the other tests.
+        std::env::set_var("CRUX_TORRENT_REQUEST_TIMEOUT", "45");
+        let args = Args::parse_from(["crux-torrent"]);
+        std::env::remove_var("CRUX_TORRENT_REQUEST_TIMEOUT");
+        config.apply(&args.session);
+        assert_eq!(config.timeouts.request, 45);
+    }
+
+    #[test]
+    fn test_shown_config_reads_back() {
+        let config = Config::default();
+        let shown = toml::to_string(&config).unwrap();
+        let read: Config = toml::from_str(&shown).unwrap();
+        assert_eq!(read.network.port, config.network.port);
+        assert_eq!(read.timeouts.request, config.timeouts.request);
+    }
+
+    #[test]
+    fn test_unknown_settings_are_rejected() {
+        assert!(toml::from_str::<Config>("[limits]\nmax_conections = 10\n").is_err());
+    }
+}
@@ -0,0 +1,383 @@
+//! keeps a torrent connected to as many peers as the limits allow, reconnecting to peers as
+//! others drop off.
+mod pool;
+
+pub use pool::PeerPool;
+
+use crate::peers::download_worker::{
+    ConnectionConfig, InboundPeer, PeerAddr, PeerDownloadWorker, PeerDownloaderConnection,
+};
+use crate::peers::{PeerAlerts, PeerError, PeerErrorAction};
+use crate::prelude::*;
+use crate::torrent::{InfoHash, PeerId};
+use std::collections::HashSet;
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    /// connections across all torrents.
+    pub max_connections: usize,
+    pub max_connections_per_torrent: usize,
+    /// outgoing connections across all torrents which haven't finished the handshake yet.
+    pub max_half_open: usize,
+    /// time an outgoing connection gets to finish the handshake.
+    pub connect_timeout: Duration,
+}
+
+/// connection counts shared by all the torrents, the global limits are checked against these.
+#[derive(Debug, Default)]
+pub struct ConnectionCounts {
+    connections: AtomicUsize,
+    half_open: AtomicUsize,
+}
+
+impl ConnectionCounts {
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn half_open(&self) -> usize {
+        self.half_open.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Origin {
+    Outbound(SocketAddrV4),
+    Inbound(SocketAddrV4),
+}
+
+#[derive(Debug)]
+pub struct ConnectionManager {
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    config: ConnectionConfig,
+    alerts_tx: mpsc::Sender<PeerAlerts>,
+    limits: ConnectionLimits,
+    counts: Arc<ConnectionCounts>,
+
+    pool: PeerPool,
+    // every task hands back where its connection came from, along with how it ended.
+    tasks: JoinSet<(Origin, Result<(), PeerError>)>,
+    // outgoing connections which are still connecting or handshaking.
+    half_open: HashSet<SocketAddrV4>,
+    connected_tx: mpsc::UnboundedSender<SocketAddrV4>,
+    connected_rx: mpsc::UnboundedReceiver<SocketAddrV4>,
+    refill_interval: tokio::time::Interval,
+}
+
+impl ConnectionManager {
+    // how often peers whose backoff ran out are picked up, when nothing else is going on.
+    const REFILL_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        config: ConnectionConfig,
+        alerts_tx: mpsc::Sender<PeerAlerts>,
+        limits: ConnectionLimits,
+        counts: Arc<ConnectionCounts>,
+    ) -> Self {
+        let (connected_tx, connected_rx) = mpsc::unbounded_channel();
+        Self {
+            info_hash,
+            peer_id,
+            config,
+            alerts_tx,
+            limits,
+            counts,
+            pool: PeerPool::new(),
+            tasks: JoinSet::new(),
+            half_open: HashSet::new(),
+            connected_tx,
+            connected_rx,
+            refill_interval: tokio::time::interval(Self::REFILL_INTERVAL),
+        }
+    }
+
+    /// adds peers learnt about from any source to the pool, and connects to them if there's room.
+    pub fn add_peers(&mut self, peer_addrs: impl IntoIterator<Item = SocketAddrV4>) {
+        let now = Instant::now();
+        let added = peer_addrs
+            .into_iter()
+            .filter(|peer_addr| self.pool.add(*peer_addr, now))
+            .count();
+        debug!(added, pool_size = self.pool.len(), "added peers to pool");
+        self.fill();
+    }
+
+    /// takes over a connection opened by a remote peer, unless the limits are reached.
+    pub fn accept(&mut self, inbound: InboundPeer) {
+        if !self.has_room() {
+            info!(peer = %inbound.peer_addr(), "connection limit reached, rejecting inbound peer");
+            return;
+        }
+
+        let peer_addr = inbound.peer_addr();
+        self.counts.connections.fetch_add(1, Ordering::Relaxed);
+        let connection = accept_peer(
+            inbound,
+            self.alerts_tx.clone(),
+            self.info_hash.clone(),
+            self.peer_id.clone(),
+            self.config.clone(),
+            self.limits.connect_timeout,
+        );
+        self.tasks
+            .spawn(async move { (Origin::Inbound(peer_addr), connection.await) });
+    }
+
+    /// waits on the next change in the state of the connections, and tops them up afterwards.
+    /// it's cancel safe.
+    pub async fn next_event(&mut self) {
+        tokio::select! {
+            Some(peer_addr) = self.connected_rx.recv() => self.on_connected(peer_addr),
+            Some(finished) = self.tasks.join_next() => {
+                // a connection which closes right away may have its connected message still queued.
+                while let Ok(peer_addr) = self.connected_rx.try_recv() {
+                    self.on_connected(peer_addr);
+                }
+                self.on_finished(finished);
+            }
+            _ = self.refill_interval.tick() => {}
+        }
+        self.fill();
+    }
+
+    fn fill(&mut self) {
+        let now = Instant::now();
+        while self.has_room() && self.counts.half_open() < self.limits.max_half_open {
+            let Some(peer_addr) = self.pool.next_candidate(now) else {
+                break;
+            };
+            self.connect(peer_addr);
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        self.tasks.len() < self.limits.max_connections_per_torrent
+            && self.counts.connections() < self.limits.max_connections
+    }
+
+    fn connect(&mut self, peer_addr: SocketAddrV4) {
+        debug!(%peer_addr, "connecting to peer");
+        self.counts.connections.fetch_add(1, Ordering::Relaxed);
+        self.counts.half_open.fetch_add(1, Ordering::Relaxed);
+        self.half_open.insert(peer_addr);
+
+        let connection = spawn_peer(
+            peer_addr,
+            self.alerts_tx.clone(),
+            self.info_hash.clone(),
+            self.peer_id.clone(),
+            self.config.clone(),
+            self.limits.connect_timeout,
+            self.connected_tx.clone(),
+        );
+        self.tasks
+            .spawn(async move { (Origin::Outbound(peer_addr), connection.await) });
+    }
+
+    fn on_connected(&mut self, peer_addr: SocketAddrV4) {
+        if self.half_open.remove(&peer_addr) {
+            self.counts.half_open.fetch_sub(1, Ordering::Relaxed);
+            self.pool.on_connected(peer_addr);
+        }
+    }
+
+    fn on_finished(&mut self, finished: Result<(Origin, Result<(), PeerError>), JoinError>) {
+        self.counts.connections.fetch_sub(1, Ordering::Relaxed);
+        let (origin, result) = match finished {
+            Ok(finished) => finished,
+            Err(err) => {
+                warn!(%err, "peer task panicked");
+                return;
+            }
+        };
+
+        let (Origin::Outbound(peer_addr) | Origin::Inbound(peer_addr)) = origin;
+        let handshaking =
+            matches!(origin, Origin::Outbound(_)) && self.half_open.remove(&peer_addr);
+        if handshaking {
+            self.counts.half_open.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        let action = match &result {
+            Ok(()) => PeerErrorAction::Retry,
+            Err(err) => err.action(),
+        };
+        match (&result, handshaking) {
+            (Ok(()), true) => info!(%peer_addr, "peer closed connection during handshake"),
+            (Err(err), true) => info!(%peer_addr, %err, ?action, "failed to connect to peer"),
+            (Ok(()), false) => info!(%peer_addr, "peer connection closed"),
+            (Err(err), false) => {
+                info!(%peer_addr, %err, ?action, "peer connection closed with error")
+            }
+        }
+
+        let now = Instant::now();
+        match action {
+            PeerErrorAction::Retry if handshaking => self.pool.on_failed(peer_addr, now),
+            PeerErrorAction::Retry => self.pool.on_disconnected(peer_addr, now),
+            PeerErrorAction::GiveUp => self.pool.forget(peer_addr),
+            PeerErrorAction::Ban => self.pool.ban(*peer_addr.ip()),
+        }
+    }
+}
+
+impl Drop for ConnectionManager {
+    // the tasks get aborted along with the join set, so their slots free up as well.
+    fn drop(&mut self) {
+        self.counts
+            .connections
+            .fetch_sub(self.tasks.len(), Ordering::Relaxed);
+        self.counts
+            .half_open
+            .fetch_sub(self.half_open.len(), Ordering::Relaxed);
+    }
+}
+
+#[instrument(
+    level = "info",
+    name = "peer worker",
+    fields(peer = %peer_addr),
+    skip_all
+)]
+async fn spawn_peer(
+    peer_addr: SocketAddrV4,
+    alerts_channel: mpsc::Sender<PeerAlerts>,
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    config: ConnectionConfig,
+    connect_timeout: Duration,
+    connected_tx: mpsc::UnboundedSender<SocketAddrV4>,
+) -> Result<(), PeerError> {
+    let connx = PeerAddr::new(peer_addr);
+    let connection = tokio::time::timeout(
+        connect_timeout,
+        connx.handshake(info_hash, peer_id, &config),
+    )
+    .await
+    .map_err(|_| PeerError::Timeout("connection"))??;
+
+    let _ = connected_tx.send(peer_addr);
+    run_peer(connection, alerts_channel).await
+}
+
+#[instrument(
+    level = "info",
+    name = "inbound peer worker",
+    fields(peer = %inbound.peer_addr()),
+    skip_all
+)]
+async fn accept_peer(
+    inbound: InboundPeer,
+    alerts_channel: mpsc::Sender<PeerAlerts>,
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    config: ConnectionConfig,
+    handshake_timeout: Duration,
+) -> Result<(), PeerError> {
+    let connection = tokio::time::timeout(
+        handshake_timeout,
+        inbound.handshake(info_hash, peer_id, &config),
+    )
+    .await
+    .map_err(|_| PeerError::Timeout("handshake"))??;
+
+    run_peer(connection, alerts_channel).await
+}
+
+async fn run_peer(
+    connection: PeerDownloaderConnection,
+    alerts_channel: mpsc::Sender<PeerAlerts>,
+) -> Result<(), PeerError> {
+    let mut worker = PeerDownloadWorker::init_from(connection, alerts_channel).await?;
+    worker.start_peer_event_loop().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_protocol::handshake::{HandshakeCodec, PeerHandshake};
+    use crate::peer_protocol::mse::EncryptionPolicy;
+    use crate::peers::download_worker::WorkerConfig;
+    use crate::peers::{PeerCommands, PeerRegistry};
+    use crate::rate_limit::StreamLimiters;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_util::codec::Framed;
+
+    #[tokio::test]
+    async fn test_peer_banned_by_engine_not_redialed() {
+        let info_hash = InfoHash::new([1; 20]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let std::net::SocketAddr::V4(peer_addr) = listener.local_addr().unwrap() else {
+            unreachable!("bound to an ipv4 address");
+        };
+
+        let config = ConnectionConfig {
+            encryption: EncryptionPolicy::Disabled,
+            utp_socket: None,
+            rate_limiters: StreamLimiters::new(),
+            peer_rate_limits: (None, None),
+            peer_registry: PeerRegistry::new(),
+            worker: WorkerConfig::default(),
+            metadata: None,
+        };
+        let limits = ConnectionLimits {
+            max_connections: 10,
+            max_connections_per_torrent: 10,
+            max_half_open: 10,
+            connect_timeout: Duration::from_secs(5),
+        };
+        let (alerts_tx, mut alerts_rx) = mpsc::channel(16);
+        let mut manager = ConnectionManager::new(
+            info_hash.clone(),
+            PeerId::random(),
+            config,
+            alerts_tx,
+            limits,
+            Arc::new(ConnectionCounts::default()),
+        );
+        manager.add_peers([peer_addr]);
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = Framed::new(stream, HandshakeCodec::new());
+        stream.next().await.unwrap().unwrap();
+        stream
+            .send(PeerHandshake::new(info_hash, PeerId::random()))
+            .await
+            .unwrap();
+        let mut stream = stream.into_inner();
+        // a bitfield with a single piece.
+        stream.write_all(&[0, 0, 0, 2, 5, 0x80]).await.unwrap();
+
+        let Some(PeerAlerts::InitPeer { commands_tx, .. }) = alerts_rx.recv().await else {
+            panic!("expected the worker to init");
+        };
+        commands_tx.send(PeerCommands::Ban).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !manager.pool.is_empty() {
+                manager.next_event().await;
+            }
+        })
+        .await
+        .unwrap();
+        // the worker closed the connection.
+        stream.read_to_end(&mut Vec::new()).await.unwrap();
+
+        manager.add_peers([peer_addr]);
+        assert!(manager.pool.is_empty());
+        let redial = tokio::time::timeout(Duration::from_millis(200), listener.accept()).await;
+        assert!(redial.is_err(), "banned peer was dialed again");
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PoolState {
+    /// can be connected to once the backoff runs out.
+    Idle {
+        retry_at: Instant,
+    },
+    Connecting,
+    Connected,
+}
+
+#[derive(Debug)]
+struct KnownPeer {
+    state: PoolState,
+    // consecutive failed connection attempts, reset once a connection goes through.
+    failures: u32,
+}
+
+/// every peer address a torrent has heard of, along with when it may next be connected to.
+#[derive(Debug, Default)]
+pub struct PeerPool {
+    peers: HashMap<SocketAddrV4, KnownPeer>,
+    // peers which misbehaved are never added back.
+    banned: HashSet<Ipv4Addr>,
+}
+
+impl PeerPool {
+    const BASE_BACKOFF: Duration = Duration::from_secs(15);
+    const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+    // peers which keep failing past this are forgotten, until some source tells us about them again.
+    const MAX_FAILURES: u32 = 8;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds a peer learnt about from a tracker or any other source, returns whether it's new.
+    pub fn add(&mut self, peer_addr: SocketAddrV4, now: Instant) -> bool {
+        if self.peers.contains_key(&peer_addr) || self.banned.contains(peer_addr.ip()) {
+            return false;
+        }
+        self.peers.insert(
+            peer_addr,
+            KnownPeer {
+                state: PoolState::Idle { retry_at: now },
+                failures: 0,
+            },
+        );
+        true
+    }
+
+    /// picks a peer which is due to be connected to, and marks it connecting.
+    pub fn next_candidate(&mut self, now: Instant) -> Option<SocketAddrV4> {
+        let (peer_addr, peer) = self
+            .peers
+            .iter_mut()
+            .filter(
+                |(_, peer)| matches!(peer.state, PoolState::Idle { retry_at } if retry_at <= now),
+            )
+            .min_by_key(|(_, peer)| peer.failures)?;
+
+        peer.state = PoolState::Connecting;
+        Some(*peer_addr)
+    }
+
+    pub fn on_connected(&mut self, peer_addr: SocketAddrV4) {
+        if let Some(peer) = self.peers.get_mut(&peer_addr) {
+            peer.state = PoolState::Connected;
+            peer.failures = 0;
+        }
+    }
+
+    /// the connection attempt failed, the peer is retried after an exponential backoff.
+    pub fn on_failed(&mut self, peer_addr: SocketAddrV4, now: Instant) {
+        let Some(peer) = self.peers.get_mut(&peer_addr) else {
+            return;
+        };
+
+        peer.failures += 1;
+        if peer.failures > Self::MAX_FAILURES {
+            self.peers.remove(&peer_addr);
+            return;
+        }
+        peer.state = PoolState::Idle {
+            retry_at: now + Self::backoff(peer.failures),
+        };
+    }
+
+    /// an established connection closed, the peer may be reconnected to after the base backoff.
+    pub fn on_disconnected(&mut self, peer_addr: SocketAddrV4, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(&peer_addr) {
+            peer.state = PoolState::Idle {
+                retry_at: now + Self::BASE_BACKOFF,
+            };
+        }
+    }
+
+    /// drops the peer, it's only connected to again if some source tells us about it again.
+    pub fn forget(&mut self, peer_addr: SocketAddrV4) {
+        self.peers.remove(&peer_addr);
+    }
+
+    /// drops every peer on the ip, and keeps them from being added again.
+    pub fn ban(&mut self, ip: Ipv4Addr) {
+        self.banned.insert(ip);
+        self.peers.retain(|peer_addr, _| *peer_addr.ip() != ip);
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    fn backoff(failures: u32) -> Duration {
+        Self::BASE_BACKOFF
+            .saturating_mul(1 << failures.saturating_sub(1).min(16))
+            .min(Self::MAX_BACKOFF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn addr(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)
+    }
+
+    #[test]
+    fn test_backoff_doubles_until_capped() {
+        assert_eq!(PeerPool::backoff(1), PeerPool::BASE_BACKOFF);
+        assert_eq!(PeerPool::backoff(3), PeerPool::BASE_BACKOFF * 4);
+        assert_eq!(PeerPool::backoff(30), PeerPool::MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_failed_peer_retried_after_backoff() {
+        let now = Instant::now();
+        let mut pool = PeerPool::new();
+        assert!(pool.add(addr(1), now));
+        assert!(!pool.add(addr(1), now));
+
+        assert_eq!(pool.next_candidate(now), Some(addr(1)));
+        assert_eq!(pool.next_candidate(now), None);
+
+        pool.on_failed(addr(1), now);
+        assert_eq!(pool.next_candidate(now), None);
+        assert_eq!(
+            pool.next_candidate(now + PeerPool::BASE_BACKOFF),
+            Some(addr(1))
+        );
+    }
+
+    #[test]
+    fn test_forgets_hopeless_peers() {
+        let now = Instant::now();
+        let mut pool = PeerPool::new();
+        pool.add(addr(1), now);
+        for _ in 0..=PeerPool::MAX_FAILURES {
+            pool.on_failed(addr(1), now);
+        }
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn test_banned_peers_not_added_again() {
+        let now = Instant::now();
+        let mut pool = PeerPool::new();
+        pool.add(addr(1), now);
+        pool.add(addr(2), now);
+
+        pool.ban(*addr(1).ip());
+        assert_eq!(pool.len(), 0);
+        assert!(!pool.add(addr(3), now));
+    }
+}
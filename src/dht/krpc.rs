@@ -0,0 +1,215 @@
+//! KRPC, the bencoded query/response protocol dht nodes talk over UDP.
+use super::routing::NodeId;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub const PROTOCOL_ERROR: (i64, &str) = (203, "protocol error");
+pub const METHOD_UNKNOWN: (i64, &str) = (204, "method unknown");
+
+const COMPACT_PEER_LEN: usize = 6;
+const COMPACT_NODE_LEN: usize = NodeId::LEN + COMPACT_PEER_LEN;
+
+/// a query, response or error, told apart by `y`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    /// arguments of a query.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub a: Option<Args>,
+
+    /// error code and message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<(i64, String)>,
+
+    /// the queried method.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+
+    /// values of a response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<Reply>,
+
+    /// transaction id picked by the querying node and echoed back.
+    pub t: ByteBuf,
+
+    /// "q", "r" or "e".
+    pub y: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Args {
+    pub id: ByteBuf,
+
+    /// announce the port the message came from instead of `port`, for peers behind a NAT.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<i64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Reply {
+    pub id: ByteBuf,
+
+    /// compact node infos of the closest nodes the responder knows of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+
+    /// handed out by get_peers, and required to announce to the responder.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+
+    /// compact addresses of peers of the torrent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+}
+
+impl Message {
+    pub fn query(transaction: &[u8], method: &str, args: Args) -> Self {
+        Self {
+            a: Some(args),
+            q: Some(method.to_owned()),
+            t: ByteBuf::from(transaction),
+            y: "q".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn reply(transaction: &[u8], reply: Reply) -> Self {
+        Self {
+            r: Some(reply),
+            t: ByteBuf::from(transaction),
+            y: "r".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn error(transaction: &[u8], (code, message): (i64, &str)) -> Self {
+        Self {
+            e: Some((code, message.to_owned())),
+            t: ByteBuf::from(transaction),
+            y: "e".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_bencode::Error> {
+        serde_bencode::from_bytes(bytes)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, serde_bencode::Error> {
+        serde_bencode::to_bytes(self)
+    }
+}
+
+impl Args {
+    pub fn new(id: &NodeId) -> Self {
+        Self {
+            id: ByteBuf::from(id.as_bytes().as_slice()),
+            ..Default::default()
+        }
+    }
+}
+
+impl Reply {
+    pub fn new(id: &NodeId) -> Self {
+        Self {
+            id: ByteBuf::from(id.as_bytes().as_slice()),
+            ..Default::default()
+        }
+    }
+
+    pub fn nodes(&self) -> Vec<(NodeId, SocketAddrV4)> {
+        self.nodes
+            .as_ref()
+            .map(|nodes| decode_nodes(nodes))
+            .unwrap_or_default()
+    }
+
+    pub fn peers(&self) -> Vec<SocketAddrV4> {
+        self.values
+            .iter()
+            .flatten()
+            .filter_map(|value| decode_peer(value))
+            .collect()
+    }
+}
+
+pub fn encode_nodes(nodes: &[(NodeId, SocketAddrV4)]) -> ByteBuf {
+    let mut bytes = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+    for (id, addr) in nodes {
+        bytes.extend_from_slice(id.as_bytes());
+        bytes.extend_from_slice(&encode_peer(*addr));
+    }
+    ByteBuf::from(bytes)
+}
+
+/// trailing bytes which don't make up a whole node are ignored.
+pub fn decode_nodes(bytes: &[u8]) -> Vec<(NodeId, SocketAddrV4)> {
+    bytes
+        .chunks_exact(COMPACT_NODE_LEN)
+        .filter_map(|chunk| {
+            let (id, addr) = chunk.split_at(NodeId::LEN);
+            Some((NodeId::from_bytes(id)?, decode_peer(addr)?))
+        })
+        .collect()
+}
+
+pub fn encode_peer(addr: SocketAddrV4) -> ByteBuf {
+    let mut bytes = Vec::with_capacity(COMPACT_PEER_LEN);
+    bytes.extend_from_slice(&addr.ip().octets());
+    bytes.extend_from_slice(&addr.port().to_be_bytes());
+    ByteBuf::from(bytes)
+}
+
+pub fn decode_peer(bytes: &[u8]) -> Option<SocketAddrV4> {
+    let bytes: [u8; COMPACT_PEER_LEN] = bytes.try_into().ok()?;
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    Some(SocketAddrV4::new(
+        ip,
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodes_like_the_bep() {
+        let id = NodeId::from_bytes(b"abcdefghij0123456789").unwrap();
+        let ping = Message::query(b"aa", "ping", Args::new(&id));
+        let bytes = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        assert_eq!(ping.to_bytes().unwrap(), bytes);
+        assert_eq!(Message::from_bytes(bytes).unwrap(), ping);
+
+        let bytes = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+        let error = Message::from_bytes(bytes).unwrap();
+        assert_eq!(
+            error,
+            Message::error(b"aa", (201, "A Generic Error Ocurred"))
+        );
+        assert_eq!(error.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_compact_nodes_roundtrip() {
+        let nodes = vec![
+            (NodeId::random(), "10.0.0.1:6881".parse().unwrap()),
+            (NodeId::random(), "192.168.1.20:51413".parse().unwrap()),
+        ];
+        let mut bytes = encode_nodes(&nodes).into_vec();
+        bytes.push(0);
+        assert_eq!(decode_nodes(&bytes), nodes);
+    }
+}
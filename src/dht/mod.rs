@@ -0,0 +1,508 @@
+//! the mainline dht, finding the peers of a torrent without asking a tracker.
+//! https://www.bittorrent.org/beps/bep_0005.html
+mod krpc;
+mod routing;
+
+use crate::peer_protocol::utp::UtpSocket;
+use crate::prelude::*;
+use crate::torrent::InfoHash;
+use krpc::{Args, Message, Reply};
+use routing::{NodeId, RoutingTable};
+use serde_bytes::ByteBuf;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DhtError {
+    #[error("dht node didn't answer")]
+    Timeout,
+    #[error("dht node answered with error {0}: {1}")]
+    Remote(i64, String),
+    #[error("invalid dht message: {0}")]
+    Invalid(&'static str),
+    #[error("failed to encode dht message: {0}")]
+    Encode(#[from] serde_bencode::Error),
+    #[error("failed to send dht message: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhtConfig {
+    pub enabled: bool,
+    /// host:port of the nodes the routing table is filled from, as long as it's empty.
+    pub bootstrap_nodes: Vec<String>,
+}
+
+impl DhtConfig {
+    pub const DEFAULT_BOOTSTRAP_NODES: [&'static str; 3] = [
+        "router.bittorrent.com:6881",
+        "dht.transmissionbt.com:6881",
+        "router.utorrent.com:6881",
+    ];
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            bootstrap_nodes: Self::DEFAULT_BOOTSTRAP_NODES.map(str::to_owned).to_vec(),
+        }
+    }
+}
+
+/// a dht node sharing the UDP port of a uTP socket, it can be cheaply cloned.
+#[derive(Debug, Clone)]
+pub struct DhtNode {
+    shared: Arc<DhtShared>,
+    _driver: Arc<DriverHandle>,
+}
+
+type PendingReply = oneshot::Sender<Result<Reply, DhtError>>;
+
+#[derive(Debug)]
+struct DhtShared {
+    id: NodeId,
+    socket: UtpSocket,
+    bootstrap_nodes: Vec<String>,
+    state: Mutex<DhtState>,
+}
+
+#[derive(Debug)]
+struct DhtState {
+    table: RoutingTable,
+    next_transaction: u16,
+    pending: HashMap<u16, (SocketAddrV4, PendingReply)>,
+    /// peers announced to us, with when they did.
+    peers: HashMap<NodeId, HashMap<SocketAddrV4, Instant>>,
+    secret: [u8; 16],
+    previous_secret: [u8; 16],
+    secret_rotated_at: Instant,
+}
+
+// stops answering queries once the last node handle is gone.
+#[derive(Debug)]
+struct DriverHandle(JoinHandle<()>);
+
+impl Drop for DriverHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// the outcome of an iterative lookup.
+#[derive(Debug, Default)]
+struct Lookup {
+    peers: Vec<SocketAddrV4>,
+    /// the closest nodes which answered, with the token they handed out for announcing.
+    closest: Vec<(SocketAddrV4, Option<ByteBuf>)>,
+}
+
+impl DhtNode {
+    /// queries in flight at once during a lookup.
+    const ALPHA: usize = 3;
+    const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+    const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+    const TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+    const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+    /// keeps get_peers replies within a single datagram.
+    const MAX_REPLY_PEERS: usize = 50;
+    const MAX_STORED_PEERS: usize = 1000;
+
+    /// panics if the socket already serves another dht node.
+    pub fn new(socket: UtpSocket, bootstrap_nodes: Vec<String>) -> Self {
+        let datagrams = socket
+            .take_dht_datagrams()
+            .expect("the socket already serves a dht node");
+        let id = NodeId::random();
+        let shared = Arc::new(DhtShared {
+            id,
+            socket,
+            bootstrap_nodes,
+            state: Mutex::new(DhtState {
+                table: RoutingTable::new(id),
+                next_transaction: rand::random(),
+                pending: HashMap::new(),
+                peers: HashMap::new(),
+                secret: rand::random(),
+                previous_secret: rand::random(),
+                secret_rotated_at: Instant::now(),
+            }),
+        });
+        let driver = tokio::spawn(Self::drive(shared.clone(), datagrams));
+
+        Self {
+            shared,
+            _driver: Arc::new(DriverHandle(driver)),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+
+    /// the number of nodes in the routing table.
+    pub fn node_count(&self) -> usize {
+        self.shared.state.lock().unwrap().table.len()
+    }
+
+    /// fills the routing table from the bootstrap nodes, and then with the nodes closest to us.
+    pub async fn bootstrap(&self) {
+        let mut addrs = Vec::new();
+        for node in &self.shared.bootstrap_nodes {
+            match tokio::net::lookup_host(node.as_str()).await {
+                Ok(resolved) => addrs.extend(resolved.filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                })),
+                Err(err) => debug!(%err, node, "failed to resolve dht bootstrap node"),
+            }
+        }
+
+        let id = self.shared.id;
+        let queries = addrs.into_iter().map(|addr| async move {
+            let mut args = Args::new(&id);
+            args.target = Some(ByteBuf::from(id.as_bytes().as_slice()));
+            if let Err(err) = self.shared.query(addr, "find_node", args).await {
+                debug!(%err, %addr, "dht bootstrap node didn't answer");
+            }
+        });
+        futures::future::join_all(queries).await;
+        self.shared.lookup(id, None).await;
+        debug!(nodes = self.node_count(), "dht bootstrapped");
+    }
+
+    pub async fn get_peers(&self, info_hash: &InfoHash) -> Vec<SocketAddrV4> {
+        self.bootstrap_if_empty().await;
+        self.shared
+            .lookup(info_hash.into(), Some(info_hash))
+            .await
+            .peers
+    }
+
+    /// looks up the peers of the torrent, and tells the closest nodes we're one of them.
+    pub async fn announce(&self, info_hash: &InfoHash, port: u16) -> Vec<SocketAddrV4> {
+        self.bootstrap_if_empty().await;
+        let lookup = self.shared.lookup(info_hash.into(), Some(info_hash)).await;
+
+        let announces = lookup
+            .closest
+            .into_iter()
+            .filter_map(|(addr, token)| Some((addr, token?)))
+            .map(|(addr, token)| {
+                let mut args = Args::new(&self.shared.id);
+                args.info_hash = Some(ByteBuf::from(info_hash.as_ref().as_slice()));
+                args.port = Some(port);
+                args.token = Some(token);
+                async move {
+                    if let Err(err) = self.shared.query(addr, "announce_peer", args).await {
+                        trace!(%err, %addr, "dht announce failed");
+                    }
+                }
+            });
+        futures::future::join_all(announces).await;
+        lookup.peers
+    }
+
+    async fn bootstrap_if_empty(&self) {
+        if self.node_count() == 0 {
+            self.bootstrap().await;
+        }
+    }
+
+    async fn drive(shared: Arc<DhtShared>, mut datagrams: mpsc::Receiver<(Vec<u8>, SocketAddr)>) {
+        let mut maintenance = tokio::time::interval(Self::MAINTENANCE_INTERVAL);
+        maintenance.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                Some((datagram, remote)) = datagrams.recv() => {
+                    // ipv6 has a dht of its own, https://www.bittorrent.org/beps/bep_0032.html
+                    let SocketAddr::V4(remote) = remote else {
+                        continue;
+                    };
+                    shared.on_datagram(&datagram, remote).await;
+                }
+                _ = maintenance.tick() => shared.state.lock().unwrap().maintain(),
+                else => break,
+            }
+        }
+    }
+}
+
+impl DhtShared {
+    async fn on_datagram(&self, datagram: &[u8], remote: SocketAddrV4) {
+        let message = match Message::from_bytes(datagram) {
+            Ok(message) => message,
+            Err(err) => {
+                trace!(%err, %remote, "dropping invalid dht message");
+                return;
+            }
+        };
+
+        match message.y.as_str() {
+            "q" => {
+                let response = match self.answer(&message, remote) {
+                    Ok(reply) => Message::reply(&message.t, reply),
+                    Err(error) => Message::error(&message.t, error),
+                };
+                self.send(&response, remote).await.ok();
+            }
+            "r" | "e" => {
+                let Ok(transaction) = <[u8; 2]>::try_from(message.t.as_slice()) else {
+                    return;
+                };
+                let mut state = self.state.lock().unwrap();
+                let transaction = u16::from_be_bytes(transaction);
+                // a response from anyone but the queried node is ignored.
+                if !matches!(state.pending.get(&transaction), Some((addr, _)) if *addr == remote) {
+                    return;
+                }
+                let (_, reply_tx) = state.pending.remove(&transaction).unwrap();
+                let result = match (message.r, message.e) {
+                    (Some(reply), _) => Ok(reply),
+                    (None, Some((code, message))) => Err(DhtError::Remote(code, message)),
+                    (None, None) => Err(DhtError::Invalid("response without values")),
+                };
+                reply_tx.send(result).ok();
+            }
+            y => trace!(%remote, y, "dropping dht message of unknown type"),
+        }
+    }
+
+    fn answer(&self, query: &Message, remote: SocketAddrV4) -> Result<Reply, (i64, &'static str)> {
+        let args = query.a.as_ref().ok_or(krpc::PROTOCOL_ERROR)?;
+        let id = NodeId::from_bytes(&args.id).ok_or(krpc::PROTOCOL_ERROR)?;
+        let mut state = self.state.lock().unwrap();
+        state.table.insert(id, remote);
+
+        let mut reply = Reply::new(&self.id);
+        match query.q.as_deref() {
+            Some("ping") => {}
+            Some("find_node") => {
+                let target = args
+                    .target
+                    .as_deref()
+                    .and_then(|bytes| NodeId::from_bytes(bytes));
+                let target = target.ok_or(krpc::PROTOCOL_ERROR)?;
+                let closest = state.table.closest(&target, RoutingTable::BUCKET_SIZE);
+                reply.nodes = Some(krpc::encode_nodes(&closest));
+            }
+            Some("get_peers") => {
+                let info_hash = args
+                    .info_hash
+                    .as_deref()
+                    .and_then(|bytes| NodeId::from_bytes(bytes));
+                let info_hash = info_hash.ok_or(krpc::PROTOCOL_ERROR)?;
+                reply.token = Some(DhtState::token(remote.ip(), &state.secret));
+                let peers = state.peers.get(&info_hash).into_iter().flatten();
+                let peers: Vec<_> = peers
+                    .take(DhtNode::MAX_REPLY_PEERS)
+                    .map(|(addr, _)| krpc::encode_peer(*addr))
+                    .collect();
+                if peers.is_empty() {
+                    let closest = state.table.closest(&info_hash, RoutingTable::BUCKET_SIZE);
+                    reply.nodes = Some(krpc::encode_nodes(&closest));
+                } else {
+                    reply.values = Some(peers);
+                }
+            }
+            Some("announce_peer") => {
+                let info_hash = args
+                    .info_hash
+                    .as_deref()
+                    .and_then(|bytes| NodeId::from_bytes(bytes));
+                let info_hash = info_hash.ok_or(krpc::PROTOCOL_ERROR)?;
+                let token = args.token.as_deref().ok_or(krpc::PROTOCOL_ERROR)?;
+                if !state.is_valid_token(token, remote.ip()) {
+                    return Err((203, "bad token"));
+                }
+                let port = match args.implied_port {
+                    Some(1) => remote.port(),
+                    _ => args.port.ok_or(krpc::PROTOCOL_ERROR)?,
+                };
+                let peers = state.peers.entry(info_hash).or_default();
+                if peers.len() < DhtNode::MAX_STORED_PEERS {
+                    peers.insert(SocketAddrV4::new(*remote.ip(), port), Instant::now());
+                }
+            }
+            _ => return Err(krpc::METHOD_UNKNOWN),
+        }
+        Ok(reply)
+    }
+
+    async fn query(&self, addr: SocketAddrV4, method: &str, args: Args) -> Result<Reply, DhtError> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let transaction = {
+            let mut state = self.state.lock().unwrap();
+            let transaction = state.next_transaction;
+            state.next_transaction = transaction.wrapping_add(1);
+            state.pending.insert(transaction, (addr, reply_tx));
+            transaction
+        };
+
+        let message = Message::query(&transaction.to_be_bytes(), method, args);
+        let result = async {
+            self.send(&message, addr).await?;
+            match tokio::time::timeout(DhtNode::QUERY_TIMEOUT, reply_rx).await {
+                Ok(Ok(result)) => result,
+                _ => Err(DhtError::Timeout),
+            }
+        }
+        .await;
+
+        let mut state = self.state.lock().unwrap();
+        state.pending.remove(&transaction);
+        match &result {
+            Ok(reply) => match NodeId::from_bytes(&reply.id) {
+                Some(id) => state.table.insert(id, addr),
+                None => return Err(DhtError::Invalid("node id isn't 20 bytes")),
+            },
+            Err(DhtError::Timeout) => state.table.failed(addr),
+            Err(_) => {}
+        }
+        result
+    }
+
+    async fn send(&self, message: &Message, addr: SocketAddrV4) -> Result<(), DhtError> {
+        let bytes = message.to_bytes()?;
+        self.socket.send_datagram(&bytes, addr.into()).await?;
+        Ok(())
+    }
+
+    /// walks towards the target, asking the closest nodes we know of for ones closer still
+    /// until the closest have all been asked. with an info hash it's a get_peers lookup,
+    /// collecting the peers on the way.
+    async fn lookup(&self, target: NodeId, info_hash: Option<&InfoHash>) -> Lookup {
+        let mut candidates = {
+            let state = self.state.lock().unwrap();
+            state.table.closest(&target, RoutingTable::BUCKET_SIZE)
+        };
+        let mut seen: HashSet<_> = candidates.iter().map(|(_, addr)| *addr).collect();
+        let mut asked = HashSet::new();
+        let mut answered = Vec::new();
+        let mut peers = HashSet::new();
+
+        loop {
+            candidates.sort_by_key(|(id, _)| id.distance(&target));
+            let batch: Vec<_> = candidates
+                .iter()
+                .take(RoutingTable::BUCKET_SIZE)
+                .filter(|(_, addr)| !asked.contains(addr))
+                .take(DhtNode::ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            asked.extend(batch.iter().map(|(_, addr)| *addr));
+
+            let queries = batch.iter().map(|(_, addr)| {
+                let mut args = Args::new(&self.id);
+                let target = ByteBuf::from(target.as_bytes().as_slice());
+                match info_hash {
+                    Some(_) => {
+                        args.info_hash = Some(target);
+                        self.query(*addr, "get_peers", args)
+                    }
+                    None => {
+                        args.target = Some(target);
+                        self.query(*addr, "find_node", args)
+                    }
+                }
+            });
+            let replies = futures::future::join_all(queries).await;
+
+            for ((id, addr), reply) in batch.into_iter().zip(replies) {
+                match reply {
+                    Ok(reply) => {
+                        for (id, addr) in reply.nodes() {
+                            if id != self.id && seen.insert(addr) {
+                                candidates.push((id, addr));
+                            }
+                        }
+                        peers.extend(reply.peers());
+                        answered.push((id, addr, reply.token));
+                    }
+                    Err(err) => {
+                        trace!(%err, %addr, "dht lookup query failed");
+                        // so the closest nodes which still answer get asked instead.
+                        candidates.retain(|(_, candidate)| *candidate != addr);
+                    }
+                }
+            }
+        }
+
+        answered.sort_by_key(|(id, _, _)| id.distance(&target));
+        answered.truncate(RoutingTable::BUCKET_SIZE);
+        Lookup {
+            peers: peers.into_iter().collect(),
+            closest: answered
+                .into_iter()
+                .map(|(_, addr, token)| (addr, token))
+                .collect(),
+        }
+    }
+}
+
+impl DhtState {
+    fn maintain(&mut self) {
+        let now = Instant::now();
+        if now - self.secret_rotated_at >= DhtNode::TOKEN_LIFETIME {
+            self.previous_secret = std::mem::replace(&mut self.secret, rand::random());
+            self.secret_rotated_at = now;
+        }
+        for peers in self.peers.values_mut() {
+            peers.retain(|_, announced_at| now - *announced_at < DhtNode::PEER_LIFETIME);
+        }
+        self.peers.retain(|_, peers| !peers.is_empty());
+        // queries whose lookup was dropped before they timed out.
+        self.pending
+            .retain(|_, (_, reply_tx)| !reply_tx.is_closed());
+    }
+
+    /// tokens are tied to the ip they were handed to, and stay valid for one more rotation
+    /// of the secret.
+    fn token(ip: &Ipv4Addr, secret: &[u8; 16]) -> ByteBuf {
+        let mut hasher = sha1_smol::Sha1::new();
+        hasher.update(secret);
+        hasher.update(&ip.octets());
+        ByteBuf::from(&hasher.digest().bytes()[..8])
+    }
+
+    fn is_valid_token(&self, token: &[u8], ip: &Ipv4Addr) -> bool {
+        [&self.secret, &self.previous_secret]
+            .into_iter()
+            .any(|secret| Self::token(ip, secret).as_slice() == token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn node(bootstrap_nodes: Vec<String>) -> DhtNode {
+        let socket = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        DhtNode::new(socket, bootstrap_nodes)
+    }
+
+    #[tokio::test]
+    async fn test_announced_peers_are_found() {
+        let router = node(Vec::new()).await;
+        let router_addr = router.local_addr().unwrap().to_string();
+        let seeder = node(vec![router_addr.clone()]).await;
+        let leecher = node(vec![router_addr]).await;
+        seeder.bootstrap().await;
+        leecher.bootstrap().await;
+        assert_eq!(router.node_count(), 2);
+
+        let info_hash = InfoHash::new([7; 20]);
+        assert!(seeder.announce(&info_hash, 6881).await.is_empty());
+        let peers = leecher.get_peers(&info_hash).await;
+        assert_eq!(peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+}
@@ -0,0 +1,176 @@
+//! the nodes we know of, kept in buckets by how close they are to our own id.
+use crate::torrent::InfoHash;
+use std::net::SocketAddrV4;
+
+/// ids of nodes and lookup targets share the 160 bit space of info hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId([u8; NodeId::LEN]);
+
+impl NodeId {
+    pub const LEN: usize = InfoHash::INFO_HASH_SIZE;
+
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok().map(Self)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; Self::LEN] {
+        &self.0
+    }
+
+    /// the xor metric, compared as a big endian number.
+    pub fn distance(&self, other: &Self) -> [u8; Self::LEN] {
+        std::array::from_fn(|i| self.0[i] ^ other.0[i])
+    }
+
+    fn common_prefix_len(&self, other: &Self) -> usize {
+        let distance = self.distance(other);
+        let first_difference = distance.iter().position(|byte| *byte != 0);
+        first_difference.map_or(Self::LEN * 8, |i| {
+            i * 8 + distance[i].leading_zeros() as usize
+        })
+    }
+}
+
+impl From<&InfoHash> for NodeId {
+    fn from(info_hash: &InfoHash) -> Self {
+        Self(*info_hash.as_ref())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    id: NodeId,
+    addr: SocketAddrV4,
+    /// queries in a row which went unanswered.
+    failures: u32,
+}
+
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: NodeId,
+    /// indexed by the length of the prefix a node shares with our id, so the closer buckets
+    /// cover an ever smaller part of the id space.
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub const BUCKET_SIZE: usize = 8;
+    const MAX_FAILURES: u32 = 3;
+
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); NodeId::LEN * 8],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    /// records a node which answered us, or queried us itself.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddrV4) {
+        if id == self.id {
+            return;
+        }
+        // a node which came back with a new id takes the place of its old entry.
+        for bucket in &mut self.buckets {
+            bucket.retain(|node| node.addr != addr || node.id == id);
+        }
+
+        let bucket = &mut self.buckets[self.id.common_prefix_len(&id)];
+        if let Some(node) = bucket.iter_mut().find(|node| node.id == id) {
+            node.addr = addr;
+            node.failures = 0;
+            return;
+        }
+        let node = Node {
+            id,
+            addr,
+            failures: 0,
+        };
+        if bucket.len() < Self::BUCKET_SIZE {
+            bucket.push(node);
+            return;
+        }
+        // nodes which keep answering are kept over new ones, one which stopped makes room.
+        if let Some(stale) = bucket
+            .iter_mut()
+            .filter(|node| node.failures > 0)
+            .max_by_key(|node| node.failures)
+        {
+            *stale = node;
+        }
+    }
+
+    /// a query to the node went unanswered, it's dropped after a few in a row.
+    pub fn failed(&mut self, addr: SocketAddrV4) {
+        for bucket in &mut self.buckets {
+            for node in bucket.iter_mut().filter(|node| node.addr == addr) {
+                node.failures += 1;
+            }
+            bucket.retain(|node| node.failures < Self::MAX_FAILURES);
+        }
+    }
+
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<(NodeId, SocketAddrV4)> {
+        let mut nodes: Vec<_> = self
+            .buckets
+            .iter()
+            .flatten()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        nodes.sort_by_key(|(id, _)| id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(first_byte: u8) -> NodeId {
+        let mut bytes = [0; NodeId::LEN];
+        bytes[0] = first_byte;
+        NodeId(bytes)
+    }
+
+    fn addr(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new([127, 0, 0, 1].into(), port)
+    }
+
+    #[test]
+    fn test_closest_are_sorted_by_distance() {
+        let mut table = RoutingTable::new(id(0));
+        for i in 1..=6 {
+            table.insert(id(i), addr(i as u16));
+        }
+        let closest = table.closest(&id(4), 3);
+        assert_eq!(
+            closest,
+            vec![(id(4), addr(4)), (id(5), addr(5)), (id(6), addr(6))]
+        );
+    }
+
+    #[test]
+    fn test_full_bucket_only_replaces_failing_nodes() {
+        let mut table = RoutingTable::new(id(0));
+        // all of these share no prefix with our id, and land in the same bucket.
+        for i in 0..RoutingTable::BUCKET_SIZE as u8 {
+            table.insert(id(0x80 | i), addr(i as u16));
+        }
+        table.insert(id(0xff), addr(100));
+        assert_eq!(table.len(), RoutingTable::BUCKET_SIZE);
+        assert!(table.closest(&id(0xff), 1)[0].0 != id(0xff));
+
+        table.failed(addr(3));
+        table.insert(id(0xff), addr(100));
+        assert_eq!(table.closest(&id(0xff), 1), vec![(id(0xff), addr(100))]);
+        assert!(table.closest(&id(0x83), 1)[0].0 != id(0x83));
+    }
+}
@@ -0,0 +1,123 @@
+use crate::metainfo::PieceHash;
+use crate::peers::PieceIndex;
+use sha1_smol::Sha1;
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+
+/// keeps track of peers which sent data that failed the hash check.
+///
+/// every copy of a piece is downloaded from a single peer, so a failed piece is held against the
+/// peer it came from, and the peer is banned once it has sent too many of them. smart ban comes
+/// on top of that: the block hashes of a failed copy are kept around, and once a good copy comes
+/// in from another source, the peer is banned straight away if its blocks don't match it.
+#[derive(Debug)]
+pub struct BanList {
+    smart_ban: bool,
+    strikes: HashMap<Ipv4Addr, u32>,
+    banned: HashSet<Ipv4Addr>,
+    // for every failed piece, the block hashes of the copy each peer sent.
+    suspects: HashMap<PieceIndex, HashMap<Ipv4Addr, Vec<PieceHash>>>,
+}
+
+impl BanList {
+    pub const MAX_HASH_FAILURES: u32 = 3;
+    const BLOCK_SIZE: usize = 1 << 14;
+
+    pub fn new(smart_ban: bool) -> Self {
+        Self {
+            smart_ban,
+            strikes: HashMap::new(),
+            banned: HashSet::new(),
+            suspects: HashMap::new(),
+        }
+    }
+
+    pub fn is_banned(&self, ip: &Ipv4Addr) -> bool {
+        self.banned.contains(ip)
+    }
+
+    /// whether the peer already sent bad data for the piece, so it shouldn't be the one to
+    /// download it again.
+    pub fn is_suspect(&self, ip: &Ipv4Addr, index: PieceIndex) -> bool {
+        self.suspects
+            .get(&index)
+            .is_some_and(|suspects| suspects.contains_key(ip))
+    }
+
+    /// records a copy of a piece from the peer which failed the hash check, returns whether the
+    /// peer is now banned for sending too many of them.
+    pub fn record_failure(&mut self, index: PieceIndex, ip: Ipv4Addr, piece: &[u8]) -> bool {
+        if self.smart_ban {
+            self.suspects
+                .entry(index)
+                .or_default()
+                .insert(ip, Self::block_hashes(piece));
+        }
+
+        let strikes = self.strikes.entry(ip).or_default();
+        *strikes += 1;
+        *strikes >= Self::MAX_HASH_FAILURES && self.banned.insert(ip)
+    }
+
+    /// records a piece which passed the hash check, returns the peers found to have sent bad
+    /// blocks for an earlier copy of it.
+    pub fn record_success(&mut self, index: PieceIndex, piece: &[u8]) -> Vec<Ipv4Addr> {
+        let Some(suspects) = self.suspects.remove(&index) else {
+            return Vec::new();
+        };
+
+        let good_hashes = Self::block_hashes(piece);
+        suspects
+            .into_iter()
+            .filter(|(_, hashes)| *hashes != good_hashes)
+            .filter_map(|(ip, _)| self.banned.insert(ip).then_some(ip))
+            .collect()
+    }
+
+    fn block_hashes(piece: &[u8]) -> Vec<PieceHash> {
+        piece
+            .chunks(Self::BLOCK_SIZE)
+            .map(|block| Sha1::from(block).digest().bytes())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const GOOD: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const BAD: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    #[rstest]
+    fn test_bans_repeat_offenders() {
+        let mut bans = BanList::new(false);
+        for index in 0..BanList::MAX_HASH_FAILURES as usize - 1 {
+            assert!(!bans.record_failure(index, BAD, &[0; 10]));
+        }
+        assert!(!bans.is_banned(&BAD));
+
+        assert!(bans.record_failure(7, BAD, &[0; 10]));
+        assert!(bans.is_banned(&BAD));
+        assert!(!bans.is_suspect(&BAD, 7));
+    }
+
+    #[rstest]
+    fn test_smart_ban_finds_guilty_peer() {
+        let mut bans = BanList::new(true);
+        let good_piece = vec![1u8; 3 * BanList::BLOCK_SIZE];
+        let mut bad_piece = good_piece.clone();
+        bad_piece[BanList::BLOCK_SIZE + 5] = 0;
+
+        assert!(!bans.record_failure(0, BAD, &bad_piece));
+        assert!(bans.is_suspect(&BAD, 0));
+        // banned on the first bad piece, well before running out of strikes.
+        assert_eq!(bans.record_success(0, &good_piece), [BAD]);
+        assert!(bans.is_banned(&BAD));
+        assert!(!bans.is_suspect(&BAD, 0));
+        // pieces nobody failed on don't ban anyone.
+        assert!(bans.record_success(1, &good_piece).is_empty());
+        assert!(!bans.is_banned(&GOOD));
+    }
+}
@@ -0,0 +1,147 @@
+use rand::seq::IteratorRandom;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+/// a peer which is up for consideration in a choking round.
+#[derive(Debug, Clone)]
+pub struct ChokeCandidate {
+    pub peer_addr: SocketAddrV4,
+    /// download rate from the peer while leeching, upload rate to the peer while seeding.
+    pub rate: u64,
+    pub interested: bool,
+}
+
+/// tit-for-tat choker, the peers which give us the most get unchoked, plus one optimistic slot
+/// which rotates between the rest so that new peers get a chance to prove themselves.
+/// https://www.bittorrent.org/beps/bep_0003.html
+#[derive(Debug)]
+pub struct Choker {
+    unchoke_slots: usize,
+    optimistic: Option<SocketAddrV4>,
+    last_optimistic_rotation: Option<Instant>,
+}
+
+impl Choker {
+    pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+    const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+    pub fn new(unchoke_slots: usize) -> Self {
+        Self {
+            unchoke_slots,
+            optimistic: None,
+            last_optimistic_rotation: None,
+        }
+    }
+
+    /// returns the set of peers that should be unchoked, every other peer should be choked.
+    pub fn rechoke(
+        &mut self,
+        candidates: &[ChokeCandidate],
+        now: Instant,
+    ) -> HashSet<SocketAddrV4> {
+        if self.unchoke_slots == 0 {
+            return HashSet::new();
+        }
+
+        let mut interested: Vec<_> = candidates.iter().filter(|peer| peer.interested).collect();
+        interested.sort_by_key(|peer| Reverse(peer.rate));
+
+        // one slot is always held back for the optimistic unchoke.
+        let mut unchoked: HashSet<_> = interested
+            .iter()
+            .take(self.unchoke_slots - 1)
+            .map(|peer| peer.peer_addr)
+            .collect();
+
+        let optimistic_still_valid = self.optimistic.is_some_and(|optimistic| {
+            !unchoked.contains(&optimistic)
+                && interested.iter().any(|peer| peer.peer_addr == optimistic)
+        });
+        let rotation_due = self
+            .last_optimistic_rotation
+            .is_none_or(|last| now.duration_since(last) >= Self::OPTIMISTIC_INTERVAL);
+
+        if rotation_due || !optimistic_still_valid {
+            self.optimistic = interested
+                .iter()
+                .map(|peer| peer.peer_addr)
+                .filter(|peer_addr| !unchoked.contains(peer_addr))
+                .choose(&mut rand::thread_rng());
+            self.last_optimistic_rotation = Some(now);
+        }
+
+        unchoked.extend(self.optimistic);
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+    use std::net::Ipv4Addr;
+
+    fn addr(n: u8) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, n), 6881)
+    }
+
+    fn candidate(n: u8, rate: u64, interested: bool) -> ChokeCandidate {
+        ChokeCandidate {
+            peer_addr: addr(n),
+            rate,
+            interested,
+        }
+    }
+
+    #[fixture]
+    fn candidates() -> Vec<ChokeCandidate> {
+        vec![
+            candidate(1, 100, true),
+            candidate(2, 500, true),
+            candidate(3, 300, true),
+            candidate(4, 900, false),
+            candidate(5, 10, true),
+        ]
+    }
+
+    #[rstest]
+    fn test_unchokes_fastest_interested(candidates: Vec<ChokeCandidate>) {
+        let mut choker = Choker::new(3);
+        let unchoked = choker.rechoke(&candidates, Instant::now());
+
+        assert_eq!(unchoked.len(), 3);
+        assert!(unchoked.contains(&addr(2)));
+        assert!(unchoked.contains(&addr(3)));
+        // uninterested peers are never unchoked.
+        assert!(!unchoked.contains(&addr(4)));
+    }
+
+    #[rstest]
+    fn test_optimistic_kept_until_rotation(candidates: Vec<ChokeCandidate>) {
+        let mut choker = Choker::new(2);
+        let start = Instant::now();
+        choker.rechoke(&candidates, start);
+        let optimistic = choker.optimistic.unwrap();
+        assert_ne!(optimistic, addr(2));
+
+        for round in 1..3 {
+            choker.rechoke(&candidates, start + Choker::RECHOKE_INTERVAL * round);
+            assert_eq!(choker.optimistic, Some(optimistic));
+        }
+
+        choker.rechoke(&candidates, start + Choker::OPTIMISTIC_INTERVAL);
+        assert_eq!(
+            choker.last_optimistic_rotation,
+            Some(start + Choker::OPTIMISTIC_INTERVAL)
+        );
+    }
+
+    #[rstest]
+    fn test_no_slots(candidates: Vec<ChokeCandidate>) {
+        assert!(Choker::new(0)
+            .rechoke(&candidates, Instant::now())
+            .is_empty());
+    }
+}
@@ -0,0 +1,880 @@
+mod ban;
+mod choker;
+mod picker;
+
+pub use ban::BanList;
+pub use choker::{ChokeCandidate, Choker};
+pub use picker::PiecePicker;
+
+use crate::metainfo::{DownloadInfo, FilePriority};
+use crate::peers::{
+    PeerAlerts, PeerCommands, PeerStats, PieceIndex, PieceRequestInfo, WebSeedAlert,
+};
+use crate::prelude::*;
+use crate::session::{SessionEvent, Streaming, TorrentState, TorrentStats};
+use crate::storage::Storage;
+use crate::torrent::Bitfield;
+use crate::tracker::request::Requestable;
+use reqwest::Url;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
+
+#[derive(Debug)]
+struct PeerSession {
+    bitfield: Bitfield,
+    commands_tx: mpsc::Sender<PeerCommands>,
+    stats: Arc<PeerStats>,
+    we_are_choking: bool,
+    we_are_interested: bool,
+    // pieces handed to the peer which it hasn't finished yet.
+    assigned: HashSet<PieceIndex>,
+    // pieces we got which the peer hasn't been told about, they go out once its worker has room.
+    unannounced: Vec<PieceIndex>,
+
+    // counter values as of the last rechoke, the difference gives the rate over the round.
+    last_downloaded: u64,
+    last_uploaded: u64,
+}
+
+#[derive(Debug)]
+struct WebSeedSession {
+    commands_tx: mpsc::Sender<PeerCommands>,
+    stats: Arc<PeerStats>,
+    assigned: HashSet<PieceIndex>,
+    last_downloaded: u64,
+}
+
+#[derive(Debug)]
+pub struct Engine {
+    alerts_rx: mpsc::Receiver<PeerAlerts>,
+    pieces: Vec<PieceRequestInfo>,
+    picker: PiecePicker,
+    bans: BanList,
+    peers: HashMap<SocketAddrV4, PeerSession>,
+    web_seeds: HashMap<Url, WebSeedSession>,
+    // what a web seed has, which is everything.
+    all_pieces: Bitfield,
+    choker: Choker,
+    have: Bitfield,
+    last_rechoke: Instant,
+    storage: Arc<Mutex<Storage>>,
+    priorities_rx: watch::Receiver<Vec<FilePriority>>,
+    streaming_rx: watch::Receiver<Streaming>,
+    // the torrent drops all its connections while paused.
+    state_rx: watch::Receiver<TorrentState>,
+    stats: Arc<TorrentStats>,
+    events_tx: broadcast::Sender<SessionEvent>,
+}
+
+impl Engine {
+    // enough pieces to keep a fast peer's request pipeline full across piece boundaries.
+    const PIECES_PER_PEER: usize = 8;
+    // a web seed fetches one piece at a time, the next one is only queued up so it can go
+    // straight on to it.
+    const PIECES_PER_WEB_SEED: usize = 2;
+    // the pieces right after a reader's position are urgent, each one needed a step later than
+    // the one before it.
+    const URGENT_WINDOW: usize = 8;
+    const URGENT_STEP: Duration = Duration::from_millis(500);
+    const DEADLINE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+    pub fn new(
+        alerts_rx: mpsc::Receiver<PeerAlerts>,
+        download_info: &DownloadInfo,
+        unchoke_slots: usize,
+        smart_ban: bool,
+        storage: Arc<Mutex<Storage>>,
+        stats: Arc<TorrentStats>,
+        events_tx: broadcast::Sender<SessionEvent>,
+    ) -> Self {
+        let piece_length = download_info.piece_length();
+        let total_length = download_info.get_request_length();
+        let pieces: Vec<_> = download_info
+            .piece_hashes()
+            .iter()
+            .enumerate()
+            .map(|(index, hash)| {
+                // the last piece only covers whatever is left over.
+                let length = piece_length.min(total_length - index * piece_length);
+                PieceRequestInfo::new(index, length as u32, *hash)
+            })
+            .collect();
+        let num_pieces = pieces.len();
+
+        Self {
+            alerts_rx,
+            pieces,
+            picker: PiecePicker::new(num_pieces),
+            bans: BanList::new(smart_ban),
+            peers: HashMap::new(),
+            web_seeds: HashMap::new(),
+            all_pieces: Bitfield::repeat(true, num_pieces),
+            choker: Choker::new(unchoke_slots),
+            have: Bitfield::repeat(false, num_pieces),
+            last_rechoke: Instant::now(),
+            priorities_rx: stats.watch_priorities(),
+            streaming_rx: stats.watch_streaming(),
+            state_rx: stats.watch_state(),
+            storage,
+            stats,
+            events_tx,
+        }
+    }
+
+    /// runs until it fails. it keeps going once every wanted piece is there, to serve the peers
+    /// and to pick up pieces which are wanted later on, and tells the task through
+    /// [`TorrentStats::set_complete`].
+    #[instrument(level = "info", name = "engine", skip_all)]
+    pub async fn run(mut self) -> anyhow::Result<Infallible> {
+        let mut rechoke_interval = tokio::time::interval(Choker::RECHOKE_INTERVAL);
+        let mut deadline_interval = tokio::time::interval(Self::DEADLINE_CHECK_INTERVAL);
+        self.update_streaming();
+        // the priorities may have changed since the torrent was added, every file may even have
+        // been skipped.
+        self.update_priorities().await?;
+        loop {
+            tokio::select! {
+                alert = self.alerts_rx.recv() => {
+                    let alert = match alert {
+                        Some(alert) => alert,
+                        None => anyhow::bail!("all peers closed down"),
+                    };
+                    self.handle_alert(alert).await?;
+                }
+
+                Ok(()) = self.priorities_rx.changed() => self.update_priorities().await?,
+
+                Ok(()) = self.streaming_rx.changed() => {
+                    self.update_streaming();
+                    self.assign_all_pieces().await;
+                }
+
+                Ok(()) = self.state_rx.changed() => {
+                    let state = *self.state_rx.borrow_and_update();
+                    match state {
+                        TorrentState::Paused => self.drop_all_peers(),
+                        TorrentState::Downloading => self.assign_all_pieces().await,
+                        _ => {}
+                    }
+                }
+
+                _ = deadline_interval.tick() => {
+                    if self.picker.has_late(Instant::now()) {
+                        self.assign_all_pieces().await;
+                    }
+                }
+
+                _ = rechoke_interval.tick() => self.rechoke(),
+            }
+        }
+    }
+
+    async fn handle_alert(&mut self, alert: PeerAlerts) -> anyhow::Result<()> {
+        type PA = PeerAlerts;
+        match alert {
+            PA::InitPeer {
+                peer_addr,
+                mut bitfield,
+                commands_tx,
+                stats,
+            } => {
+                let span = info_span!("processing init from {peer}", peer = peer_addr.to_string());
+                let _gaurd = span.enter();
+                info!("received init peer");
+                // the worker was dropped along with its connections, e.g on pause.
+                if commands_tx.is_closed() {
+                    debug!("worker is already gone");
+                    return Ok(());
+                }
+                if self.bans.is_banned(peer_addr.ip()) {
+                    info!("peer is banned, shutting it down");
+                    send_ban(commands_tx);
+                    return Ok(());
+                }
+                // bitfields are padded to whole bytes, and peers without any pieces may not send
+                // one at all.
+                bitfield.resize(self.pieces.len(), false);
+                self.picker.add_peer(&bitfield);
+                self.stats.add_peer(peer_addr, stats.clone());
+                // the worker doesn't send a bitfield, the peer learns what we have from haves.
+                let unannounced = self.have.iter_ones().collect();
+                self.peers.insert(
+                    peer_addr,
+                    PeerSession {
+                        bitfield,
+                        commands_tx,
+                        stats,
+                        we_are_choking: true,
+                        we_are_interested: false,
+                        assigned: HashSet::new(),
+                        unannounced,
+                        last_downloaded: 0,
+                        last_uploaded: 0,
+                    },
+                );
+                self.announce_pieces(peer_addr);
+                self.update_interest(peer_addr);
+                self.assign_pieces(peer_addr).await;
+            }
+            PA::UpdateBitfield {
+                peer_addr,
+                has_piece,
+            } => {
+                if let Some(session) = self.peers.get_mut(&peer_addr) {
+                    if has_piece < session.bitfield.len() && !session.bitfield[has_piece] {
+                        session.bitfield.set(has_piece, true);
+                        self.picker.add_have(has_piece);
+                    }
+                }
+                self.update_interest(peer_addr);
+                self.assign_pieces(peer_addr).await;
+            }
+            PA::DonePiece {
+                peer_addr,
+                piece_index,
+                piece,
+            } => {
+                info!(piece_index, "received piece done");
+                if let Some(session) = self.peers.get_mut(&peer_addr) {
+                    session.assigned.remove(&piece_index);
+                }
+                self.piece_done(piece_index, piece).await?;
+                self.assign_pieces(peer_addr).await;
+            }
+            PA::PieceFailed {
+                peer_addr,
+                piece_index,
+                piece,
+            } => {
+                warn!(%peer_addr, piece_index, "piece failed hash check, re-queueing it");
+                self.stats.record_hash_failure();
+                if let Some(session) = self.peers.get_mut(&peer_addr) {
+                    session.assigned.remove(&piece_index);
+                }
+                self.picker.unrequest(piece_index);
+
+                // the whole piece came from the peer, so it's the only one to blame.
+                let ip = *peer_addr.ip();
+                if self.bans.record_failure(piece_index, ip, &piece) {
+                    warn!(%ip, "peer sent too many bad pieces, banning it");
+                    self.ban(ip);
+                }
+                self.assign_all_pieces().await;
+            }
+            PA::ReleasePieces { peer_addr, pieces } => {
+                info!(%peer_addr, ?pieces, "peer released pieces");
+                if let Some(session) = self.peers.get_mut(&peer_addr) {
+                    for index in &pieces {
+                        session.assigned.remove(index);
+                    }
+                }
+                for index in pieces {
+                    self.picker.unrequest(index);
+                }
+
+                // the other peers get first dibs, otherwise the slow peer would get them back.
+                let peer_addrs: Vec<_> = self
+                    .peers
+                    .keys()
+                    .filter(|other| **other != peer_addr)
+                    .copied()
+                    .collect();
+                for other in peer_addrs {
+                    self.assign_pieces(other).await;
+                }
+                self.assign_pieces(peer_addr).await;
+            }
+            PA::Unchoked { peer_addr } => {
+                debug!(%peer_addr, "peer unchoked us");
+                self.assign_pieces(peer_addr).await;
+            }
+            PA::Request {
+                peer_addr,
+                index,
+                begin,
+                length,
+            } => self.serve_request(peer_addr, index, begin, length),
+            PA::Disconnected { peer_addr } => {
+                info!(%peer_addr, "peer disconnected");
+                self.remove_peer(&peer_addr);
+
+                // the pieces the peer dropped can go to whoever has room for them.
+                self.assign_all_pieces().await;
+            }
+            PA::WebSeed { url, alert } => return self.handle_web_seed_alert(url, alert).await,
+        }
+        Ok(())
+    }
+
+    async fn handle_web_seed_alert(&mut self, url: Url, alert: WebSeedAlert) -> anyhow::Result<()> {
+        match alert {
+            WebSeedAlert::Ready { commands_tx, stats } => {
+                info!(%url, "web seed ready");
+                self.remove_web_seed(&url);
+                self.web_seeds.insert(
+                    url.clone(),
+                    WebSeedSession {
+                        commands_tx,
+                        last_downloaded: stats.downloaded(),
+                        stats,
+                        assigned: HashSet::new(),
+                    },
+                );
+                self.assign_web_seed_pieces(&url).await;
+            }
+            WebSeedAlert::DonePiece { piece_index, piece } => {
+                info!(%url, piece_index, "received piece done from web seed");
+                if let Some(seed) = self.web_seeds.get_mut(&url) {
+                    seed.assigned.remove(&piece_index);
+                }
+                self.piece_done(piece_index, piece).await?;
+                self.assign_web_seed_pieces(&url).await;
+            }
+            WebSeedAlert::PieceFailed { piece_index } => {
+                // the seed goes down right after, which hands the piece out again.
+                warn!(%url, piece_index, "piece from web seed failed hash check");
+                self.stats.record_hash_failure();
+            }
+            WebSeedAlert::Down => {
+                info!(%url, "web seed went down");
+                self.remove_web_seed(&url);
+                self.assign_all_pieces().await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn piece_done(&mut self, piece_index: PieceIndex, piece: Vec<u8>) -> anyhow::Result<()> {
+        // late pieces are downloaded from more than one source, the first one wins.
+        if self.have[piece_index] {
+            debug!(piece_index, "piece was already downloaded from elsewhere");
+            return Ok(());
+        }
+        let mut storage = self.storage.lock().await;
+        let started = Instant::now();
+        storage.write_piece(piece_index, &piece).await?;
+        self.stats.record_disk_write(started.elapsed());
+        drop(storage);
+        for ip in self.bans.record_success(piece_index, &piece) {
+            warn!(%ip, piece_index, "peer sent corrupt blocks, banning it");
+            self.ban(ip);
+        }
+        self.picker.mark_have(piece_index);
+        self.have.set(piece_index, true);
+        let peer_addrs: Vec<_> = self.peers.keys().copied().collect();
+        for peer_addr in peer_addrs {
+            if let Some(session) = self.peers.get_mut(&peer_addr) {
+                session.unannounced.push(piece_index);
+            }
+            self.announce_pieces(peer_addr);
+        }
+        self.stats
+            .record_piece_done(piece_index, self.pieces[piece_index].length);
+        let _ = self.events_tx.send(SessionEvent::PieceCompleted {
+            info_hash: self.stats.info_hash().clone(),
+            index: piece_index,
+        });
+        self.update_complete();
+        Ok(())
+    }
+
+    async fn update_priorities(&mut self) -> anyhow::Result<()> {
+        let priorities = self.priorities_rx.borrow_and_update().clone();
+        debug!(?priorities, "updating file priorities");
+        let mut storage = self.storage.lock().await;
+        storage.set_priorities(priorities, &self.have).await?;
+        self.picker.set_priorities(storage.piece_priorities());
+        drop(storage);
+        self.update_complete();
+        self.assign_all_pieces().await;
+        Ok(())
+    }
+
+    // the pieces left may all have been skipped, or a skipped file may be wanted after all.
+    fn update_complete(&mut self) {
+        let complete = self.picker.is_complete();
+        if complete != *self.stats.watch_complete().borrow() {
+            match complete {
+                true => info!("all wanted pieces downloaded"),
+                false => info!("more pieces are wanted"),
+            }
+        }
+        self.stats.set_complete(complete);
+    }
+
+    // the pieces right after each reader are given deadlines, counting from now.
+    fn update_streaming(&mut self) {
+        let streaming = self.streaming_rx.borrow_and_update().clone();
+        let positions: Vec<_> = streaming.positions.values().copied().collect();
+        let now = Instant::now();
+        let mut deadlines = HashMap::new();
+        for &position in &positions {
+            let window = (position..self.pieces.len()).take(Self::URGENT_WINDOW);
+            for (step, index) in window.enumerate() {
+                let deadline = now + Self::URGENT_STEP * (step as u32 + 1);
+                deadlines
+                    .entry(index)
+                    .and_modify(|earliest: &mut Instant| *earliest = (*earliest).min(deadline))
+                    .or_insert(deadline);
+            }
+        }
+        debug!(
+            ?positions,
+            sequential = streaming.sequential,
+            "streaming changed"
+        );
+        self.picker.set_sequential(streaming.sequential);
+        self.picker.set_streaming(positions, deadlines);
+    }
+
+    // tells the peer we're interested once it has something we want, peers usually wait for
+    // that before unchoking us.
+    fn update_interest(&mut self, peer_addr: SocketAddrV4) {
+        let Some(session) = self.peers.get_mut(&peer_addr) else {
+            return;
+        };
+        if session.we_are_interested || !self.picker.wants_from(&session.bitfield) {
+            return;
+        }
+        // a full buffer means the worker is busy downloading, and interested already.
+        if session
+            .commands_tx
+            .try_send(PeerCommands::Interested)
+            .is_ok()
+        {
+            session.we_are_interested = true;
+        }
+    }
+
+    // tops the peer up to its share of pieces, as long as it lets us download from it. pieces
+    // handed to a peer which chokes us come back as released.
+    async fn assign_pieces(&mut self, peer_addr: SocketAddrV4) {
+        if !self.is_downloading() {
+            return;
+        }
+        let Some(session) = self.peers.get_mut(&peer_addr) else {
+            return;
+        };
+        if !session.stats.unchoked_by_peer() {
+            return;
+        }
+
+        let ip = peer_addr.ip();
+        // snubbed peers only get a single piece, until they start sending blocks again.
+        let quota = if session.stats.snubbed() {
+            1
+        } else {
+            Self::PIECES_PER_PEER
+        };
+        while session.assigned.len() < quota {
+            // peers don't get another go at pieces they corrupted.
+            let Some(index) = self
+                .picker
+                .pick_where(&session.bitfield, |index| !self.bans.is_suspect(ip, index))
+            else {
+                break;
+            };
+
+            debug!(%peer_addr, index, "assigning piece to peer");
+            // never block the engine on a busy worker, it's topped up again as it finishes
+            // pieces.
+            let command = PeerCommands::DownloadPiece(self.pieces[index].clone());
+            if session.commands_tx.try_send(command).is_err() {
+                self.picker.unrequest(index);
+                break;
+            }
+            session.assigned.insert(index);
+        }
+
+        // a late urgent piece is worth going past the quota for, as long as the peer is keeping
+        // up.
+        if session.stats.snubbed() {
+            return;
+        }
+        let assigned = &session.assigned;
+        let Some(index) = self.picker.pick_late(
+            &session.bitfield,
+            |index| !assigned.contains(&index) && !self.bans.is_suspect(ip, index),
+            Instant::now(),
+        ) else {
+            return;
+        };
+        debug!(%peer_addr, index, "assigning late piece to another peer");
+        let command = PeerCommands::DownloadPiece(self.pieces[index].clone());
+        if session.commands_tx.try_send(command).is_ok() {
+            session.assigned.insert(index);
+        }
+    }
+
+    async fn assign_web_seed_pieces(&mut self, url: &Url) {
+        if !self.is_downloading() {
+            return;
+        }
+        let Some(seed) = self.web_seeds.get_mut(url) else {
+            return;
+        };
+        while seed.assigned.len() < Self::PIECES_PER_WEB_SEED {
+            let Some(index) = self.picker.pick_where(&self.all_pieces, |_| true) else {
+                break;
+            };
+
+            debug!(%url, index, "assigning piece to web seed");
+            let command = PeerCommands::DownloadPiece(self.pieces[index].clone());
+            if seed.commands_tx.try_send(command).is_err() {
+                self.picker.unrequest(index);
+                break;
+            }
+            seed.assigned.insert(index);
+        }
+    }
+
+    // pieces are only handed out while the torrent holds an active slot, a torrent waiting in
+    // the queue to download more of itself keeps serving its peers meanwhile.
+    fn is_downloading(&self) -> bool {
+        *self.state_rx.borrow() == TorrentState::Downloading
+    }
+
+    async fn assign_all_pieces(&mut self) {
+        let peer_addrs: Vec<_> = self.peers.keys().copied().collect();
+        for peer_addr in peer_addrs {
+            self.assign_pieces(peer_addr).await;
+        }
+        let urls: Vec<_> = self.web_seeds.keys().cloned().collect();
+        for url in urls {
+            self.assign_web_seed_pieces(&url).await;
+        }
+    }
+
+    // tells the peer about the pieces it doesn't know we have, those it has itself are left out.
+    fn announce_pieces(&mut self, peer_addr: SocketAddrV4) {
+        let Some(session) = self.peers.get_mut(&peer_addr) else {
+            return;
+        };
+        let bitfield = &session.bitfield;
+        session
+            .unannounced
+            .retain(|&index| !bitfield.get(index).is_some_and(|has| *has));
+        if session.unannounced.is_empty() {
+            return;
+        }
+        let pieces = std::mem::take(&mut session.unannounced);
+        // never block the engine on a busy worker, they're sent along with the next ones.
+        if let Err(TrySendError::Full(PeerCommands::Have(pieces))) =
+            session.commands_tx.try_send(PeerCommands::Have(pieces))
+        {
+            session.unannounced = pieces;
+        }
+    }
+
+    // the block is read in the background so that the engine isn't held up by the disk, the
+    // worker sends it unless the request was cancelled in the meantime.
+    fn serve_request(
+        &mut self,
+        peer_addr: SocketAddrV4,
+        index: PieceIndex,
+        begin: u32,
+        length: u32,
+    ) {
+        let Some(session) = self.peers.get(&peer_addr) else {
+            return;
+        };
+        if session.we_are_choking {
+            debug!(%peer_addr, index, begin, "ignoring request from choked peer");
+            return;
+        }
+        let in_piece = self
+            .pieces
+            .get(index)
+            .is_some_and(|piece| begin as u64 + length as u64 <= piece.length as u64);
+        if !in_piece || !self.have[index] {
+            debug!(%peer_addr, index, begin, length, "ignoring request for a block we don't have");
+            return;
+        }
+
+        let storage = self.storage.clone();
+        let stats = self.stats.clone();
+        let commands_tx = session.commands_tx.clone();
+        tokio::spawn(async move {
+            let mut data = vec![0; length as usize];
+            let storage = storage.lock().await;
+            let started = Instant::now();
+            let result = storage.read_block(index, begin as usize, &mut data).await;
+            stats.record_disk_read(started.elapsed());
+            drop(storage);
+            match result {
+                Ok(()) => {
+                    let _ = commands_tx
+                        .send(PeerCommands::SendBlock { index, begin, data })
+                        .await;
+                }
+                Err(err) => warn!(%err, %peer_addr, index, begin, "failed to read requested block"),
+            }
+        });
+    }
+
+    // shuts down all connections to the ip, new ones are turned away on init.
+    fn ban(&mut self, ip: Ipv4Addr) {
+        let peer_addrs: Vec<_> = self
+            .peers
+            .keys()
+            .filter(|peer_addr| *peer_addr.ip() == ip)
+            .copied()
+            .collect();
+        for peer_addr in peer_addrs {
+            if let Some(session) = self.peers.get(&peer_addr) {
+                send_ban(session.commands_tx.clone());
+            }
+            self.remove_peer(&peer_addr);
+        }
+    }
+
+    fn remove_peer(&mut self, peer_addr: &SocketAddrV4) {
+        let Some(session) = self.peers.remove(peer_addr) else {
+            return;
+        };
+
+        self.picker.remove_peer(&session.bitfield);
+        for index in session.assigned {
+            self.picker.unrequest(index);
+        }
+        self.stats.remove_peer(peer_addr);
+    }
+
+    // the workers and web seeds are gone without a word, their pieces are up for grabs again.
+    fn drop_all_peers(&mut self) {
+        info!("torrent paused, dropping all peers");
+        let peer_addrs: Vec<_> = self.peers.keys().copied().collect();
+        for peer_addr in &peer_addrs {
+            self.remove_peer(peer_addr);
+        }
+        let urls: Vec<_> = self.web_seeds.keys().cloned().collect();
+        for url in &urls {
+            self.remove_web_seed(url);
+        }
+    }
+
+    fn remove_web_seed(&mut self, url: &Url) {
+        let Some(seed) = self.web_seeds.remove(url) else {
+            return;
+        };
+        for index in seed.assigned {
+            self.picker.unrequest(index);
+        }
+    }
+
+    // there's nothing left to download, so peers are ranked by how fast they take our pieces
+    // rather than by how fast they give us theirs.
+    fn is_seeding(&self) -> bool {
+        self.picker.is_complete()
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    fn rechoke(&mut self) {
+        let now = Instant::now();
+        let elapsed_secs = now.duration_since(self.last_rechoke).as_secs_f64().max(1.0);
+        self.last_rechoke = now;
+
+        let closed: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, session)| session.commands_tx.is_closed())
+            .map(|(peer_addr, _)| *peer_addr)
+            .collect();
+        for peer_addr in &closed {
+            self.remove_peer(peer_addr);
+        }
+
+        let candidates = self.choke_candidates(elapsed_secs);
+        let unchoked = self.choker.rechoke(&candidates, now);
+        for (peer_addr, session) in self.peers.iter_mut() {
+            let should_choke = !unchoked.contains(peer_addr);
+            if should_choke == session.we_are_choking {
+                continue;
+            }
+
+            let command = if should_choke {
+                PeerCommands::Choke
+            } else {
+                PeerCommands::Unchoke
+            };
+            // never block the engine on a busy worker, it'll be retried next round.
+            match session.commands_tx.try_send(command) {
+                Ok(()) => {
+                    debug!(%peer_addr, choked = should_choke, "updated choke state");
+                    session.we_are_choking = should_choke;
+                }
+                Err(err) => debug!(%peer_addr, %err, "could not update choke state"),
+            }
+        }
+
+        // haves which didn't fit before get another go.
+        let peer_addrs: Vec<_> = self.peers.keys().copied().collect();
+        for peer_addr in peer_addrs {
+            self.announce_pieces(peer_addr);
+        }
+    }
+
+    // the rates of the peers over the round, which are also added to the torrent's totals.
+    fn choke_candidates(&mut self, elapsed_secs: f64) -> Vec<ChokeCandidate> {
+        let seeding = self.is_seeding();
+        let (mut total_downloaded, mut total_uploaded) = (0, 0);
+        let candidates: Vec<_> = self
+            .peers
+            .iter_mut()
+            .map(|(peer_addr, session)| {
+                let (downloaded, uploaded) = (session.stats.downloaded(), session.stats.uploaded());
+                let (downloaded_delta, uploaded_delta) = (
+                    downloaded - session.last_downloaded,
+                    uploaded - session.last_uploaded,
+                );
+                total_downloaded += downloaded_delta;
+                total_uploaded += uploaded_delta;
+                let transferred = if seeding {
+                    uploaded_delta
+                } else {
+                    downloaded_delta
+                };
+                session.last_downloaded = downloaded;
+                session.last_uploaded = uploaded;
+
+                ChokeCandidate {
+                    peer_addr: *peer_addr,
+                    rate: (transferred as f64 / elapsed_secs) as u64,
+                    interested: session.stats.peer_interested(),
+                }
+            })
+            .collect();
+        // web seeds aren't choked, they only count towards the rates.
+        for seed in self.web_seeds.values_mut() {
+            let downloaded = seed.stats.downloaded();
+            total_downloaded += downloaded - seed.last_downloaded;
+            seed.last_downloaded = downloaded;
+        }
+
+        self.stats
+            .record_transfer(total_downloaded, total_uploaded, elapsed_secs);
+        candidates
+    }
+}
+
+// unlike shutdown, the ban has to reach the worker, or the pool would dial the peer again.
+fn send_ban(commands_tx: mpsc::Sender<PeerCommands>) {
+    if let Err(TrySendError::Full(_)) = commands_tx.try_send(PeerCommands::Ban) {
+        tokio::spawn(async move {
+            let _ = commands_tx.send(PeerCommands::Ban).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::{download_info, temp_dir};
+    use crate::torrent::InfoHash;
+
+    // a torrent of two 4 byte pieces, along with the alerts to feed its engine.
+    fn engine(dir: &std::path::Path) -> (Engine, mpsc::Sender<PeerAlerts>) {
+        let info = download_info(&[8], &[FilePriority::Normal]);
+        let info_hash = InfoHash::new([1; 20]);
+        let storage = Storage::new(dir, &info_hash, &info);
+        let stats = Arc::new(TorrentStats::new(info_hash, &info, 0));
+        let (alerts_tx, alerts_rx) = mpsc::channel(8);
+        let (events_tx, _) = broadcast::channel(8);
+        let engine = Engine::new(
+            alerts_rx,
+            &info,
+            4,
+            false,
+            Arc::new(Mutex::new(storage)),
+            stats,
+            events_tx,
+        );
+        (engine, alerts_tx)
+    }
+
+    async fn add_peer(
+        engine: &mut Engine,
+        n: u8,
+    ) -> (SocketAddrV4, Arc<PeerStats>, mpsc::Receiver<PeerCommands>) {
+        let peer_addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, n), 6881);
+        let (commands_tx, commands_rx) = mpsc::channel(8);
+        let stats = Arc::new(PeerStats::default());
+        stats.set_peer_interested(true);
+        engine
+            .handle_alert(PeerAlerts::InitPeer {
+                peer_addr,
+                bitfield: Bitfield::new(),
+                commands_tx,
+                stats: stats.clone(),
+            })
+            .await
+            .unwrap();
+        (peer_addr, stats, commands_rx)
+    }
+
+    #[tokio::test]
+    async fn test_peers_are_ranked_by_upload_once_seeding() {
+        let dir = temp_dir();
+        let (mut engine, _alerts_tx) = engine(&dir);
+        let (giver, giver_stats, _giver_rx) = add_peer(&mut engine, 1).await;
+        let (taker, taker_stats, _taker_rx) = add_peer(&mut engine, 2).await;
+        let rate = |candidates: &[ChokeCandidate], peer_addr| {
+            candidates
+                .iter()
+                .find(|candidate| candidate.peer_addr == peer_addr)
+                .unwrap()
+                .rate
+        };
+
+        giver_stats.record_downloaded(1000);
+        taker_stats.record_uploaded(1000);
+        let candidates = engine.choke_candidates(1.0);
+        assert_eq!(rate(&candidates, giver), 1000);
+        assert_eq!(rate(&candidates, taker), 0);
+
+        for index in 0..2 {
+            engine.picker.mark_have(index);
+        }
+        giver_stats.record_downloaded(1000);
+        taker_stats.record_uploaded(1000);
+        let candidates = engine.choke_candidates(1.0);
+        assert_eq!(rate(&candidates, giver), 0);
+        assert_eq!(rate(&candidates, taker), 1000);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_served_from_storage() {
+        let dir = temp_dir();
+        let (mut engine, _alerts_tx) = engine(&dir);
+        engine.piece_done(0, vec![1, 2, 3, 4]).await.unwrap();
+
+        let (peer_addr, _, mut commands_rx) = add_peer(&mut engine, 1).await;
+        // the peer is told about the piece it can ask for.
+        assert!(matches!(
+            commands_rx.recv().await,
+            Some(PeerCommands::Have(pieces)) if pieces == [0]
+        ));
+        while commands_rx.try_recv().is_ok() {}
+
+        // nothing is served to a choked peer, or out of pieces we don't have.
+        engine.serve_request(peer_addr, 0, 1, 2);
+        engine.peers.get_mut(&peer_addr).unwrap().we_are_choking = false;
+        engine.serve_request(peer_addr, 1, 0, 2);
+        engine.serve_request(peer_addr, 0, 1, 2);
+        let command = tokio::time::timeout(Duration::from_secs(1), commands_rx.recv()).await;
+        assert!(matches!(
+            command,
+            Ok(Some(PeerCommands::SendBlock { index: 0, begin: 1, data })) if data == [2, 3]
+        ));
+        assert!(commands_rx.try_recv().is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
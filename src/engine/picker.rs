@@ -0,0 +1,281 @@
+use crate::metainfo::FilePriority;
+use crate::peers::PieceIndex;
+use crate::torrent::Bitfield;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceState {
+    Missing,
+    Requested,
+    Have,
+}
+
+/// decides which piece a peer should download next. urgent pieces, which a reader is about to
+/// get to, go first by their deadline. then the pieces of higher priority files, and among those
+/// the rarest pieces so that they don't disappear from the swarm along with the few peers which
+/// have them, or in sequential mode the ones closest after where the readers are. skipped pieces
+/// aren't picked at all.
+#[derive(Debug)]
+pub struct PiecePicker {
+    states: Vec<PieceState>,
+    // number of connected peers which have each piece.
+    availability: Vec<u32>,
+    priorities: Vec<FilePriority>,
+    sequential: bool,
+    // the pieces the readers are at.
+    positions: Vec<PieceIndex>,
+    // when each of the urgent pieces is needed by.
+    deadlines: HashMap<PieceIndex, Instant>,
+}
+
+impl PiecePicker {
+    // how long a late piece is given with another peer, before it's handed out yet again.
+    const LATE_RETRY: Duration = Duration::from_secs(2);
+
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            states: vec![PieceState::Missing; num_pieces],
+            availability: vec![0; num_pieces],
+            priorities: vec![FilePriority::Normal; num_pieces],
+            sequential: false,
+            positions: Vec::new(),
+            deadlines: HashMap::new(),
+        }
+    }
+
+    pub fn set_priorities(&mut self, priorities: Vec<FilePriority>) {
+        debug_assert_eq!(priorities.len(), self.states.len());
+        self.priorities = priorities;
+    }
+
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    /// replaces the read positions and the deadlines of the urgent pieces.
+    pub fn set_streaming(
+        &mut self,
+        positions: Vec<PieceIndex>,
+        deadlines: HashMap<PieceIndex, Instant>,
+    ) {
+        self.positions = positions;
+        self.deadlines = deadlines;
+        self.deadlines.retain(|&index, _| {
+            self.states
+                .get(index)
+                .is_some_and(|s| *s != PieceState::Have)
+        });
+    }
+
+    /// whether some urgent piece has missed its deadline.
+    pub fn has_late(&self, now: Instant) -> bool {
+        self.deadlines.values().any(|deadline| *deadline <= now)
+    }
+
+    pub fn add_peer(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_ones().take_while(|&i| i < self.states.len()) {
+            self.availability[index] += 1;
+        }
+    }
+
+    pub fn remove_peer(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_ones().take_while(|&i| i < self.states.len()) {
+            self.availability[index] = self.availability[index].saturating_sub(1);
+        }
+    }
+
+    pub fn add_have(&mut self, index: PieceIndex) {
+        if let Some(availability) = self.availability.get_mut(index) {
+            *availability += 1;
+        }
+    }
+
+    /// picks the most wanted, then rarest, missing piece which the peer has and `allowed`
+    /// accepts, and marks it requested.
+    pub fn pick_where(
+        &mut self,
+        bitfield: &Bitfield,
+        allowed: impl Fn(PieceIndex) -> bool,
+    ) -> Option<PieceIndex> {
+        let index = (0..self.states.len())
+            .filter(|&i| self.states[i] == PieceState::Missing)
+            .filter(|&i| self.priorities[i] != FilePriority::Skip)
+            .filter(|&i| bitfield.get(i).is_some_and(|has| *has))
+            .filter(|&i| allowed(i))
+            .min_by_key(|&i| {
+                let deadline = self.deadlines.get(&i);
+                let rank = match self.sequential {
+                    true => self.distance(i),
+                    false => self.availability[i] as usize,
+                };
+                (
+                    deadline.is_none(),
+                    deadline.copied(),
+                    Reverse(self.priorities[i]),
+                    rank,
+                )
+            })?;
+
+        self.states[index] = PieceState::Requested;
+        Some(index)
+    }
+
+    /// picks an urgent piece which is requested from some other peer, but has missed its
+    /// deadline, so that it's downloaded from this peer as well. it gets a while longer before
+    /// it's handed out again.
+    pub fn pick_late(
+        &mut self,
+        bitfield: &Bitfield,
+        allowed: impl Fn(PieceIndex) -> bool,
+        now: Instant,
+    ) -> Option<PieceIndex> {
+        let (&index, _) = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .filter(|(&i, _)| self.states[i] == PieceState::Requested)
+            .filter(|(&i, _)| bitfield.get(i).is_some_and(|has| *has))
+            .filter(|(&i, _)| allowed(i))
+            .min_by_key(|(_, deadline)| **deadline)?;
+
+        self.deadlines.insert(index, now + Self::LATE_RETRY);
+        Some(index)
+    }
+
+    // how far the piece is ahead of the closest reader behind it, pieces behind every reader go
+    // last. without any readers, it's from the start.
+    fn distance(&self, index: PieceIndex) -> usize {
+        if self.positions.is_empty() {
+            return index;
+        }
+        self.positions
+            .iter()
+            .filter(|&&position| position <= index)
+            .map(|position| index - position)
+            .min()
+            .unwrap_or(self.states.len() + index)
+    }
+
+    /// whether the peer has any piece which is still wanted, requested ones included.
+    pub fn wants_from(&self, bitfield: &Bitfield) -> bool {
+        bitfield
+            .iter_ones()
+            .take_while(|&i| i < self.states.len())
+            .any(|i| self.states[i] != PieceState::Have && self.priorities[i] != FilePriority::Skip)
+    }
+
+    /// hands a requested piece back to be picked again.
+    pub fn unrequest(&mut self, index: PieceIndex) {
+        if self.states.get(index) == Some(&PieceState::Requested) {
+            self.states[index] = PieceState::Missing;
+        }
+    }
+
+    pub fn mark_have(&mut self, index: PieceIndex) {
+        if let Some(state) = self.states.get_mut(index) {
+            *state = PieceState::Have;
+        }
+        self.deadlines.remove(&index);
+    }
+
+    /// whether every piece which isn't skipped is there.
+    pub fn is_complete(&self) -> bool {
+        self.states
+            .iter()
+            .zip(&self.priorities)
+            .all(|(&state, &priority)| state == PieceState::Have || priority == FilePriority::Skip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn bitfield(bits: &[bool]) -> Bitfield {
+        bits.iter().copied().collect()
+    }
+
+    #[rstest]
+    fn test_picks_rarest_first() {
+        let mut picker = PiecePicker::new(3);
+        picker.add_peer(&bitfield(&[true, true, true]));
+        picker.add_peer(&bitfield(&[true, false, true]));
+        picker.add_peer(&bitfield(&[false, false, true]));
+
+        let everything = bitfield(&[true, true, true]);
+        assert_eq!(picker.pick_where(&everything, |_| true), Some(1));
+        assert_eq!(picker.pick_where(&everything, |_| true), Some(0));
+        assert_eq!(picker.pick_where(&everything, |_| true), Some(2));
+        assert_eq!(picker.pick_where(&everything, |_| true), None);
+    }
+
+    #[rstest]
+    fn test_unrequest_and_complete() {
+        let mut picker = PiecePicker::new(2);
+        let everything = bitfield(&[true, true]);
+        assert_eq!(
+            picker.pick_where(&bitfield(&[false, true]), |_| true),
+            Some(1)
+        );
+
+        picker.unrequest(1);
+        picker.mark_have(0);
+        assert_eq!(picker.pick_where(&everything, |_| true), Some(1));
+        assert!(!picker.is_complete());
+
+        picker.mark_have(1);
+        assert!(picker.is_complete());
+    }
+
+    #[rstest]
+    fn test_priorities_come_before_rarity() {
+        use FilePriority::*;
+        let mut picker = PiecePicker::new(4);
+        picker.add_peer(&bitfield(&[true, true, true, true]));
+        picker.add_peer(&bitfield(&[true, false, false, false]));
+        picker.set_priorities(vec![High, Skip, Normal, Low]);
+
+        let everything = bitfield(&[true, true, true, true]);
+        assert_eq!(picker.pick_where(&everything, |_| true), Some(0));
+        assert_eq!(picker.pick_where(&everything, |_| true), Some(2));
+        assert_eq!(picker.pick_where(&everything, |_| true), Some(3));
+        assert_eq!(picker.pick_where(&everything, |_| true), None);
+
+        // a peer with only the skipped piece, or pieces we have, has nothing we want.
+        let skipped_only = bitfield(&[false, true, false, false]);
+        assert!(picker.wants_from(&everything));
+        for index in [0, 2, 3] {
+            picker.mark_have(index);
+        }
+        assert!(picker.is_complete());
+        assert!(!picker.wants_from(&skipped_only));
+        assert!(!picker.wants_from(&everything));
+    }
+
+    #[rstest]
+    fn test_streaming() {
+        let mut picker = PiecePicker::new(6);
+        let everything = bitfield(&[true; 6]);
+        picker.add_peer(&everything);
+        picker.add_peer(&bitfield(&[false, false, false, false, false, true]));
+        picker.set_sequential(true);
+
+        let now = Instant::now();
+        let deadlines = [(3, now + Duration::from_secs(2)), (2, now)].into();
+        picker.set_streaming(vec![2], deadlines);
+        // urgent pieces by deadline, then onwards from the reader, then what's behind it.
+        let picks: Vec<_> =
+            std::iter::from_fn(|| picker.pick_where(&everything, |_| true)).collect();
+        assert_eq!(picks, vec![2, 3, 4, 5, 0, 1]);
+
+        // the late piece goes to another peer as well, but only once for a while.
+        assert!(picker.has_late(now));
+        assert_eq!(picker.pick_late(&everything, |_| true, now), Some(2));
+        assert_eq!(picker.pick_late(&everything, |_| true, now), None);
+        picker.mark_have(2);
+        assert!(!picker.has_late(now + Duration::from_secs(1)));
+    }
+}
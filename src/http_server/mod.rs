@@ -0,0 +1,272 @@
+//! serves the files of the torrents of a session over http while they download, so that media
+//! players and the like can read them straight from the torrent. requests wait for the pieces
+//! they cover, which are downloaded ahead of the rest, and ranges are supported for seeking.
+use crate::{InfoHash, Session};
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use std::fmt::Write;
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::net::TcpListener;
+use tokio_util::io::ReaderStream;
+
+/// serves the files of every torrent of the session until the listener fails. the files are at
+/// their path relative to the download directory, and `/` lists them. reading a skipped file
+/// wants it again, HEAD requests are answered without touching the file.
+pub async fn serve(session: Arc<Session>, listener: TcpListener) -> std::io::Result<()> {
+    let app = Router::new()
+        .route("/", get(index))
+        .route("/*path", get(file))
+        .with_state(session);
+    axum::serve(listener, app).await
+}
+
+async fn index(State(session): State<Arc<Session>>) -> Html<String> {
+    let mut page = String::from("<!DOCTYPE html>\n<html><body>\n");
+    for status in session.torrents() {
+        let _ = writeln!(page, "<h3>{}</h3>\n<ul>", escape(&status.name));
+        for file in session.files(&status.info_hash).unwrap_or_default() {
+            let href: Vec<_> = file.path.split('/').map(urlencoding::encode).collect();
+            let _ = writeln!(
+                page,
+                "<li><a href=\"/{}\">{}</a> ({} of {} bytes)</li>",
+                href.join("/"),
+                escape(&file.path),
+                file.bytes_done,
+                file.length,
+            );
+        }
+        page.push_str("</ul>\n");
+    }
+    page.push_str("</body></html>\n");
+    Html(page)
+}
+
+async fn file(
+    State(session): State<Arc<Session>>,
+    method: Method,
+    Path(path): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some((info_hash, index, length)) = find_file(&session, &path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let range = match headers.get(header::RANGE).map(|value| value.to_str()) {
+        Some(Ok(value)) => match parse_range(value, length) {
+            Ok(range) => Some(range),
+            Err(RangeError::Unsatisfiable) => {
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, format!("bytes */{length}"))],
+                )
+                    .into_response()
+            }
+            // a range which can't be made sense of is ignored, as http allows.
+            Err(RangeError::Invalid) => None,
+        },
+        _ => None,
+    };
+
+    let Range { start, end } = range.clone().unwrap_or(0..length);
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start));
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(content_type(&path)),
+    );
+    let status = match range {
+        Some(_) => {
+            let content_range = format!("bytes {start}-{}/{length}", end - 1);
+            response_headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&content_range).expect("the content range is ascii"),
+            );
+            StatusCode::PARTIAL_CONTENT
+        }
+        None => StatusCode::OK,
+    };
+    // opening the file would want it again if it's skipped, which a HEAD request shouldn't do.
+    if method == Method::HEAD {
+        return (status, response_headers).into_response();
+    }
+
+    let mut stream = match session.open_file(&info_hash, index) {
+        Ok(stream) => stream,
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    };
+    if let Err(err) = stream.seek(SeekFrom::Start(start)).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+    }
+    let body = Body::from_stream(ReaderStream::new(stream.take(end - start)));
+    (status, response_headers, body).into_response()
+}
+
+// the first torrent which has a file at the path, along with the file's index and length.
+fn find_file(session: &Session, path: &str) -> Option<(InfoHash, usize, u64)> {
+    session.torrents().into_iter().find_map(|status| {
+        let files = session.files(&status.info_hash).ok()?;
+        let (index, file) = files
+            .iter()
+            .enumerate()
+            .find(|(_, file)| file.path == path)?;
+        Some((status.info_hash, index, file.length))
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RangeError {
+    Invalid,
+    Unsatisfiable,
+}
+
+/// parses a `Range` header into the bytes it covers, only single ranges are supported.
+pub(crate) fn parse_range(value: &str, length: u64) -> Result<Range<u64>, RangeError> {
+    let spec = value
+        .trim()
+        .strip_prefix("bytes=")
+        .ok_or(RangeError::Invalid)?;
+    if spec.contains(',') {
+        return Err(RangeError::Invalid);
+    }
+    let (first, last) = spec.split_once('-').ok_or(RangeError::Invalid)?;
+    let parse = |s: &str| s.trim().parse::<u64>().map_err(|_| RangeError::Invalid);
+
+    let range = match (first.trim(), last.trim()) {
+        // the last n bytes.
+        ("", last) => {
+            let suffix = parse(last)?;
+            length.saturating_sub(suffix)..length
+        }
+        (first, "") => parse(first)?..length,
+        (first, last) => {
+            let (first, last) = (parse(first)?, parse(last)?);
+            if last < first {
+                return Err(RangeError::Invalid);
+            }
+            first..(last + 1).min(length)
+        }
+    };
+    if range.start >= length || range.is_empty() {
+        return Err(RangeError::Unsatisfiable);
+    }
+    Ok(range)
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        Some("avi") => "video/x-msvideo",
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg") => "audio/ogg",
+        Some("txt" | "log" | "nfo") => "text/plain; charset=utf-8",
+        Some("json") => "application/json",
+        Some("pdf") => "application/pdf",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        _ => "application/octet-stream",
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::{FilePriority, Metainfo};
+    use crate::rpc::tests::TORRENT;
+    use crate::SessionConfig;
+    use rstest::rstest;
+    use std::net::Ipv4Addr;
+
+    #[rstest]
+    #[case("bytes=0-99", Ok(0..100))]
+    #[case("bytes=100-", Ok(100..1000))]
+    #[case("bytes=-100", Ok(900..1000))]
+    #[case("bytes=900-5000", Ok(900..1000))]
+    #[case("bytes=1000-", Err(RangeError::Unsatisfiable))]
+    #[case("bytes=-0", Err(RangeError::Unsatisfiable))]
+    #[case("bytes=5-1", Err(RangeError::Invalid))]
+    #[case("bytes=0-1,5-6", Err(RangeError::Invalid))]
+    #[case("items=0-1", Err(RangeError::Invalid))]
+    fn test_parse_range(#[case] value: &str, #[case] expected: Result<Range<u64>, RangeError>) {
+        assert_eq!(parse_range(value, 1000), expected);
+    }
+
+    #[tokio::test]
+    async fn test_serves_ranges() {
+        // the torrent stays queued, so that reads keep waiting rather than failing.
+        let session = Session::new(SessionConfig {
+            port: 0,
+            max_active_torrents: 0,
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap();
+        let info_hash = session
+            .add_torrent(Metainfo::from_bytes(TORRENT).unwrap())
+            .unwrap();
+        session
+            .set_file_priority(&info_hash, 0, FilePriority::Skip)
+            .unwrap();
+        let session = Arc::new(session);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(session.clone(), listener));
+        let client = reqwest::Client::new();
+
+        let index = client.get(&url).send().await.unwrap().text().await.unwrap();
+        assert!(index.contains("<a href=\"/test\">test</a>"));
+
+        // HEAD leaves the skipped file alone.
+        let response = client.head(format!("{url}/test")).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-length"], "16384");
+        let priority = session.files(&info_hash).unwrap()[0].priority;
+        assert_eq!(priority, FilePriority::Skip);
+
+        // the headers come right away, the body as the pieces arrive. reqwest is on an older
+        // version of the http crate, so the headers and statuses are spelled out.
+        let response = client
+            .get(format!("{url}/test"))
+            .header("range", "bytes=100-199")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.headers()["content-range"], "bytes 100-199/16384");
+        assert_eq!(response.headers()["content-length"], "100");
+        let priority = session.files(&info_hash).unwrap()[0].priority;
+        assert_eq!(priority, FilePriority::Normal);
+
+        let response = client
+            .get(format!("{url}/test"))
+            .header("range", "bytes=16384-")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 416);
+        assert_eq!(response.headers()["content-range"], "bytes */16384");
+
+        let response = client.get(format!("{url}/nope")).send().await.unwrap();
+        assert_eq!(response.status(), 404);
+    }
+}
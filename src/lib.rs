@@ -0,0 +1,24 @@
+//! a bittorrent (v1) client. [`Session`] is the entry point, it downloads any number of torrents
+//! while sharing the listen port, peer id and connection limits between them. the protocol layers
+//! it's built from are public as well, for anyone who'd rather drive them on their own.
+pub mod connection_manager;
+pub mod dht;
+mod engine;
+pub mod http_server;
+pub mod metainfo;
+pub mod metrics;
+pub mod peer_protocol;
+pub mod peers;
+mod prelude;
+pub mod rate_limit;
+pub mod rpc;
+pub mod session;
+mod storage;
+pub mod torrent;
+pub mod tracker;
+pub mod webseed;
+
+pub use session::{
+    FileStream, Session, SessionConfig, SessionError, SessionEvent, TorrentState, TorrentStatus,
+};
+pub use torrent::{InfoHash, PeerId};
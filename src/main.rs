@@ -1,170 +1,349 @@
 mod cli;
-mod metainfo;
-mod peer_protocol;
-mod peers;
-mod prelude;
-mod torrent;
-mod tracker;
+mod config;
+mod ui;
 
 use clap::Parser;
-use cli::Cli;
-use prelude::*;
-
-use tokio::sync::mpsc;
-use tokio::task;
-use tracing::Level;
-
-use metainfo::{url::TrackerUrl, DownloadInfo};
-use peers::{
-    download_worker::{PeerAddr, PeerDownloadWorker},
-    PeerAlerts, PeerCommands, PieceRequestInfo,
-};
-use torrent::{Bitfield, InfoHash, PeerId};
-
-use std::net::SocketAddrV4;
-
-use tracker::{
-    request::{Requestable, TrackerRequest},
-    Announce, HttpTracker,
+use cli::{
+    Cli, Command, ConfigCommand, FileFilter, MetainfoFilePath, RemoteCommand, SessionArgs, UiMode,
 };
-
-struct PeerSession {
-    peer_addr: std::net::SocketAddrV4,
-    bitfield: Bitfield,
-    commands_tx: mpsc::Sender<PeerCommands>,
-}
+use config::Config;
+use crux_torrent::metainfo::{DownloadInfo, Metainfo};
+use crux_torrent::rpc::{self, Method, RpcClient, TorrentSource};
+use crux_torrent::{http_server, metrics};
+use crux_torrent::{InfoHash, Session, SessionEvent};
+use reqwest::Url;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{error, info, warn, Level};
+use ui::bar::ProgressBar;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    tracing_subscriber::fmt()
-        .with_max_level(Level::INFO)
-        .pretty()
-        .with_target(false)
-        .init();
     let matches = Cli::parse();
-    let metainfo = metainfo::Metainfo::from_bencode_file(matches.source).await?;
-
-    let peer_id = PeerId::random();
-    let request = TrackerRequest::new(peer_id.clone(), matches.port, &metainfo.file_info)?;
-    let client = reqwest::Client::new();
-    let response = match metainfo.announce {
-        // TODO: handle udp trackers, BEP: https://www.bittorrent.org/beps/bep_0015.html
-        TrackerUrl::Udp(udp_url) => todo!(),
-        TrackerUrl::Http(http_url) => {
-            HttpTracker::new(&client, http_url)
-                .announce(&request)
-                .await?
-        }
-    };
-    let piece_index = 0;
-    let (length, hash) = match &metainfo.file_info {
-        DownloadInfo::MultiFile {
-            piece_length,
-            pieces,
-            ..
-        } => (*piece_length as u32, pieces[piece_index]),
-        DownloadInfo::SingleFile {
-            piece_length,
-            pieces,
-            ..
-        } => (*piece_length as u32, pieces[piece_index]),
+    let ui = match matches.command {
+        Some(_) => UiMode::Log,
+        None => matches.ui.resolve(),
     };
+    init_tracing(ui, matches.log_file.as_deref())?;
+
+    match matches.command {
+        Some(Command::Daemon { session }) => daemon(&session).await,
+        Some(Command::Serve {
+            source,
+            http,
+            files,
+            session,
+        }) => serve(&source, http, &files, &session).await,
+        Some(Command::Remote { rpc_url, command }) => remote(rpc_url, command).await,
+        Some(Command::Config {
+            command: ConfigCommand::Show { session },
+        }) => show_config(&session),
+        None => download(matches, ui).await,
+    }
+}
 
-    let piece_request_info = PieceRequestInfo::new(piece_index, length, hash);
+fn init_tracing(ui: UiMode, log_file: Option<&Path>) -> Result<(), anyhow::Error> {
+    let subscriber = tracing_subscriber::fmt().with_target(false);
+    if let Some(log_file) = log_file {
+        let log_file = std::fs::File::options()
+            .create(true)
+            .append(true)
+            .open(log_file)?;
+        subscriber
+            .with_max_level(Level::INFO)
+            .with_ansi(false)
+            .with_writer(Mutex::new(log_file))
+            .init();
+        return Ok(());
+    }
+
+    match ui {
+        // the screen belongs to the ui, logs only go to a log file.
+        UiMode::Tui => {}
+        // anything logged is printed over the bar, so only what's worth the mess.
+        UiMode::Bar => subscriber
+            .with_max_level(Level::WARN)
+            .compact()
+            .with_writer(std::io::stderr)
+            .init(),
+        UiMode::Log | UiMode::Auto => subscriber.with_max_level(Level::INFO).pretty().init(),
+    }
+    Ok(())
+}
 
-    let info_hash = metainfo.file_info.get_info_hash()?;
-    let mut join_set = task::JoinSet::<anyhow::Result<()>>::new();
+// downloads the torrents given on the command line, and exits once they're all done.
+async fn download(matches: Cli, ui: UiMode) -> Result<(), anyhow::Error> {
+    let mut metainfos = Vec::with_capacity(matches.sources.len());
+    for source in &matches.sources {
+        metainfos.push(load_metainfo(source, &matches.files).await?);
+    }
+    let config = Config::from_args(&matches.session)?;
+    let session = Arc::new(Session::new(config.session_config()).await?);
+    if let Some(metrics_addr) = config.network.metrics_addr {
+        serve_metrics(session.clone(), metrics_addr).await?;
+    }
 
-    let (alerts_tx, alerts_rx) = mpsc::channel::<PeerAlerts>(100);
-    let mut abort_handles = Vec::new();
-    for addr in &response.peer_addreses {
-        let addr = *addr;
-        let info_hash = info_hash.clone();
-        let peer_id = peer_id.clone();
-        let alerts_channel = alerts_tx.clone();
+    // subscribed before adding the torrents, so that none of their events are missed.
+    let events = session.subscribe();
+    let mut remaining = HashSet::new();
+    for metainfo in metainfos {
+        let info_hash = session.add_torrent(metainfo)?;
+        if matches.sequential {
+            session.set_sequential(&info_hash, true)?;
+        }
+        remaining.insert(info_hash);
+    }
+    let done = wait_for_torrents(events, remaining);
 
-        let handle = join_set.spawn(spawn_peer(addr, alerts_channel, info_hash, peer_id));
+    let failed = match ui {
+        UiMode::Tui => {
+            let finished = Arc::new(AtomicBool::new(false));
+            let mut tui = tokio::task::spawn_blocking({
+                let (session, finished) = (session.clone(), finished.clone());
+                move || ui::tui::run(&session, &finished)
+            });
+            let failed = tokio::select! {
+                failed = done => {
+                    finished.store(true, Ordering::Relaxed);
+                    tui.await??;
+                    failed
+                }
+                quit = &mut tui => {
+                    quit??;
+                    info!("quit before the torrents were done");
+                    return Ok(());
+                }
+            };
+            // the ui leaves nothing behind on the screen, so this is all that's left of it.
+            for status in session.torrents() {
+                println!("{}: {:?}", status.name, status.state);
+            }
+            failed
+        }
+        UiMode::Bar => {
+            let bar = ProgressBar::new(session.clone());
+            let failed = tokio::select! {
+                failed = done => failed,
+                () = bar.run() => unreachable!("the bar is drawn until it's dropped"),
+            };
+            bar.finish();
+            failed
+        }
+        UiMode::Log | UiMode::Auto => done.await,
+    };
 
-        abort_handles.push(handle);
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} torrents failed", matches.sources.len());
     }
+    Ok(())
+}
 
-    let mut engine_handle = task::spawn(engine(alerts_rx, piece_request_info));
-    loop {
-        tokio::select! {
-            Some(result) = join_set.join_next() => {
-                eprintln!("{:?}", result);
+// reads the torrent file, with the priorities of its files set as the filter has them.
+async fn load_metainfo(
+    source: &MetainfoFilePath,
+    filter: &FileFilter,
+) -> Result<Metainfo, anyhow::Error> {
+    let mut metainfo = Metainfo::from_bencode_file(source).await?;
+    if let DownloadInfo::MultiFile { files, .. } = &mut metainfo.file_info {
+        for file in files {
+            let path: PathBuf = file.path.iter().collect();
+            file.priority = filter.priority(&path);
+        }
+    }
+    Ok(metainfo)
+}
+
+// waits for every one of the torrents to either complete or fail, and counts the failures.
+async fn wait_for_torrents(
+    mut events: broadcast::Receiver<SessionEvent>,
+    mut remaining: HashSet<InfoHash>,
+) -> usize {
+    let mut failed = 0;
+    while !remaining.is_empty() {
+        match events.recv().await {
+            Ok(SessionEvent::TorrentCompleted { info_hash }) => {
+                info!(?info_hash, "torrent complete");
+                remaining.remove(&info_hash);
             }
+            Ok(SessionEvent::TorrentFailed { info_hash, error }) => {
+                error!(?info_hash, %error, "torrent failed");
+                remaining.remove(&info_hash);
+                failed += 1;
+            }
+            Ok(event) => info!(?event, "session event"),
+            Err(RecvError::Lagged(missed)) => warn!(missed, "missed session events"),
+            Err(RecvError::Closed) => break,
+        }
+    }
+    failed
+}
+
+async fn daemon(session_args: &SessionArgs) -> Result<(), anyhow::Error> {
+    let config = Config::from_args(session_args)?;
+    let rpc_addr = config.network.rpc_addr;
+    let transmission_addr = config.network.transmission_addr;
+    let session = Arc::new(Session::new(config.session_config()).await?);
+    tokio::spawn(log_events(session.subscribe()));
+    if let Some(metrics_addr) = config.network.metrics_addr {
+        serve_metrics(session.clone(), metrics_addr).await?;
+    }
 
-            _ = &mut engine_handle => {
-                break;
+    let listener = TcpListener::bind(rpc_addr).await?;
+    info!(%rpc_addr, "serving rpc requests");
+    let transmission = match transmission_addr {
+        Some(transmission_addr) => {
+            let listener = TcpListener::bind(transmission_addr).await?;
+            info!(%transmission_addr, "serving transmission rpc requests");
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let serve_transmission = {
+        let session = session.clone();
+        async move {
+            match transmission {
+                Some(listener) => rpc::transmission::serve(session, listener).await,
+                None => std::future::pending().await,
             }
         }
+    };
+    tokio::select! {
+        result = rpc::serve(session, listener) => result?,
+        result = serve_transmission => result?,
+        _ = tokio::signal::ctrl_c() => info!("shutting down"),
+    }
+    Ok(())
+}
+
+// the pieces are downloaded in order, the ones which are being read from ahead of the rest.
+async fn serve(
+    source: &MetainfoFilePath,
+    http_addr: SocketAddr,
+    filter: &FileFilter,
+    session_args: &SessionArgs,
+) -> Result<(), anyhow::Error> {
+    let metainfo = load_metainfo(source, filter).await?;
+    let config = Config::from_args(session_args)?;
+    let session = Arc::new(Session::new(config.session_config()).await?);
+    tokio::spawn(log_events(session.subscribe()));
+    if let Some(metrics_addr) = config.network.metrics_addr {
+        serve_metrics(session.clone(), metrics_addr).await?;
+    }
+
+    let info_hash = session.add_torrent(metainfo)?;
+    session.set_sequential(&info_hash, true)?;
+    let listener = TcpListener::bind(http_addr).await?;
+    info!("serving the files of the torrent on http://{http_addr}/");
+    tokio::select! {
+        result = http_server::serve(session, listener) => result?,
+        _ = tokio::signal::ctrl_c() => info!("shutting down"),
     }
     Ok(())
 }
 
-#[instrument(
-    level = "info",
-    name = "peer worker",
-    fields(peer = %peer_addr),
-    skip_all
-)]
-async fn spawn_peer(
-    peer_addr: SocketAddrV4,
-    alerts_channel: mpsc::Sender<PeerAlerts>,
-    info_hash: InfoHash,
-    peer_id: PeerId,
-) -> anyhow::Result<()> {
-    let connx = PeerAddr::new(peer_addr);
-    let mut worker =
-        PeerDownloadWorker::init_from(connx.handshake(info_hash, peer_id).await?, alerts_channel)
-            .await?;
-    worker.start_peer_event_loop().await?;
+// binds right away so that a port which is taken is reported, then serves in the background.
+async fn serve_metrics(
+    session: Arc<Session>,
+    metrics_addr: SocketAddr,
+) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::bind(metrics_addr).await?;
+    info!(%metrics_addr, "serving metrics");
+    tokio::spawn(async move {
+        if let Err(err) = metrics::serve(session, listener).await {
+            error!(%err, "metrics server stopped");
+        }
+    });
+    Ok(())
+}
+
+fn show_config(session_args: &SessionArgs) -> Result<(), anyhow::Error> {
+    let config = Config::from_args(session_args)?;
+    let path = session_args.config.clone().or_else(Config::default_path);
+    match path {
+        Some(path) if path.is_file() => println!("# read from {}", path.display()),
+        _ => println!("# no config file was found"),
+    }
+    print!("{}", toml::to_string(&config)?);
     Ok(())
 }
 
-#[instrument(level = "info", name = "engine", skip(alerts_rx))]
-async fn engine(
-    mut alerts_rx: mpsc::Receiver<PeerAlerts>,
-    request_info: PieceRequestInfo,
-) -> anyhow::Result<()> {
-    let mut peers_listen = Vec::new();
+async fn log_events(mut events: broadcast::Receiver<SessionEvent>) {
     loop {
-        let alert = match alerts_rx.recv().await {
-            Some(alert) => alert,
-            None => anyhow::bail!("all peers closed down"),
-        };
-
-        type PA = PeerAlerts;
-        match alert {
-            PA::InitPeer {
-                peer_addr,
-                bitfield,
-                commands_tx,
-            } => {
-                let span = info_span!("processing init from {peer}", peer = peer_addr.to_string());
-                let _gaurd = span.enter();
-                info!("received init peer");
-                if bitfield[request_info.index] {
-                    commands_tx
-                        .send(PeerCommands::DownloadPiece(request_info.clone()))
-                        .await?;
-                };
-                peers_listen.push(PeerSession {
-                    peer_addr,
-                    bitfield,
-                    commands_tx,
-                });
-            }
-            PA::DonePiece {
-                piece_index,
-                piece: _,
-            } => {
-                info!(piece_index, "received piece done");
-                break;
-            }
-            _ => todo!(),
+        match events.recv().await {
+            Ok(event) => info!(?event, "session event"),
+            Err(RecvError::Lagged(missed)) => warn!(missed, "missed session events"),
+            Err(RecvError::Closed) => break,
         }
     }
+}
+
+async fn remote(rpc_url: Url, command: RemoteCommand) -> Result<(), anyhow::Error> {
+    let method = match command {
+        RemoteCommand::Add { source } => Method::AddTorrent {
+            source: if source.starts_with("magnet:") {
+                TorrentSource::Magnet(source)
+            } else if source.starts_with("http://") || source.starts_with("https://") {
+                TorrentSource::Url(source)
+            } else {
+                TorrentSource::File(tokio::fs::read(&source).await?)
+            },
+        },
+        RemoteCommand::List => Method::ListTorrents,
+        RemoteCommand::Status { info_hash } => Method::TorrentStatus { info_hash },
+        RemoteCommand::Pause { info_hash } => Method::PauseTorrent { info_hash },
+        RemoteCommand::Resume { info_hash } => Method::ResumeTorrent { info_hash },
+        RemoteCommand::Remove {
+            info_hash,
+            delete_data,
+        } => Method::RemoveTorrent {
+            info_hash,
+            delete_data,
+        },
+        RemoteCommand::Limits { torrent: None } => Method::GetRateLimits,
+        RemoteCommand::Limits {
+            torrent: Some(info_hash),
+        } => Method::GetTorrentRateLimits { info_hash },
+        RemoteCommand::SetLimits {
+            torrent: None,
+            download,
+            upload,
+        } => Method::SetRateLimits { download, upload },
+        RemoteCommand::SetLimits {
+            torrent: Some(info_hash),
+            download,
+            upload,
+        } => Method::SetTorrentRateLimits {
+            info_hash,
+            download,
+            upload,
+        },
+        RemoteCommand::Peers { info_hash } => Method::ListPeers { info_hash },
+        RemoteCommand::Trackers { info_hash } => Method::ListTrackers { info_hash },
+        RemoteCommand::Files { info_hash } => Method::ListFiles { info_hash },
+        RemoteCommand::Sequential { info_hash, enabled } => Method::SetSequential {
+            info_hash,
+            sequential: enabled,
+        },
+        RemoteCommand::Priority {
+            info_hash,
+            file,
+            priority,
+        } => Method::SetFilePriority {
+            info_hash,
+            file,
+            priority,
+        },
+    };
+
+    let result: serde_json::Value = RpcClient::new(rpc_url).call(method).await?;
+    if !result.is_null() {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    }
     Ok(())
 }
@@ -1,8 +1,9 @@
-use super::{FileInfo, PieceHash};
+use super::{FileInfo, FilePriority, PieceHash};
 use crate::torrent::InfoHash;
 use crate::tracker::request::Requestable;
 use serde::{Deserialize, Serialize};
 use sha1_smol::Sha1;
+use std::path::{Component, Path, PathBuf};
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
@@ -42,6 +43,100 @@ pub enum DownloadInfo {
     },
 }
 
+impl DownloadInfo {
+    /// the file name, or the directory name for multi file torrents.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::SingleFile { filename, .. } => filename,
+            Self::MultiFile { dirname, .. } => dirname,
+        }
+    }
+
+    /// private torrents get their peers from their trackers only, never from the dht.
+    /// https://www.bittorrent.org/beps/bep_0027.html
+    pub fn is_private(&self) -> bool {
+        match self {
+            Self::SingleFile { private, .. } | Self::MultiFile { private, .. } => {
+                *private == Some(1)
+            }
+        }
+    }
+
+    pub fn piece_length(&self) -> usize {
+        match self {
+            Self::SingleFile { piece_length, .. } | Self::MultiFile { piece_length, .. } => {
+                *piece_length
+            }
+        }
+    }
+
+    /// the path of every file relative to the download directory, along with its length, in the
+    /// order they're laid out one after the other in the pieces.
+    pub fn files(&self) -> Vec<(PathBuf, usize)> {
+        match self {
+            Self::SingleFile {
+                filename, length, ..
+            } => vec![(PathBuf::from(filename), *length)],
+            Self::MultiFile { dirname, files, .. } => files
+                .iter()
+                .map(|file| {
+                    let path: PathBuf = std::iter::once(dirname).chain(&file.path).collect();
+                    (path, file.length)
+                })
+                .collect(),
+        }
+    }
+
+    /// the priority each of the files starts out with, in the same order as [`Self::files`].
+    /// single file torrents are always downloaded whole.
+    pub fn file_priorities(&self) -> Vec<FilePriority> {
+        match self {
+            Self::SingleFile { .. } => vec![FilePriority::Normal],
+            Self::MultiFile { files, .. } => files.iter().map(|file| file.priority).collect(),
+        }
+    }
+
+    /// makes sure each part of the paths of the files names a single entry, so that the files
+    /// all end up within the download directory rather than wherever the torrent says.
+    pub fn check_paths(&self) -> anyhow::Result<()> {
+        match self {
+            Self::SingleFile { filename, .. } => check_path_segment(filename),
+            Self::MultiFile { dirname, files, .. } => {
+                check_path_segment(dirname)?;
+                for file in files {
+                    if file.path.is_empty() {
+                        anyhow::bail!("file in torrent has an empty path");
+                    }
+                    file.path
+                        .iter()
+                        .try_for_each(|segment| check_path_segment(segment))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn piece_hashes(&self) -> &[PieceHash] {
+        match self {
+            Self::SingleFile { pieces, .. } | Self::MultiFile { pieces, .. } => pieces,
+        }
+    }
+}
+
+// separators are rejected whichever platform this runs on, a torrent made on windows would
+// otherwise be read differently than elsewhere.
+fn check_path_segment(segment: &str) -> anyhow::Result<()> {
+    let mut components = Path::new(segment).components();
+    let is_normal = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    );
+    if !is_normal || segment.contains(['/', '\\']) {
+        anyhow::bail!("invalid path segment {segment:?} in torrent");
+    }
+    Ok(())
+}
+
 impl Requestable for DownloadInfo {
     fn get_info_hash(&self) -> anyhow::Result<InfoHash> {
         let info_hash = serde_bencode::to_bytes(self)?;
@@ -96,7 +191,7 @@ mod piece_hashes_parser {
         {
             let n_bytes = bytes.len();
 
-            if n_bytes % HASH_SIZE != 0 {
+            if !n_bytes.is_multiple_of(HASH_SIZE) {
                 return Err(E::custom(static_format!(
                     "piece hash pieces should be a multiple of length {}",
                     HASH_SIZE
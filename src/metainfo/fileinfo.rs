@@ -7,4 +7,34 @@ pub struct FileInfo {
 
     #[serde(default)]
     pub md5sum: Option<String>,
+
+    /// not part of the metainfo, it's the priority the file starts out with once the torrent is
+    /// added to a session.
+    #[serde(skip)]
+    pub priority: FilePriority,
+}
+
+/// how badly a file of a torrent is wanted, the pieces of higher priority files are picked first.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Default,
+    clap::ValueEnum,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FilePriority {
+    /// not downloaded at all, apart from the pieces it shares with files which are.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
 }
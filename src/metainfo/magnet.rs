@@ -0,0 +1,159 @@
+//! magnet links, which name a torrent by its info hash alone. the info dictionary has to be
+//! fetched from peers before it can be downloaded.
+//! https://www.bittorrent.org/beps/bep_0009.html#magnet-uri-format
+use crate::torrent::InfoHash;
+use crate::tracker::request::Requestable;
+use reqwest::Url;
+use std::net::SocketAddrV4;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: InfoHash,
+    /// the name to show until the metadata's in, `dn`.
+    pub name: Option<String>,
+    /// tracker urls, `tr`.
+    pub trackers: Vec<String>,
+    /// web seed urls, `ws`.
+    pub web_seeds: Vec<String>,
+    /// peers to ask for the metadata right away, `x.pe`. only ip:port ones are kept.
+    pub peers: Vec<SocketAddrV4>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseMagnetError {
+    #[error("not a magnet link")]
+    NotMagnet,
+    #[error("magnet link has no bittorrent info hash")]
+    MissingInfoHash,
+    #[error("invalid info hash {0:?} in magnet link")]
+    InvalidInfoHash(String),
+}
+
+impl FromStr for MagnetLink {
+    type Err = ParseMagnetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|_| ParseMagnetError::NotMagnet)?;
+        if url.scheme() != "magnet" {
+            return Err(ParseMagnetError::NotMagnet);
+        }
+
+        let mut info_hash = None;
+        let mut magnet = Self {
+            info_hash: InfoHash::new([0; InfoHash::INFO_HASH_SIZE]),
+            name: None,
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+            peers: Vec::new(),
+        };
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                // other kinds of urns, e.g the btmh of v2 torrents, are skipped.
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => magnet.name = Some(value.into_owned()),
+                "tr" => magnet.trackers.push(value.into_owned()),
+                "ws" => magnet.web_seeds.push(value.into_owned()),
+                "x.pe" => magnet.peers.extend(value.parse::<SocketAddrV4>().ok()),
+                _ => {}
+            }
+        }
+        magnet.info_hash = info_hash.ok_or(ParseMagnetError::MissingInfoHash)?;
+        Ok(magnet)
+    }
+}
+
+/// the size isn't known before the metadata is, a left of 0 would pass us off as a seed though.
+impl Requestable for MagnetLink {
+    fn get_info_hash(&self) -> anyhow::Result<InfoHash> {
+        Ok(self.info_hash.clone())
+    }
+
+    fn get_request_length(&self) -> usize {
+        1
+    }
+}
+
+// 40 hex digits, or 32 base32 ones as older clients made them.
+fn parse_info_hash(hash: &str) -> Result<InfoHash, ParseMagnetError> {
+    let invalid = || ParseMagnetError::InvalidInfoHash(hash.to_owned());
+    if hash.len() == 40 {
+        return hash.parse().map_err(|_| invalid());
+    }
+    if hash.len() != 32 {
+        return Err(invalid());
+    }
+
+    let mut bytes = [0; InfoHash::INFO_HASH_SIZE];
+    let (mut buffer, mut bits, mut len) = (0u64, 0, 0);
+    for c in hash.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return Err(invalid()),
+        };
+        buffer = buffer << 5 | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes[len] = (buffer >> bits) as u8;
+            len += 1;
+        }
+    }
+    Ok(InfoHash::new(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let magnet: MagnetLink = "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\
+            &dn=some%20name&tr=http%3A%2F%2Ftracker.example%2Fannounce\
+            &tr=udp%3A%2F%2Ftracker.example%3A6969&x.pe=10.0.0.1:6881&x.pe=peer.example:6881"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            magnet.info_hash.to_string(),
+            "c12fe1c06bba254a9dc9f519b335aa7c1367a88a"
+        );
+        assert_eq!(magnet.name.as_deref(), Some("some name"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                "http://tracker.example/announce",
+                "udp://tracker.example:6969"
+            ]
+        );
+        assert_eq!(magnet.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_parse_base32_info_hash() {
+        let magnet: MagnetLink = "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            magnet.info_hash.to_string(),
+            "c12fe1c06bba254a9dc9f519b335aa7c1367a88a"
+        );
+    }
+
+    #[test]
+    fn test_info_hash_is_required() {
+        assert!(matches!(
+            "magnet:?dn=name".parse::<MagnetLink>(),
+            Err(ParseMagnetError::MissingInfoHash)
+        ));
+        assert!(matches!(
+            "http://example.com/?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a"
+                .parse::<MagnetLink>(),
+            Err(ParseMagnetError::NotMagnet)
+        ));
+    }
+}
@@ -1,12 +1,18 @@
-use super::url::TrackerUrl;
-use super::DownloadInfo;
+use super::url::{self, TrackerUrl};
+use super::{DownloadInfo, MagnetLink};
+use crate::prelude::*;
+use crate::tracker::request::Requestable;
+use reqwest::Url;
 use serde::Deserialize;
 use std::path::Path;
 use tokio::fs;
 
 #[derive(Debug, Deserialize)]
 pub struct Metainfo {
-    pub announce: TrackerUrl,
+    /// missing for torrents which get their peers from the dht only, and for magnet links
+    /// without an http or udp tracker.
+    #[serde(default)]
+    pub announce: Option<TrackerUrl>,
 
     #[serde(rename = "info")]
     pub file_info: DownloadInfo,
@@ -28,13 +34,160 @@ pub struct Metainfo {
 
     #[serde(default)]
     pub encoding: Option<String>,
+
+    /// web seeds (BEP 19), servers which host the files of the torrent. it's either a single url
+    /// or a list of them.
+    #[serde(default)]
+    #[serde(rename = "url-list", deserialize_with = "url::string_or_list")]
+    pub url_list: Vec<String>,
+
+    /// http seeds (BEP 17), scripts which serve the pieces of the torrent by index.
+    #[serde(default)]
+    #[serde(deserialize_with = "url::string_or_list")]
+    pub httpseeds: Vec<String>,
 }
 
 impl Metainfo {
     pub async fn from_bencode_file(file: impl AsRef<Path>) -> anyhow::Result<Self> {
         let file_contents = fs::read(file).await?;
-        let metainfo: Metainfo =
-            serde_bencode::from_bytes(&file_contents).map_err(anyhow::Error::msg)?;
+        Self::from_bytes(&file_contents)
+    }
+
+    /// parses the contents of a torrent file, e.g one downloaded over http.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let metainfo: Metainfo = serde_bencode::from_bytes(bytes).map_err(anyhow::Error::msg)?;
+        metainfo.file_info.check_paths()?;
         Ok(metainfo)
     }
+
+    /// puts a torrent together from a magnet link and the info dictionary fetched for it, which
+    /// has to hash to the magnet's info hash once parsed. that's also how the info hash of torrent
+    /// files is worked out, so it catches keys which would be lost on the way.
+    pub fn from_magnet(magnet: &MagnetLink, info: &[u8]) -> anyhow::Result<Self> {
+        let file_info: DownloadInfo =
+            serde_bencode::from_bytes(info).map_err(anyhow::Error::msg)?;
+        file_info.check_paths()?;
+        if file_info.get_info_hash()? != magnet.info_hash {
+            anyhow::bail!("info dictionary has keys which aren't supported");
+        }
+
+        let trackers: Vec<_> = magnet
+            .trackers
+            .iter()
+            .filter_map(|url| TrackerUrl::new(url.as_str()).ok())
+            .collect();
+        // udp trackers can't be announced to yet, so an http one goes first.
+        let announce = trackers
+            .iter()
+            .find(|url| matches!(url, TrackerUrl::Http(_)))
+            .or(trackers.first())
+            .cloned();
+        Ok(Self {
+            announce,
+            file_info,
+            // each tracker in a tier of its own, as BEP 12 has it for magnet links.
+            announce_list: (!magnet.trackers.is_empty()).then(|| {
+                magnet
+                    .trackers
+                    .iter()
+                    .map(|url| vec![url.clone()])
+                    .collect()
+            }),
+            creation_date: None,
+            created_by: None,
+            comment: None,
+            encoding: None,
+            url_list: magnet.web_seeds.clone(),
+            httpseeds: Vec::new(),
+        })
+    }
+
+    /// the web seeds of the `url-list` which can be downloaded from. only http(s) ones are
+    /// supported, BEP 19 also allows ftp ones but they're skipped with a warning.
+    pub fn web_seeds(&self) -> Vec<Url> {
+        http_urls(&self.url_list)
+    }
+
+    /// the same for the `httpseeds`.
+    pub fn http_seeds(&self) -> Vec<Url> {
+        http_urls(&self.httpseeds)
+    }
+}
+
+fn http_urls(urls: &[String]) -> Vec<Url> {
+    urls.iter()
+        .filter(|url| !url.is_empty())
+        .filter_map(|url| match Url::parse(url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => Some(url),
+            // TODO: ftp web seeds, BEP: https://www.bittorrent.org/beps/bep_0019.html
+            Ok(url) => {
+                warn!(%url, "ignoring web seed with an unsupported scheme");
+                None
+            }
+            Err(err) => {
+                warn!(url, %err, "ignoring invalid web seed");
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(b"", &[])]
+    #[case(b"8:url-list0:", &[])]
+    #[case(b"8:url-list18:http://a.test/pub/", &["http://a.test/pub/"])]
+    #[case(
+        b"8:url-listl13:http://a.test14:ftp://b.test/f16:https://c.test/xe",
+        &["http://a.test/", "https://c.test/x"]
+    )]
+    fn test_web_seeds(#[case] url_list: &[u8], #[case] expected: &[&str]) {
+        let mut torrent = b"d8:announce26:udp://127.0.0.1:1/announce".to_vec();
+        torrent.extend_from_slice(
+            b"4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+        );
+        torrent.extend_from_slice(url_list);
+        torrent.push(b'e');
+
+        let metainfo = Metainfo::from_bytes(&torrent).unwrap();
+        let web_seeds: Vec<_> = metainfo.web_seeds().iter().map(Url::to_string).collect();
+        assert_eq!(web_seeds, expected);
+    }
+
+    #[rstest]
+    #[case(b"4:name3:dir", b"l1:xe", true)]
+    #[case(b"4:name3:dir", b"l3:sub1:xe", true)]
+    #[case(b"4:name3:dir", b"l2:..1:xe", false)]
+    #[case(b"4:name3:dir", b"l3:a/b1:xe", false)]
+    #[case(b"4:name3:dir", b"l3:a\\be", false)]
+    #[case(b"4:name3:dir", b"l1:.e", false)]
+    #[case(b"4:name3:dir", b"le", false)]
+    #[case(b"4:name4:/etc", b"l1:xe", false)]
+    #[case(b"4:name2:..", b"l1:xe", false)]
+    fn test_rejects_paths_outside_the_download_dir(
+        #[case] name: &[u8],
+        #[case] path: &[u8],
+        #[case] valid: bool,
+    ) {
+        let mut torrent =
+            b"d8:announce26:udp://127.0.0.1:1/announce4:infod5:filesld6:lengthi1e4:path".to_vec();
+        torrent.extend_from_slice(path);
+        torrent.extend_from_slice(b"ee");
+        torrent.extend_from_slice(name);
+        torrent.extend_from_slice(b"12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee");
+        assert_eq!(Metainfo::from_bytes(&torrent).is_ok(), valid);
+    }
+
+    #[test]
+    fn test_http_seeds() {
+        let torrent = b"d8:announce26:udp://127.0.0.1:1/announce9:httpseedsl22:http://a.test/seed.phpe4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let metainfo = Metainfo::from_bytes(torrent).unwrap();
+        let http_seeds: Vec<_> = metainfo.http_seeds().iter().map(Url::to_string).collect();
+        assert_eq!(http_seeds, ["http://a.test/seed.php"]);
+        assert!(metainfo.web_seeds().is_empty());
+    }
 }
@@ -1,5 +1,7 @@
+//! parsing of .torrent files, i.e the metainfo dictionary and the info dictionary within it.
 mod download_info;
 mod fileinfo;
+mod magnet;
 #[allow(clippy::module_inception)]
 mod metainfo;
 pub mod url;
@@ -7,5 +9,6 @@ pub mod url;
 pub type PieceHash = [u8; sha1_smol::DIGEST_LENGTH];
 
 pub use download_info::DownloadInfo;
-pub use fileinfo::FileInfo;
+pub use fileinfo::{FileInfo, FilePriority};
+pub use magnet::{MagnetLink, ParseMagnetError};
 pub use metainfo::Metainfo;
@@ -25,6 +25,15 @@ impl AsRef<str> for UdpUrl {
     }
 }
 
+impl AsRef<str> for TrackerUrl {
+    fn as_ref(&self) -> &str {
+        match self {
+            Self::Http(url) => url.as_ref(),
+            Self::Udp(url) => url.as_ref(),
+        }
+    }
+}
+
 impl HttpUrl {
     pub fn into_inner(self) -> Url {
         self.0
@@ -52,7 +61,7 @@ impl Into<Url> for UdpUrl {
 }
 
 impl TrackerUrl {
-    fn new(url: impl IntoUrl) -> anyhow::Result<Self> {
+    pub(crate) fn new(url: impl IntoUrl) -> anyhow::Result<Self> {
         let url = url.into_url()?;
         Ok(match url.scheme() {
             "http" => Self::Http(HttpUrl(url)),
@@ -94,3 +103,46 @@ impl<'a> Visitor<'a> for TrackerUrlVisitor {
         TrackerUrl::new(v).map_err(serde::de::Error::custom)
     }
 }
+
+/// a string, or a list of them as some fields of the metainfo can be either.
+pub(super) fn string_or_list<'a, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'a>,
+{
+    deserializer.deserialize_any(StringOrListVisitor)
+}
+
+struct StringOrListVisitor;
+impl<'a> Visitor<'a> for StringOrListVisitor {
+    type Value = Vec<String>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a string or a list of strings")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(vec![v.to_owned()])
+    }
+
+    // bencode strings are bytes, which is what serde_bencode hands over when it's asked for any.
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(vec![String::from_utf8_lossy(v).into_owned()])
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'a>,
+    {
+        let mut strings = Vec::new();
+        while let Some(bytes) = seq.next_element::<serde_bytes::ByteBuf>()? {
+            strings.push(String::from_utf8_lossy(&bytes).into_owned());
+        }
+        Ok(strings)
+    }
+}
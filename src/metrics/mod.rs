@@ -0,0 +1,354 @@
+//! prometheus metrics of a session, put together from the counters the engine, the workers and
+//! the rate limiters keep anyway, and served as text on `/metrics`.
+use crate::{Session, TorrentState};
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+pub const DEFAULT_METRICS_PORT: u16 = 8862;
+pub const METRICS_PATH: &str = "/metrics";
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// serves the metrics of the session until the listener fails.
+pub async fn serve(session: Arc<Session>, listener: TcpListener) -> std::io::Result<()> {
+    let app = Router::new()
+        .route(METRICS_PATH, get(metrics))
+        .with_state(session);
+    axum::serve(listener, app).await
+}
+
+async fn metrics(State(session): State<Arc<Session>>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, CONTENT_TYPE)], render(&session))
+}
+
+/// the metrics of the session in the prometheus text format.
+pub fn render(session: &Session) -> String {
+    let torrents: Vec<_> = session
+        .torrent_stats()
+        .into_iter()
+        .map(|stats| {
+            let status = stats.status();
+            let labels = Labels::new(&[
+                ("info_hash", &status.info_hash.to_string()),
+                ("name", &status.name),
+            ]);
+            (stats, status, labels)
+        })
+        .collect();
+    let mut out = Encoder::default();
+
+    out.family(
+        "crux_torrent_torrents",
+        "gauge",
+        "torrents of the session by state.",
+    );
+    for state in [
+        TorrentState::Queued,
+        TorrentState::Announcing,
+        TorrentState::Downloading,
+        TorrentState::Paused,
+        TorrentState::Complete,
+        TorrentState::Failed,
+    ] {
+        let count = torrents
+            .iter()
+            .filter(|(_, status, _)| status.state == state)
+            .count();
+        out.sample(&Labels::new(&[("state", state_name(state))]), count);
+    }
+
+    out.family(
+        "crux_torrent_downloaded_bytes_total",
+        "counter",
+        "payload bytes received from peers, including the ones thrown away.",
+    );
+    for (_, status, labels) in &torrents {
+        out.sample(labels, status.downloaded);
+    }
+    out.family(
+        "crux_torrent_uploaded_bytes_total",
+        "counter",
+        "payload bytes sent to peers.",
+    );
+    for (_, status, labels) in &torrents {
+        out.sample(labels, status.uploaded);
+    }
+
+    out.family(
+        "crux_torrent_pieces",
+        "gauge",
+        "pieces of the torrent, either all of them or the ones which passed the hash check.",
+    );
+    for (_, status, labels) in &torrents {
+        out.sample(&labels.with("state", "total"), status.num_pieces);
+        out.sample(&labels.with("state", "done"), status.pieces_done);
+    }
+    out.family(
+        "crux_torrent_hash_failures_total",
+        "counter",
+        "pieces which failed the hash check and had to be downloaded again.",
+    );
+    for (stats, _, labels) in &torrents {
+        out.sample(labels, stats.hash_failures());
+    }
+    out.family(
+        "crux_torrent_request_timeouts_total",
+        "counter",
+        "block requests which peers sat on for so long that they were cancelled.",
+    );
+    for (stats, _, labels) in &torrents {
+        out.sample(labels, stats.request_timeouts());
+    }
+
+    out.family(
+        "crux_torrent_peers",
+        "gauge",
+        "connected peers by state, a peer counts towards every state it's in.",
+    );
+    for (stats, _, labels) in &torrents {
+        let counts = stats.peer_counts();
+        for (state, count) in [
+            ("connected", counts.connected),
+            ("unchoked_by_peer", counts.unchoked_by_peer),
+            ("unchoked_by_us", counts.unchoked_by_us),
+            ("interested", counts.interested),
+            ("snubbed", counts.snubbed),
+        ] {
+            out.sample(&labels.with("state", state), count);
+        }
+    }
+
+    out.family(
+        "crux_torrent_announce_duration_seconds",
+        "histogram",
+        "time taken by each attempt at announcing to the tracker.",
+    );
+    for (stats, _, labels) in &torrents {
+        out.histogram(labels, stats.announce_latency());
+    }
+    out.family(
+        "crux_torrent_announce_errors_total",
+        "counter",
+        "announces which failed, each retry counts on its own.",
+    );
+    for (stats, _, labels) in &torrents {
+        out.sample(labels, stats.announce_errors());
+    }
+
+    out.family(
+        "crux_torrent_disk_duration_seconds",
+        "histogram",
+        "time taken by the storage to write a downloaded piece, or to read for a file stream.",
+    );
+    for (stats, _, labels) in &torrents {
+        out.histogram(&labels.with("op", "write"), stats.disk_write_latency());
+        out.histogram(&labels.with("op", "read"), stats.disk_read_latency());
+    }
+
+    let counts = session.connection_counts();
+    out.family(
+        "crux_torrent_connections",
+        "gauge",
+        "peer connections across all torrents, half open ones haven't finished the handshake.",
+    );
+    out.sample(&Labels::new(&[("state", "open")]), counts.connections());
+    out.sample(&Labels::new(&[("state", "half_open")]), counts.half_open());
+
+    let rate_limits = &session.config().rate_limits;
+    let directions = [
+        ("download", rate_limits.download.waits()),
+        ("upload", rate_limits.upload.waits()),
+    ];
+    out.family(
+        "crux_torrent_rate_limit_waits_total",
+        "counter",
+        "times connections were held back by the session's rate limits.",
+    );
+    for (direction, (waits, _)) in directions {
+        out.sample(&Labels::new(&[("direction", direction)]), waits);
+    }
+    out.family(
+        "crux_torrent_rate_limit_wait_seconds_total",
+        "counter",
+        "time connections were held back by the session's rate limits.",
+    );
+    for (direction, (_, waited)) in directions {
+        out.sample(
+            &Labels::new(&[("direction", direction)]),
+            waited.as_secs_f64(),
+        );
+    }
+
+    out.text
+}
+
+fn state_name(state: TorrentState) -> &'static str {
+    match state {
+        TorrentState::Queued => "queued",
+        TorrentState::Announcing => "announcing",
+        TorrentState::Downloading => "downloading",
+        TorrentState::Paused => "paused",
+        TorrentState::Complete => "complete",
+        TorrentState::Failed => "failed",
+    }
+}
+
+/// a histogram of durations with fixed buckets, which can be observed from anywhere.
+#[derive(Debug)]
+pub struct Histogram {
+    /// upper bounds of the buckets in seconds, in increasing order.
+    bounds: &'static [f64],
+    // the observations which fell in each bucket, with the last one for whatever's left over.
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// the number of observations at or below each bound, then the total count and sum.
+    fn snapshot(&self) -> (Vec<u64>, u64, f64) {
+        let mut cumulative = 0;
+        let counts: Vec<_> = self
+            .buckets
+            .iter()
+            .map(|bucket| {
+                cumulative += bucket.load(Ordering::Relaxed);
+                cumulative
+            })
+            .collect();
+        let sum = Duration::from_micros(self.sum_micros.load(Ordering::Relaxed));
+        (counts, cumulative, sum.as_secs_f64())
+    }
+}
+
+/// the labels of a sample, already rendered.
+#[derive(Debug, Clone, Default)]
+struct Labels(String);
+
+impl Labels {
+    fn new(pairs: &[(&str, &str)]) -> Self {
+        pairs.iter().fold(Self::default(), |labels, (name, value)| {
+            labels.with(name, value)
+        })
+    }
+
+    fn with(&self, name: &str, value: &str) -> Self {
+        let mut labels = self.0.clone();
+        if !labels.is_empty() {
+            labels.push(',');
+        }
+        let value = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(labels, "{name}=\"{value}\"");
+        Self(labels)
+    }
+}
+
+/// writes out metrics in the text format, the samples of a family have to follow right after it.
+#[derive(Debug, Default)]
+struct Encoder {
+    text: String,
+    family: &'static str,
+}
+
+impl Encoder {
+    fn family(&mut self, name: &'static str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {name} {help}");
+        let _ = writeln!(self.text, "# TYPE {name} {kind}");
+        self.family = name;
+    }
+
+    fn sample(&mut self, labels: &Labels, value: impl Display) {
+        self.write(self.family, "", labels, value);
+    }
+
+    fn histogram(&mut self, labels: &Labels, histogram: &Histogram) {
+        let (counts, count, sum) = histogram.snapshot();
+        let bounds = histogram.bounds.iter().map(f64::to_string);
+        for (bound, count) in bounds.chain(["+Inf".to_string()]).zip(counts) {
+            self.write(self.family, "_bucket", &labels.with("le", &bound), count);
+        }
+        self.write(self.family, "_sum", labels, sum);
+        self.write(self.family, "_count", labels, count);
+    }
+
+    fn write(&mut self, name: &str, suffix: &str, labels: &Labels, value: impl Display) {
+        let _ = match labels.0.as_str() {
+            "" => writeln!(self.text, "{name}{suffix} {value}"),
+            labels => writeln!(self.text, "{name}{suffix}{{{labels}}} {value}"),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_is_cumulative() {
+        let histogram = Histogram::new(&[0.1, 1.0]);
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_secs(5));
+
+        let mut out = Encoder::default();
+        out.family("latency_seconds", "histogram", "how long things took.");
+        out.histogram(&Labels::new(&[("name", "a \"b\"")]), &histogram);
+        assert_eq!(
+            out.text,
+            "# HELP latency_seconds how long things took.\n\
+             # TYPE latency_seconds histogram\n\
+             latency_seconds_bucket{name=\"a \\\"b\\\"\",le=\"0.1\"} 1\n\
+             latency_seconds_bucket{name=\"a \\\"b\\\"\",le=\"1\"} 2\n\
+             latency_seconds_bucket{name=\"a \\\"b\\\"\",le=\"+Inf\"} 3\n\
+             latency_seconds_sum{name=\"a \\\"b\\\"\"} 5.55\n\
+             latency_seconds_count{name=\"a \\\"b\\\"\"} 3\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_metrics() {
+        let config = crate::SessionConfig {
+            port: 0,
+            ..Default::default()
+        };
+        let session = Session::new(config).await.unwrap();
+        let metainfo = crate::metainfo::Metainfo::from_bytes(crate::rpc::tests::TORRENT).unwrap();
+        session.add_torrent(metainfo).unwrap();
+        let metrics = render(&session);
+        assert!(metrics.contains("# TYPE crux_torrent_downloaded_bytes_total counter\n"));
+        assert!(metrics.contains("crux_torrent_connections{state=\"open\"} 0\n"));
+        assert!(metrics.contains("crux_torrent_rate_limit_waits_total{direction=\"upload\"} 0\n"));
+        assert!(metrics.contains("# TYPE crux_torrent_disk_duration_seconds histogram\n"));
+        assert!(metrics.contains(",name=\"test\",op=\"write\"} 0\n"));
+    }
+}
@@ -1,11 +1,39 @@
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::{
     bytes::{self, Buf, BufMut},
-    codec::{length_delimited::LengthDelimitedCodec, Decoder, Encoder, Framed},
+    codec::{length_delimited::LengthDelimitedCodec, Decoder, Encoder, Framed, FramedParts},
 };
 
+use super::handshake::HandshakeCodec;
 use crate::torrent::Bitfield;
 
+#[derive(Debug, thiserror::Error)]
+pub enum CodecError {
+    #[error(
+        "payload of length {len} is too short for message tag {tag}, expected at least {expected}"
+    )]
+    PayloadTooShort {
+        tag: u8,
+        len: usize,
+        expected: usize,
+    },
+    #[error("invalid protocol tag for peer message: {0}")]
+    InvalidTag(u8),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl CodecError {
+    /// whether the peer broke the protocol, as opposed to the connection failing.
+    pub fn is_protocol_violation(&self) -> bool {
+        match self {
+            Self::PayloadTooShort { .. } | Self::InvalidTag(_) => true,
+            // frames over the size limit are reported by the length delimited codec.
+            Self::Io(err) => err.kind() == std::io::ErrorKind::InvalidData,
+        }
+    }
+}
+
 struct PeerMessageTags;
 impl PeerMessageTags {
     // tags according to https://www.bittorrent.org/beps/bep_0003.html
@@ -18,6 +46,11 @@ impl PeerMessageTags {
     const REQUEST: u8 = 6;
     const PIECE: u8 = 7;
     const CANCEL: u8 = 8;
+    // https://www.bittorrent.org/beps/bep_0010.html
+    const EXTENDED: u8 = 20;
+    // keep alives are empty frames without a tag on the wire, this one only tells them apart in
+    // memory.
+    const KEEP_ALIVE: u8 = u8::MAX;
 }
 
 #[repr(u8)]
@@ -44,6 +77,11 @@ pub enum PeerMessage {
         begin: u32,
         length: u32,
     } = PeerMessageTags::CANCEL,
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    } = PeerMessageTags::EXTENDED,
+    KeepAlive = PeerMessageTags::KEEP_ALIVE,
 }
 
 impl PeerMessage {
@@ -62,43 +100,62 @@ pub struct PeerMessageCodec {
     inner_codec: LengthDelimitedCodec,
 }
 
+impl Default for PeerMessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PeerMessageCodec {
-    const MAX_FRAME_SIZE: usize = 2 * (1 << 20);
+    /// large enough for the bitfield of a torrent with millions of pieces.
+    pub const DEFAULT_MAX_FRAME_SIZE: usize = 2 * (1 << 20);
 
     pub fn new() -> Self {
+        Self::with_max_frame_size(Self::DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// a codec which turns down messages longer than `max_frame_size`.
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
         Self {
             inner_codec: LengthDelimitedCodec::builder()
-                .max_frame_length(Self::MAX_FRAME_SIZE)
+                .max_frame_length(max_frame_size)
                 .new_codec(),
         }
     }
 
     // helper method to bail if the peer sends invalid (less than what is required) payload for the particular variant.
-    fn bail_on_size_mismatch(src: &mut bytes::BytesMut, min_size: usize) -> anyhow::Result<()> {
+    fn bail_on_size_mismatch(
+        src: &mut bytes::BytesMut,
+        tag: u8,
+        min_size: usize,
+    ) -> Result<(), CodecError> {
         let len = src.len();
         if len < min_size {
-            anyhow::bail!(
-                "buf size sent by peer {} does not match size for tag {}",
+            return Err(CodecError::PayloadTooShort {
+                tag,
                 len,
-                min_size
-            )
+                expected: min_size,
+            });
         }
         Ok(())
     }
 
     // helper method for the Cancel and Request variants only.
-    fn decode_triple_variant(src: &mut bytes::BytesMut) -> anyhow::Result<(u32, u32, u32)> {
+    fn decode_triple_variant(
+        src: &mut bytes::BytesMut,
+        tag: u8,
+    ) -> Result<(u32, u32, u32), CodecError> {
         const TRIPLE_SIZE: usize = 3 * std::mem::size_of::<u32>();
-        Self::bail_on_size_mismatch(src, TRIPLE_SIZE)?;
+        Self::bail_on_size_mismatch(src, tag, TRIPLE_SIZE)?;
         Ok((src.get_u32(), src.get_u32(), src.get_u32()))
     }
 }
 
 impl Decoder for PeerMessageCodec {
     type Item = PeerMessage;
-    type Error = anyhow::Error;
+    type Error = CodecError;
 
-    fn decode(&mut self, src: &mut bytes::BytesMut) -> anyhow::Result<Option<Self::Item>> {
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, CodecError> {
         let mut frame = match self.inner_codec.decode(src)? {
             Some(frame) => frame,
             None => return Ok(None),
@@ -106,7 +163,7 @@ impl Decoder for PeerMessageCodec {
 
         // message was a keepalive (length = 0)
         if frame.is_empty() {
-            return Ok(None);
+            return Ok(Some(PeerMessage::KeepAlive));
         }
 
         let tag = frame.get_u8();
@@ -118,13 +175,13 @@ impl Decoder for PeerMessageCodec {
             PeerMessageTags::INTERERSTED => PM::Interested,
             PeerMessageTags::NOT_INTERESTED => PM::NotInterested,
             PeerMessageTags::HAVE => {
-                Self::bail_on_size_mismatch(&mut frame, std::mem::size_of::<u32>())?;
+                Self::bail_on_size_mismatch(&mut frame, tag, std::mem::size_of::<u32>())?;
                 PM::Have(frame.get_u32())
             }
             // a panic shouldn't happen here as any amount of bytes is valid
             PeerMessageTags::BITFIELD => PM::Bitfield(Bitfield::from_vec(frame.to_vec())),
             PeerMessageTags::REQUEST => {
-                let (index, begin, length) = Self::decode_triple_variant(&mut frame)?;
+                let (index, begin, length) = Self::decode_triple_variant(&mut frame, tag)?;
 
                 PM::Request {
                     index,
@@ -133,7 +190,7 @@ impl Decoder for PeerMessageCodec {
                 }
             }
             PeerMessageTags::PIECE => {
-                Self::bail_on_size_mismatch(&mut frame, 2 * std::mem::size_of::<u32>())?;
+                Self::bail_on_size_mismatch(&mut frame, tag, 2 * std::mem::size_of::<u32>())?;
 
                 PM::Piece {
                     index: frame.get_u32(),
@@ -142,7 +199,7 @@ impl Decoder for PeerMessageCodec {
                 }
             }
             PeerMessageTags::CANCEL => {
-                let (index, begin, length) = Self::decode_triple_variant(&mut frame)?;
+                let (index, begin, length) = Self::decode_triple_variant(&mut frame, tag)?;
 
                 PM::Cancel {
                     index,
@@ -150,7 +207,15 @@ impl Decoder for PeerMessageCodec {
                     length,
                 }
             }
-            invalid_tag => anyhow::bail!("invalid protocol tag for peer message: {}", invalid_tag),
+            PeerMessageTags::EXTENDED => {
+                Self::bail_on_size_mismatch(&mut frame, tag, std::mem::size_of::<u8>())?;
+
+                PM::Extended {
+                    id: frame.get_u8(),
+                    payload: frame.to_vec(),
+                }
+            }
+            invalid_tag => return Err(CodecError::InvalidTag(invalid_tag)),
         };
 
         Ok(Some(msg))
@@ -158,7 +223,7 @@ impl Decoder for PeerMessageCodec {
 }
 
 impl Encoder<PeerMessage> for PeerMessageCodec {
-    type Error = anyhow::Error;
+    type Error = CodecError;
     fn encode(&mut self, item: PeerMessage, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
         // inner codec is not used as it would require allocating another BytesMut
         // instead we write directly to the dst buffer of the Framed instance.
@@ -167,6 +232,7 @@ impl Encoder<PeerMessage> for PeerMessageCodec {
 
         type PM = PeerMessage;
         match item {
+            PM::KeepAlive => dst.put_u32(0),
             PM::Choke | PM::Unchoke | PM::Interested | PM::NotInterested => {
                 dst.put_u32(TAG_LEN);
                 dst.put_u8(tag);
@@ -214,6 +280,14 @@ impl Encoder<PeerMessage> for PeerMessageCodec {
 
                 dst.put(bitfield.as_raw_slice());
             }
+
+            PM::Extended { id, payload } => {
+                dst.put_u32(TAG_LEN + (std::mem::size_of::<u8>() + payload.len()) as u32);
+                dst.put_u8(tag);
+
+                dst.put_u8(id);
+                dst.put(payload.as_slice());
+            }
         }
         Ok(())
     }
@@ -227,3 +301,73 @@ where
 {
     PeerFrames::new(stream, PeerMessageCodec::new())
 }
+
+/// swaps out the handshake codec for the peer message one, the stream can be wrapped along the
+/// way. bytes read past the handshake are carried over, so no messages get lost.
+pub fn upgrade_handshaked<T, U>(
+    stream: Framed<T, HandshakeCodec>,
+    codec: PeerMessageCodec,
+    wrap: impl FnOnce(T) -> U,
+) -> PeerFrames<U>
+where
+    U: AsyncRead + AsyncWrite,
+{
+    let handshake_parts = stream.into_parts();
+    let mut parts = FramedParts::new(wrap(handshake_parts.io), codec);
+    parts.read_buf = handshake_parts.read_buf;
+    parts.write_buf = handshake_parts.write_buf;
+    PeerFrames::from_parts(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keepalive_does_not_stall_following_frames() {
+        let mut codec = PeerMessageCodec::new();
+        let mut buf = bytes::BytesMut::new();
+        codec.encode(PeerMessage::KeepAlive, &mut buf).unwrap();
+        codec.encode(PeerMessage::Unchoke, &mut buf).unwrap();
+        assert_eq!(&buf[..4], &[0, 0, 0, 0]);
+
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(PeerMessage::KeepAlive)
+        ));
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(PeerMessage::Unchoke)
+        ));
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_keeps_bytes_read_past_handshake() {
+        use crate::peer_protocol::handshake::PeerHandshake;
+        use crate::torrent::{InfoHash, PeerId};
+        use futures::StreamExt;
+        use tokio::io::AsyncWriteExt;
+
+        let handshake = PeerHandshake::new(InfoHash::new([1; 20]), PeerId::random());
+        let mut buf = bytes::BytesMut::new();
+        HandshakeCodec::new()
+            .encode(handshake.clone(), &mut buf)
+            .unwrap();
+        PeerMessageCodec::new()
+            .encode(PeerMessage::Unchoke, &mut buf)
+            .unwrap();
+
+        // both go out in a single write, so they're read in one go as well.
+        let (mut ours, theirs) = tokio::io::duplex(1024);
+        ours.write_all(&buf).await.unwrap();
+
+        let mut stream = Framed::new(theirs, HandshakeCodec::new());
+        assert_eq!(stream.next().await.unwrap().unwrap(), handshake);
+        let mut stream = upgrade_handshaked(stream, PeerMessageCodec::new(), |stream| stream);
+        assert!(matches!(
+            stream.next().await.unwrap().unwrap(),
+            PeerMessage::Unchoke
+        ));
+    }
+}
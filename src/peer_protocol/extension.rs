@@ -0,0 +1,85 @@
+use super::metadata;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+
+/// the extended message id reserved for the extension handshake itself.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// extension protocol handshake, https://www.bittorrent.org/beps/bep_0010.html
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExtensionHandshake {
+    /// names of the supported extensions, mapped to the ids they should be sent with.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+
+    /// the size of the info dictionary, for peers which serve it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+
+    /// the number of outstanding requests the sender is willing to queue up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+
+    /// client name and version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+}
+
+impl ExtensionHandshake {
+    pub const CLIENT_NAME: &'static str = concat!("crux-torrent ", env!("CARGO_PKG_VERSION"));
+
+    /// the handshake we send out, advertising how many requests we'll queue up and the metadata
+    /// exchange, along with the size of the metadata once we have it.
+    pub fn ours(reqq: u32, metadata_size: Option<usize>) -> Self {
+        Self {
+            m: BTreeMap::from([(metadata::EXTENSION_NAME.to_owned(), metadata::OUR_ID as i64)]),
+            metadata_size: metadata_size.map(|size| size as i64),
+            reqq: Some(reqq),
+            v: Some(ByteBuf::from(Self::CLIENT_NAME.as_bytes())),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_bencode::Error> {
+        serde_bencode::from_bytes(bytes)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, serde_bencode::Error> {
+        serde_bencode::to_bytes(self)
+    }
+
+    /// the id the peer wants metadata messages sent with, if it speaks the metadata exchange.
+    pub fn metadata_id(&self) -> Option<u8> {
+        let id = *self.m.get(metadata::EXTENSION_NAME)?;
+        // an id of 0 disables the extension.
+        u8::try_from(id).ok().filter(|id| *id != 0)
+    }
+
+    pub fn client_name(&self) -> Option<String> {
+        self.v
+            .as_ref()
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let handshake = ExtensionHandshake::ours(250, Some(1000));
+        let bytes = handshake.to_bytes().unwrap();
+        assert_eq!(ExtensionHandshake::from_bytes(&bytes).unwrap(), handshake);
+    }
+
+    #[test]
+    fn test_ignores_unknown_keys() {
+        let bytes =
+            b"d1:md11:ut_metadatai3ee1:pi6881e4:reqqi500e1:v6:Tixati6:yourip4:\x7f\x00\x00\x01e";
+        let handshake = ExtensionHandshake::from_bytes(bytes).unwrap();
+        assert_eq!(handshake.reqq, Some(500));
+        assert_eq!(handshake.metadata_id(), Some(3));
+        assert_eq!(handshake.client_name().as_deref(), Some("Tixati"));
+    }
+}
@@ -1,46 +1,305 @@
-use crate::prelude::*;
 use crate::torrent::{InfoHash, PeerId};
+use tokio_util::{
+    bytes::{Buf, BufMut, BytesMut},
+    codec::{Decoder, Encoder},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    #[error("unknown protocol prefix in handshake {0:?}")]
+    UnknownProtocol(String),
+    #[error("peer handshake is for a torrent which is not being served")]
+    InfoHashMismatch,
+    #[error("connected to ourselves")]
+    SelfConnection,
+    #[error("already connected to a peer with the same peer id")]
+    DuplicatePeer,
+    #[error("peer closed the connection during the handshake")]
+    Closed,
+    #[error("failed to read handshake: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// extensions a peer can signal support for through the reserved bytes of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// https://www.bittorrent.org/beps/bep_0005.html
+    Dht,
+    /// https://www.bittorrent.org/beps/bep_0006.html
+    Fast,
+    /// https://www.bittorrent.org/beps/bep_0010.html
+    Extension,
+}
+
+impl Feature {
+    const ALL: [Feature; 3] = [Feature::Dht, Feature::Fast, Feature::Extension];
+
+    // the reserved byte, and the bit within it, the feature is signalled by.
+    fn position(self) -> (usize, u8) {
+        match self {
+            Feature::Dht => (7, 0x01),
+            Feature::Fast => (7, 0x04),
+            Feature::Extension => (5, 0x10),
+        }
+    }
+}
+
+/// the reserved bytes of a handshake. bits which aren't known are kept as they are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features([u8; Features::SIZE]);
+
+impl Features {
+    pub const SIZE: usize = 8;
+
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; Self::SIZE] {
+        &self.0
+    }
+
+    pub fn insert(&mut self, feature: Feature) {
+        let (byte, mask) = feature.position();
+        self.0[byte] |= mask;
+    }
+
+    pub fn contains(&self, feature: Feature) -> bool {
+        let (byte, mask) = feature.position();
+        self.0[byte] & mask != 0
+    }
+
+    /// the known features which are set.
+    pub fn iter(&self) -> impl Iterator<Item = Feature> + '_ {
+        Feature::ALL
+            .into_iter()
+            .filter(|feature| self.contains(*feature))
+    }
+}
+
+impl FromIterator<Feature> for Features {
+    fn from_iter<I: IntoIterator<Item = Feature>>(features: I) -> Self {
+        let mut out = Self::default();
+        features.into_iter().for_each(|feature| out.insert(feature));
+        out
+    }
+}
+
+/// the beginning of a handshake, up to and including the info hash. it's all that's needed to
+/// tell which torrent a peer connected for, before reading the rest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandshakePrefix {
+    protocol: Vec<u8>,
+    pub features: Features,
+    pub info_hash: InfoHash,
+}
+
+impl HandshakePrefix {
+    fn complete(self, peer_id: PeerId) -> PeerHandshake {
+        PeerHandshake {
+            protocol: self.protocol,
+            features: self.features,
+            info_hash: self.info_hash,
+            peer_id,
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
-#[repr(C)] // makes sure the struct fields are arranged in the same order, there's also no padding
-           // in between because all the fields are byte aligned. (i.e this can be treated as a
-           // simple array of bytes).
 pub struct PeerHandshake {
-    protocol_prefix_length: u8,
-    protocol_prefix: [u8; 19],
-    reserved_bytes: [u8; 8],
+    protocol: Vec<u8>,
+    pub features: Features,
     pub info_hash: InfoHash,
     pub peer_id: PeerId,
 }
 
 impl PeerHandshake {
     pub const PROTOCOL_PREFIX: [u8; 19] = *b"BitTorrent protocol";
+
     pub fn new(info_hash: InfoHash, peer_id: PeerId) -> Self {
         Self {
-            protocol_prefix_length: Self::PROTOCOL_PREFIX.len() as u8,
-            protocol_prefix: Self::PROTOCOL_PREFIX,
-            reserved_bytes: [0; 8],
+            protocol: Self::PROTOCOL_PREFIX.to_vec(),
+            features: Features::default(),
             info_hash,
             peer_id,
         }
     }
 
-    // the unsafe is fine becuase the struct is just plain old data, any sequence of bits is valid.
-    pub fn from_bytes(bytes: [u8; std::mem::size_of::<Self>()]) -> Self {
-        let handshake =
-            unsafe { std::mem::transmute::<[u8; std::mem::size_of::<Self>()], Self>(bytes) };
-        if handshake.protocol_prefix != Self::PROTOCOL_PREFIX {
-            warn!(
-                "unknown protocol prefix in handshake '{}'",
-                String::from_utf8_lossy(&handshake.protocol_prefix[..])
-            );
+    pub fn with_feature(mut self, feature: Feature) -> Self {
+        self.features.insert(feature);
+        self
+    }
+
+    /// advertises support for the extension protocol.
+    pub fn with_extension_protocol(self) -> Self {
+        self.with_feature(Feature::Extension)
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.features.contains(Feature::Extension)
+    }
+
+    /// number of bytes the handshake takes up on the wire.
+    pub fn encoded_len(&self) -> usize {
+        1 + self.protocol.len() + Features::SIZE + InfoHash::INFO_HASH_SIZE + PeerId::PEER_ID_SIZE
+    }
+
+    /// checks that the peer's handshake is for the torrent we asked for, and that it isn't a
+    /// connection to ourselves.
+    pub fn validate(
+        &self,
+        info_hash: &InfoHash,
+        our_peer_id: &PeerId,
+    ) -> Result<(), HandshakeError> {
+        if self.info_hash != *info_hash {
+            return Err(HandshakeError::InfoHashMismatch);
+        }
+        if self.peer_id == *our_peer_id {
+            return Err(HandshakeError::SelfConnection);
         }
+        Ok(())
+    }
+}
+
+/// decodes just the handshake prefix, so the peer id can be waited on after routing the
+/// connection, see [`HandshakeCodec::after_prefix`].
+#[derive(Debug, Clone)]
+pub struct HandshakePrefixCodec {
+    protocol: Vec<u8>,
+}
 
-        handshake
+impl HandshakePrefixCodec {
+    pub fn new() -> Self {
+        Self::with_protocol(PeerHandshake::PROTOCOL_PREFIX.to_vec())
     }
-    // the unsafe is fine becuase the struct is just plain old data, any sequence of bits is valid.
-    pub fn into_bytes(self) -> [u8; std::mem::size_of::<Self>()] {
-        unsafe { std::mem::transmute::<Self, [u8; std::mem::size_of::<Self>()]>(self) }
+
+    /// a codec for a protocol other than plain bittorrent, the protocol string has to fit in a
+    /// byte long length prefix.
+    pub fn with_protocol(protocol: Vec<u8>) -> Self {
+        assert!(
+            protocol.len() <= u8::MAX as usize,
+            "protocol string too long"
+        );
+        Self { protocol }
+    }
+}
+
+impl Default for HandshakePrefixCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for HandshakePrefixCodec {
+    type Item = HandshakePrefix;
+    type Error = HandshakeError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(&protocol_len) = src.first() else {
+            return Ok(None);
+        };
+        let protocol_len = protocol_len as usize;
+
+        // the protocol string is checked as soon as it's there, a peer speaking something else
+        // could otherwise keep us waiting on bytes which never arrive.
+        let available = src.len().min(1 + protocol_len);
+        if protocol_len != self.protocol.len()
+            || src[1..available] != self.protocol[..available - 1]
+        {
+            return Err(HandshakeError::UnknownProtocol(
+                String::from_utf8_lossy(&src[1..available]).into_owned(),
+            ));
+        }
+
+        let prefix_len = 1 + protocol_len + Features::SIZE + InfoHash::INFO_HASH_SIZE;
+        if src.len() < prefix_len {
+            src.reserve(prefix_len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(1);
+        let protocol = src.split_to(protocol_len).to_vec();
+        let mut features = [0u8; Features::SIZE];
+        src.copy_to_slice(&mut features);
+        let mut info_hash = [0u8; InfoHash::INFO_HASH_SIZE];
+        src.copy_to_slice(&mut info_hash);
+
+        Ok(Some(HandshakePrefix {
+            protocol,
+            features: Features::from_bytes(features),
+            info_hash: InfoHash::new(info_hash),
+        }))
+    }
+}
+
+/// reads and writes whole handshakes, it's meant to be swapped out for the peer message codec
+/// once the handshake is done, keeping whatever was read past the handshake.
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeCodec {
+    prefix_codec: HandshakePrefixCodec,
+    // the prefix, once it's been decoded and the peer id is still being waited on.
+    prefix: Option<HandshakePrefix>,
+}
+
+impl HandshakeCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_protocol(protocol: Vec<u8>) -> Self {
+        Self {
+            prefix_codec: HandshakePrefixCodec::with_protocol(protocol),
+            prefix: None,
+        }
+    }
+
+    /// continues a handshake whose prefix was already decoded by a [`HandshakePrefixCodec`].
+    pub fn after_prefix(prefix: HandshakePrefix) -> Self {
+        Self {
+            prefix_codec: HandshakePrefixCodec::with_protocol(prefix.protocol.clone()),
+            prefix: Some(prefix),
+        }
+    }
+}
+
+impl Decoder for HandshakeCodec {
+    type Item = PeerHandshake;
+    type Error = HandshakeError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if self.prefix.is_none() {
+            self.prefix = self.prefix_codec.decode(src)?;
+        }
+        if self.prefix.is_none() {
+            return Ok(None);
+        }
+
+        if src.len() < PeerId::PEER_ID_SIZE {
+            src.reserve(PeerId::PEER_ID_SIZE - src.len());
+            return Ok(None);
+        }
+
+        let mut peer_id = [0u8; PeerId::PEER_ID_SIZE];
+        src.copy_to_slice(&mut peer_id);
+        let prefix = self.prefix.take().expect("prefix was decoded");
+        Ok(Some(prefix.complete(PeerId::from_bytes(peer_id))))
+    }
+}
+
+impl Encoder<PeerHandshake> for HandshakeCodec {
+    type Error = HandshakeError;
+
+    fn encode(&mut self, item: PeerHandshake, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let protocol_len = u8::try_from(item.protocol.len())
+            .map_err(|_| HandshakeError::UnknownProtocol("protocol string too long".into()))?;
+
+        dst.reserve(item.encoded_len());
+        dst.put_u8(protocol_len);
+        dst.put_slice(&item.protocol);
+        dst.put_slice(item.features.as_bytes());
+        dst.put_slice(item.info_hash.as_ref());
+        dst.put_slice(item.peer_id.as_ref());
+        Ok(())
     }
 }
 
@@ -48,11 +307,11 @@ impl PeerHandshake {
 mod tests {
     use super::*;
     use crate::torrent::{InfoHash, PeerId};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
     use rstest::*;
     const INFO_HASH: [u8; 20] = [0; 20];
     const PEER_ID_SUFFIX: [u8; PeerId::SUFFIX_LEN] = [7; PeerId::SUFFIX_LEN];
     type PH = PeerHandshake;
-    type HB = [u8; std::mem::size_of::<PeerHandshake>()];
 
     #[fixture]
     fn peer_id() -> PeerId {
@@ -70,28 +329,171 @@ mod tests {
     }
 
     #[fixture]
-    fn handshake_bytes(info_hash: InfoHash, peer_id: PeerId) -> HB {
-        let out = {
-            let mut out: Vec<u8> = Vec::new();
-            out.push(19);
-            out.extend_from_slice(b"BitTorrent protocol");
-            out.extend_from_slice(&[0; 8]);
-            out.extend_from_slice(info_hash.as_ref());
-            out.extend_from_slice(peer_id.as_ref());
-            out
-        };
+    fn handshake_bytes(info_hash: InfoHash, peer_id: PeerId) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::new();
+        out.push(19);
+        out.extend_from_slice(b"BitTorrent protocol");
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(info_hash.as_ref());
+        out.extend_from_slice(peer_id.as_ref());
+        out
+    }
+
+    fn encode(handshake: PH) -> BytesMut {
+        let mut buf = BytesMut::new();
+        HandshakeCodec::new().encode(handshake, &mut buf).unwrap();
+        buf
+    }
 
-        out.try_into().unwrap()
+    #[rstest]
+    fn test_encode(handshake: PH, handshake_bytes: Vec<u8>) {
+        assert_eq!(handshake.encoded_len(), handshake_bytes.len());
+        assert_eq!(encode(handshake)[..], handshake_bytes[..]);
+    }
+
+    #[rstest]
+    fn test_decode_leaves_following_bytes(handshake: PH, handshake_bytes: Vec<u8>) {
+        let mut buf = BytesMut::from(&handshake_bytes[..]);
+        buf.extend_from_slice(&[0, 0, 0, 1, 1]);
+
+        let out = HandshakeCodec::new().decode(&mut buf).unwrap();
+        assert_eq!(out, Some(handshake));
+        assert_eq!(&buf[..], &[0, 0, 0, 1, 1]);
+    }
+
+    #[rstest]
+    fn test_decode_partial_reads(handshake: PH, handshake_bytes: Vec<u8>) {
+        let mut codec = HandshakeCodec::new();
+        let mut buf = BytesMut::new();
+        for (i, byte) in handshake_bytes.iter().enumerate() {
+            assert_eq!(codec.decode(&mut buf).unwrap(), None, "decoded at byte {i}");
+            buf.put_u8(*byte);
+        }
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(handshake));
+        assert!(buf.is_empty());
+    }
+
+    #[rstest]
+    fn test_rejects_unknown_protocol(mut handshake_bytes: Vec<u8>) {
+        handshake_bytes[1] = b'b';
+        // rejected as soon as the mismatching byte is in.
+        let mut buf = BytesMut::from(&handshake_bytes[..2]);
+        assert!(matches!(
+            HandshakeCodec::new().decode(&mut buf),
+            Err(HandshakeError::UnknownProtocol(_))
+        ));
     }
 
     #[rstest]
-    fn test_into_bytes(handshake: PeerHandshake, handshake_bytes: HB) {
-        assert_eq!(handshake.into_bytes(), handshake_bytes);
+    fn test_decode_prefix_then_rest(handshake: PH, handshake_bytes: Vec<u8>) {
+        let mut buf = BytesMut::from(&handshake_bytes[..]);
+        let prefix = HandshakePrefixCodec::new()
+            .decode(&mut buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(prefix.info_hash, handshake.info_hash);
+        assert_eq!(buf.len(), PeerId::PEER_ID_SIZE);
+
+        let mut codec = HandshakeCodec::after_prefix(prefix);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(handshake));
     }
 
     #[rstest]
-    fn test_decode_from_bytes(handshake_bytes: HB) {
-        let out = PH::from_bytes(handshake_bytes);
-        assert_eq!(out.into_bytes(), handshake_bytes);
+    fn test_features(handshake: PH) {
+        let handshake = handshake.with_extension_protocol();
+        assert!(handshake.supports_extension_protocol());
+        assert_eq!(handshake.features.as_bytes(), &[0, 0, 0, 0, 0, 0x10, 0, 0]);
+
+        let mut features = Features::from_bytes([0xff, 0, 0, 0, 0, 0, 0, 0x05]);
+        assert_eq!(
+            features.iter().collect::<Vec<_>>(),
+            [Feature::Dht, Feature::Fast]
+        );
+        features.insert(Feature::Extension);
+        // unknown bits survive.
+        assert_eq!(features.as_bytes()[0], 0xff);
+        assert_eq!(
+            [Feature::Dht, Feature::Fast, Feature::Extension]
+                .into_iter()
+                .collect::<Features>()
+                .iter()
+                .count(),
+            3
+        );
+    }
+
+    #[rstest]
+    fn test_custom_protocol_roundtrip(info_hash: InfoHash, peer_id: PeerId) {
+        let protocol = b"some longer protocol string".to_vec();
+        let handshake = PH {
+            protocol: protocol.clone(),
+            ..PH::new(info_hash, peer_id)
+        };
+
+        let mut codec = HandshakeCodec::with_protocol(protocol);
+        let mut buf = BytesMut::new();
+        codec.encode(handshake.clone(), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(handshake.clone()));
+
+        codec.encode(handshake, &mut buf).unwrap();
+        assert!(HandshakeCodec::new().decode(&mut buf).is_err());
+    }
+
+    #[rstest]
+    fn test_validate(handshake: PH, info_hash: InfoHash, peer_id: PeerId) {
+        assert!(matches!(
+            handshake.validate(&info_hash, &peer_id),
+            Err(HandshakeError::SelfConnection)
+        ));
+        assert!(handshake.validate(&info_hash, &PeerId::random()).is_ok());
+        assert!(matches!(
+            handshake.validate(&InfoHash::new([1; 20]), &PeerId::random()),
+            Err(HandshakeError::InfoHashMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_fuzz_roundtrip_split_reads() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let features = Features::from_bytes(rng.gen());
+            let handshake = PH {
+                features,
+                ..PH::new(InfoHash::new(rng.gen()), PeerId::from_bytes(rng.gen()))
+            };
+            let bytes = encode(handshake.clone());
+
+            // feeds the codec the handshake in randomly sized pieces.
+            let mut codec = HandshakeCodec::new();
+            let mut buf = BytesMut::new();
+            let mut rest = &bytes[..];
+            let decoded = loop {
+                if let Some(decoded) = codec.decode(&mut buf).unwrap() {
+                    break decoded;
+                }
+                let (chunk, remaining) = rest.split_at(rng.gen_range(1..=rest.len()));
+                buf.extend_from_slice(chunk);
+                rest = remaining;
+            };
+            assert_eq!(decoded, handshake);
+            assert!(rest.is_empty() && buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_fuzz_garbage_never_panics() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..1000 {
+            let len = rng.gen_range(0..100);
+            let mut bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            // keeps some of the inputs past the protocol check.
+            if rng.gen_bool(0.5) && len > 20 {
+                bytes[0] = 19;
+                bytes[1..20].copy_from_slice(&PH::PROTOCOL_PREFIX);
+            }
+
+            let mut buf = BytesMut::from(&bytes[..]);
+            let _ = HandshakeCodec::new().decode(&mut buf);
+        }
     }
 }
@@ -0,0 +1,156 @@
+//! the metadata exchange, through which the info dictionary of a torrent is fetched from its
+//! peers when all there is to go on is a magnet link.
+//! https://www.bittorrent.org/beps/bep_0009.html
+use serde::de::Error as _;
+use serde::{Deserialize, Serialize};
+
+/// the name the extension goes by in extension handshakes.
+pub const EXTENSION_NAME: &str = "ut_metadata";
+/// the extended message id peers send us metadata messages with.
+pub const OUR_ID: u8 = 2;
+/// the metadata is sent in pieces of this size, only the last one may be shorter.
+pub const PIECE_SIZE: usize = 1 << 14;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request {
+        piece: usize,
+    },
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    /// the peer doesn't have the piece, or doesn't want to hand it out.
+    Reject {
+        piece: usize,
+    },
+}
+
+// the dictionary every message starts with, data messages carry the piece right after it.
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    msg_type: i64,
+    piece: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<i64>,
+}
+
+impl MetadataMessage {
+    const REQUEST: i64 = 0;
+    const DATA: i64 = 1;
+    const REJECT: i64 = 2;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_bencode::Error> {
+        let header_len = bencoded_len(bytes)
+            .ok_or_else(|| serde_bencode::Error::custom("truncated metadata message"))?;
+        let header: Header = serde_bencode::from_bytes(&bytes[..header_len])?;
+        let piece = usize::try_from(header.piece)
+            .map_err(|_| serde_bencode::Error::custom("negative metadata piece"))?;
+
+        match header.msg_type {
+            Self::REQUEST => Ok(Self::Request { piece }),
+            Self::DATA => {
+                let total_size = header
+                    .total_size
+                    .and_then(|size| usize::try_from(size).ok())
+                    .ok_or_else(|| serde_bencode::Error::custom("metadata without a size"))?;
+                Ok(Self::Data {
+                    piece,
+                    total_size,
+                    data: bytes[header_len..].to_vec(),
+                })
+            }
+            Self::REJECT => Ok(Self::Reject { piece }),
+            msg_type => Err(serde_bencode::Error::custom(format!(
+                "unknown metadata message type {msg_type}"
+            ))),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, serde_bencode::Error> {
+        let (msg_type, piece, total_size, data) = match self {
+            Self::Request { piece } => (Self::REQUEST, piece, None, None),
+            Self::Data {
+                piece,
+                total_size,
+                data,
+            } => (Self::DATA, piece, Some(*total_size as i64), Some(data)),
+            Self::Reject { piece } => (Self::REJECT, piece, None, None),
+        };
+        let mut bytes = serde_bencode::to_bytes(&Header {
+            msg_type,
+            piece: *piece as i64,
+            total_size,
+        })?;
+        if let Some(data) = data {
+            bytes.extend_from_slice(data);
+        }
+        Ok(bytes)
+    }
+}
+
+/// the length of the bencoded value at the start of `bytes`, if all of it is there.
+fn bencoded_len(bytes: &[u8]) -> Option<usize> {
+    let mut pos = 0;
+    let mut depth = 0usize;
+    loop {
+        match *bytes.get(pos)? {
+            b'd' | b'l' => {
+                depth += 1;
+                pos += 1;
+            }
+            b'e' => {
+                depth = depth.checked_sub(1)?;
+                pos += 1;
+            }
+            b'i' => pos += bytes[pos..].iter().position(|byte| *byte == b'e')? + 1,
+            b'0'..=b'9' => {
+                let colon = pos + bytes[pos..].iter().position(|byte| *byte == b':')?;
+                let len: usize = std::str::from_utf8(&bytes[pos..colon]).ok()?.parse().ok()?;
+                pos = colon
+                    .checked_add(1 + len)
+                    .filter(|end| *end <= bytes.len())?;
+            }
+            _ => return None,
+        }
+        if depth == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodes_like_the_bep() {
+        let request = MetadataMessage::Request { piece: 0 };
+        assert_eq!(request.to_bytes().unwrap(), b"d8:msg_typei0e5:piecei0ee");
+
+        let bytes = b"d8:msg_typei1e5:piecei0e10:total_sizei8eexxxxxxxx";
+        let data = MetadataMessage::from_bytes(bytes).unwrap();
+        assert_eq!(
+            data,
+            MetadataMessage::Data {
+                piece: 0,
+                total_size: 8,
+                data: b"xxxxxxxx".to_vec(),
+            }
+        );
+        assert_eq!(data.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_data_may_look_like_bencode() {
+        let data = MetadataMessage::Data {
+            piece: 1,
+            total_size: PIECE_SIZE + 9,
+            data: b"d1:ai1ee:".to_vec(),
+        };
+        let bytes = data.to_bytes().unwrap();
+        assert_eq!(MetadataMessage::from_bytes(&bytes).unwrap(), data);
+        assert!(MetadataMessage::from_bytes(&bytes[..10]).is_err());
+    }
+}
@@ -1,2 +1,9 @@
+//! the peer wire protocol, https://www.bittorrent.org/beps/bep_0003.html, along with the
+//! transports and extensions it's spoken over.
 pub mod codec;
+pub mod extension;
 pub mod handshake;
+pub mod metadata;
+pub mod mse;
+pub mod transport;
+pub mod utp;
@@ -0,0 +1,94 @@
+use super::MseError;
+use num_bigint::BigUint;
+use rand::RngCore;
+
+/// size in bytes of the public keys and the shared secret sent over the wire.
+pub(super) const KEY_SIZE: usize = 96;
+
+// 768 bit safe prime from the MSE spec, the generator is 2.
+const PRIME: [u8; KEY_SIZE] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const GENERATOR: u32 = 2;
+
+// the spec recommends a private key of at least 128 bits, 160 is what most clients use.
+const PRIVATE_KEY_SIZE: usize = 20;
+
+/// one side of the diffie-hellman key exchange.
+pub(super) struct DhKeyPair {
+    private_key: BigUint,
+    public_key: [u8; KEY_SIZE],
+}
+
+impl DhKeyPair {
+    pub fn random() -> Self {
+        let mut private_bytes = [0u8; PRIVATE_KEY_SIZE];
+        rand::thread_rng().fill_bytes(&mut private_bytes);
+        let private_key = BigUint::from_bytes_be(&private_bytes);
+
+        let public_key = BigUint::from(GENERATOR).modpow(&private_key, &Self::prime());
+        Self {
+            public_key: to_key_bytes(&public_key),
+            private_key,
+        }
+    }
+
+    pub fn public_key(&self) -> &[u8; KEY_SIZE] {
+        &self.public_key
+    }
+
+    /// computes the shared secret S from the public key sent by the remote side.
+    pub fn shared_secret(
+        &self,
+        remote_public_key: &[u8; KEY_SIZE],
+    ) -> Result<[u8; KEY_SIZE], MseError> {
+        let prime = Self::prime();
+        let remote_key = BigUint::from_bytes_be(remote_public_key);
+
+        // keys outside of (1, P - 1) would force the secret to a known value.
+        if remote_key <= BigUint::from(1u32) || remote_key >= &prime - 1u32 {
+            return Err(MseError::InvalidPublicKey);
+        }
+
+        Ok(to_key_bytes(&remote_key.modpow(&self.private_key, &prime)))
+    }
+
+    fn prime() -> BigUint {
+        BigUint::from_bytes_be(&PRIME)
+    }
+}
+
+// keys are sent as big endian, left padded with zeroes to the full key size.
+fn to_key_bytes(num: &BigUint) -> [u8; KEY_SIZE] {
+    let bytes = num.to_bytes_be();
+    let mut out = [0u8; KEY_SIZE];
+    out[KEY_SIZE - bytes.len()..].copy_from_slice(&bytes);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_both_sides_agree_on_secret() {
+        let initiator = DhKeyPair::random();
+        let responder = DhKeyPair::random();
+
+        let initiator_secret = initiator.shared_secret(responder.public_key()).unwrap();
+        let responder_secret = responder.shared_secret(initiator.public_key()).unwrap();
+        assert_eq!(initiator_secret, responder_secret);
+    }
+
+    #[test]
+    fn test_reject_degenerate_key() {
+        let mut one = [0u8; KEY_SIZE];
+        one[KEY_SIZE - 1] = 1;
+        assert!(DhKeyPair::random().shared_secret(&one).is_err());
+    }
+}
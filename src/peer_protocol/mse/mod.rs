@@ -0,0 +1,376 @@
+//! Message Stream Encryption (a.k.a Protocol Encryption), obfuscates the peer wire protocol with a
+//! diffie-hellman key exchange followed by an RC4 stream.
+//! https://wiki.vuze.com/w/Message_Stream_Encryption
+mod dh;
+mod rc4;
+mod stream;
+
+pub use stream::MseStream;
+
+use crate::peer_protocol::handshake::PeerHandshake;
+use crate::prelude::*;
+use crate::torrent::InfoHash;
+use dh::{DhKeyPair, KEY_SIZE};
+use rand::Rng;
+use rc4::Rc4;
+use sha1_smol::Sha1;
+use stream::CipherPair;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionPolicy {
+    /// only ever use plaintext connections.
+    Disabled,
+    /// prefer encrypted connections, but fall back to plaintext ones.
+    #[default]
+    Enabled,
+    /// only ever use RC4 encrypted connections.
+    Forced,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MseError {
+    #[error("peer attempted a plaintext connection while encryption is forced")]
+    PlaintextForced,
+    #[error("peer attempted an encrypted connection while encryption is disabled")]
+    EncryptionDisabled,
+    #[error("peer sent an invalid diffie-hellman public key")]
+    InvalidPublicKey,
+    #[error("peer requested an info hash which is not being served")]
+    UnknownInfoHash,
+    #[error("peer sent an invalid verification constant")]
+    InvalidVerification,
+    #[error("no acceptable crypto method, peer offered {0:#x}")]
+    NoCryptoMethod(u32),
+    #[error("could not sync on mse handshake within the maximum padding length")]
+    SyncFailed,
+    #[error("peer sent padding of length {0} which is too long")]
+    PaddingTooLong(usize),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+struct CryptoMethods;
+impl CryptoMethods {
+    // bits of crypto_provide and crypto_select.
+    const PLAINTEXT: u32 = 0x01;
+    const RC4: u32 = 0x02;
+}
+
+type Hash = [u8; sha1_smol::DIGEST_LENGTH];
+
+// verification constant, 8 zero bytes which are sent encrypted to sync on the RC4 stream.
+const VC: [u8; 8] = [0; 8];
+const MAX_PAD_LEN: usize = 512;
+
+/// performs the MSE handshake as the side which opened the connection. the plaintext peer
+/// handshake is sent afterwards over the returned stream (i.e no initial payload is used).
+#[instrument(name = "mse initiate", level = "debug", skip_all, fields(?policy))]
+pub async fn initiate<S>(
+    mut stream: S,
+    info_hash: &InfoHash,
+    policy: EncryptionPolicy,
+) -> Result<MseStream<S>, MseError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let crypto_provide = match policy {
+        EncryptionPolicy::Disabled => return Ok(MseStream::plaintext(stream)),
+        EncryptionPolicy::Enabled => CryptoMethods::RC4 | CryptoMethods::PLAINTEXT,
+        EncryptionPolicy::Forced => CryptoMethods::RC4,
+    };
+
+    let keys = DhKeyPair::random();
+    debug!("sending public key");
+    stream.write_all(keys.public_key()).await?;
+    stream.write_all(&random_padding()).await?;
+
+    let mut remote_key = [0u8; KEY_SIZE];
+    stream.read_exact(&mut remote_key).await?;
+    let secret = keys.shared_secret(&remote_key)?;
+
+    let skey = info_hash.as_ref();
+    let mut ciphers = CipherPair {
+        encrypt: Rc4::new_discarded(&hash(&[b"keyA", &secret, skey])),
+        decrypt: Rc4::new_discarded(&hash(&[b"keyB", &secret, skey])),
+    };
+
+    let mut encrypted_part = Vec::new();
+    encrypted_part.extend_from_slice(&VC);
+    encrypted_part.extend_from_slice(&crypto_provide.to_be_bytes());
+    encrypted_part.extend_from_slice(&0u16.to_be_bytes()); // len(PadC)
+    encrypted_part.extend_from_slice(&0u16.to_be_bytes()); // len(IA)
+    ciphers.encrypt.apply_keystream(&mut encrypted_part);
+
+    let mut message = Vec::new();
+    message.extend_from_slice(&hash(&[b"req1", &secret]));
+    message.extend_from_slice(&xor(hash(&[b"req2", skey]), hash(&[b"req3", &secret])));
+    message.extend_from_slice(&encrypted_part);
+    debug!("sending crypto provide");
+    stream.write_all(&message).await?;
+
+    // the responder's padding is of unknown length, so look for where the encrypted VC starts.
+    let mut encrypted_vc = VC;
+    ciphers.decrypt.apply_keystream(&mut encrypted_vc);
+    sync_on(&mut stream, &encrypted_vc).await?;
+
+    let mut select_and_pad_len = [0u8; 6];
+    stream.read_exact(&mut select_and_pad_len).await?;
+    ciphers.decrypt.apply_keystream(&mut select_and_pad_len);
+    let [select @ .., pad_len_hi, pad_len_lo] = select_and_pad_len;
+    let crypto_select = u32::from_be_bytes(select);
+
+    let mut pad = vec![0u8; read_pad_len([pad_len_hi, pad_len_lo])?];
+    stream.read_exact(&mut pad).await?;
+    ciphers.decrypt.apply_keystream(&mut pad);
+
+    let ciphers = match crypto_select & crypto_provide {
+        CryptoMethods::RC4 => Some(ciphers),
+        CryptoMethods::PLAINTEXT => None,
+        _ => return Err(MseError::NoCryptoMethod(crypto_select)),
+    };
+    debug!(encrypted = ciphers.is_some(), "mse handshake complete");
+    Ok(MseStream::new(stream, ciphers, Vec::new()))
+}
+
+/// performs the MSE handshake as the side which accepted the connection. plaintext connections
+/// are detected by the peer handshake prefix and passed through untouched when the policy allows
+/// it. `info_hashes` are the torrents the remote side may be asking for.
+#[instrument(name = "mse accept", level = "debug", skip_all, fields(?policy))]
+pub async fn accept<S>(
+    mut stream: S,
+    info_hashes: &[InfoHash],
+    policy: EncryptionPolicy,
+) -> Result<MseStream<S>, MseError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    const PLAINTEXT_PREFIX_LEN: usize = 1 + PeerHandshake::PROTOCOL_PREFIX.len();
+    let mut remote_key = [0u8; KEY_SIZE];
+    stream
+        .read_exact(&mut remote_key[..PLAINTEXT_PREFIX_LEN])
+        .await?;
+
+    let (prefix_len, prefix) = remote_key.split_at(1);
+    if prefix_len[0] as usize == PeerHandshake::PROTOCOL_PREFIX.len()
+        && prefix[..PeerHandshake::PROTOCOL_PREFIX.len()] == PeerHandshake::PROTOCOL_PREFIX
+    {
+        if policy == EncryptionPolicy::Forced {
+            return Err(MseError::PlaintextForced);
+        }
+        debug!("plaintext connection");
+        return Ok(MseStream::new(
+            stream,
+            None,
+            remote_key[..PLAINTEXT_PREFIX_LEN].to_vec(),
+        ));
+    }
+
+    if policy == EncryptionPolicy::Disabled {
+        return Err(MseError::EncryptionDisabled);
+    }
+    stream
+        .read_exact(&mut remote_key[PLAINTEXT_PREFIX_LEN..])
+        .await?;
+
+    let keys = DhKeyPair::random();
+    debug!("sending public key");
+    stream.write_all(keys.public_key()).await?;
+    stream.write_all(&random_padding()).await?;
+    let secret = keys.shared_secret(&remote_key)?;
+
+    sync_on(&mut stream, &hash(&[b"req1", &secret])).await?;
+
+    let mut skey_hash = Hash::default();
+    stream.read_exact(&mut skey_hash).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let Some(info_hash) = info_hashes
+        .iter()
+        .find(|info_hash| xor(hash(&[b"req2", info_hash.as_ref()]), req3) == skey_hash)
+    else {
+        return Err(MseError::UnknownInfoHash);
+    };
+
+    let skey = info_hash.as_ref();
+    let mut ciphers = CipherPair {
+        encrypt: Rc4::new_discarded(&hash(&[b"keyB", &secret, skey])),
+        decrypt: Rc4::new_discarded(&hash(&[b"keyA", &secret, skey])),
+    };
+
+    let mut vc_provide_pad_len = [0u8; VC.len() + 6];
+    stream.read_exact(&mut vc_provide_pad_len).await?;
+    ciphers.decrypt.apply_keystream(&mut vc_provide_pad_len);
+    let [vc @ .., p1, p2, p3, p4, pad_len_hi, pad_len_lo] = vc_provide_pad_len;
+    if vc != VC {
+        return Err(MseError::InvalidVerification);
+    }
+    let crypto_provide = u32::from_be_bytes([p1, p2, p3, p4]);
+
+    let mut pad = vec![0u8; read_pad_len([pad_len_hi, pad_len_lo])?];
+    stream.read_exact(&mut pad).await?;
+    ciphers.decrypt.apply_keystream(&mut pad);
+
+    let mut initial_payload_len = [0u8; 2];
+    stream.read_exact(&mut initial_payload_len).await?;
+    ciphers.decrypt.apply_keystream(&mut initial_payload_len);
+    let mut initial_payload = vec![0u8; u16::from_be_bytes(initial_payload_len) as usize];
+    stream.read_exact(&mut initial_payload).await?;
+    ciphers.decrypt.apply_keystream(&mut initial_payload);
+
+    let crypto_select = if crypto_provide & CryptoMethods::RC4 != 0 {
+        CryptoMethods::RC4
+    } else if crypto_provide & CryptoMethods::PLAINTEXT != 0 && policy != EncryptionPolicy::Forced {
+        CryptoMethods::PLAINTEXT
+    } else {
+        return Err(MseError::NoCryptoMethod(crypto_provide));
+    };
+
+    let mut reply = Vec::new();
+    reply.extend_from_slice(&VC);
+    reply.extend_from_slice(&crypto_select.to_be_bytes());
+    reply.extend_from_slice(&0u16.to_be_bytes()); // len(PadD)
+    ciphers.encrypt.apply_keystream(&mut reply);
+    debug!("sending crypto select");
+    stream.write_all(&reply).await?;
+
+    let ciphers = (crypto_select == CryptoMethods::RC4).then_some(ciphers);
+    debug!(encrypted = ciphers.is_some(), "mse handshake complete");
+    Ok(MseStream::new(stream, ciphers, initial_payload))
+}
+
+// reads byte by byte until the pattern is found, allowing for at most MAX_PAD_LEN bytes of random
+// padding in front of it.
+async fn sync_on<S: AsyncRead + Unpin>(stream: &mut S, pattern: &[u8]) -> Result<(), MseError> {
+    let mut window = Vec::with_capacity(MAX_PAD_LEN + pattern.len());
+    while window.len() < MAX_PAD_LEN + pattern.len() {
+        window.push(stream.read_u8().await?);
+        if window.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+    Err(MseError::SyncFailed)
+}
+
+fn read_pad_len(bytes: [u8; 2]) -> Result<usize, MseError> {
+    let pad_len = u16::from_be_bytes(bytes) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(MseError::PaddingTooLong(pad_len));
+    }
+    Ok(pad_len)
+}
+
+fn random_padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut pad = vec![0u8; rng.gen_range(0..=MAX_PAD_LEN)];
+    rng.fill(&mut pad[..]);
+    pad
+}
+
+fn hash(parts: &[&[u8]]) -> Hash {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.digest().bytes()
+}
+
+fn xor(mut lhs: Hash, rhs: Hash) -> Hash {
+    lhs.iter_mut().zip(rhs).for_each(|(l, r)| *l ^= r);
+    lhs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_protocol::handshake::HandshakeCodec;
+    use rstest::*;
+    use tokio::io::duplex;
+    use tokio_util::{bytes::BytesMut, codec::Encoder};
+
+    const INFO_HASH: [u8; 20] = [3; 20];
+    type EP = EncryptionPolicy;
+
+    async fn negotiate(
+        initiator_policy: EP,
+        responder_policy: EP,
+    ) -> (
+        Result<MseStream<tokio::io::DuplexStream>, MseError>,
+        Result<MseStream<tokio::io::DuplexStream>, MseError>,
+    ) {
+        let (initiator, responder) = duplex(4096);
+        let info_hash = InfoHash::new(INFO_HASH);
+        let accept_hashes = [InfoHash::new([1; 20]), info_hash.clone()];
+
+        let (initiated, accepted) = tokio::join!(
+            async {
+                // the plaintext handshake prefix is what lets the responder tell the two apart.
+                let mut stream = initiate(initiator, &info_hash, initiator_policy).await?;
+                if !stream.is_encrypted() {
+                    let handshake = PeerHandshake::new(info_hash.clone(), crate::PeerId::random());
+                    let mut bytes = BytesMut::new();
+                    HandshakeCodec::new().encode(handshake, &mut bytes).unwrap();
+                    stream.write_all(&bytes).await?;
+                }
+                Ok::<_, MseError>(stream)
+            },
+            accept(responder, &accept_hashes, responder_policy)
+        );
+        (initiated, accepted)
+    }
+
+    #[rstest]
+    #[case(EP::Enabled, EP::Enabled, true)]
+    #[case(EP::Forced, EP::Enabled, true)]
+    #[case(EP::Enabled, EP::Forced, true)]
+    #[case(EP::Disabled, EP::Enabled, false)]
+    #[tokio::test]
+    async fn test_negotiation(
+        #[case] initiator_policy: EP,
+        #[case] responder_policy: EP,
+        #[case] encrypted: bool,
+    ) {
+        let (initiated, accepted) = negotiate(initiator_policy, responder_policy).await;
+        let (mut initiated, mut accepted) = (initiated.unwrap(), accepted.unwrap());
+        assert_eq!(initiated.is_encrypted(), encrypted);
+        assert_eq!(accepted.is_encrypted(), encrypted);
+
+        if !encrypted {
+            let handshake_len =
+                PeerHandshake::new(InfoHash::new(INFO_HASH), crate::PeerId::random()).encoded_len();
+            let mut handshake = vec![0u8; handshake_len];
+            accepted.read_exact(&mut handshake).await.unwrap();
+            assert_eq!(handshake[1..20], PeerHandshake::PROTOCOL_PREFIX);
+        }
+
+        initiated.write_all(b"ping").await.unwrap();
+        initiated.flush().await.unwrap();
+        let mut buf = [0u8; 4];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        accepted.write_all(b"pong").await.unwrap();
+        accepted.flush().await.unwrap();
+        initiated.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[rstest]
+    #[case(EP::Disabled, EP::Forced)]
+    #[case(EP::Forced, EP::Disabled)]
+    #[tokio::test]
+    async fn test_policy_mismatch(#[case] initiator_policy: EP, #[case] responder_policy: EP) {
+        let (_, accepted) = negotiate(initiator_policy, responder_policy).await;
+        assert!(accepted.is_err());
+    }
+}
@@ -0,0 +1,79 @@
+/// plain RC4 keystream generator, as used by the MSE spec.
+/// https://wiki.vuze.com/w/Message_Stream_Encryption
+#[derive(Clone)]
+pub(super) struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl std::fmt::Debug for Rc4 {
+    // the keystream state is not something that should end up in the logs.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Rc4 { .. }")
+    }
+}
+
+impl Rc4 {
+    // MSE discards the first 1024 bytes of the keystream to avoid the known weak key bytes.
+    const DISCARD_LEN: usize = 1024;
+
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// creates a new cipher with the first 1024 bytes of the keystream already discarded.
+    pub fn new_discarded(key: &[u8]) -> Self {
+        let mut cipher = Self::new(key);
+        cipher.apply_keystream(&mut [0u8; Self::DISCARD_LEN]);
+        cipher
+    }
+
+    /// xors the keystream with buf in place, encrypting or decrypting it.
+    pub fn apply_keystream(&mut self, buf: &mut [u8]) {
+        for byte in buf.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    // test vectors from https://en.wikipedia.org/wiki/RC4#Test_vectors
+    #[rstest]
+    #[case(b"Key", b"Plaintext", &[0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3])]
+    #[case(b"Wiki", b"pedia", &[0x10, 0x21, 0xBF, 0x04, 0x20])]
+    fn test_known_vectors(#[case] key: &[u8], #[case] plaintext: &[u8], #[case] expected: &[u8]) {
+        let mut buf = plaintext.to_vec();
+        Rc4::new(key).apply_keystream(&mut buf);
+        assert_eq!(buf, expected);
+    }
+
+    #[rstest]
+    fn test_roundtrip_discarded() {
+        let mut buf = b"BitTorrent protocol".to_vec();
+        Rc4::new_discarded(b"secret").apply_keystream(&mut buf);
+        assert_ne!(&buf[..], b"BitTorrent protocol");
+        Rc4::new_discarded(b"secret").apply_keystream(&mut buf);
+        assert_eq!(&buf[..], b"BitTorrent protocol");
+    }
+}
@@ -0,0 +1,132 @@
+use super::rc4::Rc4;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// a pair of RC4 ciphers, one for each direction of the connection.
+#[derive(Debug)]
+pub(super) struct CipherPair {
+    pub encrypt: Rc4,
+    pub decrypt: Rc4,
+}
+
+/// transport wrapper which sits under the peer message codec, transparently de/obfuscating the
+/// stream if RC4 was negotiated during the MSE handshake, or passing bytes straight through when
+/// the connection is plaintext.
+#[derive(Debug)]
+pub struct MseStream<S> {
+    inner: S,
+    ciphers: Option<CipherPair>,
+
+    // already decrypted payload bytes which were read off the wire while negotiating the
+    // handshake, these are handed out before reading from the inner stream again.
+    read_prefix: Vec<u8>,
+
+    // encrypted bytes which were accepted from the caller, but not yet written to inner stream.
+    pending_write: Vec<u8>,
+    pending_write_offset: usize,
+}
+
+impl<S> MseStream<S> {
+    pub fn plaintext(inner: S) -> Self {
+        Self::new(inner, None, Vec::new())
+    }
+
+    pub(super) fn new(inner: S, ciphers: Option<CipherPair>, read_prefix: Vec<u8>) -> Self {
+        Self {
+            inner,
+            ciphers,
+            read_prefix,
+            pending_write: Vec::new(),
+            pending_write_offset: 0,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.ciphers.is_some()
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pending_write_offset < self.pending_write.len() {
+            let unwritten = &self.pending_write[self.pending_write_offset..];
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, unwritten))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_write_offset += n;
+        }
+        self.pending_write.clear();
+        self.pending_write_offset = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.read_prefix.is_empty() {
+            let n = std::cmp::min(buf.remaining(), this.read_prefix.len());
+            buf.put_slice(&this.read_prefix[..n]);
+            this.read_prefix.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled_before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(CipherPair { decrypt, .. }) = &mut this.ciphers {
+            decrypt.apply_keystream(&mut buf.filled_mut()[filled_before..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.ciphers.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        // the keystream has already advanced over the pending bytes, so they have to be written
+        // out before anything new can be accepted.
+        if this.pending_write_offset < this.pending_write.len() {
+            ready!(this.poll_write_pending(cx))?;
+        }
+
+        if let Some(CipherPair { encrypt, .. }) = &mut this.ciphers {
+            let start = this.pending_write.len();
+            this.pending_write.extend_from_slice(buf);
+            encrypt.apply_keystream(&mut this.pending_write[start..]);
+        }
+
+        // try to push the bytes out right away, the buffered bytes count as written either way.
+        if let Poll::Ready(Err(err)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,639 @@
+use super::packet::{Packet, PacketType};
+use super::timestamp_micros;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::ReadBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ConnectionState {
+    SynSent,
+    Connected,
+    Reset,
+}
+
+#[derive(Debug)]
+struct SentPacket {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+/// state machine for a single uTP connection, it never touches the socket itself. packets that
+/// need to go out are queued up in the outbox, which the owner drains after every call.
+#[derive(Debug)]
+pub(super) struct Connection {
+    pub state: ConnectionState,
+    pub remote: SocketAddr,
+    pub recv_id: u16,
+    send_id: u16,
+    seq_nr: u16,
+    ack_nr: u16,
+
+    // send side
+    send_buf: VecDeque<u8>,
+    in_flight: VecDeque<SentPacket>,
+    bytes_in_flight: usize,
+    max_window: f64,
+    peer_window: u32,
+    closing: bool,
+    fin_sent: bool,
+    duplicate_acks: u32,
+    last_ack_nr: u16,
+
+    // receive side
+    recv_buf: VecDeque<u8>,
+    out_of_order: HashMap<u16, Packet>,
+    fin_seq_nr: Option<u16>,
+    eof: bool,
+
+    // congestion control
+    reply_delay: u32,
+    base_delays: VecDeque<(Instant, u32)>,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+
+    /// set while no UtpStream owns the connection, the socket forgets it once it has wound down.
+    pub orphaned: bool,
+    outbox: Vec<Packet>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    connect_waker: Option<Waker>,
+}
+
+impl Connection {
+    pub const MAX_PAYLOAD: usize = 1400 - Packet::HEADER_SIZE;
+    const SEND_BUF_CAPACITY: usize = 1 << 18;
+    const RECV_BUF_CAPACITY: usize = 1 << 20;
+    const MAX_OUT_OF_ORDER: u16 = 1024;
+
+    // LEDBAT parameters from BEP 29.
+    const TARGET_DELAY_MICROS: f64 = 100_000.0;
+    const MAX_WINDOW_INCREASE_PER_RTT: f64 = 3000.0;
+    const MIN_WINDOW: f64 = Self::MAX_PAYLOAD as f64;
+    const MAX_WINDOW: f64 = Self::RECV_BUF_CAPACITY as f64;
+    const INITIAL_WINDOW: f64 = 4.0 * Self::MAX_PAYLOAD as f64;
+    const BASE_DELAY_BUCKET: Duration = Duration::from_secs(60);
+    const BASE_DELAY_HISTORY: usize = 2;
+
+    const INITIAL_RTO: Duration = Duration::from_secs(1);
+    const MIN_RTO: Duration = Duration::from_millis(500);
+    const MAX_RTO: Duration = Duration::from_secs(16);
+    const MAX_TRANSMISSIONS: u32 = 6;
+    const MAX_SYN_TRANSMISSIONS: u32 = 3;
+    const DUPLICATE_ACK_THRESHOLD: u32 = 3;
+
+    fn new(remote: SocketAddr, recv_id: u16, send_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            state: ConnectionState::SynSent,
+            remote,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr,
+            send_buf: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            bytes_in_flight: 0,
+            max_window: Self::INITIAL_WINDOW,
+            peer_window: Self::RECV_BUF_CAPACITY as u32,
+            closing: false,
+            fin_sent: false,
+            duplicate_acks: 0,
+            last_ack_nr: 0,
+            recv_buf: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            fin_seq_nr: None,
+            eof: false,
+            reply_delay: 0,
+            base_delays: VecDeque::new(),
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            rto: Self::INITIAL_RTO,
+            orphaned: false,
+            outbox: Vec::new(),
+            read_waker: None,
+            write_waker: None,
+            connect_waker: None,
+        }
+    }
+
+    /// creates a connection on the connecting side and queues up the SYN.
+    pub fn outgoing(remote: SocketAddr, recv_id: u16) -> Self {
+        let mut conn = Self::new(remote, recv_id, recv_id.wrapping_add(1), 1, 0);
+        // nothing owns the connection until the handshake completes.
+        conn.orphaned = true;
+        conn.send_packet(PacketType::Syn, Vec::new());
+        conn
+    }
+
+    /// creates a connection on the accepting side from the SYN, and queues up its ack.
+    pub fn incoming(remote: SocketAddr, syn: &Packet) -> Self {
+        let mut conn = Self::new(
+            remote,
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+            rand::random(),
+            syn.seq_nr,
+        );
+        conn.state = ConnectionState::Connected;
+        conn.reply_delay = timestamp_micros().wrapping_sub(syn.timestamp);
+        conn.send_state();
+        conn
+    }
+
+    pub fn take_outbox(&mut self) -> Vec<Packet> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// whether the connection is done and can be forgotten by the socket.
+    pub fn is_finished(&self) -> bool {
+        self.orphaned
+            && (self.state == ConnectionState::Reset
+                || (self.fin_sent && self.in_flight.is_empty()))
+    }
+
+    pub fn on_packet(&mut self, packet: Packet) {
+        self.reply_delay = timestamp_micros().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.wnd_size;
+
+        match packet.packet_type {
+            PacketType::Reset => {
+                self.state = ConnectionState::Reset;
+                self.wake_all();
+                return;
+            }
+            // our ack of the SYN got lost, so the peer is retrying.
+            PacketType::Syn => {
+                self.send_state();
+                return;
+            }
+            _ => {}
+        }
+
+        if self.state == ConnectionState::SynSent {
+            if packet.packet_type != PacketType::State {
+                return;
+            }
+            self.state = ConnectionState::Connected;
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            if let Some(waker) = self.connect_waker.take() {
+                waker.wake();
+            }
+        }
+
+        self.process_ack(&packet);
+        if matches!(packet.packet_type, PacketType::Data | PacketType::Fin) {
+            self.process_incoming(packet);
+        }
+        self.flush_send_buf();
+    }
+
+    pub fn on_tick(&mut self, now: Instant) {
+        let rto = self.rto;
+        let Some(oldest) = self.in_flight.front_mut() else {
+            return;
+        };
+        if now.duration_since(oldest.sent_at) < rto {
+            return;
+        }
+
+        let max_transmissions = match oldest.packet.packet_type {
+            PacketType::Syn => Self::MAX_SYN_TRANSMISSIONS,
+            _ => Self::MAX_TRANSMISSIONS,
+        };
+        if oldest.transmissions >= max_transmissions {
+            self.state = ConnectionState::Reset;
+            self.wake_all();
+            return;
+        }
+
+        // a timeout means the path is congested, so start over from the smallest window.
+        self.max_window = Self::MIN_WINDOW;
+        self.rto = std::cmp::min(self.rto * 2, Self::MAX_RTO);
+        self.retransmit_oldest(now);
+    }
+
+    pub fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.state {
+            ConnectionState::Connected => Poll::Ready(Ok(())),
+            ConnectionState::Reset => Poll::Ready(Err(io::ErrorKind::ConnectionRefused.into())),
+            ConnectionState::SynSent => {
+                self.connect_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    pub fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.recv_buf.is_empty() {
+            let window_was_closed = self.recv_window() < Self::MAX_PAYLOAD as u32;
+
+            let n = std::cmp::min(buf.remaining(), self.recv_buf.len());
+            let (front, back) = self.recv_buf.as_slices();
+            let from_front = std::cmp::min(n, front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..n - from_front]);
+            self.recv_buf.drain(..n);
+
+            if window_was_closed {
+                // let the peer know it can start sending again.
+                self.send_state();
+            }
+            return Poll::Ready(Ok(()));
+        }
+
+        if self.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if self.state == ConnectionState::Reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        self.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.state == ConnectionState::Reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if self.closing {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let n = std::cmp::min(buf.len(), Self::SEND_BUF_CAPACITY - self.send_buf.len());
+        if n == 0 {
+            self.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        self.send_buf.extend(&buf[..n]);
+        self.flush_send_buf();
+        Poll::Ready(Ok(n))
+    }
+
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.state == ConnectionState::Reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if self.send_buf.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        self.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub fn poll_shutdown(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.close();
+        if self.state == ConnectionState::Reset || (self.fin_sent && self.in_flight.is_empty()) {
+            return Poll::Ready(Ok(()));
+        }
+        self.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// sends a FIN once everything that was written has been sent.
+    pub fn close(&mut self) {
+        self.closing = true;
+        self.flush_send_buf();
+    }
+
+    /// gives up on the connection right away, and tells the peer to do the same.
+    pub fn reset(&mut self) {
+        if self.state == ConnectionState::Reset {
+            return;
+        }
+        self.outbox.push(Packet {
+            packet_type: PacketType::Reset,
+            connection_id: self.send_id,
+            timestamp: 0,
+            timestamp_difference: self.reply_delay,
+            wnd_size: 0,
+            seq_nr: self.seq_nr,
+            ack_nr: self.ack_nr,
+            payload: Vec::new(),
+        });
+        self.in_flight.clear();
+        self.bytes_in_flight = 0;
+        self.state = ConnectionState::Reset;
+        self.wake_all();
+    }
+
+    fn process_ack(&mut self, packet: &Packet) {
+        let now = Instant::now();
+        let mut acked_bytes = 0;
+        let mut rtt_sample = None;
+
+        while let Some(sent) = self.in_flight.front() {
+            if !seq_less_equal(sent.packet.seq_nr, packet.ack_nr) {
+                break;
+            }
+            // karn's algorithm, retransmitted packets give ambiguous rtt samples.
+            if sent.transmissions == 1 {
+                rtt_sample = Some(now.duration_since(sent.sent_at));
+            }
+            acked_bytes += sent.packet.payload.len();
+            self.bytes_in_flight -= sent.packet.payload.len();
+            self.in_flight.pop_front();
+        }
+
+        if acked_bytes == 0
+            && packet.packet_type == PacketType::State
+            && packet.ack_nr == self.last_ack_nr
+            && !self.in_flight.is_empty()
+        {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == Self::DUPLICATE_ACK_THRESHOLD {
+                // the packet after ack_nr was most likely lost.
+                self.max_window = f64::max(self.max_window / 2.0, Self::MIN_WINDOW);
+                self.retransmit_oldest(now);
+            }
+        } else {
+            self.duplicate_acks = 0;
+        }
+        self.last_ack_nr = packet.ack_nr;
+
+        if let Some(sample) = rtt_sample {
+            self.update_rto(sample);
+        }
+        if acked_bytes > 0 {
+            self.update_window(acked_bytes, packet.timestamp_difference, now);
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn process_incoming(&mut self, packet: Packet) {
+        let expected = self.ack_nr.wrapping_add(1);
+        if seq_less(packet.seq_nr, expected) {
+            // a retransmission of something we already have, our ack was probably lost.
+            self.send_state();
+            return;
+        }
+        if packet.seq_nr.wrapping_sub(expected) > Self::MAX_OUT_OF_ORDER {
+            return;
+        }
+
+        if packet.packet_type == PacketType::Fin {
+            self.fin_seq_nr = Some(packet.seq_nr);
+        }
+
+        if packet.seq_nr != expected {
+            self.out_of_order.insert(packet.seq_nr, packet);
+            self.send_state();
+            return;
+        }
+
+        self.accept_in_order(packet);
+        while let Some(next) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.accept_in_order(next);
+        }
+        self.send_state();
+
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn accept_in_order(&mut self, packet: Packet) {
+        self.ack_nr = packet.seq_nr;
+        self.recv_buf.extend(packet.payload);
+        if self.fin_seq_nr == Some(packet.seq_nr) {
+            self.eof = true;
+        }
+    }
+
+    // LEDBAT, grow the window while the one way delay stays below target, shrink it once the
+    // delay goes above. this makes uTP back off in favour of other traffic on the link.
+    fn update_window(&mut self, acked_bytes: usize, delay_sample: u32, now: Instant) {
+        // a zero difference means the peer hasn't received anything from us to measure with.
+        if delay_sample == 0 {
+            return;
+        }
+
+        match self.base_delays.back_mut() {
+            Some((bucket_start, min_delay))
+                if now.duration_since(*bucket_start) < Self::BASE_DELAY_BUCKET =>
+            {
+                *min_delay = std::cmp::min(*min_delay, delay_sample);
+            }
+            _ => {
+                self.base_delays.push_back((now, delay_sample));
+                if self.base_delays.len() > Self::BASE_DELAY_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+        let base_delay = self
+            .base_delays
+            .iter()
+            .map(|(_, delay)| *delay)
+            .min()
+            .unwrap_or(delay_sample);
+
+        let our_delay = delay_sample.wrapping_sub(base_delay) as f64;
+        let delay_factor = (Self::TARGET_DELAY_MICROS - our_delay) / Self::TARGET_DELAY_MICROS;
+        let window_factor = acked_bytes as f64 / f64::max(self.max_window, acked_bytes as f64);
+        let gain = Self::MAX_WINDOW_INCREASE_PER_RTT * delay_factor * window_factor;
+
+        self.max_window = (self.max_window + gain).clamp(Self::MIN_WINDOW, Self::MAX_WINDOW);
+    }
+
+    fn update_rto(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let delta = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+        let rto = self.rtt.unwrap_or(Self::INITIAL_RTO) + self.rtt_var * 4;
+        self.rto = rto.clamp(Self::MIN_RTO, Self::MAX_RTO);
+    }
+
+    fn flush_send_buf(&mut self) {
+        if self.state != ConnectionState::Connected {
+            return;
+        }
+
+        let window = f64::min(self.max_window, self.peer_window as f64) as usize;
+        while !self.send_buf.is_empty() {
+            let len = std::cmp::min(self.send_buf.len(), Self::MAX_PAYLOAD);
+            // always allow a single packet in flight, it doubles as a zero window probe.
+            if self.bytes_in_flight > 0 && self.bytes_in_flight + len > window {
+                break;
+            }
+            let payload = self.send_buf.drain(..len).collect();
+            self.send_packet(PacketType::Data, payload);
+        }
+
+        if self.closing && !self.fin_sent && self.send_buf.is_empty() {
+            self.fin_sent = true;
+            self.send_packet(PacketType::Fin, Vec::new());
+        }
+    }
+
+    fn send_packet(&mut self, packet_type: PacketType, payload: Vec<u8>) {
+        let connection_id = match packet_type {
+            PacketType::Syn => self.recv_id,
+            _ => self.send_id,
+        };
+        let packet = Packet {
+            packet_type,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: self.reply_delay,
+            wnd_size: self.recv_window(),
+            seq_nr: self.seq_nr,
+            ack_nr: self.ack_nr,
+            payload,
+        };
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.bytes_in_flight += packet.payload.len();
+
+        self.outbox.push(packet.clone());
+        self.in_flight.push_back(SentPacket {
+            packet,
+            sent_at: Instant::now(),
+            transmissions: 1,
+        });
+    }
+
+    fn retransmit_oldest(&mut self, now: Instant) {
+        let (ack_nr, reply_delay, wnd_size) = (self.ack_nr, self.reply_delay, self.recv_window());
+        if let Some(oldest) = self.in_flight.front_mut() {
+            oldest.sent_at = now;
+            oldest.transmissions += 1;
+            if oldest.packet.packet_type != PacketType::Syn {
+                oldest.packet.ack_nr = ack_nr;
+            }
+            oldest.packet.timestamp_difference = reply_delay;
+            oldest.packet.wnd_size = wnd_size;
+            self.outbox.push(oldest.packet.clone());
+        }
+    }
+
+    fn send_state(&mut self) {
+        self.outbox.push(Packet {
+            packet_type: PacketType::State,
+            connection_id: self.send_id,
+            timestamp: 0,
+            timestamp_difference: self.reply_delay,
+            wnd_size: self.recv_window(),
+            seq_nr: self.seq_nr,
+            ack_nr: self.ack_nr,
+            payload: Vec::new(),
+        });
+    }
+
+    fn recv_window(&self) -> u32 {
+        Self::RECV_BUF_CAPACITY.saturating_sub(self.recv_buf.len()) as u32
+    }
+
+    fn wake_all(&mut self) {
+        for waker in [
+            self.read_waker.take(),
+            self.write_waker.take(),
+            self.connect_waker.take(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            waker.wake();
+        }
+    }
+}
+
+// sequence numbers wrap around, so compare them by their signed distance.
+fn seq_less(lhs: u16, rhs: u16) -> bool {
+    (lhs.wrapping_sub(rhs) as i16) < 0
+}
+
+fn seq_less_equal(lhs: u16, rhs: u16) -> bool {
+    lhs == rhs || seq_less(lhs, rhs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn remote() -> SocketAddr {
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881).into()
+    }
+
+    // runs the handshake between two connections, handing packets over directly.
+    fn connected_pair() -> (Connection, Connection) {
+        let mut initiator = Connection::outgoing(remote(), 100);
+        let syn = initiator.take_outbox().remove(0);
+        let mut responder = Connection::incoming(remote(), &syn);
+        for packet in responder.take_outbox() {
+            initiator.on_packet(packet);
+        }
+        assert_eq!(initiator.state, ConnectionState::Connected);
+        (initiator, responder)
+    }
+
+    #[test]
+    fn test_seq_wraparound() {
+        assert!(seq_less(u16::MAX, 0));
+        assert!(!seq_less(0, u16::MAX));
+        assert!(seq_less_equal(5, 5));
+    }
+
+    #[test]
+    fn test_out_of_order_delivery() {
+        let (mut initiator, mut responder) = connected_pair();
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let data = vec![7u8; 3 * Connection::MAX_PAYLOAD];
+        assert!(initiator.poll_write(&mut cx, &data).is_ready());
+        let mut packets = initiator.take_outbox();
+        assert_eq!(packets.len(), 3);
+        packets.reverse();
+        for packet in packets {
+            responder.on_packet(packet);
+        }
+
+        let mut out = vec![0u8; data.len()];
+        let mut buf = ReadBuf::new(&mut out);
+        assert!(responder.poll_read(&mut cx, &mut buf).is_ready());
+        assert_eq!(buf.filled(), &data[..]);
+    }
+
+    #[test]
+    fn test_window_shrinks_above_target_delay() {
+        let (mut initiator, _) = connected_pair();
+        let now = Instant::now();
+        initiator.update_window(1000, 10_000, now);
+        let window = initiator.max_window;
+
+        // delay 200ms above the base delay is well over the 100ms target.
+        initiator.update_window(1000, 210_000, now);
+        assert!(initiator.max_window < window);
+    }
+
+    #[test]
+    fn test_timeout_resets_window() {
+        let (mut initiator, _) = connected_pair();
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(initiator.poll_write(&mut cx, b"data").is_ready());
+        initiator.take_outbox();
+
+        initiator.on_tick(Instant::now() + Duration::from_secs(2));
+        assert_eq!(initiator.max_window, Connection::MIN_WINDOW);
+        assert_eq!(initiator.take_outbox().len(), 1);
+    }
+}
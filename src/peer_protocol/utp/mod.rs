@@ -0,0 +1,355 @@
+//! uTP, a reliable stream transport over UDP which uses LEDBAT congestion control to yield to
+//! other traffic on the link. https://www.bittorrent.org/beps/bep_0029.html
+mod connection;
+mod packet;
+mod stream;
+
+pub use stream::UtpStream;
+
+use crate::prelude::*;
+use connection::Connection;
+use packet::{Packet, PacketType};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+type ConnectionHandle = Arc<Mutex<Connection>>;
+type ConnectionKey = (SocketAddr, u16);
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// a UDP socket multiplexing any number of uTP connections, it can be cheaply cloned.
+#[derive(Debug, Clone)]
+pub struct UtpSocket {
+    shared: Arc<SocketShared>,
+    _driver: Arc<DriverHandle>,
+}
+
+#[derive(Debug)]
+struct SocketShared {
+    socket: UdpSocket,
+    connections: Mutex<HashMap<ConnectionKey, ConnectionHandle>>,
+    incoming_rx: tokio::sync::Mutex<mpsc::Receiver<ConnectionHandle>>,
+    // the dht shares the port, its messages are bencoded dictionaries and never parse as uTP.
+    dht_tx: mpsc::Sender<Datagram>,
+    dht_rx: Mutex<Option<mpsc::Receiver<Datagram>>>,
+}
+
+// stops the socket driver once the last socket handle or stream is gone.
+#[derive(Debug)]
+struct DriverHandle(JoinHandle<()>);
+
+impl Drop for DriverHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl UtpSocket {
+    const INCOMING_BUFFER_SIZE: usize = 32;
+    const TICK_INTERVAL: Duration = Duration::from_millis(50);
+    const MAX_DATAGRAM_SIZE: usize = 1 << 16;
+    const DHT_BUFFER_SIZE: usize = 256;
+
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        // sends are non blocking, and would fail until the first readiness event comes in.
+        socket.writable().await?;
+        let (incoming_tx, incoming_rx) = mpsc::channel(Self::INCOMING_BUFFER_SIZE);
+        let (dht_tx, dht_rx) = mpsc::channel(Self::DHT_BUFFER_SIZE);
+
+        let shared = Arc::new(SocketShared {
+            socket,
+            connections: Mutex::new(HashMap::new()),
+            incoming_rx: tokio::sync::Mutex::new(incoming_rx),
+            dht_tx,
+            dht_rx: Mutex::new(Some(dht_rx)),
+        });
+        let driver = tokio::spawn(Self::drive(shared.clone(), incoming_tx));
+
+        Ok(Self {
+            shared,
+            _driver: Arc::new(DriverHandle(driver)),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+
+    pub async fn connect(&self, remote: SocketAddr) -> io::Result<UtpStream> {
+        let conn = {
+            let mut connections = self.shared.connections.lock().unwrap();
+            // the peer answers on recv_id + 1, so both ids have to be free.
+            let recv_id = loop {
+                let recv_id: u16 = rand::random();
+                if !connections.contains_key(&(remote, recv_id))
+                    && !connections.contains_key(&(remote, recv_id.wrapping_add(1)))
+                {
+                    break recv_id;
+                }
+            };
+            let conn = Arc::new(Mutex::new(Connection::outgoing(remote, recv_id)));
+            connections.insert((remote, recv_id), conn.clone());
+            conn
+        };
+        let outbox = conn.lock().unwrap().take_outbox();
+        self.shared.send_packets(remote, outbox);
+
+        // the future can be dropped mid handshake, e.g. by a connect timeout, and nothing would
+        // ever own the connection once the SYN-ACK shows up.
+        let guard = PendingConnect {
+            socket: self,
+            conn: &conn,
+        };
+        futures::future::poll_fn(|cx| conn.lock().unwrap().poll_connected(cx)).await?;
+        std::mem::forget(guard);
+        conn.lock().unwrap().orphaned = false;
+        Ok(UtpStream::new(conn, self.clone()))
+    }
+
+    pub async fn accept(&self) -> io::Result<(UtpStream, SocketAddr)> {
+        let conn = self
+            .shared
+            .incoming_rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(io::ErrorKind::BrokenPipe)?;
+        let remote = conn.lock().unwrap().remote;
+        Ok((UtpStream::new(conn, self.clone()), remote))
+    }
+
+    /// the dht messages which came in on the socket, only the first caller gets them.
+    pub fn take_dht_datagrams(&self) -> Option<mpsc::Receiver<Datagram>> {
+        self.shared.dht_rx.lock().unwrap().take()
+    }
+
+    pub async fn send_datagram(&self, datagram: &[u8], remote: SocketAddr) -> io::Result<()> {
+        self.shared.socket.send_to(datagram, remote).await?;
+        Ok(())
+    }
+
+    fn send_packets(&self, remote: SocketAddr, packets: Vec<Packet>) {
+        self.shared.send_packets(remote, packets);
+    }
+
+    async fn drive(shared: Arc<SocketShared>, incoming_tx: mpsc::Sender<ConnectionHandle>) {
+        let mut buf = vec![0u8; Self::MAX_DATAGRAM_SIZE];
+        let mut tick = tokio::time::interval(Self::TICK_INTERVAL);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                received = shared.socket.recv_from(&mut buf) => {
+                    match received {
+                        Ok((len, remote)) => shared.on_datagram(&buf[..len], remote, &incoming_tx),
+                        // icmp errors from earlier sends can surface here, they don't affect
+                        // the socket itself.
+                        Err(err) => trace!(%err, "utp socket receive error"),
+                    }
+                }
+                _ = tick.tick() => shared.on_tick(),
+            }
+        }
+    }
+}
+
+impl SocketShared {
+    fn on_datagram(
+        &self,
+        datagram: &[u8],
+        remote: SocketAddr,
+        incoming_tx: &mpsc::Sender<ConnectionHandle>,
+    ) {
+        if datagram.first() == Some(&b'd') {
+            if self.dht_tx.try_send((datagram.to_vec(), remote)).is_err() {
+                trace!(%remote, "dropping dht message");
+            }
+            return;
+        }
+
+        let packet = match Packet::decode(datagram) {
+            Ok(packet) => packet,
+            Err(err) => {
+                trace!(%err, %remote, "dropping invalid utp packet");
+                return;
+            }
+        };
+
+        let key = match packet.packet_type {
+            PacketType::Syn => (remote, packet.connection_id.wrapping_add(1)),
+            _ => (remote, packet.connection_id),
+        };
+
+        let existing = self.connections.lock().unwrap().get(&key).cloned();
+        let conn = match existing {
+            Some(conn) => conn,
+            None if packet.packet_type == PacketType::Syn => {
+                let conn = Arc::new(Mutex::new(Connection::incoming(remote, &packet)));
+                if incoming_tx.try_send(conn.clone()).is_err() {
+                    debug!(%remote, "utp accept backlog full, dropping connection");
+                    return;
+                }
+                self.connections.lock().unwrap().insert(key, conn.clone());
+                let outbox = conn.lock().unwrap().take_outbox();
+                self.send_packets(remote, outbox);
+                return;
+            }
+            None => {
+                trace!(%remote, "utp packet for unknown connection");
+                return;
+            }
+        };
+
+        let outbox = {
+            let mut conn = conn.lock().unwrap();
+            conn.on_packet(packet);
+            conn.take_outbox()
+        };
+        self.send_packets(remote, outbox);
+    }
+
+    fn on_tick(&self) {
+        let now = Instant::now();
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|_, conn| {
+            let mut conn = conn.lock().unwrap();
+            conn.on_tick(now);
+            let outbox = conn.take_outbox();
+            self.send_packets(conn.remote, outbox);
+            !conn.is_finished()
+        });
+    }
+
+    fn send_packets(&self, remote: SocketAddr, packets: Vec<Packet>) {
+        for mut packet in packets {
+            packet.timestamp = timestamp_micros();
+            // a full send buffer is no different from a dropped packet, it gets retransmitted.
+            if let Err(err) = self.socket.try_send_to(&packet.encode(), remote) {
+                trace!(%err, %remote, "failed to send utp packet");
+            }
+        }
+    }
+}
+
+// resets and forgets a connection whose connect didn't run to completion.
+struct PendingConnect<'a> {
+    socket: &'a UtpSocket,
+    conn: &'a ConnectionHandle,
+}
+
+impl Drop for PendingConnect<'_> {
+    fn drop(&mut self) {
+        let (key, outbox) = {
+            let mut conn = self.conn.lock().unwrap();
+            conn.reset();
+            ((conn.remote, conn.recv_id), conn.take_outbox())
+        };
+        self.socket.shared.connections.lock().unwrap().remove(&key);
+        self.socket.send_packets(key.0, outbox);
+    }
+}
+
+/// microsecond timestamp used for one way delay measurements, it only has to be monotonic on
+/// our side as the peer only echoes the difference back.
+fn timestamp_micros() -> u32 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_micros() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_transfer_both_ways() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let data: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+        let (connected, accepted) = tokio::join!(client.connect(server_addr), server.accept());
+        let (mut connected, (mut accepted, _)) = (connected.unwrap(), accepted.unwrap());
+
+        let (_, received) = tokio::join!(
+            async {
+                connected.write_all(&data).await.unwrap();
+                connected.shutdown().await.unwrap();
+            },
+            async {
+                let mut received = Vec::new();
+                accepted.read_to_end(&mut received).await.unwrap();
+                received
+            }
+        );
+        assert_eq!(received, data);
+
+        accepted.write_all(b"bye").await.unwrap();
+        accepted.flush().await.unwrap();
+        let mut buf = [0u8; 3];
+        connected.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"bye");
+    }
+
+    #[tokio::test]
+    async fn test_connect_fails_without_listener() {
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        // bound, but never answers.
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            client.connect(silent.local_addr().unwrap()),
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_connect_is_forgotten() {
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = vec![0u8; UtpSocket::MAX_DATAGRAM_SIZE];
+
+        let connect = tokio::time::timeout(
+            Duration::from_millis(100),
+            client.connect(server.local_addr().unwrap()),
+        );
+        let (result, syn) = tokio::join!(connect, async {
+            let (len, _) = server.recv_from(&mut buf).await.unwrap();
+            Packet::decode(&buf[..len]).unwrap()
+        });
+        assert!(result.is_err());
+        assert_eq!(syn.packet_type, PacketType::Syn);
+
+        // the peer is told we gave up.
+        let (len, client_addr) = server.recv_from(&mut buf).await.unwrap();
+        let reset = Packet::decode(&buf[..len]).unwrap();
+        assert_eq!(reset.packet_type, PacketType::Reset);
+
+        // the SYN-ACK shows up late.
+        let syn_ack = Packet {
+            packet_type: PacketType::State,
+            connection_id: syn.connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            wnd_size: 1 << 20,
+            seq_nr: 1000,
+            ack_nr: syn.seq_nr,
+            payload: Vec::new(),
+        };
+        server
+            .send_to(&syn_ack.encode(), client_addr)
+            .await
+            .unwrap();
+        tokio::time::sleep(UtpSocket::TICK_INTERVAL * 2).await;
+        assert!(client.shared.connections.lock().unwrap().is_empty());
+    }
+}
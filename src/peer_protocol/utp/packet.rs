@@ -0,0 +1,149 @@
+use tokio_util::bytes::{Buf, BufMut};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum PacketType {
+    Data,
+    Fin,
+    State,
+    Reset,
+    Syn,
+}
+
+impl PacketType {
+    fn from_u8(value: u8) -> anyhow::Result<Self> {
+        Ok(match value {
+            0 => Self::Data,
+            1 => Self::Fin,
+            2 => Self::State,
+            3 => Self::Reset,
+            4 => Self::Syn,
+            invalid => anyhow::bail!("invalid utp packet type {}", invalid),
+        })
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Self::Data => 0,
+            Self::Fin => 1,
+            Self::State => 2,
+            Self::Reset => 3,
+            Self::Syn => 4,
+        }
+    }
+}
+
+/// a single uTP packet, header layout according to https://www.bittorrent.org/beps/bep_0029.html
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    pub timestamp_difference: u32,
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub const HEADER_SIZE: usize = 20;
+    const VERSION: u8 = 1;
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::HEADER_SIZE + self.payload.len());
+        out.put_u8(self.packet_type.as_u8() << 4 | Self::VERSION);
+        out.put_u8(0); // no extensions are sent.
+        out.put_u16(self.connection_id);
+        out.put_u32(self.timestamp);
+        out.put_u32(self.timestamp_difference);
+        out.put_u32(self.wnd_size);
+        out.put_u16(self.seq_nr);
+        out.put_u16(self.ack_nr);
+        out.put_slice(&self.payload);
+        out
+    }
+
+    pub fn decode(mut bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.len() < Self::HEADER_SIZE {
+            anyhow::bail!("utp packet of size {} is smaller than header", bytes.len());
+        }
+
+        let type_version = bytes.get_u8();
+        if type_version & 0x0F != Self::VERSION {
+            anyhow::bail!("unsupported utp version {}", type_version & 0x0F);
+        }
+        let packet_type = PacketType::from_u8(type_version >> 4)?;
+        let mut extension = bytes.get_u8();
+
+        let mut packet = Self {
+            packet_type,
+            connection_id: bytes.get_u16(),
+            timestamp: bytes.get_u32(),
+            timestamp_difference: bytes.get_u32(),
+            wnd_size: bytes.get_u32(),
+            seq_nr: bytes.get_u16(),
+            ack_nr: bytes.get_u16(),
+            payload: Vec::new(),
+        };
+
+        // extensions (e.g selective acks) are not used, but have to be skipped over.
+        while extension != 0 {
+            if bytes.len() < 2 {
+                anyhow::bail!("truncated utp extension header");
+            }
+            extension = bytes.get_u8();
+            let len = bytes.get_u8() as usize;
+            if bytes.len() < len {
+                anyhow::bail!("truncated utp extension");
+            }
+            bytes.advance(len);
+        }
+
+        packet.payload = bytes.to_vec();
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let packet = Packet {
+            packet_type: PacketType::Data,
+            connection_id: 0xBEEF,
+            timestamp: 1,
+            timestamp_difference: 2,
+            wnd_size: 3,
+            seq_nr: 4,
+            ack_nr: 5,
+            payload: b"payload".to_vec(),
+        };
+        let bytes = packet.encode();
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(Packet::decode(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_skip_extensions() {
+        let mut bytes = Packet {
+            packet_type: PacketType::State,
+            connection_id: 1,
+            timestamp: 0,
+            timestamp_difference: 0,
+            wnd_size: 0,
+            seq_nr: 0,
+            ack_nr: 0,
+            payload: Vec::new(),
+        }
+        .encode();
+        // selective ack extension with a 4 byte bitmask.
+        bytes[1] = 1;
+        bytes.extend_from_slice(&[0, 4, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+        let packet = Packet::decode(&bytes).unwrap();
+        assert_eq!(packet.packet_type, PacketType::State);
+        assert!(packet.payload.is_empty());
+    }
+}
@@ -0,0 +1,79 @@
+use super::connection::Connection;
+use super::{ConnectionHandle, UtpSocket};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// a single uTP connection, usable like any other byte stream.
+#[derive(Debug)]
+pub struct UtpStream {
+    conn: ConnectionHandle,
+    socket: UtpSocket,
+    remote: SocketAddr,
+}
+
+impl UtpStream {
+    pub(super) fn new(conn: ConnectionHandle, socket: UtpSocket) -> Self {
+        let remote = conn.lock().unwrap().remote;
+        Self {
+            conn,
+            socket,
+            remote,
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    // runs f on the connection, then sends out whatever packets it queued up.
+    fn with_connection<T>(&self, f: impl FnOnce(&mut Connection) -> T) -> T {
+        let (out, outbox) = {
+            let mut conn = self.conn.lock().unwrap();
+            let out = f(&mut conn);
+            (out, conn.take_outbox())
+        };
+        self.socket.send_packets(self.remote, outbox);
+        out
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.with_connection(|conn| conn.poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.with_connection(|conn| conn.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with_connection(|conn| conn.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with_connection(|conn| conn.poll_shutdown(cx))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        // the socket keeps the connection around until the FIN has been acked.
+        self.with_connection(|conn| {
+            conn.orphaned = true;
+            conn.close();
+        });
+    }
+}
@@ -1,6 +1,8 @@
-use super::{PieceIndex, PieceLength};
-use crate::Bitfield;
+use super::{PeerStats, PieceIndex, PieceLength};
+use crate::torrent::Bitfield;
+use reqwest::Url;
 use std::net::SocketAddrV4;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 
 use crate::metainfo::PieceHash;
@@ -24,9 +26,22 @@ impl PieceRequestInfo {
 
 #[derive(Debug, Clone)]
 pub enum PeerCommands {
+    Interested,
     NotInterested,
+    Choke,
+    Unchoke,
     DownloadPiece(PieceRequestInfo),
+    /// pieces we have which the peer hasn't been told about yet.
+    Have(Vec<PieceIndex>),
+    /// a block the peer requested, as read from storage.
+    SendBlock {
+        index: PieceIndex,
+        begin: u32,
+        data: Vec<u8>,
+    },
     Shutdown,
+    /// the engine banned the peer, it's dropped and never dialed again.
+    Ban,
 }
 
 #[derive(Debug, Clone)]
@@ -35,13 +50,62 @@ pub enum PeerAlerts {
         peer_addr: SocketAddrV4,
         bitfield: Bitfield,
         commands_tx: mpsc::Sender<PeerCommands>,
+        stats: Arc<PeerStats>,
     },
     UpdateBitfield {
         peer_addr: std::net::SocketAddrV4,
         has_piece: PieceIndex,
     },
     DonePiece {
+        peer_addr: SocketAddrV4,
+        piece_index: PieceIndex,
+        piece: Vec<u8>,
+    },
+    /// the piece failed the hash check, the data is passed along so the engine can tell which
+    /// blocks were bad once a good copy turns up.
+    PieceFailed {
+        peer_addr: SocketAddrV4,
         piece_index: PieceIndex,
         piece: Vec<u8>,
     },
+    /// the worker gave up on the pieces, as the peer didn't answer the requests for them in time
+    /// or choked us.
+    ReleasePieces {
+        peer_addr: SocketAddrV4,
+        pieces: Vec<PieceIndex>,
+    },
+    /// the peer lets us request blocks again, it's only handed pieces while it does.
+    Unchoked { peer_addr: SocketAddrV4 },
+    /// the peer requested a block while unchoked, it's sent back as a
+    /// [`PeerCommands::SendBlock`] if we have the piece.
+    Request {
+        peer_addr: SocketAddrV4,
+        index: PieceIndex,
+        begin: u32,
+        length: u32,
+    },
+    /// the worker shut down, any pieces it was assigned have to be handed out again.
+    Disconnected { peer_addr: SocketAddrV4 },
+    /// from a web seed rather than a peer, see [`crate::webseed`].
+    WebSeed { url: Url, alert: WebSeedAlert },
+}
+
+/// a web seed has every piece and isn't choked, so there's less to tell the engine than for a
+/// peer. it only takes [`PeerCommands::DownloadPiece`] and [`PeerCommands::Shutdown`].
+#[derive(Debug, Clone)]
+pub enum WebSeedAlert {
+    /// the seed is ready for pieces, sent again on every retry after it went down.
+    Ready {
+        commands_tx: mpsc::Sender<PeerCommands>,
+        stats: Arc<PeerStats>,
+    },
+    DonePiece {
+        piece_index: PieceIndex,
+        piece: Vec<u8>,
+    },
+    PieceFailed {
+        piece_index: PieceIndex,
+    },
+    /// the seed failed and backs off for a while, its pieces have to be handed out again.
+    Down,
 }
@@ -1,45 +1,74 @@
 use crate::peer_protocol::codec::PeerFrames;
+use std::time::Duration;
 use tokio::sync::mpsc;
+use tokio::time::{Instant, Interval};
 
-use super::{PeerAlerts, PeerCommands};
-use std::collections::VecDeque;
+use super::{PeerAlerts, PeerCommands, PeerStats};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
 
+use super::download_worker::{LimitedPeerStream, WorkerConfig};
+use super::pipeline::RequestPipeline;
 use super::PieceRequestInfo;
-use crate::PeerId;
 use std::net::SocketAddrV4;
-use tokio::net::TcpStream;
 
 #[derive(Debug)]
 /// data struct that owns all the types which describe the state of the worker independent of the
 /// download state.
 pub(super) struct WorkerStateDescriptor {
     pub peer_addr: SocketAddrV4,
-    pub peer_id: PeerId,
-    pub peer_stream: PeerFrames<TcpStream>,
+    pub peer_stream: PeerFrames<LimitedPeerStream>,
     pub commands_rx: mpsc::Receiver<PeerCommands>,
     pub alerts_tx: mpsc::Sender<PeerAlerts>,
     pub download_queue: VecDeque<PieceRequestInfo>,
+    pub pipeline: RequestPipeline,
     pub peer_is_choked: bool,
     pub we_are_interested: bool,
+    pub we_are_choking: bool,
+    // blocks the peer requested, as (index, begin, length), which are being read from storage.
+    // a cancel or a choke drops them.
+    pub peer_requests: HashSet<(u32, u32, u32)>,
+    pub stats: Arc<PeerStats>,
+    // the info dictionary, for peers fetching it, and the id they want it sent with.
+    pub metadata: Option<Arc<Vec<u8>>>,
+    pub peer_metadata_id: Option<u8>,
+    pub last_received: Instant,
+    pub last_keepalive: Instant,
+    pub config: WorkerConfig,
+    // wakes the worker up to check on timeouts when nothing else is happening.
+    pub timeout_timer: Interval,
 }
 
 impl WorkerStateDescriptor {
+    const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
     pub fn new(
-        peer_stream: PeerFrames<TcpStream>,
+        peer_stream: PeerFrames<LimitedPeerStream>,
         peer_addr: SocketAddrV4,
-        peer_id: PeerId,
         alerts_tx: mpsc::Sender<PeerAlerts>,
         commands_rx: mpsc::Receiver<PeerCommands>,
+        stats: Arc<PeerStats>,
+        pipeline: RequestPipeline,
+        config: WorkerConfig,
     ) -> Self {
         Self {
             peer_stream,
             peer_addr,
-            peer_id,
             alerts_tx,
             commands_rx,
             peer_is_choked: true,
             we_are_interested: false,
+            we_are_choking: true,
+            peer_requests: HashSet::new(),
+            stats,
+            metadata: None,
+            peer_metadata_id: None,
             download_queue: VecDeque::new(),
+            pipeline,
+            last_received: Instant::now(),
+            last_keepalive: Instant::now(),
+            config,
+            timeout_timer: tokio::time::interval(Self::TIMEOUT_CHECK_INTERVAL),
         }
     }
 }
@@ -1,39 +1,159 @@
-use super::PeerAlerts;
-use futures::StreamExt;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use super::{PeerAlerts, PeerError, PeerRegistration, PeerRegistry, PeerStats};
+use futures::{SinkExt, StreamExt};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
+use tokio_util::codec::Framed;
 
 use crate::prelude::*;
-use crate::torrent::{InfoHash, PeerId};
+use crate::torrent::{Bitfield, InfoHash, PeerId};
 use std::net::SocketAddrV4;
+use std::sync::Arc;
 
 use super::descriptor::WorkerStateDescriptor;
+use super::pipeline::RequestPipeline;
 use super::worker_fsm::WorkerState;
 
-use crate::peer_protocol::codec::{self, PeerMessage};
-use crate::peer_protocol::handshake::PeerHandshake;
+use crate::peer_protocol::codec::{self, PeerFrames, PeerMessage, PeerMessageCodec};
+use crate::peer_protocol::extension::{self, ExtensionHandshake};
+use crate::peer_protocol::handshake::{
+    HandshakeCodec, HandshakeError, HandshakePrefixCodec, PeerHandshake,
+};
+use crate::peer_protocol::mse::{self, EncryptionPolicy, MseStream};
+use crate::peer_protocol::transport::PeerTransport;
+use crate::peer_protocol::utp::UtpSocket;
+use crate::rate_limit::{RateLimitedStream, RateLimits, StreamLimiters};
+use std::time::Duration;
+
+/// transport which the peer messages are framed over.
+pub type PeerStream = MseStream<PeerTransport>;
+/// the peer stream as seen by the codec, after rate limiting.
+pub type LimitedPeerStream = RateLimitedStream<PeerStream>;
+
+/// tunables of the workers, which talk to a peer each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkerConfig {
+    /// how often a keepalive is sent to peers.
+    pub keepalive_interval: Duration,
+    /// peers which don't send anything for this long are disconnected.
+    pub inactivity_timeout: Duration,
+    /// requests outstanding for longer are cancelled, so that other peers can pick them up.
+    pub request_timeout: Duration,
+    /// a peer which has sat on our requests for this long is snubbing us.
+    pub snub_timeout: Duration,
+    /// commands from the engine which can queue up for a worker.
+    pub command_buffer_size: usize,
+    /// the longest message accepted from a peer.
+    pub max_frame_size: usize,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            keepalive_interval: Duration::from_secs(2 * 60),
+            // peers are expected to send keepalives at least as often as we do.
+            inactivity_timeout: Duration::from_secs(2 * 2 * 60),
+            request_timeout: Duration::from_secs(60),
+            snub_timeout: Duration::from_secs(30),
+            command_buffer_size: 5,
+            max_frame_size: PeerMessageCodec::DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+}
+
+/// settings shared by all the peer connections of a torrent.
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    pub encryption: EncryptionPolicy,
+    /// outgoing connections try uTP over this socket first, before falling back to TCP.
+    pub utp_socket: Option<UtpSocket>,
+    /// limiters shared between connections, i.e the global and per torrent limits.
+    pub rate_limiters: StreamLimiters,
+    /// download and upload rates each connection is limited to on its own.
+    pub peer_rate_limits: (Option<u64>, Option<u64>),
+    /// peers connected to for the torrent, to turn away duplicate connections.
+    pub peer_registry: PeerRegistry,
+    pub worker: WorkerConfig,
+    /// the bencoded info dictionary, handed out to peers which only have a magnet link.
+    pub metadata: Option<Arc<Vec<u8>>>,
+}
+
+impl ConnectionConfig {
+    fn stream_limiters(&self) -> StreamLimiters {
+        match self.peer_rate_limits {
+            (None, None) => self.rate_limiters.clone(),
+            (download, upload) => self
+                .rate_limiters
+                .clone()
+                .with(&RateLimits::new(download, upload)),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct PeerAddr {
     peer_addr: SocketAddrV4,
 }
 
+/// a connection opened by a remote peer, before it's known which torrent it's for.
+#[derive(Debug)]
+pub struct IncomingPeer {
+    peer_addr: SocketAddrV4,
+    stream: PeerTransport,
+}
+
+/// a connection opened by a remote peer, which has asked for a torrent but has not been
+/// handshaked yet.
+#[derive(Debug)]
+pub struct InboundPeer {
+    peer_addr: SocketAddrV4,
+    info_hash: InfoHash,
+    // positioned right after the info hash of the peer's handshake.
+    stream: Framed<PeerStream, HandshakeCodec>,
+}
+
 /// interface type between PeerAddr and PeerDownloadWorker
 #[derive(Debug)]
 pub struct PeerDownloaderConnection {
     peer_addr: SocketAddrV4,
     peer_id: PeerId,
-    stream: TcpStream,
+    // still framed by the handshake codec, it may hold bytes the peer sent after its handshake.
+    stream: Framed<PeerStream, HandshakeCodec>,
+    limiters: StreamLimiters,
+    supports_extensions: bool,
+    registration: PeerRegistration,
+    worker: WorkerConfig,
+    metadata: Option<Arc<Vec<u8>>>,
+}
+
+impl PeerDownloaderConnection {
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.supports_extensions
+    }
+
+    /// the connection framed for peer messages, to talk to the peer without a worker.
+    pub fn into_frames(self) -> PeerFrames<LimitedPeerStream> {
+        let codec = PeerMessageCodec::with_max_frame_size(self.worker.max_frame_size);
+        codec::upgrade_handshaked(self.stream, codec, |stream| {
+            RateLimitedStream::new(stream, self.limiters)
+        })
+    }
 }
 
 #[derive(Debug)]
 pub struct PeerDownloadWorker {
     state: WorkerState,
     descriptor: WorkerStateDescriptor,
+    // keeps the peer id registered until the worker goes away.
+    _registration: PeerRegistration,
 }
 
 impl PeerAddr {
+    const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
     pub fn new(peer_addr: SocketAddrV4) -> Self {
         Self { peer_addr }
     }
@@ -43,63 +163,254 @@ impl PeerAddr {
         self,
         info_hash: InfoHash,
         peer_id: PeerId,
-    ) -> anyhow::Result<PeerDownloaderConnection> {
-        info!("connecting to peer");
-        let mut stream = TcpStream::connect(&self.peer_addr).await?;
+        config: &ConnectionConfig,
+    ) -> Result<PeerDownloaderConnection, PeerError> {
+        let stream = self.connect(&info_hash, config).await?;
+        info!(encrypted = stream.is_encrypted(), "connected to peer");
 
-        let handshake = PeerHandshake::new(info_hash, peer_id);
-        let mut bytes = handshake.into_bytes();
+        let mut stream = Framed::new(stream, HandshakeCodec::new());
+        let handshake =
+            PeerHandshake::new(info_hash.clone(), peer_id.clone()).with_extension_protocol();
 
         info!("sending handshake to peer");
-        stream.write_all(&bytes).await?;
+        stream.send(handshake).await?;
 
         info!("waiting for peer handshake");
-        stream.read_exact(&mut bytes).await?;
-
-        let handshake = PeerHandshake::from_bytes(bytes);
+        let handshake = stream.next().await.ok_or(HandshakeError::Closed)??;
         info!("peer handshake received");
         debug!(peer_handshake_reply = ?handshake);
 
+        handshake.validate(&info_hash, &peer_id)?;
+        let registration = config.peer_registry.register(handshake.peer_id.clone())?;
+
         Ok(PeerDownloaderConnection {
             stream,
+            supports_extensions: handshake.supports_extension_protocol(),
             peer_id: handshake.peer_id,
             peer_addr: self.peer_addr,
+            limiters: config.stream_limiters(),
+            registration,
+            worker: config.worker,
+            metadata: config.metadata.clone(),
+        })
+    }
+
+    async fn connect(
+        &self,
+        info_hash: &InfoHash,
+        config: &ConnectionConfig,
+    ) -> Result<PeerStream, PeerError> {
+        let transport = self.open_transport(config).await?;
+
+        match mse::initiate(transport, info_hash, config.encryption).await {
+            // peers which don't support encryption usually just close the connection on us, so
+            // the plaintext attempt has to be made on a fresh connection.
+            Err(err) if config.encryption == EncryptionPolicy::Enabled => {
+                info!(%err, "encrypted handshake failed, reconnecting in plaintext");
+                let transport = self.open_transport(config).await?;
+                Ok(MseStream::plaintext(transport))
+            }
+            result => Ok(result?),
+        }
+    }
+
+    async fn open_transport(&self, config: &ConnectionConfig) -> Result<PeerTransport, PeerError> {
+        if let Some(utp_socket) = &config.utp_socket {
+            info!("connecting to peer over utp");
+            let connect = utp_socket.connect(self.peer_addr.into());
+            match tokio::time::timeout(Self::UTP_CONNECT_TIMEOUT, connect).await {
+                Ok(Ok(stream)) => return Ok(PeerTransport::Utp(stream)),
+                Ok(Err(err)) => info!(%err, "utp connection failed, falling back to tcp"),
+                Err(_) => info!("utp connection timed out, falling back to tcp"),
+            }
+        }
+
+        info!("connecting to peer over tcp");
+        Ok(PeerTransport::Tcp(
+            TcpStream::connect(&self.peer_addr).await?,
+        ))
+    }
+}
+
+impl IncomingPeer {
+    pub fn new(stream: PeerTransport, peer_addr: SocketAddrV4) -> Self {
+        Self { peer_addr, stream }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.peer_addr
+    }
+
+    /// reads as much of the peer's handshake as it takes to tell which of `info_hashes` it's
+    /// after, so that it can be handed to that torrent.
+    #[instrument(name = "inbound identify mode", level = "info", skip_all)]
+    pub async fn identify(
+        self,
+        info_hashes: &[InfoHash],
+        encryption: EncryptionPolicy,
+    ) -> Result<InboundPeer, PeerError> {
+        let stream = mse::accept(self.stream, info_hashes, encryption).await?;
+        info!(encrypted = stream.is_encrypted(), "peer connected");
+
+        info!("waiting for peer handshake");
+        // the info hash is checked before reading any further, there's no point waiting on the
+        // rest from a peer that's after some other torrent.
+        let mut stream = Framed::new(stream, HandshakePrefixCodec::new());
+        let prefix = stream.next().await.ok_or(HandshakeError::Closed)??;
+        if !info_hashes.contains(&prefix.info_hash) {
+            return Err(HandshakeError::InfoHashMismatch.into());
+        }
+
+        let info_hash = prefix.info_hash.clone();
+        Ok(InboundPeer {
+            peer_addr: self.peer_addr,
+            info_hash,
+            stream: stream.map_codec(|_| HandshakeCodec::after_prefix(prefix)),
+        })
+    }
+}
+
+impl InboundPeer {
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.peer_addr
+    }
+
+    pub fn info_hash(&self) -> &InfoHash {
+        &self.info_hash
+    }
+
+    #[instrument(name = "inbound handshake mode", level = "info", skip_all)]
+    pub async fn handshake(
+        self,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        config: &ConnectionConfig,
+    ) -> Result<PeerDownloaderConnection, PeerError> {
+        if self.info_hash != info_hash {
+            return Err(HandshakeError::InfoHashMismatch.into());
+        }
+
+        let mut stream = self.stream;
+        let peer_handshake = stream.next().await.ok_or(HandshakeError::Closed)??;
+        info!("peer handshake received");
+        debug!(peer_handshake = ?peer_handshake);
+        peer_handshake.validate(&info_hash, &peer_id)?;
+        let registration = config
+            .peer_registry
+            .register(peer_handshake.peer_id.clone())?;
+
+        info!("sending handshake to peer");
+        let handshake = PeerHandshake::new(info_hash, peer_id).with_extension_protocol();
+        stream.send(handshake).await?;
+
+        Ok(PeerDownloaderConnection {
+            stream,
+            supports_extensions: peer_handshake.supports_extension_protocol(),
+            peer_id: peer_handshake.peer_id,
+            peer_addr: self.peer_addr,
+            limiters: config.stream_limiters(),
+            registration,
+            worker: config.worker,
+            metadata: config.metadata.clone(),
         })
     }
 }
 
 impl PeerDownloadWorker {
-    const COMMAND_BUFFER_SIZE: usize = 5;
+    // the number of requests we advertise we're willing to queue up from a peer.
+    pub(super) const OUR_REQQ: u32 = 250;
 
     pub async fn init_from(
         PeerDownloaderConnection {
             stream,
             peer_id,
             peer_addr,
-            ..
+            limiters,
+            supports_extensions,
+            registration,
+            worker,
+            metadata,
         }: PeerDownloaderConnection,
         alerts_tx: mpsc::Sender<PeerAlerts>,
-    ) -> anyhow::Result<PeerDownloadWorker> {
-        let mut peer_stream = codec::upgrade_stream(stream);
-
-        let msg = match peer_stream.next().await {
-            Some(msg_res) => msg_res?,
-            None => {
-                warn!("peer closed connection before handshake");
-                anyhow::bail!("peer closed connection before handshake");
-            }
-        };
+    ) -> Result<PeerDownloadWorker, PeerError> {
+        let codec = PeerMessageCodec::with_max_frame_size(worker.max_frame_size);
+        let mut peer_stream = codec::upgrade_handshaked(stream, codec, |stream| {
+            RateLimitedStream::new(stream, limiters)
+        });
+        let mut pipeline = RequestPipeline::new();
+        // the name the peer gives in its extension handshake beats guessing from its peer id.
+        let mut client = peer_id.client_name();
+        let mut peer_metadata_id = None;
 
         type PM = PeerMessage;
-        let bitfield = match msg {
-            PM::Bitfield(bitfield) => bitfield,
-            _ => {
-                warn!("first message sent by peer was not a bitfield");
-                anyhow::bail!("first message sent by peer not bitfield {:?}", msg);
+        if supports_extensions {
+            info!("sending extension handshake");
+            let metadata_size = metadata.as_ref().map(|metadata| metadata.len());
+            let payload = ExtensionHandshake::ours(Self::OUR_REQQ, metadata_size).to_bytes()?;
+            peer_stream
+                .send(PM::Extended {
+                    id: extension::HANDSHAKE_ID,
+                    payload,
+                })
+                .await?;
+        }
+
+        // the extension handshake may arrive ahead of the bitfield. a peer without any pieces may
+        // skip the bitfield, the message it sends instead is handled once the worker is set up.
+        let mut skipped_bitfield = None;
+        let bitfield = loop {
+            let msg = match peer_stream.next().await {
+                Some(msg_res) => msg_res?,
+                None => {
+                    warn!("peer closed connection before handshake");
+                    return Err(PeerError::Closed);
+                }
+            };
+
+            match msg {
+                PM::Bitfield(bitfield) => break bitfield,
+                PM::KeepAlive => continue,
+                PM::Extended {
+                    id: extension::HANDSHAKE_ID,
+                    payload,
+                } if supports_extensions => {
+                    let handshake = ExtensionHandshake::from_bytes(&payload)?;
+                    debug!(extension_handshake = ?handshake, "received extension handshake");
+                    if let Some(reqq) = handshake.reqq {
+                        pipeline.set_peer_reqq(reqq);
+                    }
+                    peer_metadata_id = handshake.metadata_id();
+                    if let Some(v) = handshake.v {
+                        client = Some(String::from_utf8_lossy(&v).into_owned());
+                    }
+                }
+                // a peer fetching the metadata has no pieces, and has no idea how many there
+                // are to make a bitfield out of.
+                msg @ (PM::Have(_)
+                | PM::Interested
+                | PM::NotInterested
+                | PM::Choke
+                | PM::Unchoke
+                | PM::Extended { .. }) => {
+                    debug!(?msg, "peer skipped its bitfield");
+                    skipped_bitfield = Some(msg);
+                    break Bitfield::new();
+                }
+                _ => {
+                    warn!("first message sent by peer was not a bitfield");
+                    return Err(PeerError::Protocol(format!(
+                        "first message sent by peer not bitfield {:?}",
+                        msg
+                    )));
+                }
             }
         };
 
-        let (commands_tx, commands_rx) = mpsc::channel(Self::COMMAND_BUFFER_SIZE);
+        let (commands_tx, commands_rx) = mpsc::channel(worker.command_buffer_size);
+        let stats = Arc::new(PeerStats::default());
+        if let Some(client) = client {
+            stats.set_client(client);
+        }
 
         info!("sending init peer alert to engine");
         alerts_tx
@@ -107,20 +418,104 @@ impl PeerDownloadWorker {
                 peer_addr,
                 bitfield,
                 commands_tx,
+                stats: stats.clone(),
             })
             .await?;
-        let descriptor =
-            WorkerStateDescriptor::new(peer_stream, peer_addr, peer_id, alerts_tx, commands_rx);
+        let mut descriptor = WorkerStateDescriptor::new(
+            peer_stream,
+            peer_addr,
+            alerts_tx,
+            commands_rx,
+            stats,
+            pipeline,
+            worker,
+        );
+        descriptor.metadata = metadata;
+        descriptor.peer_metadata_id = peer_metadata_id;
+        if let Some(msg) = skipped_bitfield {
+            WorkerState::handle_peer_message(msg, &mut descriptor, &mut Vec::new()).await?;
+        }
 
         Ok(Self {
             descriptor,
             state: WorkerState::Idle,
+            _registration: registration,
         })
     }
 
-    pub async fn start_peer_event_loop(&mut self) -> anyhow::Result<()> {
-        loop {
-            self.state.transition(&mut self.descriptor).await?;
-        }
+    pub async fn start_peer_event_loop(&mut self) -> Result<(), PeerError> {
+        let err = loop {
+            if let Err(err) = self.state.transition(&mut self.descriptor).await {
+                break err;
+            }
+        };
+
+        // the engine might already be gone, in which case there's nobody left to tell.
+        let _ = self
+            .descriptor
+            .alerts_tx
+            .send(PeerAlerts::Disconnected {
+                peer_addr: self.descriptor.peer_addr,
+            })
+            .await;
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_inbound_peer_is_identified_by_its_info_hash() {
+        let info_hashes = [InfoHash::new([1; 20]), InfoHash::new([2; 20])];
+        let (our_id, their_id) = (PeerId::random(), PeerId::random());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn({
+            let info_hash = info_hashes[1].clone();
+            let their_id = their_id.clone();
+            async move {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let mut stream = Framed::new(stream, HandshakeCodec::new());
+                stream
+                    .send(PeerHandshake::new(info_hash, their_id))
+                    .await
+                    .unwrap();
+                stream.next().await.unwrap().unwrap()
+            }
+        });
+
+        let (stream, peer_addr) = listener.accept().await.unwrap();
+        let std::net::SocketAddr::V4(peer_addr) = peer_addr else {
+            unreachable!("bound to an ipv4 address");
+        };
+        let incoming = IncomingPeer::new(PeerTransport::Tcp(stream), peer_addr);
+        let inbound = incoming
+            .identify(&info_hashes, EncryptionPolicy::Enabled)
+            .await
+            .unwrap();
+        assert_eq!(inbound.info_hash(), &info_hashes[1]);
+
+        let config = ConnectionConfig {
+            encryption: EncryptionPolicy::Enabled,
+            utp_socket: None,
+            rate_limiters: StreamLimiters::new(),
+            peer_rate_limits: (None, None),
+            peer_registry: PeerRegistry::new(),
+            worker: WorkerConfig::default(),
+            metadata: None,
+        };
+        let connection = inbound
+            .handshake(info_hashes[1].clone(), our_id.clone(), &config)
+            .await
+            .unwrap();
+        assert_eq!(connection.peer_id(), &their_id);
+
+        let reply = remote.await.unwrap();
+        assert_eq!(reply.info_hash, info_hashes[1]);
+        assert_eq!(reply.peer_id, our_id);
     }
 }
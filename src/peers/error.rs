@@ -0,0 +1,112 @@
+use super::PeerAlerts;
+use crate::peer_protocol::codec::CodecError;
+use crate::peer_protocol::handshake::HandshakeError;
+use crate::peer_protocol::mse::MseError;
+use std::time::Duration;
+use tokio::sync::mpsc::error::SendError;
+
+/// why a peer connection ended.
+#[derive(Debug, thiserror::Error)]
+pub enum PeerError {
+    #[error("handshake failed: {0}")]
+    Handshake(#[from] HandshakeError),
+    #[error("encryption handshake failed: {0}")]
+    Encryption(#[from] MseError),
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+    #[error("invalid extension message: {0}")]
+    Extension(#[from] serde_bencode::Error),
+    #[error("peer broke the protocol: {0}")]
+    Protocol(String),
+    #[error("{0} timed out")]
+    Timeout(&'static str),
+    #[error("peer inactive for {0:?}")]
+    Inactive(Duration),
+    #[error("peer closed connection")]
+    Closed,
+    #[error("worker was shut down")]
+    Shutdown,
+    #[error("peer was banned")]
+    Banned,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// what to do about a peer, once its connection ended with an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerErrorAction {
+    /// the connection just failed, the peer may be tried again after a while.
+    Retry,
+    /// the peer is useless to us, but may be tried again if some source tells us about it again.
+    GiveUp,
+    /// the peer misbehaved, and is never to be connected to again.
+    Ban,
+}
+
+impl PeerError {
+    pub fn action(&self) -> PeerErrorAction {
+        type A = PeerErrorAction;
+        match self {
+            Self::Handshake(HandshakeError::Io(_) | HandshakeError::Closed) => A::Retry,
+            // it's our own listen address, banning the ip would also ban other clients behind it.
+            Self::Handshake(
+                HandshakeError::SelfConnection
+                | HandshakeError::UnknownProtocol(_)
+                | HandshakeError::InfoHashMismatch
+                | HandshakeError::DuplicatePeer,
+            ) => A::GiveUp,
+
+            Self::Encryption(MseError::Io(_)) => A::Retry,
+            Self::Encryption(
+                MseError::PlaintextForced
+                | MseError::EncryptionDisabled
+                | MseError::UnknownInfoHash
+                | MseError::NoCryptoMethod(_),
+            ) => A::GiveUp,
+            Self::Encryption(
+                MseError::InvalidPublicKey
+                | MseError::InvalidVerification
+                | MseError::SyncFailed
+                | MseError::PaddingTooLong(_),
+            ) => A::Ban,
+
+            Self::Codec(err) if err.is_protocol_violation() => A::Ban,
+            Self::Extension(_) | Self::Protocol(_) | Self::Banned => A::Ban,
+
+            Self::Codec(_)
+            | Self::Timeout(_)
+            | Self::Inactive(_)
+            | Self::Closed
+            | Self::Shutdown
+            | Self::Io(_) => A::Retry,
+        }
+    }
+}
+
+// the engine going away is what shuts the workers down.
+impl From<SendError<PeerAlerts>> for PeerError {
+    fn from(_: SendError<PeerAlerts>) -> Self {
+        Self::Shutdown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(PeerError::Closed, PeerErrorAction::Retry)]
+    #[case(PeerError::Codec(CodecError::InvalidTag(42)), PeerErrorAction::Ban)]
+    #[case(
+        PeerError::Codec(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into()),
+        PeerErrorAction::Retry
+    )]
+    #[case(HandshakeError::InfoHashMismatch.into(), PeerErrorAction::GiveUp)]
+    #[case(HandshakeError::SelfConnection.into(), PeerErrorAction::GiveUp)]
+    #[case(MseError::InvalidVerification.into(), PeerErrorAction::Ban)]
+    #[case(PeerError::Banned, PeerErrorAction::Ban)]
+    fn test_action(#[case] err: PeerError, #[case] action: PeerErrorAction) {
+        assert_eq!(err.action(), action);
+    }
+}
@@ -0,0 +1,111 @@
+//! fetching the info dictionary of a torrent from a peer, which is all a magnet link is missing.
+use super::download_worker::{PeerDownloadWorker, PeerDownloaderConnection};
+use super::PeerError;
+use crate::peer_protocol::codec::PeerMessage;
+use crate::peer_protocol::extension::{self, ExtensionHandshake};
+use crate::peer_protocol::metadata::{self, MetadataMessage};
+use crate::prelude::*;
+use crate::torrent::InfoHash;
+use futures::{SinkExt, StreamExt};
+use sha1_smol::Sha1;
+
+/// info dictionaries of even huge torrents are a fraction of this.
+const MAX_METADATA_SIZE: usize = 16 << 20;
+
+/// asks the peer for all the pieces of the metadata at once, and checks it against the info
+/// hash the connection was made for.
+#[instrument(name = "metadata fetch", level = "info", skip_all)]
+pub async fn fetch_metadata(
+    connection: PeerDownloaderConnection,
+    info_hash: &InfoHash,
+) -> Result<Vec<u8>, PeerError> {
+    if !connection.supports_extensions() {
+        return Err(PeerError::Protocol(
+            "peer doesn't support the extension protocol".to_string(),
+        ));
+    }
+    let mut stream = connection.into_frames();
+    type PM = PeerMessage;
+
+    let handshake = ExtensionHandshake::ours(PeerDownloadWorker::OUR_REQQ, None);
+    stream
+        .send(PM::Extended {
+            id: extension::HANDSHAKE_ID,
+            payload: handshake.to_bytes()?,
+        })
+        .await?;
+
+    // the bitfield and the like may come first, they don't matter here.
+    let (peer_metadata_id, size) = loop {
+        if let PM::Extended {
+            id: extension::HANDSHAKE_ID,
+            payload,
+        } = stream.next().await.ok_or(PeerError::Closed)??
+        {
+            let handshake = ExtensionHandshake::from_bytes(&payload)?;
+            match (handshake.metadata_id(), handshake.metadata_size) {
+                (Some(id), Some(size)) => break (id, size),
+                _ => {
+                    return Err(PeerError::Protocol(
+                        "peer doesn't serve the metadata".to_string(),
+                    ))
+                }
+            }
+        }
+    };
+    let size = usize::try_from(size)
+        .ok()
+        .filter(|size| (1..=MAX_METADATA_SIZE).contains(size))
+        .ok_or_else(|| PeerError::Protocol(format!("invalid metadata size {size}")))?;
+
+    let pieces = size.div_ceil(metadata::PIECE_SIZE);
+    info!(size, pieces, "requesting metadata");
+    for piece in 0..pieces {
+        stream
+            .send(PM::Extended {
+                id: peer_metadata_id,
+                payload: MetadataMessage::Request { piece }.to_bytes()?,
+            })
+            .await?;
+    }
+
+    let mut metadata = vec![0; size];
+    let mut received = vec![false; pieces];
+    while received.contains(&false) {
+        let PM::Extended {
+            id: metadata::OUR_ID,
+            payload,
+        } = stream.next().await.ok_or(PeerError::Closed)??
+        else {
+            continue;
+        };
+        match MetadataMessage::from_bytes(&payload)? {
+            MetadataMessage::Data { piece, data, .. } if piece < pieces => {
+                let begin = piece * metadata::PIECE_SIZE;
+                let end = size.min(begin + metadata::PIECE_SIZE);
+                if data.len() != end - begin {
+                    return Err(PeerError::Protocol(format!(
+                        "metadata piece {piece} is {} bytes long",
+                        data.len()
+                    )));
+                }
+                metadata[begin..end].copy_from_slice(&data);
+                received[piece] = true;
+            }
+            MetadataMessage::Reject { piece } => {
+                return Err(PeerError::Protocol(format!(
+                    "peer rejected metadata piece {piece}"
+                )))
+            }
+            message => debug!(?message, "ignoring metadata message"),
+        }
+    }
+
+    if Sha1::from(&metadata).digest().bytes() != *info_hash.as_ref() {
+        return Err(PeerError::Protocol(
+            "metadata doesn't match the info hash".to_string(),
+        ));
+    }
+    info!("metadata fetched");
+    Ok(metadata)
+}
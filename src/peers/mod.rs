@@ -1,11 +1,21 @@
+//! peer connections, from the handshake through to the worker which downloads pieces from the
+//! peer on behalf of the engine.
 pub mod download_worker;
+pub mod metadata;
 
 mod comms;
 mod descriptor;
+mod error;
+mod pipeline;
 mod progress;
+mod registry;
+mod stats;
 mod worker_fsm;
 
 pub use comms::*;
+pub use error::{PeerError, PeerErrorAction};
+pub use registry::{PeerRegistration, PeerRegistry};
+pub use stats::PeerStats;
 
 pub type PieceIndex = usize;
 pub type PieceLength = u32;
@@ -0,0 +1,245 @@
+use super::{BlockLength, BlockOffset, PieceIndex};
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// tracks the block requests outstanding with a peer, and how many there should be. the depth is
+/// sized to twice the bandwidth delay product of the connection, so that the pipe never runs dry
+/// while the next requests are on their way.
+#[derive(Debug)]
+pub(super) struct RequestPipeline {
+    outstanding: HashMap<(PieceIndex, BlockOffset), (BlockLength, Instant)>,
+    peer_reqq: Option<u32>,
+    // when a block last arrived, or the pipeline last went from empty to busy.
+    last_progress: Instant,
+
+    // minimum request latency, tracked over two consecutive windows so that it can adapt when
+    // the path changes.
+    min_rtt: Option<Duration>,
+    prev_min_rtt: Option<Duration>,
+    rtt_window_start: Instant,
+
+    rate: f64,
+    rate_window_bytes: u64,
+    rate_window_start: Instant,
+}
+
+impl RequestPipeline {
+    const BLOCK_SIZE: u32 = 1 << 14;
+    const MIN_DEPTH: usize = 4;
+    // used when the peer didn't advertise its reqq.
+    const DEFAULT_MAX_DEPTH: usize = 32;
+    const MAX_DEPTH: usize = 500;
+    const RTT_WINDOW: Duration = Duration::from_secs(30);
+    const RATE_WINDOW: Duration = Duration::from_secs(1);
+    // requested blocks should all be able to arrive within this many seconds under the rate limit.
+    const LIMITER_DRAIN_SECS: f64 = 10.0;
+
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            outstanding: HashMap::new(),
+            peer_reqq: None,
+            last_progress: now,
+            min_rtt: None,
+            prev_min_rtt: None,
+            rtt_window_start: now,
+            rate: 0.0,
+            rate_window_bytes: 0,
+            rate_window_start: now,
+        }
+    }
+
+    pub fn set_peer_reqq(&mut self, reqq: u32) {
+        self.peer_reqq = Some(reqq);
+    }
+
+    /// the number of requests which should be outstanding, capped by the peer's reqq and by
+    /// what the rate limiter lets through, pipelining more than that only makes the requests sit
+    /// around until they time out.
+    pub fn target_depth(&self, rate_limit: Option<u64>) -> usize {
+        let bdp_blocks = match self.rtt() {
+            Some(rtt) => 2.0 * self.rate * rtt.as_secs_f64() / Self::BLOCK_SIZE as f64,
+            None => 0.0,
+        };
+
+        let max_depth = self
+            .peer_reqq
+            .map_or(Self::DEFAULT_MAX_DEPTH, |reqq| reqq as usize)
+            .min(Self::MAX_DEPTH);
+        let mut depth = (bdp_blocks.ceil() as usize).clamp(Self::MIN_DEPTH, max_depth.max(1));
+
+        if let Some(rate_limit) = rate_limit {
+            let drainable = rate_limit as f64 * Self::LIMITER_DRAIN_SECS / Self::BLOCK_SIZE as f64;
+            depth = depth.min((drainable as usize).max(1));
+        }
+        depth
+    }
+
+    pub fn has_capacity(&self, rate_limit: Option<u64>) -> bool {
+        self.outstanding.len() < self.target_depth(rate_limit)
+    }
+
+    pub fn on_request(&mut self, index: PieceIndex, begin: BlockOffset, length: BlockLength) {
+        let now = Instant::now();
+        if self.outstanding.is_empty() {
+            self.last_progress = now;
+        }
+        self.outstanding.insert((index, begin), (length, now));
+    }
+
+    /// records the arrival of a block, returns whether it was requested.
+    pub fn on_block(&mut self, index: PieceIndex, begin: BlockOffset, length: usize) -> bool {
+        let Some((_, requested_at)) = self.outstanding.remove(&(index, begin)) else {
+            return false;
+        };
+
+        let now = Instant::now();
+        self.last_progress = now;
+        self.record_rtt(now.duration_since(requested_at), now);
+        self.record_bytes(length as u64, now);
+        true
+    }
+
+    /// forgets all outstanding requests, e.g when the peer chokes us.
+    pub fn clear(&mut self) {
+        self.outstanding.clear();
+    }
+
+    /// the pieces which have requests outstanding for longer than `timeout`.
+    pub fn expired(&self, now: Instant, timeout: Duration) -> BTreeSet<PieceIndex> {
+        self.outstanding
+            .iter()
+            .filter(|(_, (_, requested_at))| now.duration_since(*requested_at) >= timeout)
+            .map(|((index, _), _)| *index)
+            .collect()
+    }
+
+    /// forgets the outstanding requests for a piece, returning them so they can be cancelled.
+    pub fn take_piece(&mut self, index: PieceIndex) -> Vec<(BlockOffset, BlockLength)> {
+        let mut taken = Vec::new();
+        self.outstanding
+            .retain(|&(piece_index, begin), &mut (length, _)| {
+                if piece_index == index {
+                    taken.push((begin, length));
+                }
+                piece_index != index
+            });
+        taken
+    }
+
+    /// how long requests have been outstanding without a single block arriving.
+    pub fn stalled_for(&self, now: Instant) -> Option<Duration> {
+        (!self.outstanding.is_empty()).then(|| now.duration_since(self.last_progress))
+    }
+
+    pub fn len(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        match (self.min_rtt, self.prev_min_rtt) {
+            (Some(current), Some(prev)) => Some(current.min(prev)),
+            (current, prev) => current.or(prev),
+        }
+    }
+
+    fn record_rtt(&mut self, sample: Duration, now: Instant) {
+        if now.duration_since(self.rtt_window_start) >= Self::RTT_WINDOW {
+            self.prev_min_rtt = self.min_rtt.take();
+            self.rtt_window_start = now;
+        }
+        self.min_rtt = Some(self.min_rtt.map_or(sample, |min_rtt| min_rtt.min(sample)));
+    }
+
+    fn record_bytes(&mut self, nbytes: u64, now: Instant) {
+        self.rate_window_bytes += nbytes;
+        let elapsed = now.duration_since(self.rate_window_start);
+        if elapsed < Self::RATE_WINDOW {
+            return;
+        }
+
+        let sample = self.rate_window_bytes as f64 / elapsed.as_secs_f64();
+        self.rate = if self.rate == 0.0 {
+            sample
+        } else {
+            (self.rate + sample) / 2.0
+        };
+        self.rate_window_bytes = 0;
+        self.rate_window_start = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline_with(rate: f64, rtt: Duration) -> RequestPipeline {
+        let mut pipeline = RequestPipeline::new();
+        pipeline.rate = rate;
+        pipeline.min_rtt = Some(rtt);
+        pipeline
+    }
+
+    #[test]
+    fn test_starts_at_min_depth() {
+        assert_eq!(
+            RequestPipeline::new().target_depth(None),
+            RequestPipeline::MIN_DEPTH
+        );
+    }
+
+    #[test]
+    fn test_depth_follows_bandwidth_delay_product() {
+        // 4 MiB/s over 250ms is 1 MiB in flight, doubled that's 128 blocks.
+        let mut pipeline = pipeline_with(4.0 * (1 << 20) as f64, Duration::from_millis(250));
+        pipeline.set_peer_reqq(250);
+        assert_eq!(pipeline.target_depth(None), 128);
+    }
+
+    #[test]
+    fn test_depth_capped_by_reqq_and_limiter() {
+        let mut pipeline = pipeline_with(4.0 * (1 << 20) as f64, Duration::from_millis(200));
+        assert_eq!(
+            pipeline.target_depth(None),
+            RequestPipeline::DEFAULT_MAX_DEPTH
+        );
+
+        pipeline.set_peer_reqq(10);
+        assert_eq!(pipeline.target_depth(None), 10);
+        // 16 KiB/s drains 10 blocks in the drain window.
+        assert_eq!(pipeline.target_depth(Some(1 << 14)), 10);
+        assert_eq!(pipeline.target_depth(Some(1 << 12)), 2);
+    }
+
+    #[test]
+    fn test_unrequested_block() {
+        let mut pipeline = RequestPipeline::new();
+        pipeline.on_request(0, 0, 10);
+        assert!(!pipeline.on_block(0, RequestPipeline::BLOCK_SIZE, 10));
+        assert!(pipeline.on_block(0, 0, 10));
+        assert_eq!(pipeline.len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_requests() {
+        let mut pipeline = RequestPipeline::new();
+        pipeline.on_request(0, 0, 10);
+        pipeline.on_request(1, 0, 10);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        pipeline.on_request(1, 10, 10);
+        pipeline.on_block(0, 0, 10);
+
+        let now = Instant::now();
+        assert_eq!(pipeline.stalled_for(now), Some(Duration::ZERO));
+        assert_eq!(
+            pipeline.expired(now, Duration::from_secs(30)),
+            BTreeSet::from([1])
+        );
+
+        let mut taken = pipeline.take_piece(1);
+        taken.sort();
+        assert_eq!(taken, [(0, 10), (10, 10)]);
+        assert_eq!(pipeline.stalled_for(now), None);
+    }
+}
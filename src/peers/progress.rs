@@ -1,97 +1,145 @@
 use super::{BlockLength, BlockOffset, PieceLength};
 use crate::prelude::*;
+use bitvec::vec::BitVec;
 use std::cmp::min;
 
+#[derive(Debug, thiserror::Error)]
+pub(super) enum BlockError {
+    #[error("block offset {0} is not a block boundary")]
+    Misaligned(BlockOffset),
+    #[error("block at offset {begin} has length {length}, expected {expected}")]
+    WrongLength {
+        begin: BlockOffset,
+        length: BlockLength,
+        expected: BlockLength,
+    },
+    #[error("duplicate block at offset {0}")]
+    Duplicate(BlockOffset),
+    #[error("block at offset {0} was never requested")]
+    Unrequested(BlockOffset),
+}
+
+/// per block bookkeeping of a piece download, blocks may arrive in any order.
 #[derive(Debug, Clone)]
 pub(super) struct PieceDownloadProgress {
     piece_length: PieceLength,
-    request_pending: BlockOffset,
-    downloaded: BlockOffset,
-    pending_blocks: u32,
+    // blocks ever requested from the peer, it outlives the request pipeline being cleared.
+    requested: BitVec,
+    received: BitVec,
 }
 
 impl PieceDownloadProgress {
     const MAX_BLOCK_SIZE: u32 = 1 << 14;
-    const MAX_PENDING_BLOCKS: u32 = 5;
 
     pub fn new(piece_length: u32) -> Self {
+        let num_blocks = piece_length.div_ceil(Self::MAX_BLOCK_SIZE) as usize;
         Self {
             piece_length,
-            request_pending: 0,
-            downloaded: 0,
-            pending_blocks: 0,
+            requested: BitVec::repeat(false, num_blocks),
+            received: BitVec::repeat(false, num_blocks),
         }
     }
 
+    /// the next block of the piece which hasn't been requested yet.
     pub fn next_block_info(&mut self) -> Option<(BlockOffset, BlockLength)> {
-        if self.request_pending == self.piece_length || self.reached_max_pending() {
-            trace!("request blocks pipeline filled");
+        let Some(block) = self.requested.first_zero() else {
+            trace!("all blocks of piece requested");
             return None;
-        }
+        };
 
-        let nbytes_to_end = self.piece_length - self.request_pending;
-        debug_assert!(self.request_pending < self.piece_length);
-
-        let length = min(nbytes_to_end, Self::MAX_BLOCK_SIZE);
-        let out = Some((self.request_pending, length));
-
-        trace!("increment pending blocks");
-        self.pending_blocks += 1;
-        trace!(
-            "move forward request pending offset by next_block_len={}",
-            length
-        );
-        self.request_pending += length;
-        out
+        self.requested.set(block, true);
+        Some((self.block_begin(block), self.block_length(block)))
     }
 
+    /// marks a block as received, rejecting blocks which don't line up with a block of the piece,
+    /// which were never requested or which were already received. blocks which aren't requested
+    /// at the moment are still accepted, they are late answers to requests forgotten on a reset.
     pub fn update_downloaded(
         &mut self,
         block_begin: BlockOffset,
         length: BlockLength,
-    ) -> anyhow::Result<()> {
-        if block_begin != self.downloaded {
-            warn!(
-                last_downloaded_block = self.downloaded,
-                incoming_block = block_begin,
-                "blocks given out of order by peer"
-            );
-            anyhow::bail!("blocks downloaded out of order. last downloaded offset: {}, incoming block offset: {}", self.downloaded, block_begin)
+    ) -> Result<(), BlockError> {
+        let block = (block_begin / Self::MAX_BLOCK_SIZE) as usize;
+        if !block_begin.is_multiple_of(Self::MAX_BLOCK_SIZE) || block >= self.received.len() {
+            return Err(BlockError::Misaligned(block_begin));
+        }
+        if length != self.block_length(block) {
+            return Err(BlockError::WrongLength {
+                begin: block_begin,
+                length,
+                expected: self.block_length(block),
+            });
+        }
+        if !self.requested[block] {
+            return Err(BlockError::Unrequested(block_begin));
+        }
+        if self.received[block] {
+            return Err(BlockError::Duplicate(block_begin));
         }
 
-        self.downloaded += length;
-        self.pending_blocks -= 1;
+        self.received.set(block, true);
         trace!(
-            downloaded_end_offset = self.downloaded,
-            num_pending_blocks = self.pending_blocks,
-            "update download progress",
+            num_received = self.received.count_ones(),
+            num_blocks = self.received.len(),
+            "update download progress"
         );
         Ok(())
     }
 
-    pub fn reset_progress(&mut self) {
-        debug!(
-            "reset download progress to {last_requested_block_end}",
-            last_requested_block_end = self.request_pending
-        );
-        self.request_pending = self.downloaded;
-        self.pending_blocks = 0;
+    pub fn is_done(&self) -> bool {
+        self.received.all()
     }
 
-    pub fn is_done(&self) -> bool {
-        trace!(
-            "checking if block done {last_downloaded_block_end} {piece_end}",
-            last_downloaded_block_end = self.downloaded,
-            piece_end = self.piece_length
-        );
-        self.downloaded == self.piece_length
+    fn block_begin(&self, block: usize) -> BlockOffset {
+        block as u32 * Self::MAX_BLOCK_SIZE
     }
 
-    fn reached_max_pending(&self) -> bool {
-        trace!(
-            "check if reached max pending {num_pending}",
-            num_pending = self.pending_blocks
-        );
-        self.pending_blocks >= Self::MAX_PENDING_BLOCKS
+    fn block_length(&self, block: usize) -> BlockLength {
+        min(
+            self.piece_length - self.block_begin(block),
+            Self::MAX_BLOCK_SIZE,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: u32 = PieceDownloadProgress::MAX_BLOCK_SIZE;
+
+    #[test]
+    fn test_out_of_order_completion() {
+        let mut progress = PieceDownloadProgress::new(2 * BLOCK + 10);
+        let blocks: Vec<_> = std::iter::from_fn(|| progress.next_block_info()).collect();
+        assert_eq!(blocks, [(0, BLOCK), (BLOCK, BLOCK), (2 * BLOCK, 10)]);
+
+        for (begin, length) in blocks.into_iter().rev() {
+            assert!(!progress.is_done());
+            progress.update_downloaded(begin, length).unwrap();
+        }
+        assert!(progress.is_done());
+    }
+
+    #[test]
+    fn test_rejects_bad_blocks() {
+        let mut progress = PieceDownloadProgress::new(2 * BLOCK);
+        assert_eq!(progress.next_block_info(), Some((0, BLOCK)));
+
+        // misaligned, out of bounds and wrongly sized blocks.
+        assert!(progress.update_downloaded(1, BLOCK).is_err());
+        assert!(progress.update_downloaded(2 * BLOCK, BLOCK).is_err());
+        assert!(progress.update_downloaded(0, 10).is_err());
+        // a block which was never asked for.
+        assert!(matches!(
+            progress.update_downloaded(BLOCK, BLOCK),
+            Err(BlockError::Unrequested(_))
+        ));
+
+        progress.update_downloaded(0, BLOCK).unwrap();
+        assert!(matches!(
+            progress.update_downloaded(0, BLOCK),
+            Err(BlockError::Duplicate(_))
+        ));
     }
 }
@@ -0,0 +1,60 @@
+use crate::peer_protocol::handshake::HandshakeError;
+use crate::torrent::PeerId;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// the peer ids a torrent is connected to, so that a second connection to the same peer can be
+/// turned away.
+#[derive(Debug, Clone, Default)]
+pub struct PeerRegistry {
+    peer_ids: Arc<Mutex<HashSet<PeerId>>>,
+}
+
+/// keeps the peer id registered for as long as the connection is around.
+#[derive(Debug)]
+pub struct PeerRegistration {
+    registry: PeerRegistry,
+    peer_id: PeerId,
+}
+
+impl PeerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, peer_id: PeerId) -> Result<PeerRegistration, HandshakeError> {
+        if !self.peer_ids.lock().unwrap().insert(peer_id.clone()) {
+            return Err(HandshakeError::DuplicatePeer);
+        }
+        Ok(PeerRegistration {
+            registry: self.clone(),
+            peer_id,
+        })
+    }
+}
+
+impl Drop for PeerRegistration {
+    fn drop(&mut self) {
+        self.registry.peer_ids.lock().unwrap().remove(&self.peer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_duplicates_until_dropped() {
+        let registry = PeerRegistry::new();
+        let peer_id = PeerId::random();
+
+        let registration = registry.register(peer_id.clone()).unwrap();
+        assert!(matches!(
+            registry.register(peer_id.clone()),
+            Err(HandshakeError::DuplicatePeer)
+        ));
+
+        drop(registration);
+        assert!(registry.register(peer_id).is_ok());
+    }
+}
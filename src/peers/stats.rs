@@ -0,0 +1,101 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+/// counters kept by a worker about its peer, shared with the engine so that it can rank peers
+/// without having to round trip through the worker.
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    wasted: AtomicU64,
+    peer_interested: AtomicBool,
+    snubbed: AtomicBool,
+    unchoked_by_peer: AtomicBool,
+    unchoked_by_us: AtomicBool,
+    request_timeouts: AtomicU64,
+    client: OnceLock<String>,
+}
+
+impl PeerStats {
+    pub fn record_downloaded(&self, nbytes: usize) {
+        self.downloaded.fetch_add(nbytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_uploaded(&self, nbytes: usize) {
+        self.uploaded.fetch_add(nbytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_wasted(&self, nbytes: usize) {
+        self.wasted.fetch_add(nbytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_peer_interested(&self, interested: bool) {
+        self.peer_interested.store(interested, Ordering::Relaxed);
+    }
+
+    /// total payload bytes received from the peer.
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// total payload bytes sent to the peer.
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn set_snubbed(&self, snubbed: bool) {
+        self.snubbed.store(snubbed, Ordering::Relaxed);
+    }
+
+    /// whether the peer has been sitting on our requests without sending any blocks.
+    pub fn snubbed(&self) -> bool {
+        self.snubbed.load(Ordering::Relaxed)
+    }
+
+    /// total payload bytes received from the peer which had to be thrown away, e.g duplicates or
+    /// blocks of pieces which were already finished.
+    pub fn wasted(&self) -> u64 {
+        self.wasted.load(Ordering::Relaxed)
+    }
+
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested.load(Ordering::Relaxed)
+    }
+
+    pub fn set_unchoked_by_peer(&self, unchoked: bool) {
+        self.unchoked_by_peer.store(unchoked, Ordering::Relaxed);
+    }
+
+    /// whether the peer lets us request blocks from it.
+    pub fn unchoked_by_peer(&self) -> bool {
+        self.unchoked_by_peer.load(Ordering::Relaxed)
+    }
+
+    pub fn set_unchoked_by_us(&self, unchoked: bool) {
+        self.unchoked_by_us.store(unchoked, Ordering::Relaxed);
+    }
+
+    /// whether we let the peer request blocks from us.
+    pub fn unchoked_by_us(&self) -> bool {
+        self.unchoked_by_us.load(Ordering::Relaxed)
+    }
+
+    pub fn record_request_timeouts(&self, count: usize) {
+        self.request_timeouts
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// block requests which the peer sat on for so long that they were cancelled.
+    pub fn request_timeouts(&self) -> u64 {
+        self.request_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// names the client the peer runs, only the first name given sticks.
+    pub fn set_client(&self, client: String) {
+        let _ = self.client.set(client);
+    }
+
+    pub fn client(&self) -> Option<&str> {
+        self.client.get().map(String::as_str)
+    }
+}
@@ -1,67 +1,106 @@
 use crate::metainfo::PieceHash;
 use crate::peer_protocol::codec::PeerMessage;
+use crate::peer_protocol::extension::{self, ExtensionHandshake};
+use crate::peer_protocol::metadata::{self, MetadataMessage};
 use crate::prelude::*;
 use futures::SinkExt;
 use sha1_smol::Sha1;
+use tokio::time::Instant;
 use tokio_stream::StreamExt;
 
 use super::descriptor::WorkerStateDescriptor;
+use super::download_worker::PeerDownloadWorker;
 use super::progress::PieceDownloadProgress;
-use super::{PeerAlerts, PeerCommands, PieceIndex, PieceRequestInfo};
+use super::{PeerAlerts, PeerCommands, PeerError, PieceIndex, PieceRequestInfo};
+
+/// a piece which the worker is currently requesting blocks for.
+#[derive(Debug, Clone)]
+pub struct ActivePiece {
+    index: PieceIndex,
+    download_progress: PieceDownloadProgress,
+    hash: PieceHash,
+    piece: Vec<u8>,
+}
+
+impl ActivePiece {
+    fn new(
+        PieceRequestInfo {
+            index,
+            length,
+            hash,
+        }: PieceRequestInfo,
+    ) -> Self {
+        Self {
+            index,
+            download_progress: PieceDownloadProgress::new(length),
+            hash,
+            piece: vec![0; length as usize],
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum WorkerState {
-    WaitingforPiece {
-        index: PieceIndex,
-        download_progress: PieceDownloadProgress,
-        hash: PieceHash,
-        piece: Vec<u8>,
-    },
+    Downloading { pieces: Vec<ActivePiece> },
     Idle,
 }
 
 impl WorkerState {
+    // the block size everyone requests, most clients refuse to serve larger ones as well.
+    const MAX_REQUEST_LENGTH: u32 = 1 << 14;
+
     pub async fn transition(
         &mut self,
         descriptor: &mut WorkerStateDescriptor,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), PeerError> {
         match self {
-            Self::WaitingforPiece {
-                index,
-                download_progress,
-                hash,
-                piece: piece_vec,
-            } => {
+            Self::Downloading { pieces } => {
                 let WorkerStateDescriptor {
                     alerts_tx,
+                    peer_addr,
                     peer_is_choked,
                     we_are_interested,
                     peer_stream,
-                    commands_rx,
+                    download_queue,
+                    pipeline,
                     ..
                 } = descriptor;
-                let span = info_span!("waiting for piece", index);
-                let _gaurd = span.enter();
 
-                if download_progress.is_done() {
+                if let Some(done) = pieces
+                    .iter()
+                    .position(|active| active.download_progress.is_done())
+                {
+                    let ActivePiece {
+                        index, hash, piece, ..
+                    } = pieces.swap_remove(done);
+                    let span = info_span!("finishing piece", index);
+                    let _gaurd = span.enter();
                     info!("piece download complete");
 
-                    let piece_hash = Sha1::from(&piece_vec).digest().bytes();
-                    if piece_hash != *hash {
-                        warn!("downloaded piece hash check failed");
-                        //TODO: what should happen if hash fails?.
+                    let piece_hash = Sha1::from(&piece).digest().bytes();
+                    let alert = if piece_hash != hash {
+                        warn!("downloaded piece hash check failed, discarding piece");
+                        PeerAlerts::PieceFailed {
+                            peer_addr: *peer_addr,
+                            piece_index: index,
+                            piece,
+                        }
                     } else {
                         info!("piece hash check succeeded.");
-                    }
+                        PeerAlerts::DonePiece {
+                            peer_addr: *peer_addr,
+                            piece_index: index,
+                            piece,
+                        }
+                    };
 
-                    info!("send piece done");
-                    alerts_tx
-                        .send(PeerAlerts::DonePiece {
-                            piece_index: *index,
-                            piece: std::mem::take(piece_vec),
-                        })
-                        .await?;
+                    info!("send piece result");
+                    alerts_tx.send(alert).await?;
+
+                    return Ok(());
+                }
 
+                if pieces.is_empty() && download_queue.is_empty() {
                     info!("set peer state idle");
                     *self = WorkerState::Idle;
                     return Ok(());
@@ -70,83 +109,163 @@ impl WorkerState {
                 if !*we_are_interested {
                     *we_are_interested = true;
 
-                    info!("sending unchoke");
-                    peer_stream.send(PeerMessage::Unchoke).await?;
                     info!("sending interested");
                     peer_stream.send(PeerMessage::Interested).await?;
                 }
 
                 if !*peer_is_choked {
-                    while let Some((begin, length)) = download_progress.next_block_info() {
+                    let download_rate = peer_stream.get_ref().limiters().download.rate();
+                    while pipeline.has_capacity(download_rate) {
+                        // blocks of the pieces already started are requested first, so that
+                        // they complete as soon as possible.
+                        let next_block = pieces.iter_mut().find_map(|active| {
+                            let (begin, length) = active.download_progress.next_block_info()?;
+                            Some((active.index, begin, length))
+                        });
+
+                        let Some((index, begin, length)) = next_block else {
+                            match download_queue.pop_front() {
+                                Some(req_info) => {
+                                    info!(index = req_info.index, "start downloading piece");
+                                    pieces.push(ActivePiece::new(req_info));
+                                    continue;
+                                }
+                                None => break,
+                            }
+                        };
+
                         let request = PeerMessage::Request {
-                            index: *index as u32,
+                            index: index as u32,
                             begin,
                             length,
                         };
 
-                        info!("sending request to peer {:?}", request);
+                        debug!("sending request to peer {:?}", request);
                         peer_stream.send(request).await?;
+                        pipeline.on_request(index, begin, length);
                     }
+                    debug!(outstanding = pipeline.len(), "request pipeline filled");
                 }
 
-                tokio::select! {
-                    msg = peer_stream.next() => {
-                        let msg = match msg {
-                            Some(msg) => msg?,
-                            None => {
-                                warn!("peer closed connection before piece could be downloaded");
-                                anyhow::bail!("peer closed connection before piece could be downloaded");
-                            }
-                        };
-
-                        Self::handle_peer_message(msg, *index, descriptor, piece_vec, download_progress).await?;
-                    }
-
-                    // handle commands sent by the engine
-                    Some(command) = commands_rx.recv() => {
-                        Self::handle_command(command, descriptor).await?;
-                    }
-
-                    else => {
-                        // commands channel closed and peer connection was closed
-                        info!("engine and peer shut down, shutting down worker");
-                        anyhow::bail!("shutting down.");
-                    }
-                }
+                Self::wait_for_event(descriptor, pieces).await?;
             }
 
             Self::Idle => {
-                let WorkerStateDescriptor {
-                    download_queue,
-                    commands_rx,
-                    ..
-                } = descriptor;
-
-                if let Some(PieceRequestInfo {
-                    index,
-                    length,
-                    hash,
-                }) = download_queue.pop_front()
-                {
-                    info!("change peer state to waiting for piece {}", index);
-                    *self = Self::WaitingforPiece {
-                        index,
-                        download_progress: PieceDownloadProgress::new(length),
-                        hash,
-                        piece: Vec::new(),
-                    };
+                if !descriptor.download_queue.is_empty() {
+                    info!("change peer state to downloading");
+                    *self = Self::Downloading { pieces: Vec::new() };
                     return Ok(());
                 }
 
-                info!("queue empty awaiting next command");
-                match commands_rx.recv().await {
-                    Some(command) => Self::handle_command(command, descriptor).await?,
+                debug!("queue empty awaiting next event");
+                Self::wait_for_event(descriptor, &mut Vec::new()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn wait_for_event(
+        descriptor: &mut WorkerStateDescriptor,
+        pieces: &mut Vec<ActivePiece>,
+    ) -> Result<(), PeerError> {
+        let WorkerStateDescriptor {
+            peer_stream,
+            commands_rx,
+            timeout_timer,
+            ..
+        } = descriptor;
+
+        tokio::select! {
+            msg = peer_stream.next() => {
+                let msg = match msg {
+                    Some(msg) => msg?,
                     None => {
-                        anyhow::bail!("commands channel closed while Peer was idle, shutting down")
+                        warn!("peer closed connection");
+                        return Err(PeerError::Closed);
                     }
                 };
+
+                descriptor.last_received = Instant::now();
+                Self::handle_peer_message(msg, descriptor, pieces).await?;
+            }
+
+            // handle commands sent by the engine
+            command = commands_rx.recv() => match command {
+                Some(command) => Self::handle_command(command, descriptor).await?,
+                None => {
+                    info!("engine shut down, shutting down worker");
+                    return Err(PeerError::Shutdown);
+                }
+            },
+
+            _ = timeout_timer.tick() => Self::check_timeouts(descriptor, pieces).await?,
+        }
+        Ok(())
+    }
+
+    async fn check_timeouts(
+        WorkerStateDescriptor {
+            peer_stream,
+            peer_addr,
+            alerts_tx,
+            stats,
+            pipeline,
+            last_received,
+            last_keepalive,
+            config,
+            ..
+        }: &mut WorkerStateDescriptor,
+        pieces: &mut Vec<ActivePiece>,
+    ) -> Result<(), PeerError> {
+        let now = Instant::now();
+        if now.duration_since(*last_received) >= config.inactivity_timeout {
+            warn!("peer has been silent for too long, disconnecting");
+            return Err(PeerError::Inactive(config.inactivity_timeout));
+        }
+
+        if now.duration_since(*last_keepalive) >= config.keepalive_interval {
+            trace!("sending keepalive");
+            peer_stream.send(PeerMessage::KeepAlive).await?;
+            *last_keepalive = now;
+        }
+
+        let snubbed = pipeline
+            .stalled_for(now)
+            .is_some_and(|stalled_for| stalled_for >= config.snub_timeout);
+        if snubbed && !stats.snubbed() {
+            info!("peer snubbed us");
+        }
+        stats.set_snubbed(snubbed);
+
+        let expired = pipeline.expired(now, config.request_timeout);
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        // the pieces go back to the engine, so that faster peers can pick them up.
+        warn!(pieces = ?expired, "requests timed out, releasing pieces");
+        for &index in &expired {
+            let requests = pipeline.take_piece(index);
+            stats.record_request_timeouts(requests.len());
+            for (begin, length) in requests {
+                peer_stream
+                    .feed(PeerMessage::Cancel {
+                        index: index as u32,
+                        begin,
+                        length,
+                    })
+                    .await?;
             }
         }
+        peer_stream.flush().await?;
+
+        pieces.retain(|active| !expired.contains(&active.index));
+        alerts_tx
+            .send(PeerAlerts::ReleasePieces {
+                peer_addr: *peer_addr,
+                pieces: expired.into_iter().collect(),
+            })
+            .await?;
         Ok(())
     }
 
@@ -154,22 +273,97 @@ impl WorkerState {
         command: PeerCommands,
         WorkerStateDescriptor {
             peer_stream,
+            peer_addr,
+            alerts_tx,
+            peer_is_choked,
             we_are_interested,
+            we_are_choking,
+            peer_requests,
             download_queue,
+            stats,
             ..
         }: &mut WorkerStateDescriptor,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), PeerError> {
         type PC = PeerCommands;
 
         match command {
+            PC::Interested => {
+                if !*we_are_interested {
+                    info!("sending Interested to peer");
+                    peer_stream.send(PeerMessage::Interested).await?;
+                    *we_are_interested = true;
+                }
+            }
             PC::NotInterested => {
                 info!("sending NotInterested to peer");
                 peer_stream.send(PeerMessage::NotInterested).await?;
                 *we_are_interested = false;
             }
+            PC::Choke => {
+                if !*we_are_choking {
+                    info!("sending choke to peer");
+                    peer_stream.send(PeerMessage::Choke).await?;
+                    *we_are_choking = true;
+                    stats.set_unchoked_by_us(false);
+                    // the peer knows its requests are dropped along with the choke.
+                    peer_requests.clear();
+                }
+            }
+            PC::Unchoke => {
+                if *we_are_choking {
+                    info!("sending unchoke to peer");
+                    peer_stream.send(PeerMessage::Unchoke).await?;
+                    *we_are_choking = false;
+                    stats.set_unchoked_by_us(true);
+                }
+            }
+            PC::Have(pieces) => {
+                debug!(?pieces, "sending have to peer");
+                for index in pieces {
+                    peer_stream.feed(PeerMessage::Have(index as u32)).await?;
+                }
+                peer_stream.flush().await?;
+            }
+            PC::SendBlock { index, begin, data } => {
+                let request = (index as u32, begin, data.len() as u32);
+                if !peer_requests.remove(&request) {
+                    debug!(
+                        index,
+                        begin, "block was cancelled or choked, not sending it"
+                    );
+                    return Ok(());
+                }
+                debug!(index, begin, length = data.len(), "sending block to peer");
+                let length = data.len();
+                peer_stream
+                    .send(PeerMessage::Piece {
+                        index: index as u32,
+                        begin,
+                        piece: data,
+                    })
+                    .await?;
+                stats.record_uploaded(length);
+            }
             PC::Shutdown => {
                 info!("received shutdown signal, shutting down");
-                anyhow::bail!("received shutdown signal");
+                return Err(PeerError::Shutdown);
+            }
+            PC::Ban => {
+                info!("peer was banned, shutting down");
+                return Err(PeerError::Banned);
+            }
+            // the engine handed it out before hearing about the choke.
+            PC::DownloadPiece(req_info) if *peer_is_choked => {
+                info!(
+                    index = req_info.index,
+                    "peer is choking us, releasing piece"
+                );
+                alerts_tx
+                    .send(PeerAlerts::ReleasePieces {
+                        peer_addr: *peer_addr,
+                        pieces: vec![req_info.index],
+                    })
+                    .await?;
             }
             PC::DownloadPiece(req_info) => {
                 info!(
@@ -182,28 +376,57 @@ impl WorkerState {
         Ok(())
     }
 
-    async fn handle_peer_message(
+    pub(super) async fn handle_peer_message(
         msg: PeerMessage,
-        curr_piece_index: usize,
         WorkerStateDescriptor {
             peer_is_choked,
             peer_addr,
             alerts_tx,
+            stats,
+            pipeline,
+            download_queue,
+            we_are_choking,
+            peer_requests,
+            peer_stream,
+            metadata,
+            peer_metadata_id,
             ..
         }: &mut WorkerStateDescriptor,
-        piece: &mut Vec<u8>,
-        download_progress: &mut PieceDownloadProgress,
-    ) -> anyhow::Result<()> {
+        pieces: &mut Vec<ActivePiece>,
+    ) -> Result<(), PeerError> {
         type PM = PeerMessage;
         match msg {
             PM::Choke => {
                 info!("peer choked");
                 *peer_is_choked = true;
-                download_progress.reset_progress();
+                stats.set_unchoked_by_peer(false);
+                // a choke discards all the requests the peer had queued up. the pieces go back to
+                // the engine, as there's no telling when the peer unchokes us again.
+                pipeline.clear();
+                let released: Vec<_> = pieces
+                    .drain(..)
+                    .map(|active| active.index)
+                    .chain(download_queue.drain(..).map(|req_info| req_info.index))
+                    .collect();
+                if !released.is_empty() {
+                    info!(pieces = ?released, "releasing pieces of choking peer");
+                    alerts_tx
+                        .send(PeerAlerts::ReleasePieces {
+                            peer_addr: *peer_addr,
+                            pieces: released,
+                        })
+                        .await?;
+                }
             }
             PM::Unchoke => {
                 info!("peer unchoked");
                 *peer_is_choked = false;
+                stats.set_unchoked_by_peer(true);
+                alerts_tx
+                    .send(PeerAlerts::Unchoked {
+                        peer_addr: *peer_addr,
+                    })
+                    .await?;
             }
 
             PM::Piece {
@@ -214,16 +437,38 @@ impl WorkerState {
                 let block_span = debug_span!("handle block message", begin, index = recv_index);
                 let _gaurd = block_span.enter();
 
-                info!("received block");
-                assert!(recv_index == curr_piece_index as u32);
+                debug!(block_length = block.len(), "received block");
+                let requested = pipeline.on_block(recv_index as PieceIndex, begin, block.len());
+                if !requested {
+                    // blocks still in flight when the peer choked us, or when a request was
+                    // cancelled, routinely turn up late.
+                    debug!("received block which is not outstanding");
+                }
 
-                debug!(block_length = block.len());
+                let Some(active) = pieces
+                    .iter_mut()
+                    .find(|active| active.index == recv_index as PieceIndex)
+                else {
+                    debug!("dropping block for a piece which is not being downloaded");
+                    stats.record_wasted(block.len());
+                    return Ok(());
+                };
 
-                download_progress.update_downloaded(begin, block.len() as u32)?;
+                // a late block is still worth keeping if the piece is missing it and it was asked
+                // for at some point, unsolicited blocks are dropped.
+                if let Err(err) = active
+                    .download_progress
+                    .update_downloaded(begin, block.len() as u32)
+                {
+                    debug!(%err, "dropping block");
+                    stats.record_wasted(block.len());
+                    return Ok(());
+                }
+                stats.record_downloaded(block.len());
 
-                trace!("appending block onto piece");
-                piece.extend(block);
-                debug!(new_piece_length = piece.len());
+                trace!("writing block into piece");
+                let begin = begin as usize;
+                active.piece[begin..begin + block.len()].copy_from_slice(&block);
             }
             PM::Have(piece_index) => {
                 let span = debug_span!("handle have message", piece_index);
@@ -242,12 +487,107 @@ impl WorkerState {
                 warn!("bitfield message received after first message");
             }
 
-            mesg
-            @ (PM::Cancel { .. } | PM::NotInterested | PM::Interested | PM::Request { .. }) => {
-                warn!(
-                    "received downloader side messages from peer while in inbound mode, {:?}",
-                    mesg
-                );
+            PM::Interested => {
+                info!("peer is interested");
+                stats.set_peer_interested(true);
+            }
+            PM::NotInterested => {
+                info!("peer is not interested");
+                stats.set_peer_interested(false);
+            }
+
+            PM::Extended {
+                id: extension::HANDSHAKE_ID,
+                payload,
+            } => {
+                let handshake = ExtensionHandshake::from_bytes(&payload)?;
+                debug!(extension_handshake = ?handshake, "received extension handshake");
+                if let Some(reqq) = handshake.reqq {
+                    pipeline.set_peer_reqq(reqq);
+                }
+                *peer_metadata_id = handshake.metadata_id();
+            }
+            PM::Extended {
+                id: metadata::OUR_ID,
+                payload,
+            } => {
+                let MetadataMessage::Request { piece } = MetadataMessage::from_bytes(&payload)?
+                else {
+                    debug!("ignoring metadata message which isn't a request");
+                    return Ok(());
+                };
+                let Some(id) = *peer_metadata_id else {
+                    debug!(
+                        piece,
+                        "metadata requested by peer which didn't say how to answer"
+                    );
+                    return Ok(());
+                };
+                let data = metadata.as_ref().and_then(|metadata| {
+                    Some((
+                        metadata.len(),
+                        metadata.chunks(metadata::PIECE_SIZE).nth(piece)?,
+                    ))
+                });
+                let reply = match data {
+                    Some((total_size, data)) => MetadataMessage::Data {
+                        piece,
+                        total_size,
+                        data: data.to_vec(),
+                    },
+                    None => MetadataMessage::Reject { piece },
+                };
+                debug!(piece, "answering metadata request");
+                peer_stream
+                    .send(PM::Extended {
+                        id,
+                        payload: reply.to_bytes()?,
+                    })
+                    .await?;
+            }
+            PM::KeepAlive => trace!("received keepalive"),
+            PM::Extended { id, .. } => {
+                debug!(id, "ignoring unsupported extended message");
+            }
+
+            PM::Request {
+                index,
+                begin,
+                length,
+            } => {
+                // requests which cross the choke are dropped, the peer asks again once unchoked.
+                if *we_are_choking {
+                    debug!(index, begin, "ignoring request from choked peer");
+                    return Ok(());
+                }
+                if length == 0 || length > Self::MAX_REQUEST_LENGTH {
+                    warn!(index, begin, length, "ignoring request of invalid length");
+                    return Ok(());
+                }
+                if peer_requests.len() >= PeerDownloadWorker::OUR_REQQ as usize {
+                    warn!(index, begin, "peer went past the requests it may queue up");
+                    return Ok(());
+                }
+                if !peer_requests.insert((index, begin, length)) {
+                    return Ok(());
+                }
+                debug!(index, begin, length, "peer requested block");
+                alerts_tx
+                    .send(PeerAlerts::Request {
+                        peer_addr: *peer_addr,
+                        index: index as PieceIndex,
+                        begin,
+                        length,
+                    })
+                    .await?;
+            }
+            PM::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                debug!(index, begin, length, "peer cancelled request");
+                peer_requests.remove(&(index, begin, length));
             }
         }
         Ok(())
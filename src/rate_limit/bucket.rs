@@ -0,0 +1,143 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+// tokio's clock so that paused time in tests applies to the buckets as well.
+use tokio::time::Instant;
+
+/// a token bucket refilled at `rate` bytes per second, holding at most one second worth of
+/// tokens. a rate of None means the bucket never runs dry.
+#[derive(Debug)]
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+    // how often and for how long streams were held back by this bucket.
+    waits: AtomicU64,
+    waited_micros: AtomicU64,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    rate: Option<u64>,
+    // can go negative when several streams race for the same tokens, which then just delays the
+    // next acquire for a bit longer.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl BucketState {
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now
+                .saturating_duration_since(self.last_refill)
+                .as_secs_f64();
+            self.tokens = f64::min(self.tokens + elapsed * rate as f64, rate as f64);
+        }
+        self.last_refill = now;
+    }
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            state: Mutex::new(BucketState {
+                rate,
+                tokens: rate.unwrap_or(0) as f64,
+                last_refill: Instant::now(),
+            }),
+            waits: AtomicU64::new(0),
+            waited_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None)
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.state.lock().unwrap().rate
+    }
+
+    /// changes the rate, taking effect immediately for every stream sharing the bucket.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.refill(Instant::now());
+        state.tokens = match (state.rate, rate) {
+            // coming from unlimited, start out with a full bucket.
+            (None, Some(rate)) => rate as f64,
+            (Some(_), Some(rate)) => f64::min(state.tokens, rate as f64),
+            (_, None) => 0.0,
+        };
+        state.rate = rate;
+    }
+
+    /// the number of whole tokens available, or how long to wait until there is at least one.
+    /// the wait is counted towards [`Self::waits`], as it's expected to be waited out.
+    pub fn available(&self, now: Instant) -> Result<usize, Duration> {
+        let mut state = self.state.lock().unwrap();
+        state.refill(now);
+        let wait = match state.rate {
+            None => return Ok(usize::MAX),
+            Some(0) => Duration::from_secs(1),
+            Some(_) if state.tokens >= 1.0 => return Ok(state.tokens as usize),
+            Some(rate) => Duration::from_secs_f64((1.0 - state.tokens) / rate as f64),
+        };
+        self.waits.fetch_add(1, Ordering::Relaxed);
+        self.waited_micros
+            .fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+        Err(wait)
+    }
+
+    /// the number of times a stream had to wait for tokens, and for how long all together.
+    pub fn waits(&self) -> (u64, Duration) {
+        (
+            self.waits.load(Ordering::Relaxed),
+            Duration::from_micros(self.waited_micros.load(Ordering::Relaxed)),
+        )
+    }
+
+    pub fn consume(&self, nbytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        if state.rate.is_some() {
+            state.tokens -= nbytes as f64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refills_at_rate() {
+        let bucket = TokenBucket::new(Some(1000));
+        let start = Instant::now();
+        assert_eq!(bucket.available(start), Ok(1000));
+
+        bucket.consume(1000);
+        assert!(bucket.available(start).is_err());
+        assert_eq!(
+            bucket.available(start + Duration::from_millis(500)),
+            Ok(500)
+        );
+        // never holds more than a second worth of tokens.
+        assert_eq!(bucket.available(start + Duration::from_secs(10)), Ok(1000));
+    }
+
+    #[test]
+    fn test_wait_time_covers_debt() {
+        let bucket = TokenBucket::new(Some(1000));
+        bucket.consume(1500);
+        let wait = bucket.available(Instant::now()).unwrap_err();
+        assert!(wait >= Duration::from_millis(500));
+        assert_eq!(bucket.waits().0, 1);
+    }
+
+    #[test]
+    fn test_set_rate() {
+        let bucket = TokenBucket::unlimited();
+        assert_eq!(bucket.available(Instant::now()), Ok(usize::MAX));
+
+        bucket.set_rate(Some(10));
+        assert_eq!(bucket.rate(), Some(10));
+        assert!(bucket.available(Instant::now()).unwrap() <= 10);
+    }
+}
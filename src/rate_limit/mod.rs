@@ -0,0 +1,91 @@
+//! token bucket rate limiting for peer connections. every connection draws from a chain of
+//! buckets (global, per torrent and optionally per peer), so the tightest limit always wins.
+mod bucket;
+mod stream;
+
+pub use bucket::TokenBucket;
+pub use stream::RateLimitedStream;
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// a chain of buckets which are all drawn from together.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// returns a limiter which additionally draws from the given bucket.
+    pub fn with(mut self, bucket: Arc<TokenBucket>) -> Self {
+        self.buckets.push(bucket);
+        self
+    }
+
+    /// the tightest rate out of all the buckets in the chain.
+    pub fn rate(&self) -> Option<u64> {
+        self.buckets.iter().filter_map(|bucket| bucket.rate()).min()
+    }
+
+    /// the number of bytes that may be transferred right now, or how long to wait before trying
+    /// again.
+    pub fn available(&self) -> Result<usize, Duration> {
+        let now = Instant::now();
+        let mut available = usize::MAX;
+        for bucket in &self.buckets {
+            available = std::cmp::min(available, bucket.available(now)?);
+        }
+        Ok(available)
+    }
+
+    pub fn consume(&self, nbytes: usize) {
+        for bucket in &self.buckets {
+            bucket.consume(nbytes);
+        }
+    }
+}
+
+/// the shared buckets for both directions of a set of connections.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    pub download: Arc<TokenBucket>,
+    pub upload: Arc<TokenBucket>,
+}
+
+impl RateLimits {
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        Self {
+            download: Arc::new(TokenBucket::new(download)),
+            upload: Arc::new(TokenBucket::new(upload)),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None, None)
+    }
+}
+
+/// limiters for each direction of a single connection.
+#[derive(Debug, Clone, Default)]
+pub struct StreamLimiters {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl StreamLimiters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(self, limits: &RateLimits) -> Self {
+        Self {
+            download: self.download.with(limits.download.clone()),
+            upload: self.upload.with(limits.upload.clone()),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use super::{RateLimiter, StreamLimiters};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// stream wrapper which holds reads and writes back until the limiters allow them.
+#[derive(Debug)]
+pub struct RateLimitedStream<S> {
+    inner: S,
+    limiters: StreamLimiters,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> RateLimitedStream<S> {
+    pub fn new(inner: S, limiters: StreamLimiters) -> Self {
+        Self {
+            inner,
+            limiters,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    pub fn limiters(&self) -> &StreamLimiters {
+        &self.limiters
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+// waits out any pending delay, then returns how many bytes the limiter currently allows.
+fn poll_available(
+    limiter: &RateLimiter,
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+
+        match limiter.available() {
+            Ok(available) => return Poll::Ready(available),
+            Err(wait) => *delay = Some(Box::pin(tokio::time::sleep(wait))),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RateLimitedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let available = ready!(poll_available(
+            &this.limiters.download,
+            &mut this.read_delay,
+            cx
+        ));
+
+        let allowed = std::cmp::min(available, buf.remaining());
+        let mut limited = buf.take(allowed);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+
+        let nread = limited.filled().len();
+        // SAFETY: the inner stream initialized these bytes through the limited ReadBuf, which
+        // points into the unfilled part of buf.
+        unsafe { buf.assume_init(nread) };
+        buf.advance(nread);
+        this.limiters.download.consume(nread);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RateLimitedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let available = ready!(poll_available(
+            &this.limiters.upload,
+            &mut this.write_delay,
+            cx
+        ));
+
+        let allowed = std::cmp::min(available, buf.len());
+        let nwritten = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]))?;
+        this.limiters.upload.consume(nwritten);
+        Poll::Ready(Ok(nwritten))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::RateLimits;
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_write_is_throttled() {
+        let (client, mut server) = duplex(1 << 16);
+        let limits = RateLimits::new(None, Some(1000));
+        let mut stream = RateLimitedStream::new(client, StreamLimiters::new().with(&limits));
+
+        let start = tokio::time::Instant::now();
+        let reader = tokio::spawn(async move {
+            let mut buf = vec![0u8; 3000];
+            server.read_exact(&mut buf).await.unwrap();
+        });
+        stream.write_all(&[0u8; 3000]).await.unwrap();
+        reader.await.unwrap();
+
+        // the first second worth is available right away, the rest has to be waited for.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(2), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_unlimited_passthrough() {
+        let (client, mut server) = duplex(64);
+        let mut stream = RateLimitedStream::new(client, StreamLimiters::new());
+        server.write_all(b"hello").await.unwrap();
+
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
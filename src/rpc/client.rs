@@ -0,0 +1,51 @@
+use super::{Method, RpcError, RpcOutcome, RpcRequest, RpcResponse};
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RpcClientError {
+    #[error("rpc request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Rpc(#[from] RpcError),
+    #[error("unexpected rpc result: {0}")]
+    Decode(#[from] serde_json::Error),
+}
+
+/// calls the methods of a session served by [`super::serve`].
+#[derive(Debug)]
+pub struct RpcClient {
+    http_client: reqwest::Client,
+    url: Url,
+    next_id: AtomicU64,
+}
+
+impl RpcClient {
+    /// `url` is the full url of the rpc endpoint, e.g `http://127.0.0.1:8861/rpc`.
+    pub fn new(url: Url) -> Self {
+        Self {
+            http_client: reqwest::Client::new(),
+            url,
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    pub async fn call<T: DeserializeOwned>(&self, method: Method) -> Result<T, RpcClientError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let response: RpcResponse = self
+            .http_client
+            .post(self.url.clone())
+            .json(&RpcRequest::new(id, method))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        match response.outcome {
+            RpcOutcome::Result(result) => Ok(serde_json::from_value(result)?),
+            RpcOutcome::Error(error) => Err(error.into()),
+        }
+    }
+}
@@ -0,0 +1,351 @@
+//! a json-rpc 2.0 api for controlling a session from another process. [`serve`] exposes a
+//! session over http, and [`RpcClient`] talks to it. [`transmission`] serves the same session to
+//! frontends built for transmission.
+mod client;
+mod server;
+pub mod transmission;
+
+pub use client::{RpcClient, RpcClientError};
+pub use server::serve;
+
+use crate::metainfo::FilePriority;
+use crate::session::SessionError;
+use crate::torrent::InfoHash;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// the port the daemon serves rpc requests on unless told otherwise, right next to the
+/// default listen port.
+pub const DEFAULT_RPC_PORT: u16 = 8861;
+/// the path rpc requests are posted to.
+pub const RPC_PATH: &str = "/rpc";
+
+const JSONRPC_VERSION: &str = "2.0";
+
+/// where the torrent to add comes from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TorrentSource {
+    /// the contents of a torrent file, hex encoded over the wire.
+    File(#[serde(with = "hex")] Vec<u8>),
+    /// a url the daemon downloads the torrent file from.
+    Url(String),
+    /// a magnet link, its metadata is fetched from peers before the torrent is added.
+    Magnet(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Method {
+    /// returns the info hash of the added torrent.
+    AddTorrent {
+        source: TorrentSource,
+    },
+    ListTorrents,
+    TorrentStatus {
+        #[serde(with = "crate::torrent::as_hex")]
+        info_hash: InfoHash,
+    },
+    PauseTorrent {
+        #[serde(with = "crate::torrent::as_hex")]
+        info_hash: InfoHash,
+    },
+    ResumeTorrent {
+        #[serde(with = "crate::torrent::as_hex")]
+        info_hash: InfoHash,
+    },
+    RemoveTorrent {
+        #[serde(with = "crate::torrent::as_hex")]
+        info_hash: InfoHash,
+        /// delete the downloaded files along with the torrent.
+        #[serde(default)]
+        delete_data: bool,
+    },
+    /// bytes per second across all torrents, a missing limit lifts it.
+    SetRateLimits {
+        download: Option<u64>,
+        upload: Option<u64>,
+    },
+    GetRateLimits,
+    /// bytes per second of a single torrent, the limits across all torrents still apply.
+    SetTorrentRateLimits {
+        #[serde(with = "crate::torrent::as_hex")]
+        info_hash: InfoHash,
+        download: Option<u64>,
+        upload: Option<u64>,
+    },
+    GetTorrentRateLimits {
+        #[serde(with = "crate::torrent::as_hex")]
+        info_hash: InfoHash,
+    },
+    ListPeers {
+        #[serde(with = "crate::torrent::as_hex")]
+        info_hash: InfoHash,
+    },
+    ListTrackers {
+        #[serde(with = "crate::torrent::as_hex")]
+        info_hash: InfoHash,
+    },
+    ListFiles {
+        #[serde(with = "crate::torrent::as_hex")]
+        info_hash: InfoHash,
+    },
+    SetSequential {
+        #[serde(with = "crate::torrent::as_hex")]
+        info_hash: InfoHash,
+        sequential: bool,
+    },
+    /// the file is given by its index in the list of files.
+    SetFilePriority {
+        #[serde(with = "crate::torrent::as_hex")]
+        info_hash: InfoHash,
+        file: usize,
+        priority: FilePriority,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcRequest {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(flatten)]
+    pub method: Method,
+}
+
+impl RpcRequest {
+    pub fn new(id: impl Into<Value>, method: Method) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id: id.into(),
+            method,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcResponse {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(flatten)]
+    pub outcome: RpcOutcome,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RpcOutcome {
+    Result(Value),
+    Error(RpcError),
+}
+
+impl RpcResponse {
+    pub fn new(id: Value, outcome: RpcOutcome) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            outcome,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, thiserror::Error)]
+#[error("{message} (code {code})")]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub const PARSE_ERROR: i64 = -32700;
+    pub const INVALID_REQUEST: i64 = -32600;
+    pub const INTERNAL_ERROR: i64 = -32603;
+    // the codes below are our own, out of the range reserved by the spec.
+    pub const UNKNOWN_TORRENT: i64 = -32001;
+    pub const DUPLICATE_TORRENT: i64 = -32002;
+    pub const INVALID_TORRENT: i64 = -32003;
+    pub const UNSUPPORTED: i64 = -32004;
+    pub const UNKNOWN_FILE: i64 = -32005;
+
+    pub fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<SessionError> for RpcError {
+    fn from(err: SessionError) -> Self {
+        let code = match err {
+            SessionError::DuplicateTorrent => Self::DUPLICATE_TORRENT,
+            SessionError::UnknownTorrent => Self::UNKNOWN_TORRENT,
+            SessionError::InvalidMetainfo(_) | SessionError::Metadata(_) => Self::INVALID_TORRENT,
+            SessionError::UnknownFile(_) => Self::UNKNOWN_FILE,
+            _ => Self::INTERNAL_ERROR,
+        };
+        Self::new(code, err)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{Session, SessionConfig, TorrentStatus};
+    use serde_json::json;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    // a single piece torrent, its udp tracker makes the announce fail right away.
+    pub(crate) const TORRENT: &[u8] = b"d8:announce26:udp://127.0.0.1:1/announce4:infod6:lengthi16384e4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+
+    #[test]
+    fn test_request_wire_format() {
+        let info_hash = InfoHash::new([0xab; 20]);
+        let request = RpcRequest::new(
+            7,
+            Method::RemoveTorrent {
+                info_hash,
+                delete_data: true,
+            },
+        );
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 7,
+                "method": "remove_torrent",
+                "params": { "info_hash": "ab".repeat(20), "delete_data": true },
+            })
+        );
+
+        let request: RpcRequest = serde_json::from_value(
+            json!({ "jsonrpc": "2.0", "id": "a", "method": "list_torrents" }),
+        )
+        .unwrap();
+        assert!(matches!(request.method, Method::ListTorrents));
+    }
+
+    #[tokio::test]
+    async fn test_daemon_roundtrip() {
+        let session = Session::new(SessionConfig {
+            port: 0,
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}{}", listener.local_addr().unwrap(), RPC_PATH);
+        tokio::spawn(serve(Arc::new(session), listener));
+        let client = RpcClient::new(url.parse().unwrap());
+
+        let torrents: Vec<TorrentStatus> = client.call(Method::ListTorrents).await.unwrap();
+        assert!(torrents.is_empty());
+
+        let source = TorrentSource::File(TORRENT.to_vec());
+        let info_hash: String = client
+            .call(Method::AddTorrent {
+                source: source.clone(),
+            })
+            .await
+            .unwrap();
+        let info_hash: InfoHash = info_hash.parse().unwrap();
+        let status: TorrentStatus = client
+            .call(Method::TorrentStatus {
+                info_hash: info_hash.clone(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(status.name, "test");
+
+        let duplicate = client.call::<String>(Method::AddTorrent { source }).await;
+        assert!(matches!(
+            duplicate,
+            Err(RpcClientError::Rpc(RpcError {
+                code: RpcError::DUPLICATE_TORRENT,
+                ..
+            }))
+        ));
+
+        let () = client
+            .call(Method::SetRateLimits {
+                download: Some(1024),
+                upload: None,
+            })
+            .await
+            .unwrap();
+        let limits: (Option<u64>, Option<u64>) = client.call(Method::GetRateLimits).await.unwrap();
+        assert_eq!(limits, (Some(1024), None));
+
+        let () = client
+            .call(Method::SetTorrentRateLimits {
+                info_hash: info_hash.clone(),
+                download: None,
+                upload: Some(512),
+            })
+            .await
+            .unwrap();
+        let limits: (Option<u64>, Option<u64>) = client
+            .call(Method::GetTorrentRateLimits {
+                info_hash: info_hash.clone(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(limits, (None, Some(512)));
+
+        let () = client
+            .call(Method::RemoveTorrent {
+                info_hash: info_hash.clone(),
+                delete_data: false,
+            })
+            .await
+            .unwrap();
+        let removed = client.call::<()>(Method::PauseTorrent { info_hash }).await;
+        assert!(matches!(
+            removed,
+            Err(RpcClientError::Rpc(RpcError {
+                code: RpcError::UNKNOWN_TORRENT,
+                ..
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_remove_deletes_data() {
+        let download_dir = crate::storage::tests::temp_dir();
+        let session = Session::new(SessionConfig {
+            port: 0,
+            download_dir: download_dir.clone(),
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}{}", listener.local_addr().unwrap(), RPC_PATH);
+        tokio::spawn(serve(Arc::new(session), listener));
+        let client = RpcClient::new(url.parse().unwrap());
+
+        let source = TorrentSource::File(TORRENT.to_vec());
+        let info_hash: String = client.call(Method::AddTorrent { source }).await.unwrap();
+        let info_hash: InfoHash = info_hash.parse().unwrap();
+        // what an earlier run of the torrent left behind.
+        let file = download_dir.join("test");
+        let part_file = download_dir.join(format!(".{info_hash}.parts"));
+        std::fs::write(&file, [1; 16384]).unwrap();
+        std::fs::write(&part_file, [1; 16384]).unwrap();
+        let unrelated = download_dir.join("other");
+        std::fs::write(&unrelated, [1]).unwrap();
+
+        let () = client
+            .call(Method::RemoveTorrent {
+                info_hash,
+                delete_data: true,
+            })
+            .await
+            .unwrap();
+        assert!(!file.exists());
+        assert!(!part_file.exists());
+        assert!(unrelated.exists());
+
+        std::fs::remove_dir_all(download_dir).unwrap();
+    }
+}
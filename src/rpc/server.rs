@@ -0,0 +1,133 @@
+use super::{Method, RpcError, RpcOutcome, RpcRequest, RpcResponse, TorrentSource, RPC_PATH};
+use crate::metainfo::{MagnetLink, Metainfo};
+use crate::prelude::*;
+use crate::session::Session;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::routing::post;
+use axum::{Json, Router};
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+#[derive(Debug)]
+struct RpcState {
+    session: Arc<Session>,
+    // fetches torrent files which are added by url.
+    http_client: reqwest::Client,
+}
+
+/// serves rpc requests for the session on the listener, until it fails.
+pub async fn serve(session: Arc<Session>, listener: TcpListener) -> std::io::Result<()> {
+    let state = Arc::new(RpcState {
+        session,
+        http_client: reqwest::Client::new(),
+    });
+    let router = Router::new()
+        .route(RPC_PATH, post(handle))
+        .with_state(state);
+    axum::serve(listener, router).await
+}
+
+// json-rpc reports every failure in the body, so this always responds with 200.
+async fn handle(State(state): State<Arc<RpcState>>, body: Bytes) -> Json<RpcResponse> {
+    let request: Value = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(err) => {
+            let error = RpcError::new(RpcError::PARSE_ERROR, err);
+            return Json(RpcResponse::new(Value::Null, RpcOutcome::Error(error)));
+        }
+    };
+
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let outcome = match serde_json::from_value::<RpcRequest>(request) {
+        Ok(request) => {
+            debug!(method = ?request.method, "rpc request");
+            match dispatch(&state, request.method).await {
+                Ok(result) => RpcOutcome::Result(result),
+                Err(error) => RpcOutcome::Error(error),
+            }
+        }
+        Err(err) => RpcOutcome::Error(RpcError::new(RpcError::INVALID_REQUEST, err)),
+    };
+    Json(RpcResponse::new(id, outcome))
+}
+
+async fn dispatch(state: &RpcState, method: Method) -> Result<Value, RpcError> {
+    let session = &state.session;
+    match method {
+        Method::AddTorrent { source } => {
+            let metainfo = fetch_metainfo(session, &state.http_client, source).await?;
+            to_result(session.add_torrent(metainfo)?.to_string())
+        }
+        Method::ListTorrents => to_result(session.torrents()),
+        Method::TorrentStatus { info_hash } => to_result(
+            session
+                .status(&info_hash)
+                .ok_or(crate::SessionError::UnknownTorrent)?,
+        ),
+        Method::PauseTorrent { info_hash } => to_result(session.pause(&info_hash)?),
+        Method::ResumeTorrent { info_hash } => to_result(session.resume(&info_hash)?),
+        Method::RemoveTorrent {
+            info_hash,
+            delete_data,
+        } => to_result(session.remove(&info_hash, delete_data).await?),
+        Method::SetRateLimits { download, upload } => {
+            session.set_rate_limits(download, upload);
+            to_result(())
+        }
+        Method::GetRateLimits => to_result(session.rate_limits()),
+        Method::SetTorrentRateLimits {
+            info_hash,
+            download,
+            upload,
+        } => to_result(session.set_torrent_rate_limits(&info_hash, download, upload)?),
+        Method::GetTorrentRateLimits { info_hash } => {
+            to_result(session.torrent_rate_limits(&info_hash)?)
+        }
+        Method::ListPeers { info_hash } => to_result(session.peers(&info_hash)?),
+        Method::ListTrackers { info_hash } => to_result(session.trackers(&info_hash)?),
+        Method::ListFiles { info_hash } => to_result(session.files(&info_hash)?),
+        Method::SetSequential {
+            info_hash,
+            sequential,
+        } => to_result(session.set_sequential(&info_hash, sequential)?),
+        Method::SetFilePriority {
+            info_hash,
+            file,
+            priority,
+        } => to_result(session.set_file_priority(&info_hash, file, priority)?),
+    }
+}
+
+pub(super) async fn fetch_metainfo(
+    session: &Session,
+    http_client: &reqwest::Client,
+    source: TorrentSource,
+) -> Result<Metainfo, RpcError> {
+    let contents = match source {
+        TorrentSource::File(contents) => contents,
+        TorrentSource::Url(url) => {
+            let fetch = async {
+                let response = http_client.get(url).send().await?.error_for_status()?;
+                response.bytes().await
+            };
+            fetch
+                .await
+                .map_err(|err| RpcError::new(RpcError::INVALID_TORRENT, err))?
+                .to_vec()
+        }
+        TorrentSource::Magnet(link) => {
+            let magnet: MagnetLink = link
+                .parse()
+                .map_err(|err| RpcError::new(RpcError::INVALID_TORRENT, err))?;
+            return Ok(session.fetch_magnet(&magnet).await?);
+        }
+    };
+    Metainfo::from_bytes(&contents).map_err(|err| RpcError::new(RpcError::INVALID_TORRENT, err))
+}
+
+fn to_result(result: impl Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(result).map_err(|err| RpcError::new(RpcError::INTERNAL_ERROR, err))
+}
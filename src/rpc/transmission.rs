@@ -0,0 +1,838 @@
+//! the commonly used subset of the transmission rpc protocol, so that frontends built for
+//! transmission can manage a session as is. spec:
+//! https://github.com/transmission/transmission/blob/main/docs/rpc-spec.md
+use super::server::fetch_metainfo;
+use super::TorrentSource;
+use crate::metainfo::{FilePriority, MagnetLink};
+use crate::peer_protocol::mse::EncryptionPolicy;
+use crate::prelude::*;
+use crate::session::{Session, SessionError, TorrentState, TorrentStatus};
+use crate::torrent::InfoHash;
+use crate::tracker::request::Requestable;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+
+/// the port transmission serves its rpc on, which frontends default to.
+pub const DEFAULT_TRANSMISSION_PORT: u16 = 9091;
+pub const TRANSMISSION_PATH: &str = "/transmission/rpc";
+
+// guards against csrf, requests have to echo back the id handed out in a 409 response.
+const SESSION_ID_HEADER: &str = "x-transmission-session-id";
+const RPC_VERSION: u32 = 17;
+// transmission counts speeds in kB/s.
+const SPEED_UNIT: u64 = 1000;
+
+// the fields handed out when a torrent-get doesn't ask for any in particular.
+const TORRENT_FIELDS: &[&str] = &[
+    "id",
+    "hashString",
+    "name",
+    "status",
+    "error",
+    "errorString",
+    "totalSize",
+    "leftUntilDone",
+    "percentDone",
+    "rateDownload",
+    "rateUpload",
+    "peersConnected",
+    "queuePosition",
+    "eta",
+];
+
+#[derive(Debug, Clone, Copy)]
+struct SpeedLimits {
+    down: u64,
+    down_enabled: bool,
+    up: u64,
+    up_enabled: bool,
+}
+
+impl SpeedLimits {
+    // what transmission starts out with for limits which were never set.
+    const DEFAULT_LIMIT: u64 = 100;
+
+    fn from_rates((download, upload): (Option<u64>, Option<u64>)) -> Self {
+        Self {
+            down: download.map_or(Self::DEFAULT_LIMIT, |rate| rate / SPEED_UNIT),
+            down_enabled: download.is_some(),
+            up: upload.map_or(Self::DEFAULT_LIMIT, |rate| rate / SPEED_UNIT),
+            up_enabled: upload.is_some(),
+        }
+    }
+
+    fn rates(&self) -> (Option<u64>, Option<u64>) {
+        (
+            self.down_enabled.then_some(self.down * SPEED_UNIT),
+            self.up_enabled.then_some(self.up * SPEED_UNIT),
+        )
+    }
+}
+
+#[derive(Debug)]
+struct TransmissionState {
+    session: Arc<Session>,
+    http_client: reqwest::Client,
+    session_id: String,
+    // transmission keeps the limits around while they're turned off, the session only knows
+    // about the ones in effect.
+    speed_limits: Mutex<SpeedLimits>,
+    // the same goes for the limits of each torrent, once they've been set.
+    torrent_speed_limits: Mutex<HashMap<InfoHash, SpeedLimits>>,
+    started: Instant,
+}
+
+impl TransmissionState {
+    fn torrent_speed_limits(&self, info_hash: &InfoHash) -> Result<SpeedLimits, SessionError> {
+        if let Some(limits) = self.torrent_speed_limits.lock().unwrap().get(info_hash) {
+            return Ok(*limits);
+        }
+        let rates = self.session.torrent_rate_limits(info_hash)?;
+        Ok(SpeedLimits::from_rates(rates))
+    }
+}
+
+/// serves transmission rpc requests for the session on the listener, until it fails.
+pub async fn serve(session: Arc<Session>, listener: TcpListener) -> std::io::Result<()> {
+    let state = Arc::new(TransmissionState {
+        speed_limits: Mutex::new(SpeedLimits::from_rates(session.rate_limits())),
+        torrent_speed_limits: Mutex::new(HashMap::new()),
+        session,
+        http_client: reqwest::Client::new(),
+        session_id: Alphanumeric.sample_string(&mut rand::thread_rng(), 48),
+        started: Instant::now(),
+    });
+    let router = Router::new()
+        .route(TRANSMISSION_PATH, post(handle))
+        .with_state(state);
+    axum::serve(listener, router).await
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    method: String,
+    #[serde(default)]
+    arguments: Value,
+    #[serde(default)]
+    tag: Option<Value>,
+}
+
+async fn handle(
+    State(state): State<Arc<TransmissionState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let session_id = [(SESSION_ID_HEADER, state.session_id.clone())];
+    let given_id = headers.get(SESSION_ID_HEADER).map(|id| id.as_bytes());
+    if given_id != Some(state.session_id.as_bytes()) {
+        return (StatusCode::CONFLICT, session_id, "invalid session id").into_response();
+    }
+
+    let request: Request = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    debug!(method = %request.method, "transmission rpc request");
+
+    // failures are reported as the result string, in place of "success".
+    let (result, arguments) = match dispatch(&state, &request.method, request.arguments).await {
+        Ok(arguments) => ("success".to_string(), arguments),
+        Err(err) => (err, json!({})),
+    };
+    let mut response = json!({ "result": result, "arguments": arguments });
+    if let Some(tag) = request.tag {
+        response["tag"] = tag;
+    }
+    (session_id, Json(response)).into_response()
+}
+
+async fn dispatch(
+    state: &TransmissionState,
+    method: &str,
+    arguments: Value,
+) -> Result<Value, String> {
+    let session = &state.session;
+    match method {
+        "session-get" => Ok(session_get(state)),
+        "session-set" => session_set(state, parse(arguments)?),
+        "session-stats" => Ok(session_stats(state)),
+        "torrent-get" => torrent_get(state, parse(arguments)?),
+        "torrent-add" => torrent_add(state, parse(arguments)?).await,
+        // there's no jumping the queue, the torrent starts as soon as a slot frees up.
+        "torrent-start" | "torrent-start-now" => {
+            for_each(session, parse(arguments)?, Session::resume)
+        }
+        "torrent-stop" => for_each(session, parse(arguments)?, Session::pause),
+        "torrent-set" => torrent_set(state, parse(arguments)?),
+        "torrent-remove" => {
+            let remove: TorrentRemove = parse(arguments)?;
+            for status in select(session, remove.action.ids) {
+                let info_hash = &status.info_hash;
+                state.torrent_speed_limits.lock().unwrap().remove(info_hash);
+                session
+                    .remove(info_hash, remove.delete_local_data)
+                    .await
+                    .map_err(|err| err.to_string())?;
+            }
+            Ok(json!({}))
+        }
+        _ => Err("method name not recognized".to_string()),
+    }
+}
+
+fn parse<T: DeserializeOwned>(arguments: Value) -> Result<T, String> {
+    let arguments = match arguments {
+        Value::Null => json!({}),
+        arguments => arguments,
+    };
+    serde_json::from_value(arguments).map_err(|err| format!("invalid arguments: {err}"))
+}
+
+// transmission numbers torrents from 1, in the order they were added.
+fn torrent_id(status: &TorrentStatus) -> u64 {
+    status.queue_position + 1
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Ids {
+    One(TorrentId),
+    Many(Vec<TorrentId>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TorrentId {
+    Id(u64),
+    /// a hash string, or "recently-active".
+    Hash(String),
+}
+
+impl TorrentId {
+    fn matches(&self, status: &TorrentStatus) -> bool {
+        match self {
+            Self::Id(id) => *id == torrent_id(status),
+            Self::Hash(hash) if hash == "recently-active" => status.state.is_active(),
+            Self::Hash(hash) => hash
+                .parse::<InfoHash>()
+                .is_ok_and(|info_hash| info_hash == status.info_hash),
+        }
+    }
+}
+
+// no ids at all means every torrent.
+fn select(session: &Session, ids: Option<Ids>) -> Vec<TorrentStatus> {
+    let torrents = session.torrents();
+    let ids = match ids {
+        None => return torrents,
+        Some(Ids::One(id)) => vec![id],
+        Some(Ids::Many(ids)) => ids,
+    };
+    torrents
+        .into_iter()
+        .filter(|status| ids.iter().any(|id| id.matches(status)))
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+struct TorrentAction {
+    #[serde(default)]
+    ids: Option<Ids>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TorrentRemove {
+    #[serde(flatten)]
+    action: TorrentAction,
+    #[serde(default, rename = "delete-local-data")]
+    delete_local_data: bool,
+}
+
+fn for_each(
+    session: &Session,
+    TorrentAction { ids }: TorrentAction,
+    action: impl Fn(&Session, &InfoHash) -> Result<(), SessionError>,
+) -> Result<Value, String> {
+    for status in select(session, ids) {
+        action(session, &status.info_hash).map_err(|err| err.to_string())?;
+    }
+    Ok(json!({}))
+}
+
+#[derive(Debug, Deserialize)]
+struct TorrentGet {
+    #[serde(default)]
+    ids: Option<Ids>,
+    #[serde(default)]
+    fields: Vec<String>,
+}
+
+fn torrent_get(
+    state: &TransmissionState,
+    TorrentGet { ids, fields }: TorrentGet,
+) -> Result<Value, String> {
+    let fields: Vec<&str> = match fields.is_empty() {
+        true => TORRENT_FIELDS.to_vec(),
+        false => fields.iter().map(String::as_str).collect(),
+    };
+    let torrents: Vec<Value> = select(&state.session, ids)
+        .iter()
+        .map(|status| {
+            // fields we don't know about are left out, as transmission does.
+            let object: Map<String, Value> = fields
+                .iter()
+                .filter_map(|field| Some((field.to_string(), torrent_field(state, status, field)?)))
+                .collect();
+            Value::Object(object)
+        })
+        .collect();
+    Ok(json!({ "torrents": torrents }))
+}
+
+fn torrent_field(state: &TransmissionState, status: &TorrentStatus, field: &str) -> Option<Value> {
+    let session = &state.session;
+    // pieces shared with skipped files count towards the bytes done, but not what's wanted.
+    let done = status.bytes_done.min(status.wanted_length);
+    let left = status.wanted_length - done;
+    let percent_done = match status.wanted_length {
+        0 => 1.0,
+        wanted => done as f64 / wanted as f64,
+    };
+
+    Some(match field {
+        "id" => json!(torrent_id(status)),
+        "hashString" => json!(status.info_hash.to_string()),
+        "name" => json!(status.name),
+        "status" => json!(status_code(status.state)),
+        // there's no telling tracker errors apart from local ones, so they're all local.
+        "error" => json!(if status.error.is_some() { 3 } else { 0 }),
+        "errorString" => json!(status.error.as_deref().unwrap_or_default()),
+        "totalSize" => json!(status.total_length),
+        "sizeWhenDone" => json!(status.wanted_length),
+        "leftUntilDone" => json!(left),
+        "haveValid" => json!(status.bytes_done),
+        "haveUnchecked" => json!(0),
+        "percentDone" => json!(percent_done),
+        "percentComplete" => json!(match status.total_length {
+            0 => 1.0,
+            total => status.bytes_done as f64 / total as f64,
+        }),
+        "metadataPercentComplete" => json!(1.0),
+        "downloadedEver" => json!(status.downloaded),
+        "uploadedEver" => json!(status.uploaded),
+        "uploadRatio" => json!(match status.downloaded {
+            0 => -1.0,
+            downloaded => status.uploaded as f64 / downloaded as f64,
+        }),
+        "rateDownload" => json!(status.download_rate),
+        "rateUpload" => json!(status.upload_rate),
+        "peersConnected" => json!(status.peers),
+        "queuePosition" => json!(status.queue_position),
+        "isFinished" => json!(status.state == TorrentState::Complete),
+        "isStalled" => json!(false),
+        // -1 stands for not available.
+        "eta" => json!(match status.download_rate {
+            0 => -1,
+            _ if left == 0 => -1,
+            rate => (left / rate) as i64,
+        }),
+        "pieceCount" => json!(status.num_pieces),
+        "pieceSize" => json!(status.piece_length),
+        "downloadDir" => json!(session.config().download_dir),
+        "downloadLimit" => json!(state.torrent_speed_limits(&status.info_hash).ok()?.down),
+        "downloadLimited" => {
+            json!(
+                state
+                    .torrent_speed_limits(&status.info_hash)
+                    .ok()?
+                    .down_enabled
+            )
+        }
+        "uploadLimit" => json!(state.torrent_speed_limits(&status.info_hash).ok()?.up),
+        "uploadLimited" => json!(
+            state
+                .torrent_speed_limits(&status.info_hash)
+                .ok()?
+                .up_enabled
+        ),
+        // the session wide limits always apply.
+        "honorsSessionLimits" => json!(true),
+        "files" => {
+            let files = session.files(&status.info_hash).ok()?;
+            let files: Vec<Value> = files
+                .into_iter()
+                .map(|file| {
+                    json!({
+                        "name": file.path,
+                        "length": file.length,
+                        "bytesCompleted": file.bytes_done,
+                    })
+                })
+                .collect();
+            json!(files)
+        }
+        "fileStats" => {
+            let files = session.files(&status.info_hash).ok()?;
+            let stats: Vec<Value> = files
+                .into_iter()
+                .map(|file| {
+                    json!({
+                        "bytesCompleted": file.bytes_done,
+                        "wanted": file.priority != FilePriority::Skip,
+                        "priority": priority_code(file.priority),
+                    })
+                })
+                .collect();
+            json!(stats)
+        }
+        // a bitfield with the first piece in the high bit, base64 encoded.
+        "pieces" => {
+            let pieces = session.pieces(&status.info_hash).ok()?;
+            let mut bitfield = vec![0u8; pieces.len().div_ceil(8)];
+            for (index, _) in pieces.iter().enumerate().filter(|(_, have)| **have) {
+                bitfield[index / 8] |= 0x80 >> (index % 8);
+            }
+            json!(BASE64_STANDARD.encode(bitfield))
+        }
+        "trackerStats" => {
+            let trackers = session.trackers(&status.info_hash).ok()?;
+            let stats: Vec<Value> = trackers
+                .into_iter()
+                .enumerate()
+                .map(|(id, tracker)| {
+                    let host = reqwest::Url::parse(&tracker.url)
+                        .ok()
+                        .and_then(|url| url.host_str().map(str::to_string))
+                        .unwrap_or_default();
+                    json!({
+                        "id": id,
+                        "tier": 0,
+                        "announce": tracker.url,
+                        "host": host,
+                        "hasAnnounced": true,
+                        "lastAnnounceSucceeded": tracker.error.is_none(),
+                        "lastAnnounceResult": tracker.error.as_deref().unwrap_or("Success"),
+                        "lastAnnouncePeerCount": tracker.peers,
+                    })
+                })
+                .collect();
+            json!(stats)
+        }
+        "peers" => {
+            let peers = session.peers(&status.info_hash).ok()?;
+            let peers: Vec<Value> = peers
+                .into_iter()
+                .map(|peer| {
+                    json!({
+                        "address": peer.addr.ip().to_string(),
+                        "port": peer.addr.port(),
+                        "clientName": peer.client.unwrap_or_default(),
+                        "peerIsInterested": peer.peer_interested,
+                    })
+                })
+                .collect();
+            json!(peers)
+        }
+        _ => return None,
+    })
+}
+
+// transmission's tr_priority_t, which skipped files keep alongside being unwanted.
+fn priority_code(priority: FilePriority) -> i8 {
+    match priority {
+        FilePriority::Low => -1,
+        FilePriority::Skip | FilePriority::Normal => 0,
+        FilePriority::High => 1,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TorrentSet {
+    #[serde(default)]
+    ids: Option<Ids>,
+    #[serde(default)]
+    files_wanted: Vec<usize>,
+    #[serde(default)]
+    files_unwanted: Vec<usize>,
+    #[serde(default)]
+    priority_high: Vec<usize>,
+    #[serde(default)]
+    priority_normal: Vec<usize>,
+    #[serde(default)]
+    priority_low: Vec<usize>,
+    #[serde(rename = "downloadLimit")]
+    download_limit: Option<u64>,
+    #[serde(rename = "downloadLimited")]
+    download_limited: Option<bool>,
+    #[serde(rename = "uploadLimit")]
+    upload_limit: Option<u64>,
+    #[serde(rename = "uploadLimited")]
+    upload_limited: Option<bool>,
+}
+
+// the other settings of a torrent aren't supported, they're ignored as unknown fields are.
+fn torrent_set(state: &TransmissionState, set: TorrentSet) -> Result<Value, String> {
+    let session = &state.session;
+    for status in select(session, set.ids) {
+        let info_hash = &status.info_hash;
+        let mut limits = state
+            .torrent_speed_limits(info_hash)
+            .map_err(|err| err.to_string())?;
+        limits.down = set.download_limit.unwrap_or(limits.down);
+        limits.down_enabled = set.download_limited.unwrap_or(limits.down_enabled);
+        limits.up = set.upload_limit.unwrap_or(limits.up);
+        limits.up_enabled = set.upload_limited.unwrap_or(limits.up_enabled);
+        let (download, upload) = limits.rates();
+        session
+            .set_torrent_rate_limits(info_hash, download, upload)
+            .map_err(|err| err.to_string())?;
+        state
+            .torrent_speed_limits
+            .lock()
+            .unwrap()
+            .insert(info_hash.clone(), limits);
+
+        let files = session.files(info_hash).map_err(|err| err.to_string())?;
+        let mut priorities: Vec<_> = files.iter().map(|file| file.priority).collect();
+        let mut update = |indices: &[usize], priority: &dyn Fn(FilePriority) -> FilePriority| {
+            for &index in indices {
+                let current = priorities
+                    .get_mut(index)
+                    .ok_or(SessionError::UnknownFile(index).to_string())?;
+                *current = priority(*current);
+            }
+            Ok::<_, String>(())
+        };
+        // wanted files which had been skipped come back at normal priority.
+        update(&set.files_wanted, &|current| match current {
+            FilePriority::Skip => FilePriority::Normal,
+            current => current,
+        })?;
+        update(&set.files_unwanted, &|_| FilePriority::Skip)?;
+        let unless_skipped = |priority| {
+            move |current| match current {
+                FilePriority::Skip => FilePriority::Skip,
+                _ => priority,
+            }
+        };
+        update(&set.priority_high, &unless_skipped(FilePriority::High))?;
+        update(&set.priority_normal, &unless_skipped(FilePriority::Normal))?;
+        update(&set.priority_low, &unless_skipped(FilePriority::Low))?;
+
+        for (index, priority) in priorities.into_iter().enumerate() {
+            if priority != files[index].priority {
+                session
+                    .set_file_priority(info_hash, index, priority)
+                    .map_err(|err| err.to_string())?;
+            }
+        }
+    }
+    Ok(json!({}))
+}
+
+// transmission's tr_torrent_activity.
+fn status_code(state: TorrentState) -> u8 {
+    const STOPPED: u8 = 0;
+    const DOWNLOAD_WAIT: u8 = 3;
+    const DOWNLOAD: u8 = 4;
+    const SEED: u8 = 6;
+    match state {
+        TorrentState::Queued => DOWNLOAD_WAIT,
+        TorrentState::Announcing | TorrentState::Downloading => DOWNLOAD,
+        TorrentState::Complete => SEED,
+        TorrentState::Paused | TorrentState::Failed => STOPPED,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TorrentAdd {
+    /// a path on the daemon's side, a url or a magnet link.
+    #[serde(default)]
+    filename: Option<String>,
+    /// base64 encoded contents of a torrent file.
+    #[serde(default)]
+    metainfo: Option<String>,
+    #[serde(default)]
+    paused: bool,
+}
+
+async fn torrent_add(state: &TransmissionState, add: TorrentAdd) -> Result<Value, String> {
+    let source = match (add.metainfo, add.filename) {
+        (Some(metainfo), _) => TorrentSource::File(
+            BASE64_STANDARD
+                .decode(metainfo.trim())
+                .map_err(|err| format!("invalid metainfo: {err}"))?,
+        ),
+        (None, Some(filename)) if filename.starts_with("magnet:") => {
+            TorrentSource::Magnet(filename)
+        }
+        (None, Some(filename))
+            if filename.starts_with("http://") || filename.starts_with("https://") =>
+        {
+            TorrentSource::Url(filename)
+        }
+        (None, Some(path)) => TorrentSource::File(
+            tokio::fs::read(&path)
+                .await
+                .map_err(|err| format!("failed to read {path}: {err}"))?,
+        ),
+        (None, None) => return Err("either filename or metainfo has to be given".to_string()),
+    };
+
+    // no need to fetch the metadata of a magnet link which is already in the session.
+    if let TorrentSource::Magnet(link) = &source {
+        let magnet: Option<MagnetLink> = link.parse().ok();
+        if let Some(status) = magnet.and_then(|magnet| state.session.status(&magnet.info_hash)) {
+            return Ok(added_reply("torrent-duplicate", &status));
+        }
+    }
+
+    let metainfo = fetch_metainfo(&state.session, &state.http_client, source)
+        .await
+        .map_err(|err| err.message)?;
+    let info_hash = metainfo
+        .file_info
+        .get_info_hash()
+        .map_err(|err| format!("invalid metainfo: {err}"))?;
+
+    let session = &state.session;
+    let key = match session.add_torrent(metainfo) {
+        Ok(_) if add.paused => {
+            session.pause(&info_hash).map_err(|err| err.to_string())?;
+            "torrent-added"
+        }
+        Ok(_) => "torrent-added",
+        Err(SessionError::DuplicateTorrent) => "torrent-duplicate",
+        Err(err) => return Err(err.to_string()),
+    };
+    let status = session
+        .status(&info_hash)
+        .ok_or_else(|| SessionError::UnknownTorrent.to_string())?;
+    Ok(added_reply(key, &status))
+}
+
+// the same for added and duplicate torrents, only the key differs.
+fn added_reply(key: &str, status: &TorrentStatus) -> Value {
+    json!({
+        key: {
+            "id": torrent_id(status),
+            "name": status.name,
+            "hashString": status.info_hash.to_string(),
+        }
+    })
+}
+
+fn session_get(state: &TransmissionState) -> Value {
+    let config = state.session.config();
+    let limits = *state.speed_limits.lock().unwrap();
+    json!({
+        "version": concat!("crux-torrent ", env!("CARGO_PKG_VERSION")),
+        "rpc-version": RPC_VERSION,
+        "rpc-version-minimum": RPC_VERSION,
+        "session-id": state.session_id,
+        "peer-port": config.port,
+        "encryption": match config.encryption {
+            EncryptionPolicy::Forced => "required",
+            EncryptionPolicy::Enabled => "preferred",
+            EncryptionPolicy::Disabled => "tolerated",
+        },
+        "speed-limit-down": limits.down,
+        "speed-limit-down-enabled": limits.down_enabled,
+        "speed-limit-up": limits.up,
+        "speed-limit-up-enabled": limits.up_enabled,
+        "download-queue-enabled": true,
+        "download-queue-size": config.max_active_torrents,
+        "peer-limit-global": config.connection_limits.max_connections,
+        "peer-limit-per-torrent": config.connection_limits.max_connections_per_torrent,
+        "download-dir": "",
+        "dht-enabled": false,
+        "pex-enabled": false,
+        "lpd-enabled": false,
+        "utp-enabled": true,
+        "units": {
+            "speed-units": ["kB/s", "MB/s", "GB/s", "TB/s"],
+            "speed-bytes": SPEED_UNIT,
+            "size-units": ["kB", "MB", "GB", "TB"],
+            "size-bytes": 1000,
+            "memory-units": ["KiB", "MiB", "GiB", "TiB"],
+            "memory-bytes": 1024,
+        },
+    })
+}
+
+// the settings which aren't supported are ignored, as transmission does with unknown ones.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct SessionSet {
+    speed_limit_down: Option<u64>,
+    speed_limit_down_enabled: Option<bool>,
+    speed_limit_up: Option<u64>,
+    speed_limit_up_enabled: Option<bool>,
+}
+
+fn session_set(state: &TransmissionState, set: SessionSet) -> Result<Value, String> {
+    let mut limits = state.speed_limits.lock().unwrap();
+    limits.down = set.speed_limit_down.unwrap_or(limits.down);
+    limits.down_enabled = set.speed_limit_down_enabled.unwrap_or(limits.down_enabled);
+    limits.up = set.speed_limit_up.unwrap_or(limits.up);
+    limits.up_enabled = set.speed_limit_up_enabled.unwrap_or(limits.up_enabled);
+
+    let (download, upload) = limits.rates();
+    state.session.set_rate_limits(download, upload);
+    Ok(json!({}))
+}
+
+fn session_stats(state: &TransmissionState) -> Value {
+    let torrents = state.session.torrents();
+    let count = |state: TorrentState| {
+        torrents
+            .iter()
+            .filter(|status| status.state == state)
+            .count()
+    };
+    let sum = |field: fn(&TorrentStatus) -> u64| torrents.iter().map(field).sum::<u64>();
+
+    // nothing outlives the session, so the cumulative stats are the current ones.
+    let stats = json!({
+        "downloadedBytes": sum(|status| status.downloaded),
+        "uploadedBytes": sum(|status| status.uploaded),
+        "filesAdded": torrents.len(),
+        "sessionCount": 1,
+        "secondsActive": state.started.elapsed().as_secs(),
+    });
+    json!({
+        "activeTorrentCount": torrents.iter().filter(|status| status.state.is_active()).count(),
+        "pausedTorrentCount": count(TorrentState::Paused),
+        "torrentCount": torrents.len(),
+        "downloadSpeed": sum(|status| status.download_rate),
+        "uploadSpeed": sum(|status| status.upload_rate),
+        "current-stats": stats,
+        "cumulative-stats": stats,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::tests::TORRENT;
+    use crate::SessionConfig;
+    use std::net::Ipv4Addr;
+
+    async fn call(client: &reqwest::Client, url: &str, session_id: &str, request: Value) -> Value {
+        client
+            .post(url)
+            .header(SESSION_ID_HEADER, session_id)
+            .json(&request)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_transmission_roundtrip() {
+        let session = Arc::new(
+            Session::new(SessionConfig {
+                port: 0,
+                ..SessionConfig::default()
+            })
+            .await
+            .unwrap(),
+        );
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!(
+            "http://{}{}",
+            listener.local_addr().unwrap(),
+            TRANSMISSION_PATH
+        );
+        tokio::spawn(serve(session.clone(), listener));
+        let client = reqwest::Client::new();
+
+        // the first request is turned away, handing out the session id to use.
+        let response = client.post(&url).json(&json!({ "method": "session-get" }));
+        let response = response.send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::CONFLICT);
+        let session_id = response.headers()[SESSION_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let add = json!({
+            "method": "torrent-add",
+            "arguments": { "metainfo": BASE64_STANDARD.encode(TORRENT) },
+            "tag": 3,
+        });
+        let response = call(&client, &url, &session_id, add.clone()).await;
+        assert_eq!(response["result"], "success");
+        assert_eq!(response["tag"], 3);
+        let added = &response["arguments"]["torrent-added"];
+        assert_eq!(added["id"], 1);
+        assert_eq!(added["name"], "test");
+
+        let response = call(&client, &url, &session_id, add).await;
+        assert_eq!(response["arguments"]["torrent-duplicate"]["id"], 1);
+
+        let get = json!({
+            "method": "torrent-get",
+            "arguments": { "ids": [added["hashString"]], "fields": ["id", "totalSize", "nope"] },
+        });
+        let response = call(&client, &url, &session_id, get).await;
+        assert_eq!(
+            response["arguments"]["torrents"],
+            json!([{ "id": 1, "totalSize": 16384 }])
+        );
+
+        let set = json!({
+            "method": "session-set",
+            "arguments": { "speed-limit-down": 50, "speed-limit-down-enabled": true },
+        });
+        assert_eq!(
+            call(&client, &url, &session_id, set).await["result"],
+            "success"
+        );
+        assert_eq!(session.rate_limits(), (Some(50 * SPEED_UNIT), None));
+
+        let set = json!({
+            "method": "torrent-set",
+            "arguments": { "ids": [1], "uploadLimit": 20, "uploadLimited": true },
+        });
+        assert_eq!(
+            call(&client, &url, &session_id, set).await["result"],
+            "success"
+        );
+        let info_hash: InfoHash = added["hashString"].as_str().unwrap().parse().unwrap();
+        assert_eq!(
+            session.torrent_rate_limits(&info_hash).unwrap(),
+            (None, Some(20 * SPEED_UNIT))
+        );
+        let get = json!({
+            "method": "torrent-get",
+            "arguments": { "ids": [1], "fields": ["uploadLimit", "uploadLimited", "downloadLimited"] },
+        });
+        let response = call(&client, &url, &session_id, get).await;
+        assert_eq!(
+            response["arguments"]["torrents"],
+            json!([{ "uploadLimit": 20, "uploadLimited": true, "downloadLimited": false }])
+        );
+
+        let unknown = json!({ "method": "blocklist-update" });
+        let response = call(&client, &url, &session_id, unknown).await;
+        assert_eq!(response["result"], "method name not recognized");
+    }
+}
@@ -0,0 +1,190 @@
+//! turning magnet links into torrents, by fetching their metadata from whichever peers can be
+//! found for them.
+use super::{Session, SessionError};
+use crate::metainfo::url::TrackerUrl;
+use crate::metainfo::{MagnetLink, Metainfo};
+use crate::peers::download_worker::{ConnectionConfig, PeerAddr};
+use crate::peers::metadata::fetch_metadata;
+use crate::peers::{PeerError, PeerRegistry};
+use crate::prelude::*;
+use crate::tracker::request::TrackerRequest;
+use crate::tracker::{Announce, HttpTracker};
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddrV4;
+use std::time::Duration;
+use tokio::task::JoinSet;
+
+impl Session {
+    const METADATA_TIMEOUT: Duration = Duration::from_secs(120);
+    const METADATA_PEER_TIMEOUT: Duration = Duration::from_secs(30);
+    // peers asked for the metadata at once, the first one to hand it over wins.
+    const METADATA_PEERS: usize = 8;
+
+    /// fetches the info dictionary of a magnet link from its peers, found through the peers
+    /// the link names, its http trackers and the dht. the torrent isn't added, the metainfo is
+    /// handed to [`Session::add_torrent`] as for torrent files.
+    #[instrument(level = "info", skip_all, fields(info_hash = ?magnet.info_hash))]
+    pub async fn fetch_magnet(&self, magnet: &MagnetLink) -> Result<Metainfo, SessionError> {
+        if self
+            .torrents
+            .lock()
+            .unwrap()
+            .handles
+            .contains_key(&magnet.info_hash)
+        {
+            return Err(SessionError::DuplicateTorrent);
+        }
+
+        let metadata = tokio::time::timeout(Self::METADATA_TIMEOUT, self.fetch_metadata(magnet))
+            .await
+            .map_err(|_| SessionError::Metadata("timed out".to_string()))??;
+        let metainfo = Metainfo::from_magnet(magnet, &metadata)
+            .map_err(|err| SessionError::InvalidMetainfo(err.to_string()))?;
+        info!(name = metainfo.file_info.name(), "fetched magnet metadata");
+        Ok(metainfo)
+    }
+
+    async fn fetch_metadata(&self, magnet: &MagnetLink) -> Result<Vec<u8>, SessionError> {
+        let mut lookups = JoinSet::new();
+        for tracker in &magnet.trackers {
+            // TODO: handle udp trackers, BEP: https://www.bittorrent.org/beps/bep_0015.html
+            let Ok(TrackerUrl::Http(url)) = TrackerUrl::new(tracker.as_str()) else {
+                continue;
+            };
+            let request =
+                TrackerRequest::new(self.shared.peer_id.clone(), self.shared.config.port, magnet)
+                    .expect("the info hash of a magnet link is known");
+            let client = self.shared.http_client.clone();
+            lookups.spawn(async move {
+                match HttpTracker::new(&client, url).announce(&request).await {
+                    Ok(response) => response.peer_addreses,
+                    Err(err) => {
+                        warn!(%err, "tracker announce for metadata failed");
+                        Vec::new()
+                    }
+                }
+            });
+        }
+        if let Some(dht) = self.shared.dht.clone() {
+            let info_hash = magnet.info_hash.clone();
+            lookups.spawn(async move { dht.get_peers(&info_hash).await });
+        }
+
+        let config = ConnectionConfig {
+            encryption: self.shared.config.encryption,
+            utp_socket: Some(self.shared.utp_socket.clone()),
+            rate_limiters: self.shared.rate_limiters.clone(),
+            peer_rate_limits: self.shared.config.peer_rate_limits,
+            peer_registry: PeerRegistry::new(),
+            worker: self.shared.config.worker,
+            metadata: None,
+        };
+        let mut seen: HashSet<SocketAddrV4> = magnet.peers.iter().copied().collect();
+        let mut pending: VecDeque<SocketAddrV4> = magnet.peers.iter().copied().collect();
+        let mut fetches = JoinSet::new();
+        let mut last_error = None;
+        loop {
+            while fetches.len() < Self::METADATA_PEERS {
+                let Some(peer) = pending.pop_front() else {
+                    break;
+                };
+                let (info_hash, peer_id) = (magnet.info_hash.clone(), self.shared.peer_id.clone());
+                let config = config.clone();
+                fetches.spawn(async move {
+                    let fetch = async {
+                        let connection = PeerAddr::new(peer)
+                            .handshake(info_hash.clone(), peer_id, &config)
+                            .await?;
+                        fetch_metadata(connection, &info_hash).await
+                    };
+                    let result = tokio::time::timeout(Self::METADATA_PEER_TIMEOUT, fetch).await;
+                    (
+                        peer,
+                        result.unwrap_or(Err(PeerError::Timeout("metadata fetch"))),
+                    )
+                });
+            }
+            if fetches.is_empty() && lookups.is_empty() {
+                let error = last_error.unwrap_or_else(|| "no peers were found".to_string());
+                return Err(SessionError::Metadata(error));
+            }
+
+            tokio::select! {
+                Some(peers) = lookups.join_next(), if !lookups.is_empty() => {
+                    let peers = peers.unwrap_or_default();
+                    debug!(peers = peers.len(), "found peers for metadata");
+                    pending.extend(peers.into_iter().filter(|peer| seen.insert(*peer)));
+                }
+                Some(fetch) = fetches.join_next(), if !fetches.is_empty() => match fetch {
+                    Ok((_, Ok(metadata))) => return Ok(metadata),
+                    Ok((peer, Err(err))) => {
+                        debug!(%peer, %err, "peer didn't hand over the metadata");
+                        last_error = Some(err.to_string());
+                    }
+                    Err(err) => warn!(%err, "metadata fetch panicked"),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::DhtConfig;
+    use crate::rpc::tests::TORRENT;
+    use crate::session::{SessionConfig, SessionEvent, TorrentState};
+    use crate::storage::tests::temp_dir;
+
+    #[tokio::test]
+    async fn test_fetches_metadata_from_peer() {
+        let dir = temp_dir();
+        // the udp tracker isn't supported, the dht keeps the seeder going without it.
+        let seeder = Session::new(SessionConfig {
+            port: 0,
+            download_dir: dir.clone(),
+            dht: DhtConfig {
+                enabled: true,
+                bootstrap_nodes: Vec::new(),
+            },
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap();
+        let mut events = seeder.subscribe();
+        let info_hash = seeder
+            .add_torrent(Metainfo::from_bytes(TORRENT).unwrap())
+            .unwrap();
+        while !matches!(
+            events.recv().await.unwrap(),
+            SessionEvent::StateChanged {
+                state: TorrentState::Downloading,
+                ..
+            }
+        ) {}
+
+        let leecher = Session::new(SessionConfig {
+            port: 0,
+            download_dir: dir.clone(),
+            dht: DhtConfig {
+                enabled: false,
+                bootstrap_nodes: Vec::new(),
+            },
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap();
+        let magnet: MagnetLink = format!(
+            "magnet:?xt=urn:btih:{info_hash}&x.pe=127.0.0.1:{}",
+            seeder.listen_port()
+        )
+        .parse()
+        .unwrap();
+        let metainfo = leecher.fetch_magnet(&magnet).await.unwrap();
+        assert_eq!(metainfo.file_info.name(), "test");
+        assert!(metainfo.announce.is_none());
+        assert_eq!(leecher.add_torrent(metainfo).unwrap(), info_hash);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
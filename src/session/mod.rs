@@ -0,0 +1,646 @@
+//! the public entry point of the library. a session downloads any number of torrents, sharing
+//! the listen port, peer id, rate limits, connection limits and dht node between them.
+mod magnet;
+mod stats;
+mod stream;
+mod torrent;
+
+pub use stats::{FileStatus, PeerInfo, TorrentState, TorrentStatus, TrackerInfo};
+pub(crate) use stats::{Streaming, TorrentStats};
+pub use stream::FileStream;
+
+use crate::connection_manager::{ConnectionCounts, ConnectionLimits};
+use crate::dht::{DhtConfig, DhtNode};
+use crate::metainfo::{FilePriority, Metainfo};
+use crate::peer_protocol::mse::EncryptionPolicy;
+use crate::peer_protocol::{transport::PeerTransport, utp::UtpSocket};
+use crate::peers::download_worker::{IncomingPeer, WorkerConfig};
+use crate::peers::PieceIndex;
+use crate::prelude::*;
+use crate::rate_limit::{RateLimits, StreamLimiters};
+use crate::storage::Storage;
+use crate::torrent::{InfoHash, PeerId};
+use crate::tracker::request::Requestable;
+use crate::tracker::TrackerConfig;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::{JoinHandle, JoinSet};
+use torrent::{TorrentCommand, TorrentTask};
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// the port to listen on for both tcp and utp, it's also the one announced to trackers.
+    pub port: u16,
+    pub encryption: EncryptionPolicy,
+    /// limits on the total rates across all torrents.
+    pub rate_limits: RateLimits,
+    /// download and upload rates each connection is limited to on its own.
+    pub peer_rate_limits: (Option<u64>, Option<u64>),
+    pub connection_limits: ConnectionLimits,
+    /// the number of peers of each torrent which are unchoked at once.
+    pub unchoke_slots: usize,
+    /// ban a peer which sent a corrupt piece as soon as a good copy from another peer shows its
+    /// blocks were wrong. peers are banned after repeated hash failures either way.
+    pub smart_ban: bool,
+    /// torrents which are announcing or downloading at once, the rest wait in the queue in the
+    /// order they were added. the slots are for downloading only, complete torrents keep seeding
+    /// without taking one up.
+    pub max_active_torrents: usize,
+    /// where the files of the torrents are saved.
+    pub download_dir: PathBuf,
+    pub worker: WorkerConfig,
+    /// alerts from the workers of a torrent which can queue up for its engine.
+    pub alerts_buffer_size: usize,
+    pub tracker: TrackerConfig,
+    /// finding peers through the dht, on top of the trackers. private torrents never use it.
+    pub dht: DhtConfig,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            port: 8860,
+            encryption: EncryptionPolicy::default(),
+            rate_limits: RateLimits::unlimited(),
+            peer_rate_limits: (None, None),
+            connection_limits: ConnectionLimits {
+                max_connections: 200,
+                max_connections_per_torrent: 50,
+                max_half_open: 8,
+                connect_timeout: Duration::from_secs(10),
+            },
+            unchoke_slots: 4,
+            smart_ban: false,
+            max_active_torrents: 8,
+            download_dir: PathBuf::from("."),
+            worker: WorkerConfig::default(),
+            alerts_buffer_size: 100,
+            tracker: TrackerConfig::default(),
+            dht: DhtConfig::default(),
+        }
+    }
+}
+
+/// things which happened to the torrents of a session, see [`Session::subscribe`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum SessionEvent {
+    TorrentAdded {
+        info_hash: InfoHash,
+    },
+    StateChanged {
+        info_hash: InfoHash,
+        state: TorrentState,
+    },
+    PieceCompleted {
+        info_hash: InfoHash,
+        index: PieceIndex,
+    },
+    TorrentCompleted {
+        info_hash: InfoHash,
+    },
+    /// the torrent can't make any progress, e.g its tracker refused to hand out peers.
+    TorrentFailed {
+        info_hash: InfoHash,
+        error: String,
+    },
+    TorrentRemoved {
+        info_hash: InfoHash,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum SessionError {
+    #[error("torrent is already part of the session")]
+    DuplicateTorrent,
+    #[error("torrent is not part of the session")]
+    UnknownTorrent,
+    #[error("torrent has no file {0}")]
+    UnknownFile(usize),
+    #[error("invalid metainfo: {0}")]
+    InvalidMetainfo(String),
+    #[error("failed to fetch the metadata: {0}")]
+    Metadata(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug)]
+struct TorrentHandle {
+    commands_tx: mpsc::UnboundedSender<TorrentCommand>,
+    stats: Arc<TorrentStats>,
+    storage: Arc<AsyncMutex<Storage>>,
+    // limits on the rates of this torrent alone, on top of the ones of the session.
+    rate_limits: RateLimits,
+    task: JoinHandle<()>,
+}
+
+/// what every torrent of the session shares.
+#[derive(Debug)]
+struct Shared {
+    config: SessionConfig,
+    peer_id: PeerId,
+    http_client: reqwest::Client,
+    utp_socket: UtpSocket,
+    rate_limiters: StreamLimiters,
+    counts: Arc<ConnectionCounts>,
+    dht: Option<DhtNode>,
+    events_tx: broadcast::Sender<SessionEvent>,
+    // asks the scheduler to hand out whichever active slots are free.
+    schedule_tx: mpsc::UnboundedSender<()>,
+}
+
+impl Shared {
+    fn reschedule(&self) {
+        // the scheduler only goes away along with the session.
+        let _ = self.schedule_tx.send(());
+    }
+}
+
+#[derive(Debug, Default)]
+struct Torrents {
+    handles: HashMap<InfoHash, TorrentHandle>,
+    next_queue_position: u64,
+}
+
+impl Torrents {
+    /// starts as many of the queued torrents as there are free active slots. torrents which
+    /// completed seed outside of the slots, so they never hold up the queue.
+    fn schedule(&self, max_active: usize) {
+        let active = self
+            .handles
+            .values()
+            .filter(|handle| handle.stats.state().is_active())
+            .count();
+        let mut queued: Vec<_> = self
+            .handles
+            .values()
+            .filter(|handle| handle.stats.state() == TorrentState::Queued)
+            .collect();
+        queued.sort_by_key(|handle| handle.stats.queue_position());
+
+        for handle in queued.into_iter().take(max_active.saturating_sub(active)) {
+            debug!(info_hash = ?handle.stats.info_hash(), "starting queued torrent");
+            // the slot is claimed right away, so it isn't handed out again before the task
+            // gets around to starting.
+            handle.stats.set_state(TorrentState::Announcing);
+            let _ = handle.commands_tx.send(TorrentCommand::Start);
+        }
+    }
+}
+
+/// a set of torrents being downloaded, it has to be created within a tokio runtime.
+#[derive(Debug)]
+pub struct Session {
+    shared: Arc<Shared>,
+    torrents: Arc<Mutex<Torrents>>,
+    listener: JoinHandle<()>,
+    scheduler: JoinHandle<()>,
+}
+
+impl Session {
+    const EVENTS_BUFFER_SIZE: usize = 1024;
+
+    /// binds the listen port and starts accepting peers.
+    pub async fn new(mut config: SessionConfig) -> Result<Self, SessionError> {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, config.port)).await?;
+        // a port of 0 picks any free one, uTP goes on the same one and it's the one announced.
+        config.port = listener.local_addr()?.port();
+        let utp_socket = UtpSocket::bind((Ipv4Addr::UNSPECIFIED, config.port)).await?;
+        let (events_tx, _) = broadcast::channel(Self::EVENTS_BUFFER_SIZE);
+        let (schedule_tx, schedule_rx) = mpsc::unbounded_channel();
+        let dht = config.dht.enabled.then(|| {
+            let dht = DhtNode::new(utp_socket.clone(), config.dht.bootstrap_nodes.clone());
+            // torrents added right away wait for the bootstrap on their first lookup anyway.
+            tokio::spawn({
+                let dht = dht.clone();
+                async move { dht.bootstrap().await }
+            });
+            dht
+        });
+
+        let shared = Arc::new(Shared {
+            peer_id: PeerId::random(),
+            http_client: reqwest::Client::new(),
+            utp_socket: utp_socket.clone(),
+            rate_limiters: StreamLimiters::new().with(&config.rate_limits),
+            counts: Arc::new(ConnectionCounts::default()),
+            dht,
+            events_tx,
+            schedule_tx,
+            config,
+        });
+        let torrents = Arc::new(Mutex::new(Torrents::default()));
+        let listener = tokio::spawn(accept_peers(
+            listener,
+            utp_socket,
+            shared.clone(),
+            torrents.clone(),
+        ));
+        let scheduler = tokio::spawn(run_scheduler(
+            schedule_rx,
+            torrents.clone(),
+            shared.config.max_active_torrents,
+        ));
+
+        Ok(Self {
+            shared,
+            torrents,
+            listener,
+            scheduler,
+        })
+    }
+
+    /// the port peers can connect to over both tcp and uTP.
+    pub fn listen_port(&self) -> u16 {
+        self.shared.config.port
+    }
+
+    /// queues the torrent up for downloading, returns its info hash which identifies it from then
+    /// on.
+    pub fn add_torrent(&self, metainfo: Metainfo) -> Result<InfoHash, SessionError> {
+        let info_hash = metainfo
+            .file_info
+            .get_info_hash()
+            .map_err(|err| SessionError::InvalidMetainfo(err.to_string()))?;
+
+        let mut torrents = self.torrents.lock().unwrap();
+        if torrents.handles.contains_key(&info_hash) {
+            return Err(SessionError::DuplicateTorrent);
+        }
+
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let queue_position = torrents.next_queue_position;
+        torrents.next_queue_position += 1;
+        let stats = Arc::new(TorrentStats::new(
+            info_hash.clone(),
+            &metainfo.file_info,
+            queue_position,
+        ));
+        let storage = Arc::new(AsyncMutex::new(Storage::new(
+            &self.shared.config.download_dir,
+            &info_hash,
+            &metainfo.file_info,
+        )));
+        let rate_limits = RateLimits::unlimited();
+        let task = TorrentTask::new(
+            info_hash.clone(),
+            metainfo,
+            self.shared.clone(),
+            stats.clone(),
+            storage.clone(),
+            rate_limits.clone(),
+            commands_rx,
+        );
+        torrents.handles.insert(
+            info_hash.clone(),
+            TorrentHandle {
+                commands_tx,
+                stats,
+                storage,
+                rate_limits,
+                task: tokio::spawn(task.run()),
+            },
+        );
+
+        info!(?info_hash, "added torrent");
+        self.emit(SessionEvent::TorrentAdded {
+            info_hash: info_hash.clone(),
+        });
+        self.shared.reschedule();
+        Ok(info_hash)
+    }
+
+    /// disconnects from all the peers of the torrent, the progress made so far is kept. its
+    /// active slot goes to the next queued torrent.
+    pub fn pause(&self, info_hash: &InfoHash) -> Result<(), SessionError> {
+        self.send(info_hash, TorrentCommand::Pause)
+    }
+
+    /// puts a paused torrent back in the queue, at the position it was first added at.
+    pub fn resume(&self, info_hash: &InfoHash) -> Result<(), SessionError> {
+        self.send(info_hash, TorrentCommand::Resume)
+    }
+
+    /// removes the torrent from the session, and deletes the files downloaded so far if
+    /// `delete_data` is set.
+    pub async fn remove(
+        &self,
+        info_hash: &InfoHash,
+        delete_data: bool,
+    ) -> Result<(), SessionError> {
+        let handle = self
+            .torrents
+            .lock()
+            .unwrap()
+            .handles
+            .remove(info_hash)
+            .ok_or(SessionError::UnknownTorrent)?;
+        handle.task.abort();
+        handle.stats.set_removed();
+        self.shared.reschedule();
+        info!(?info_hash, "removed torrent");
+        self.emit(SessionEvent::TorrentRemoved {
+            info_hash: info_hash.clone(),
+        });
+
+        if delete_data {
+            // the task has to be gone first, or it may write the files again.
+            let _ = handle.task.await;
+            handle.storage.lock().await.delete().await?;
+            info!(?info_hash, "deleted torrent data");
+        }
+        Ok(())
+    }
+
+    pub fn status(&self, info_hash: &InfoHash) -> Option<TorrentStatus> {
+        let torrents = self.torrents.lock().unwrap();
+        Some(torrents.handles.get(info_hash)?.stats.status())
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentStatus> {
+        let torrents = self.torrents.lock().unwrap();
+        let mut handles: Vec<_> = torrents.handles.values().collect();
+        handles.sort_by_key(|handle| handle.stats.queue_position());
+        handles
+            .into_iter()
+            .map(|handle| handle.stats.status())
+            .collect()
+    }
+
+    pub fn peers(&self, info_hash: &InfoHash) -> Result<Vec<PeerInfo>, SessionError> {
+        self.with_stats(info_hash, TorrentStats::peers)
+    }
+
+    pub fn trackers(&self, info_hash: &InfoHash) -> Result<Vec<TrackerInfo>, SessionError> {
+        self.with_stats(info_hash, TorrentStats::trackers)
+    }
+
+    /// which pieces of a torrent passed the hash check, by index.
+    pub fn pieces(&self, info_hash: &InfoHash) -> Result<Vec<bool>, SessionError> {
+        self.with_stats(info_hash, TorrentStats::pieces)
+    }
+
+    pub fn files(&self, info_hash: &InfoHash) -> Result<Vec<FileStatus>, SessionError> {
+        self.with_stats(info_hash, TorrentStats::files)
+    }
+
+    /// changes the priority of a file, by its index in [`Session::files`]. skipping a file stops
+    /// its pieces from being picked, the ones which are already there are kept.
+    pub fn set_file_priority(
+        &self,
+        info_hash: &InfoHash,
+        index: usize,
+        priority: FilePriority,
+    ) -> Result<(), SessionError> {
+        self.with_stats(info_hash, |stats| stats.set_file_priority(index, priority))?
+            .then_some(())
+            .ok_or(SessionError::UnknownFile(index))
+    }
+
+    /// downloads the pieces of the torrent in order, from where its file streams are at or from
+    /// the start, instead of the rarest ones first.
+    pub fn set_sequential(
+        &self,
+        info_hash: &InfoHash,
+        sequential: bool,
+    ) -> Result<(), SessionError> {
+        self.with_stats(info_hash, |stats| stats.set_sequential(sequential))
+    }
+
+    /// opens a file of the torrent for reading while it downloads, by its index in
+    /// [`Session::files`]. a skipped file is wanted again.
+    pub fn open_file(
+        &self,
+        info_hash: &InfoHash,
+        index: usize,
+    ) -> Result<FileStream, SessionError> {
+        let torrents = self.torrents.lock().unwrap();
+        let handle = torrents
+            .handles
+            .get(info_hash)
+            .ok_or(SessionError::UnknownTorrent)?;
+        let stream = FileStream::new(index, handle.stats.clone(), handle.storage.clone())
+            .ok_or(SessionError::UnknownFile(index))?;
+        if handle.stats.files()[index].priority == FilePriority::Skip {
+            handle.stats.set_file_priority(index, FilePriority::Normal);
+        }
+        Ok(stream)
+    }
+
+    /// changes the limits on the total rates across all torrents, `None` lifts the limit. the
+    /// connections which are already open are limited by the new rates right away.
+    pub fn set_rate_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        let rate_limits = &self.shared.config.rate_limits;
+        rate_limits.download.set_rate(download);
+        rate_limits.upload.set_rate(upload);
+    }
+
+    /// the download and upload limits on the total rates across all torrents.
+    pub fn rate_limits(&self) -> (Option<u64>, Option<u64>) {
+        let rate_limits = &self.shared.config.rate_limits;
+        (rate_limits.download.rate(), rate_limits.upload.rate())
+    }
+
+    /// changes the limits on the rates of a single torrent, `None` lifts the limit. the session
+    /// wide limits still apply on top of them.
+    pub fn set_torrent_rate_limits(
+        &self,
+        info_hash: &InfoHash,
+        download: Option<u64>,
+        upload: Option<u64>,
+    ) -> Result<(), SessionError> {
+        self.with_handle(info_hash, |handle| {
+            handle.rate_limits.download.set_rate(download);
+            handle.rate_limits.upload.set_rate(upload);
+        })
+    }
+
+    /// the download and upload limits on the rates of a single torrent.
+    pub fn torrent_rate_limits(
+        &self,
+        info_hash: &InfoHash,
+    ) -> Result<(Option<u64>, Option<u64>), SessionError> {
+        self.with_handle(info_hash, |handle| {
+            let rate_limits = &handle.rate_limits;
+            (rate_limits.download.rate(), rate_limits.upload.rate())
+        })
+    }
+
+    /// events of all the torrents of the session, from here on. a receiver which falls behind
+    /// misses the oldest events.
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.shared.events_tx.subscribe()
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.shared.config
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.shared.peer_id
+    }
+
+    /// the stats of every torrent, in queue order.
+    pub(crate) fn torrent_stats(&self) -> Vec<Arc<TorrentStats>> {
+        let torrents = self.torrents.lock().unwrap();
+        let mut stats: Vec<_> = torrents
+            .handles
+            .values()
+            .map(|handle| handle.stats.clone())
+            .collect();
+        stats.sort_by_key(|stats| stats.queue_position());
+        stats
+    }
+
+    pub(crate) fn connection_counts(&self) -> &ConnectionCounts {
+        &self.shared.counts
+    }
+
+    fn with_stats<T>(
+        &self,
+        info_hash: &InfoHash,
+        f: impl FnOnce(&TorrentStats) -> T,
+    ) -> Result<T, SessionError> {
+        self.with_handle(info_hash, |handle| f(&handle.stats))
+    }
+
+    fn with_handle<T>(
+        &self,
+        info_hash: &InfoHash,
+        f: impl FnOnce(&TorrentHandle) -> T,
+    ) -> Result<T, SessionError> {
+        let torrents = self.torrents.lock().unwrap();
+        let handle = torrents
+            .handles
+            .get(info_hash)
+            .ok_or(SessionError::UnknownTorrent)?;
+        Ok(f(handle))
+    }
+
+    fn send(&self, info_hash: &InfoHash, command: TorrentCommand) -> Result<(), SessionError> {
+        let torrents = self.torrents.lock().unwrap();
+        let handle = torrents
+            .handles
+            .get(info_hash)
+            .ok_or(SessionError::UnknownTorrent)?;
+        // the task only goes away along with its handle.
+        let _ = handle.commands_tx.send(command);
+        Ok(())
+    }
+
+    fn emit(&self, event: SessionEvent) {
+        // nobody listening is fine.
+        let _ = self.shared.events_tx.send(event);
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.listener.abort();
+        self.scheduler.abort();
+        for handle in self.torrents.lock().unwrap().handles.values() {
+            handle.task.abort();
+        }
+    }
+}
+
+async fn run_scheduler(
+    mut schedule_rx: mpsc::UnboundedReceiver<()>,
+    torrents: Arc<Mutex<Torrents>>,
+    max_active: usize,
+) {
+    while schedule_rx.recv().await.is_some() {
+        // requests which piled up in the meantime are all covered by a single pass.
+        while schedule_rx.try_recv().is_ok() {}
+        torrents.lock().unwrap().schedule(max_active);
+    }
+}
+
+// every torrent of the session shares the listener, inbound peers are handed to whichever one
+// they ask for in their handshake.
+async fn accept_peers(
+    listener: TcpListener,
+    utp_socket: UtpSocket,
+    shared: Arc<Shared>,
+    torrents: Arc<Mutex<Torrents>>,
+) {
+    // peers which are still being identified, they go away along with the listener.
+    let mut routing = JoinSet::new();
+    loop {
+        let (transport, peer_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer_addr)) => (PeerTransport::Tcp(stream), peer_addr),
+                Err(err) => {
+                    warn!(%err, "failed to accept tcp peer connection");
+                    continue;
+                }
+            },
+
+            accepted = utp_socket.accept() => match accepted {
+                Ok((stream, peer_addr)) => (PeerTransport::Utp(stream), peer_addr),
+                Err(err) => {
+                    warn!(%err, "failed to accept utp peer connection");
+                    continue;
+                }
+            },
+
+            Some(_) = routing.join_next() => continue,
+        };
+
+        let SocketAddr::V4(peer_addr) = peer_addr else {
+            warn!(%peer_addr, "ignoring connection from ipv6 peer");
+            continue;
+        };
+
+        let limits = &shared.config.connection_limits;
+        if shared.counts.connections() + routing.len() >= limits.max_connections {
+            info!(%peer_addr, "connection limit reached, rejecting inbound peer");
+            continue;
+        }
+
+        let incoming = IncomingPeer::new(transport, peer_addr);
+        routing.spawn(route_peer(incoming, shared.clone(), torrents.clone()));
+    }
+}
+
+#[instrument(
+    level = "info",
+    name = "inbound peer routing",
+    fields(peer = %incoming.peer_addr()),
+    skip_all
+)]
+async fn route_peer(incoming: IncomingPeer, shared: Arc<Shared>, torrents: Arc<Mutex<Torrents>>) {
+    let info_hashes: Vec<InfoHash> = torrents.lock().unwrap().handles.keys().cloned().collect();
+    let identify = incoming.identify(&info_hashes, shared.config.encryption);
+    let inbound =
+        match tokio::time::timeout(shared.config.connection_limits.connect_timeout, identify).await
+        {
+            Ok(Ok(inbound)) => inbound,
+            Ok(Err(err)) => {
+                info!(%err, "failed to identify inbound peer");
+                return;
+            }
+            Err(_) => {
+                info!("inbound peer didn't identify itself in time");
+                return;
+            }
+        };
+
+    let torrents = torrents.lock().unwrap();
+    match torrents.handles.get(inbound.info_hash()) {
+        Some(handle) => {
+            let _ = handle
+                .commands_tx
+                .send(TorrentCommand::Accept(Box::new(inbound)));
+        }
+        None => debug!("torrent was removed in the meantime, dropping inbound peer"),
+    }
+}
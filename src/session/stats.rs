@@ -0,0 +1,550 @@
+use crate::metainfo::{DownloadInfo, FilePriority};
+use crate::metrics::Histogram;
+use crate::peers::{PeerStats, PieceIndex};
+use crate::torrent::InfoHash;
+use crate::tracker::request::Requestable;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum TorrentState {
+    /// waiting for one of the session's active slots to free up.
+    Queued,
+    /// asking the tracker for peers.
+    Announcing,
+    Downloading,
+    Paused,
+    /// every wanted piece is there, the torrent keeps seeding. wanting a skipped file again
+    /// queues it up to download once more.
+    Complete,
+    Failed,
+}
+
+impl TorrentState {
+    /// whether the torrent takes up one of the session's active slots, complete torrents seed
+    /// without one.
+    pub fn is_active(self) -> bool {
+        matches!(self, Self::Announcing | Self::Downloading)
+    }
+}
+
+/// a snapshot of how a torrent is doing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TorrentStatus {
+    #[serde(with = "crate::torrent::as_hex")]
+    pub info_hash: InfoHash,
+    pub name: String,
+    pub state: TorrentState,
+    /// why the torrent failed, if it did.
+    pub error: Option<String>,
+    /// the order the torrent was added to the session in, queued torrents start in this order.
+    pub queue_position: u64,
+    pub total_length: u64,
+    /// the length of the files which aren't skipped.
+    pub wanted_length: u64,
+    pub piece_length: u64,
+    pub num_pieces: usize,
+    pub pieces_done: usize,
+    /// bytes of the pieces which passed the hash check.
+    pub bytes_done: u64,
+    /// payload bytes transferred with peers so far, including the ones thrown away.
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// bytes per second, averaged over the last few seconds.
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    pub peers: usize,
+}
+
+/// a snapshot of a peer the torrent is connected to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub addr: SocketAddrV4,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// bytes received from the peer which had to be thrown away.
+    pub wasted: u64,
+    pub peer_interested: bool,
+    pub snubbed: bool,
+    /// the client the peer runs, as named by the peer or guessed from its peer id.
+    pub client: Option<String>,
+}
+
+/// how far along a file of a torrent is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileStatus {
+    /// relative to the download directory.
+    pub path: String,
+    pub length: u64,
+    /// bytes of the file in pieces which passed the hash check.
+    pub bytes_done: u64,
+    pub priority: FilePriority,
+}
+
+/// how the last announce to a tracker went.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackerInfo {
+    pub url: String,
+    /// peers handed out by the last successful announce.
+    pub peers: usize,
+    /// seconds the tracker asked to wait between announces.
+    pub interval: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// how many of the connected peers are in each state, a peer can be in several at once.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct PeerCounts {
+    pub connected: usize,
+    pub unchoked_by_peer: usize,
+    pub unchoked_by_us: usize,
+    pub interested: usize,
+    pub snubbed: usize,
+}
+
+/// how the torrent is being read while it downloads, the engine picks pieces to match.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Streaming {
+    pub sequential: bool,
+    /// the piece each open file stream is at, by stream id.
+    pub positions: BTreeMap<u64, PieceIndex>,
+}
+
+/// progress of a torrent, updated by its task and engine as they go, so that a status can be
+/// put together without having to round trip through either.
+#[derive(Debug)]
+pub(crate) struct TorrentStats {
+    info_hash: InfoHash,
+    name: String,
+    queue_position: u64,
+    total_length: u64,
+    piece_length: u64,
+    num_pieces: usize,
+    files: Vec<(String, u64)>,
+    // the engine watches these, to pick pieces and store them accordingly.
+    priorities: watch::Sender<Vec<FilePriority>>,
+    streaming: watch::Sender<Streaming>,
+    next_stream_id: AtomicU64,
+    // woken up whenever a piece is done, the state or priorities change or the torrent is
+    // removed, for readers waiting on pieces.
+    progress: Notify,
+    // the torrent is gone from the session, while its file streams may still be around.
+    removed: AtomicBool,
+    // the engine watches it too, to drop its peers on pause.
+    state: watch::Sender<TorrentState>,
+    // set by the engine once every wanted piece is there, and unset when more pieces are wanted
+    // after all. the task watches it to move between downloading and complete.
+    complete: watch::Sender<bool>,
+    error: Mutex<Option<String>>,
+    pieces_done: AtomicUsize,
+    // which pieces passed the hash check, i.e the piece map.
+    have: Mutex<Vec<bool>>,
+    bytes_done: AtomicU64,
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    download_rate: AtomicU64,
+    upload_rate: AtomicU64,
+    hash_failures: AtomicU64,
+    // request timeouts of the peers which are gone, the connected ones keep their own count.
+    past_request_timeouts: AtomicU64,
+    announce_latency: Histogram,
+    announce_errors: AtomicU64,
+    disk_read_latency: Histogram,
+    disk_write_latency: Histogram,
+    peers: Mutex<HashMap<SocketAddrV4, Arc<PeerStats>>>,
+    trackers: Mutex<Vec<TrackerInfo>>,
+}
+
+impl TorrentStats {
+    // seconds, trackers which take longer than the last bound are as good as down.
+    const ANNOUNCE_LATENCY_BOUNDS: &'static [f64] =
+        &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+    // seconds, a piece read or written by the storage.
+    const DISK_LATENCY_BOUNDS: &'static [f64] = &[
+        0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+    ];
+
+    pub fn new(info_hash: InfoHash, download_info: &DownloadInfo, queue_position: u64) -> Self {
+        Self {
+            info_hash,
+            name: download_info.name().to_string(),
+            queue_position,
+            total_length: download_info.get_request_length() as u64,
+            piece_length: download_info.piece_length() as u64,
+            num_pieces: download_info.piece_hashes().len(),
+            files: download_info
+                .files()
+                .into_iter()
+                .map(|(path, length)| (path.display().to_string(), length as u64))
+                .collect(),
+            priorities: watch::Sender::new(download_info.file_priorities()),
+            streaming: watch::Sender::new(Streaming::default()),
+            next_stream_id: AtomicU64::new(0),
+            progress: Notify::new(),
+            removed: AtomicBool::new(false),
+            state: watch::Sender::new(TorrentState::Queued),
+            complete: watch::Sender::new(false),
+            error: Mutex::default(),
+            pieces_done: AtomicUsize::new(0),
+            have: Mutex::new(vec![false; download_info.piece_hashes().len()]),
+            bytes_done: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            download_rate: AtomicU64::new(0),
+            upload_rate: AtomicU64::new(0),
+            hash_failures: AtomicU64::new(0),
+            past_request_timeouts: AtomicU64::new(0),
+            announce_latency: Histogram::new(Self::ANNOUNCE_LATENCY_BOUNDS),
+            announce_errors: AtomicU64::new(0),
+            disk_read_latency: Histogram::new(Self::DISK_LATENCY_BOUNDS),
+            disk_write_latency: Histogram::new(Self::DISK_LATENCY_BOUNDS),
+            peers: Mutex::default(),
+            trackers: Mutex::default(),
+        }
+    }
+
+    pub fn info_hash(&self) -> &InfoHash {
+        &self.info_hash
+    }
+
+    pub fn state(&self) -> TorrentState {
+        *self.state.borrow()
+    }
+
+    pub fn set_state(&self, state: TorrentState) {
+        self.state.send_replace(state);
+        self.progress.notify_waiters();
+    }
+
+    pub fn watch_state(&self) -> watch::Receiver<TorrentState> {
+        self.state.subscribe()
+    }
+
+    pub fn set_complete(&self, complete: bool) {
+        self.complete.send_if_modified(|current| {
+            let changed = *current != complete;
+            *current = complete;
+            changed
+        });
+    }
+
+    pub fn watch_complete(&self) -> watch::Receiver<bool> {
+        self.complete.subscribe()
+    }
+
+    pub fn queue_position(&self) -> u64 {
+        self.queue_position
+    }
+
+    pub fn set_error(&self, error: String) {
+        *self.error.lock().unwrap() = Some(error);
+    }
+
+    /// adds the bytes transferred with peers over the last `elapsed_secs`, which the rates are
+    /// worked out from.
+    pub fn record_transfer(&self, downloaded: u64, uploaded: u64, elapsed_secs: f64) {
+        self.downloaded.fetch_add(downloaded, Ordering::Relaxed);
+        self.uploaded.fetch_add(uploaded, Ordering::Relaxed);
+        self.download_rate
+            .store((downloaded as f64 / elapsed_secs) as u64, Ordering::Relaxed);
+        self.upload_rate
+            .store((uploaded as f64 / elapsed_secs) as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_piece_done(&self, index: usize, length: u32) {
+        self.have.lock().unwrap()[index] = true;
+        self.pieces_done.fetch_add(1, Ordering::Relaxed);
+        self.bytes_done.fetch_add(length as u64, Ordering::Relaxed);
+        self.progress.notify_waiters();
+    }
+
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    /// where the file starts within the torrent, and its length.
+    pub fn file_span(&self, index: usize) -> Option<(u64, u64)> {
+        let length = self.files.get(index)?.1;
+        let offset = self.files[..index].iter().map(|(_, length)| length).sum();
+        Some((offset, length))
+    }
+
+    pub fn has_piece(&self, index: PieceIndex) -> bool {
+        self.have
+            .lock()
+            .unwrap()
+            .get(index)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    pub fn set_removed(&self) {
+        self.removed.store(true, Ordering::Relaxed);
+        self.progress.notify_waiters();
+    }
+
+    /// waits until the piece passed the hash check. fails if the piece isn't ever going to be
+    /// downloaded, i.e the torrent failed, was removed, or completed without it because it only
+    /// belongs to skipped files. a piece of a file which was wanted again after completing is
+    /// waited for, the torrent goes back to downloading.
+    pub async fn wait_for_piece(&self, index: PieceIndex) -> Result<(), &'static str> {
+        loop {
+            let notified = self.progress.notified();
+            tokio::pin!(notified);
+            // registered before checking, so that a piece which is done in between isn't missed.
+            notified.as_mut().enable();
+            if self.has_piece(index) {
+                return Ok(());
+            }
+            if self.removed.load(Ordering::Relaxed) {
+                return Err("torrent was removed before the piece was downloaded");
+            }
+            match self.state() {
+                TorrentState::Failed => {
+                    return Err("torrent failed before the piece was downloaded")
+                }
+                TorrentState::Complete if !self.is_piece_wanted(index) => {
+                    return Err("torrent completed without the piece, its file was skipped")
+                }
+                _ => {}
+            }
+            notified.await;
+        }
+    }
+
+    // whether any of the files the piece covers isn't skipped.
+    fn is_piece_wanted(&self, index: PieceIndex) -> bool {
+        let start = index as u64 * self.piece_length;
+        let end = start + self.piece_length;
+        let priorities = self.priorities.borrow();
+        let mut offset = 0;
+        self.files
+            .iter()
+            .zip(priorities.iter())
+            .any(|((_, length), &priority)| {
+                let (file_start, file_end) = (offset, offset + length);
+                offset = file_end;
+                file_start < end && start < file_end && priority != FilePriority::Skip
+            })
+    }
+
+    /// returns false if the torrent has no such file.
+    pub fn set_file_priority(&self, index: usize, priority: FilePriority) -> bool {
+        if index >= self.files.len() {
+            return false;
+        }
+        self.priorities.send_if_modified(|priorities| {
+            let changed = priorities[index] != priority;
+            priorities[index] = priority;
+            changed
+        });
+        // readers give up on skipped pieces of a complete torrent.
+        self.progress.notify_waiters();
+        true
+    }
+
+    pub fn watch_priorities(&self) -> watch::Receiver<Vec<FilePriority>> {
+        self.priorities.subscribe()
+    }
+
+    pub fn set_sequential(&self, sequential: bool) {
+        self.streaming.send_if_modified(|streaming| {
+            let changed = streaming.sequential != sequential;
+            streaming.sequential = sequential;
+            changed
+        });
+    }
+
+    pub fn new_stream_id(&self) -> u64 {
+        self.next_stream_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// moves the stream to the piece, `None` once it's closed.
+    pub fn set_read_position(&self, stream_id: u64, index: Option<PieceIndex>) {
+        self.streaming.send_if_modified(|streaming| match index {
+            Some(index) => streaming.positions.insert(stream_id, index) != Some(index),
+            None => streaming.positions.remove(&stream_id).is_some(),
+        });
+    }
+
+    pub fn watch_streaming(&self) -> watch::Receiver<Streaming> {
+        self.streaming.subscribe()
+    }
+
+    pub fn add_peer(&self, peer_addr: SocketAddrV4, stats: Arc<PeerStats>) {
+        self.peers.lock().unwrap().insert(peer_addr, stats);
+    }
+
+    pub fn remove_peer(&self, peer_addr: &SocketAddrV4) {
+        if let Some(stats) = self.peers.lock().unwrap().remove(peer_addr) {
+            self.past_request_timeouts
+                .fetch_add(stats.request_timeouts(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_hash_failure(&self) {
+        self.hash_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hash_failures(&self) -> u64 {
+        self.hash_failures.load(Ordering::Relaxed)
+    }
+
+    /// block requests which timed out, across every peer the torrent was ever connected to.
+    pub fn request_timeouts(&self) -> u64 {
+        let peers = self.peers.lock().unwrap();
+        let connected: u64 = peers.values().map(|stats| stats.request_timeouts()).sum();
+        self.past_request_timeouts.load(Ordering::Relaxed) + connected
+    }
+
+    /// records how long an attempt at announcing took, and whether it failed.
+    pub fn record_announce(&self, latency: Duration, failed: bool) {
+        self.announce_latency.observe(latency);
+        if failed {
+            self.announce_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn announce_latency(&self) -> &Histogram {
+        &self.announce_latency
+    }
+
+    pub fn announce_errors(&self) -> u64 {
+        self.announce_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn record_disk_read(&self, latency: Duration) {
+        self.disk_read_latency.observe(latency);
+    }
+
+    pub fn record_disk_write(&self, latency: Duration) {
+        self.disk_write_latency.observe(latency);
+    }
+
+    pub fn disk_read_latency(&self) -> &Histogram {
+        &self.disk_read_latency
+    }
+
+    pub fn disk_write_latency(&self) -> &Histogram {
+        &self.disk_write_latency
+    }
+
+    pub fn peer_counts(&self) -> PeerCounts {
+        let peers = self.peers.lock().unwrap();
+        peers.values().fold(
+            PeerCounts {
+                connected: peers.len(),
+                ..PeerCounts::default()
+            },
+            |mut counts, stats| {
+                counts.unchoked_by_peer += stats.unchoked_by_peer() as usize;
+                counts.unchoked_by_us += stats.unchoked_by_us() as usize;
+                counts.interested += stats.peer_interested() as usize;
+                counts.snubbed += stats.snubbed() as usize;
+                counts
+            },
+        )
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .iter()
+            .map(|(addr, stats)| PeerInfo {
+                addr: *addr,
+                downloaded: stats.downloaded(),
+                uploaded: stats.uploaded(),
+                wasted: stats.wasted(),
+                peer_interested: stats.peer_interested(),
+                snubbed: stats.snubbed(),
+                client: stats.client().map(str::to_string),
+            })
+            .collect()
+    }
+
+    /// which pieces passed the hash check, by index.
+    pub fn pieces(&self) -> Vec<bool> {
+        self.have.lock().unwrap().clone()
+    }
+
+    pub fn files(&self) -> Vec<FileStatus> {
+        let have = self.have.lock().unwrap();
+        let priorities = self.priorities.borrow();
+        let mut offset = 0;
+        self.files
+            .iter()
+            .zip(priorities.iter())
+            .map(|((path, length), priority)| {
+                let (start, end) = (offset, offset + length);
+                offset = end;
+                let bytes_done = if *length == 0 {
+                    0
+                } else {
+                    let first = (start / self.piece_length) as usize;
+                    let last = ((end - 1) / self.piece_length) as usize;
+                    (first..=last)
+                        .filter(|&index| have[index])
+                        .map(|index| {
+                            let piece_start = index as u64 * self.piece_length;
+                            let piece_end = piece_start + self.piece_length;
+                            piece_end.min(end) - piece_start.max(start)
+                        })
+                        .sum()
+                };
+                FileStatus {
+                    path: path.clone(),
+                    length: *length,
+                    bytes_done,
+                    priority: *priority,
+                }
+            })
+            .collect()
+    }
+
+    /// records how an announce went, replacing whatever was known about the tracker before.
+    pub fn set_tracker(&self, tracker: TrackerInfo) {
+        let mut trackers = self.trackers.lock().unwrap();
+        match trackers.iter_mut().find(|known| known.url == tracker.url) {
+            Some(known) => *known = tracker,
+            None => trackers.push(tracker),
+        }
+    }
+
+    pub fn trackers(&self) -> Vec<TrackerInfo> {
+        self.trackers.lock().unwrap().clone()
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        TorrentStatus {
+            info_hash: self.info_hash.clone(),
+            name: self.name.clone(),
+            state: self.state(),
+            error: self.error.lock().unwrap().clone(),
+            queue_position: self.queue_position,
+            total_length: self.total_length,
+            wanted_length: self
+                .files
+                .iter()
+                .zip(self.priorities.borrow().iter())
+                .filter(|(_, priority)| **priority != FilePriority::Skip)
+                .map(|((_, length), _)| length)
+                .sum(),
+            piece_length: self.piece_length,
+            num_pieces: self.num_pieces,
+            pieces_done: self.pieces_done.load(Ordering::Relaxed),
+            bytes_done: self.bytes_done.load(Ordering::Relaxed),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            download_rate: self.download_rate.load(Ordering::Relaxed),
+            upload_rate: self.upload_rate.load(Ordering::Relaxed),
+            peers: self.peers.lock().unwrap().len(),
+        }
+    }
+}
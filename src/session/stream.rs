@@ -0,0 +1,240 @@
+use super::TorrentStats;
+use crate::peers::PieceIndex;
+use crate::storage::Storage;
+use futures::future::BoxFuture;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::sync::Mutex;
+
+/// reads a file of a torrent while it downloads, see [`Session::open_file`]. reads wait for the
+/// pieces which aren't there yet, and the pieces right after the read position are downloaded
+/// first. seeking moves those along with it.
+///
+/// [`Session::open_file`]: super::Session::open_file
+pub struct FileStream {
+    id: u64,
+    file: usize,
+    // where the file starts within the torrent.
+    file_offset: u64,
+    length: u64,
+    position: u64,
+    stats: Arc<TorrentStats>,
+    storage: Arc<Mutex<Storage>>,
+    // what's left of the last read from storage, it starts at the position.
+    buffer: Vec<u8>,
+    fetch: Option<BoxFuture<'static, io::Result<Vec<u8>>>>,
+}
+
+impl FileStream {
+    pub(super) fn new(
+        file: usize,
+        stats: Arc<TorrentStats>,
+        storage: Arc<Mutex<Storage>>,
+    ) -> Option<Self> {
+        let (file_offset, length) = stats.file_span(file)?;
+        let stream = Self {
+            id: stats.new_stream_id(),
+            file,
+            file_offset,
+            length,
+            position: 0,
+            stats,
+            storage,
+            buffer: Vec::new(),
+            fetch: None,
+        };
+        stream.report_position();
+        Some(stream)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn piece_at(&self, position: u64) -> PieceIndex {
+        ((self.file_offset + position) / self.stats.piece_length()) as PieceIndex
+    }
+
+    fn report_position(&self) {
+        let index = (self.position < self.length).then(|| self.piece_at(self.position));
+        self.stats.set_read_position(self.id, index);
+    }
+
+    // reads from the position to the end of its piece, or of the file if that comes first.
+    fn start_fetch(&self) -> BoxFuture<'static, io::Result<Vec<u8>>> {
+        let (stats, storage) = (self.stats.clone(), self.storage.clone());
+        let (file, position) = (self.file, self.position);
+        let index = self.piece_at(position);
+        let piece_end = (index as u64 + 1) * stats.piece_length() - self.file_offset;
+        let len = (piece_end.min(self.length) - position) as usize;
+        Box::pin(async move {
+            stats
+                .wait_for_piece(index)
+                .await
+                .map_err(io::Error::other)?;
+            let mut data = vec![0; len];
+            let storage = storage.lock().await;
+            let started = Instant::now();
+            storage.read_file(file, position, &mut data).await?;
+            stats.record_disk_read(started.elapsed());
+            Ok(data)
+        })
+    }
+}
+
+impl std::fmt::Debug for FileStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileStream")
+            .field("file", &self.file)
+            .field("length", &self.length)
+            .field("position", &self.position)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for FileStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.position >= self.length || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        if self.buffer.is_empty() {
+            if self.fetch.is_none() {
+                self.report_position();
+                self.fetch = Some(self.start_fetch());
+            }
+            let result = ready!(self.fetch.as_mut().unwrap().as_mut().poll(cx));
+            self.fetch = None;
+            self.buffer = result?;
+        }
+
+        let len = buf.remaining().min(self.buffer.len());
+        buf.put_slice(&self.buffer[..len]);
+        self.buffer.drain(..len);
+        self.position += len as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for FileStream {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        let Some(position) = position else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            ));
+        };
+
+        if position != self.position {
+            self.position = position;
+            self.buffer.clear();
+            self.fetch = None;
+            self.report_position();
+        }
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+impl Drop for FileStream {
+    fn drop(&mut self) {
+        self.stats.set_read_position(self.id, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::FilePriority;
+    use crate::session::TorrentState;
+    use crate::storage::tests::{download_info, temp_dir};
+    use crate::torrent::InfoHash;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    #[tokio::test]
+    async fn test_reads_wait_for_pieces() {
+        let dir = temp_dir();
+        let info_hash = InfoHash::new([2; 20]);
+        // pieces: [0 0 0 0] [0 0 1 1] [1 1 1 1]
+        let info = download_info(&[6, 6], &[FilePriority::Normal; 2]);
+        let stats = Arc::new(TorrentStats::new(info_hash.clone(), &info, 0));
+        let storage = Arc::new(Mutex::new(Storage::new(&dir, &info_hash, &info)));
+        let mut stream = FileStream::new(1, stats.clone(), storage.clone()).unwrap();
+        assert_eq!(stream.len(), 6);
+        assert_eq!(stats.watch_streaming().borrow().positions[&stream.id], 1);
+
+        let reader = tokio::spawn(async move {
+            stream.seek(SeekFrom::Start(2)).await.unwrap();
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).await.unwrap();
+            data
+        });
+        // the reader moved on to the last piece, and waits for it.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            stats.watch_streaming().borrow().positions.values().next(),
+            Some(&2)
+        );
+        assert!(!reader.is_finished());
+
+        let piece: Vec<u8> = (0..4).collect();
+        storage.lock().await.write_piece(2, &piece).await.unwrap();
+        stats.record_piece_done(2, 4);
+        assert_eq!(reader.await.unwrap(), piece);
+        // the stream is gone along with the reader.
+        assert!(stats.watch_streaming().borrow().positions.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reads_fail_once_the_piece_wont_come() {
+        let dir = temp_dir();
+        let info_hash = InfoHash::new([3; 20]);
+        let info = download_info(&[4, 4], &[FilePriority::Normal, FilePriority::Skip]);
+        let stats = Arc::new(TorrentStats::new(info_hash.clone(), &info, 0));
+        let storage = Arc::new(Mutex::new(Storage::new(&dir, &info_hash, &info)));
+
+        // the skipped file is opened after the torrent completed without it.
+        let mut stream = FileStream::new(1, stats.clone(), storage.clone()).unwrap();
+        stats.set_state(TorrentState::Complete);
+        let err = stream.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert!(err.to_string().contains("skipped"), "{err}");
+
+        let mut stream = FileStream::new(0, stats.clone(), storage).unwrap();
+        stats.set_state(TorrentState::Downloading);
+        let reader = tokio::spawn(async move { stream.read_to_end(&mut Vec::new()).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!reader.is_finished());
+        stats.set_removed();
+        let err = reader.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("removed"), "{err}");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,448 @@
+use super::{SessionEvent, Shared, TorrentState, TorrentStats, TrackerInfo};
+use crate::connection_manager::ConnectionManager;
+use crate::dht::DhtNode;
+use crate::engine::Engine;
+use crate::metainfo::url::{HttpUrl, TrackerUrl};
+use crate::metainfo::Metainfo;
+use crate::peers::download_worker::{ConnectionConfig, InboundPeer};
+use crate::peers::{PeerAlerts, PeerRegistry};
+use crate::prelude::*;
+use crate::rate_limit::RateLimits;
+use crate::storage::Storage;
+use crate::torrent::InfoHash;
+use crate::tracker::request::TrackerRequest;
+use crate::tracker::response::TrackerResponse;
+use crate::tracker::{Announce, HttpTracker, TrackerConfig, TrackerError};
+use crate::webseed::{WebSeed, WebSeedKind};
+use reqwest::Url;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+#[derive(Debug)]
+pub(super) enum TorrentCommand {
+    /// handed out by the scheduler once there's a free active slot.
+    Start,
+    Pause,
+    Resume,
+    Accept(Box<InboundPeer>),
+}
+
+/// drives a single torrent of the session, i.e its announce, connections and engine.
+pub(super) struct TorrentTask {
+    info_hash: InfoHash,
+    metainfo: Metainfo,
+    shared: Arc<Shared>,
+    stats: Arc<TorrentStats>,
+    storage: Arc<Mutex<Storage>>,
+    // the torrent's own limits, changed by the session while the task runs.
+    rate_limits: RateLimits,
+    commands_rx: mpsc::UnboundedReceiver<TorrentCommand>,
+    // the state as far as the task is concerned, the one in the stats may have been moved ahead
+    // by the scheduler claiming a slot.
+    state: TorrentState,
+    // the peers learnt about so far, connected to again on resume.
+    known_peers: Vec<SocketAddrV4>,
+    web_seeds: Vec<(WebSeedKind, Url)>,
+    // the info dictionary, for peers which come with a magnet link.
+    metadata: Arc<Vec<u8>>,
+}
+
+impl TorrentTask {
+    const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+    // a lookup which found nobody, e.g while the dht is still bootstrapping, is retried sooner.
+    const DHT_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+    pub fn new(
+        info_hash: InfoHash,
+        metainfo: Metainfo,
+        shared: Arc<Shared>,
+        stats: Arc<TorrentStats>,
+        storage: Arc<Mutex<Storage>>,
+        rate_limits: RateLimits,
+        commands_rx: mpsc::UnboundedReceiver<TorrentCommand>,
+    ) -> Self {
+        let metadata = serde_bencode::to_bytes(&metainfo.file_info)
+            .expect("info hash was already computed from the same bytes");
+        Self {
+            info_hash,
+            web_seeds: web_seeds(&metainfo),
+            metadata: Arc::new(metadata),
+            metainfo,
+            shared,
+            stats,
+            storage,
+            rate_limits,
+            commands_rx,
+            state: TorrentState::Queued,
+            known_peers: Vec::new(),
+        }
+    }
+
+    #[instrument(level = "info", name = "torrent", skip_all, fields(info_hash = ?self.info_hash))]
+    pub async fn run(mut self) {
+        if !self.wait_for_start().await {
+            return;
+        }
+
+        self.set_state(TorrentState::Announcing);
+        match self.announce().await {
+            Ok(response) => self.known_peers = response.peer_addreses,
+            // the web seeds can still be downloaded from, and the dht can still find peers.
+            Err(err) if !self.web_seeds.is_empty() || self.dht().is_some() => {
+                warn!(%err, "tracker announce failed, carrying on without it")
+            }
+            Err(err) => {
+                warn!(%err, "tracker announce failed");
+                self.fail(err.to_string());
+                self.shared.reschedule();
+                return;
+            }
+        }
+
+        let (alerts_tx, alerts_rx) =
+            mpsc::channel::<PeerAlerts>(self.shared.config.alerts_buffer_size);
+        let config = &self.shared.config;
+        let engine = Engine::new(
+            alerts_rx,
+            &self.metainfo.file_info,
+            config.unchoke_slots,
+            config.smart_ban,
+            self.storage.clone(),
+            self.stats.clone(),
+            self.shared.events_tx.clone(),
+        );
+        let engine = engine.run();
+        tokio::pin!(engine);
+        let mut engine_done = false;
+        let mut complete_rx = self.stats.watch_complete();
+
+        // the connections are dropped while paused, which disconnects all the peers. the same
+        // goes for the web seeds. the workers don't get to say goodbye, so the engine drops
+        // them all once it sees the torrent paused. they're kept once the torrent is complete,
+        // to seed, which doesn't take up an active slot.
+        let mut connections = Some(self.start_connections(&alerts_tx));
+        let mut web_seeds = Some(self.start_web_seeds(&alerts_tx));
+        let (dht_peers_tx, mut dht_peers_rx) = mpsc::channel(1);
+        let mut dht_lookups = self.start_dht_lookups(dht_peers_tx);
+        self.set_state(TorrentState::Downloading);
+
+        loop {
+            tokio::select! {
+                _ = async { connections.as_mut().unwrap().next_event().await },
+                    if connections.is_some() => {}
+
+                Some(Err(err)) = async { web_seeds.as_mut().unwrap().join_next().await },
+                    if web_seeds.is_some() => {
+                    if err.is_panic() {
+                        warn!(%err, "web seed panicked");
+                    }
+                }
+
+                Some(Err(err)) = async { dht_lookups.as_mut().unwrap().join_next().await },
+                    if dht_lookups.is_some() => {
+                    if err.is_panic() {
+                        warn!(%err, "dht lookups panicked");
+                    }
+                }
+
+                Some(peers) = dht_peers_rx.recv() => self.add_peers(peers, connections.as_mut()),
+
+                command = self.commands_rx.recv() => match command {
+                    Some(TorrentCommand::Pause)
+                        if matches!(
+                            self.state,
+                            TorrentState::Downloading | TorrentState::Queued | TorrentState::Complete
+                        ) =>
+                    {
+                        info!("pausing torrent");
+                        connections = None;
+                        web_seeds = None;
+                        self.set_state(TorrentState::Paused);
+                        self.shared.reschedule();
+                    }
+                    Some(TorrentCommand::Resume) if self.state == TorrentState::Paused => {
+                        info!("queueing torrent to resume");
+                        self.set_state(TorrentState::Queued);
+                        self.shared.reschedule();
+                    }
+                    Some(TorrentCommand::Start) if self.state == TorrentState::Queued => {
+                        info!("resuming torrent");
+                        // a complete torrent which wants more pieces is still seeding.
+                        if connections.is_none() {
+                            connections = Some(self.start_connections(&alerts_tx));
+                            web_seeds = Some(self.start_web_seeds(&alerts_tx));
+                        }
+                        self.set_state(TorrentState::Downloading);
+                        if *complete_rx.borrow() {
+                            self.complete();
+                        }
+                    }
+                    // paused before the start came through, the slot goes to someone else.
+                    Some(TorrentCommand::Start) => self.shared.reschedule(),
+                    Some(TorrentCommand::Accept(inbound)) => match connections.as_mut() {
+                        Some(connections) => connections.accept(*inbound),
+                        None => debug!(peer = %inbound.peer_addr(), "torrent paused, dropping inbound peer"),
+                    },
+                    Some(command) => debug!(?command, "ignoring command"),
+                    None => break,
+                },
+
+                Ok(()) = complete_rx.changed(), if !engine_done => {
+                    let complete = *complete_rx.borrow_and_update();
+                    match self.state {
+                        TorrentState::Downloading if complete => self.complete(),
+                        // a skipped file was wanted again, it waits for a slot like any other
+                        // download.
+                        TorrentState::Complete if !complete => {
+                            info!("more pieces wanted, queueing torrent to download them");
+                            self.set_state(TorrentState::Queued);
+                            self.shared.reschedule();
+                        }
+                        _ => {}
+                    }
+                }
+
+                Err(err) = &mut engine, if !engine_done => {
+                    engine_done = true;
+                    connections = None;
+                    web_seeds = None;
+                    dht_lookups = None;
+                    warn!(%err, "engine stopped");
+                    self.fail(err.to_string());
+                    self.shared.reschedule();
+                }
+            }
+        }
+    }
+
+    /// waits in the queue until the scheduler hands out a slot, returns false if the torrent was
+    /// removed in the meantime.
+    async fn wait_for_start(&mut self) -> bool {
+        while let Some(command) = self.commands_rx.recv().await {
+            match command {
+                TorrentCommand::Start if self.state == TorrentState::Queued => return true,
+                TorrentCommand::Start => self.shared.reschedule(),
+                TorrentCommand::Pause if self.state == TorrentState::Queued => {
+                    self.set_state(TorrentState::Paused)
+                }
+                TorrentCommand::Resume if self.state == TorrentState::Paused => {
+                    self.set_state(TorrentState::Queued);
+                    self.shared.reschedule();
+                }
+                TorrentCommand::Accept(inbound) => {
+                    debug!(peer = %inbound.peer_addr(), "torrent not started, dropping inbound peer")
+                }
+                command => debug!(?command, "ignoring command"),
+            }
+        }
+        false
+    }
+
+    fn start_connections(&self, alerts_tx: &mpsc::Sender<PeerAlerts>) -> ConnectionManager {
+        let config = &self.shared.config;
+        let connection_config = ConnectionConfig {
+            encryption: config.encryption,
+            utp_socket: Some(self.shared.utp_socket.clone()),
+            rate_limiters: self.shared.rate_limiters.clone().with(&self.rate_limits),
+            peer_rate_limits: config.peer_rate_limits,
+            peer_registry: PeerRegistry::new(),
+            worker: config.worker,
+            metadata: Some(self.metadata.clone()),
+        };
+
+        let mut connections = ConnectionManager::new(
+            self.info_hash.clone(),
+            self.shared.peer_id.clone(),
+            connection_config,
+            alerts_tx.clone(),
+            config.connection_limits.clone(),
+            self.shared.counts.clone(),
+        );
+        connections.add_peers(self.known_peers.iter().copied());
+        connections
+    }
+
+    // the seeds are aborted along with the join set.
+    fn start_web_seeds(&self, alerts_tx: &mpsc::Sender<PeerAlerts>) -> JoinSet<()> {
+        let mut tasks = JoinSet::new();
+        for (kind, url) in &self.web_seeds {
+            let seed = WebSeed::new(
+                *kind,
+                url.clone(),
+                &self.metainfo.file_info,
+                self.shared.http_client.clone(),
+                self.shared
+                    .rate_limiters
+                    .download
+                    .clone()
+                    .with(self.rate_limits.download.clone()),
+                alerts_tx.clone(),
+            );
+            tasks.spawn(seed.run());
+        }
+        tasks
+    }
+
+    /// the session's dht node, unless the torrent is private.
+    fn dht(&self) -> Option<&DhtNode> {
+        let private = self.metainfo.file_info.is_private();
+        self.shared.dht.as_ref().filter(|_| !private)
+    }
+
+    // looks the torrent up every so often for as long as the task runs, announcing ourselves on
+    // the way. the lookups are aborted along with the join set.
+    fn start_dht_lookups(&self, peers_tx: mpsc::Sender<Vec<SocketAddrV4>>) -> Option<JoinSet<()>> {
+        let dht = self.dht()?.clone();
+        let info_hash = self.info_hash.clone();
+        let port = self.shared.config.port;
+        let mut tasks = JoinSet::new();
+        tasks.spawn(async move {
+            loop {
+                let peers = dht.announce(&info_hash, port).await;
+                debug!(peers = peers.len(), "dht lookup done");
+                if peers.is_empty() {
+                    tokio::time::sleep(Self::DHT_RETRY_INTERVAL).await;
+                    continue;
+                }
+                if peers_tx.send(peers).await.is_err() {
+                    break;
+                }
+                tokio::time::sleep(Self::DHT_ANNOUNCE_INTERVAL).await;
+            }
+        });
+        Some(tasks)
+    }
+
+    // remembered for resuming, and connected to right away unless paused.
+    fn add_peers(&mut self, peers: Vec<SocketAddrV4>, connections: Option<&mut ConnectionManager>) {
+        for peer in &peers {
+            if !self.known_peers.contains(peer) {
+                self.known_peers.push(*peer);
+            }
+        }
+        if let Some(connections) = connections {
+            connections.add_peers(peers);
+        }
+    }
+
+    async fn announce(&self) -> Result<TrackerResponse, TrackerError> {
+        let request = TrackerRequest::new(
+            self.shared.peer_id.clone(),
+            self.shared.config.port,
+            &self.metainfo.file_info,
+        )
+        .expect("info hash was already computed when the torrent was added");
+
+        let Some(tracker) = &self.metainfo.announce else {
+            return Err(TrackerError::NoTracker);
+        };
+        let result = match tracker {
+            // TODO: handle udp trackers, BEP: https://www.bittorrent.org/beps/bep_0015.html
+            TrackerUrl::Udp(_udp_url) => {
+                self.stats.record_announce(Duration::ZERO, true);
+                Err(TrackerError::Unsupported("udp"))
+            }
+            TrackerUrl::Http(http_url) => {
+                announce_with_retries(
+                    &self.shared.http_client,
+                    http_url.clone(),
+                    &request,
+                    &self.shared.config.tracker,
+                    &self.stats,
+                )
+                .await
+            }
+        };
+
+        let url = tracker.as_ref().to_string();
+        self.stats.set_tracker(match &result {
+            Ok(response) => TrackerInfo {
+                url,
+                peers: response.peer_addreses.len(),
+                interval: Some(response.request_interval_seconds),
+                error: None,
+            },
+            Err(err) => TrackerInfo {
+                url,
+                peers: 0,
+                interval: None,
+                error: Some(err.to_string()),
+            },
+        });
+        result
+    }
+
+    // the download is done, the slot goes to the next torrent while this one seeds.
+    fn complete(&mut self) {
+        self.set_state(TorrentState::Complete);
+        self.emit(SessionEvent::TorrentCompleted {
+            info_hash: self.info_hash.clone(),
+        });
+        self.shared.reschedule();
+    }
+
+    fn set_state(&mut self, state: TorrentState) {
+        self.state = state;
+        self.stats.set_state(state);
+        self.emit(SessionEvent::StateChanged {
+            info_hash: self.info_hash.clone(),
+            state,
+        });
+    }
+
+    fn fail(&mut self, error: String) {
+        self.stats.set_error(error.clone());
+        self.set_state(TorrentState::Failed);
+        self.emit(SessionEvent::TorrentFailed {
+            info_hash: self.info_hash.clone(),
+            error,
+        });
+    }
+
+    fn emit(&self, event: SessionEvent) {
+        let _ = self.shared.events_tx.send(event);
+    }
+}
+
+// both kinds of web seeds, they're told apart by the way pieces are asked for.
+fn web_seeds(metainfo: &Metainfo) -> Vec<(WebSeedKind, Url)> {
+    let url_list = metainfo
+        .web_seeds()
+        .into_iter()
+        .map(|url| (WebSeedKind::UrlList, url));
+    let http_seeds = metainfo
+        .http_seeds()
+        .into_iter()
+        .map(|url| (WebSeedKind::HttpSeed, url));
+    url_list.chain(http_seeds).collect()
+}
+
+// tracker errors which may go away on their own are retried a few times, before giving up.
+async fn announce_with_retries(
+    client: &reqwest::Client,
+    announce_url: HttpUrl,
+    request: &TrackerRequest,
+    config: &TrackerConfig,
+    stats: &TorrentStats,
+) -> Result<TrackerResponse, TrackerError> {
+    let mut attempt = 1;
+    loop {
+        let started = Instant::now();
+        let result = HttpTracker::new(client, announce_url.clone())
+            .announce(request)
+            .await;
+        stats.record_announce(started.elapsed(), result.is_err());
+        match result {
+            Err(err) if err.is_retryable() && attempt < config.announce_attempts => {
+                warn!(%err, attempt, "tracker announce failed, retrying");
+                tokio::time::sleep(config.retry_delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
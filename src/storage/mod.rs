@@ -0,0 +1,369 @@
+//! where the pieces of a torrent end up on disk. pieces are written into the files they span,
+//! except for the parts which fall in skipped files, which aren't created at all. pieces which
+//! are shared with a skipped file are kept whole in a part file instead, so that they're still
+//! around if the file is wanted after all.
+use crate::metainfo::{DownloadInfo, FilePriority};
+use crate::peers::PieceIndex;
+use crate::prelude::*;
+use crate::torrent::{Bitfield, InfoHash};
+use std::collections::BTreeSet;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// a file of the torrent, as laid out one after the other in the pieces.
+#[derive(Debug)]
+struct FileSpan {
+    path: PathBuf,
+    offset: u64,
+    length: u64,
+}
+
+/// a part of a piece which falls in a single file.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    file: usize,
+    file_offset: u64,
+    piece_offset: usize,
+    length: usize,
+}
+
+#[derive(Debug)]
+pub(crate) struct Storage {
+    files: Vec<FileSpan>,
+    priorities: Vec<FilePriority>,
+    piece_length: u64,
+    total_length: u64,
+    download_dir: PathBuf,
+    // holds every piece at index * piece_length, it's sparse so the gaps take up no space.
+    part_path: PathBuf,
+    // the pieces which are in the part file.
+    parts: BTreeSet<PieceIndex>,
+}
+
+impl Storage {
+    pub fn new(download_dir: &Path, info_hash: &InfoHash, download_info: &DownloadInfo) -> Self {
+        let mut offset = 0;
+        let files = download_info
+            .files()
+            .into_iter()
+            .map(|(path, length)| {
+                let span = FileSpan {
+                    path: download_dir.join(path),
+                    offset,
+                    length: length as u64,
+                };
+                offset += length as u64;
+                span
+            })
+            .collect();
+
+        Self {
+            files,
+            priorities: download_info.file_priorities(),
+            piece_length: download_info.piece_length() as u64,
+            total_length: offset,
+            download_dir: download_dir.to_path_buf(),
+            part_path: download_dir.join(format!(".{info_hash}.parts")),
+            parts: BTreeSet::new(),
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.total_length.div_ceil(self.piece_length) as usize
+    }
+
+    /// the highest priority of the files the piece spans, it's only skipped if all of them are.
+    pub fn piece_priority(&self, index: PieceIndex) -> FilePriority {
+        self.segments(index)
+            .map(|segment| self.priorities[segment.file])
+            .max()
+            .unwrap_or(FilePriority::Skip)
+    }
+
+    pub fn piece_priorities(&self) -> Vec<FilePriority> {
+        (0..self.num_pieces())
+            .map(|index| self.piece_priority(index))
+            .collect()
+    }
+
+    pub async fn write_piece(&mut self, index: PieceIndex, piece: &[u8]) -> std::io::Result<()> {
+        let mut shares_skipped = false;
+        for segment in self.segments(index) {
+            if self.priorities[segment.file] == FilePriority::Skip {
+                shares_skipped = true;
+                continue;
+            }
+            let data = &piece[segment.piece_offset..segment.piece_offset + segment.length];
+            let file = &self.files[segment.file];
+            write_at(&file.path, segment.file_offset, data).await?;
+        }
+
+        if shares_skipped {
+            debug!(index, "keeping piece in the part file");
+            write_at(&self.part_path, self.piece_offset(index), piece).await?;
+            self.parts.insert(index);
+        }
+        Ok(())
+    }
+
+    /// reads a piece back from wherever it was written to.
+    pub async fn read_piece(&self, index: PieceIndex) -> std::io::Result<Vec<u8>> {
+        let mut piece = vec![0; self.piece_len(index)];
+        self.read_block(index, 0, &mut piece).await?;
+        Ok(piece)
+    }
+
+    /// reads the part of a piece from `begin` on, `buf` mustn't run past the end of the piece.
+    pub async fn read_block(
+        &self,
+        index: PieceIndex,
+        begin: usize,
+        buf: &mut [u8],
+    ) -> std::io::Result<()> {
+        if self.parts.contains(&index) {
+            let offset = self.piece_offset(index) + begin as u64;
+            return read_at(&self.part_path, offset, buf).await;
+        }
+
+        let end = begin + buf.len();
+        for segment in self.segments(index) {
+            let from = segment.piece_offset.max(begin);
+            let to = (segment.piece_offset + segment.length).min(end);
+            if from >= to {
+                continue;
+            }
+            let file = &self.files[segment.file];
+            let file_offset = segment.file_offset + (from - segment.piece_offset) as u64;
+            read_at(&file.path, file_offset, &mut buf[from - begin..to - begin]).await?;
+        }
+        Ok(())
+    }
+
+    /// the length of the piece, only the last one may be shorter than the others.
+    pub fn piece_len(&self, index: PieceIndex) -> usize {
+        let start = self.piece_offset(index);
+        self.piece_length
+            .min(self.total_length.saturating_sub(start)) as usize
+    }
+
+    /// reads from a file of the torrent, `buf` mustn't run past the end of the piece `offset` is
+    /// in, which has to be there already.
+    pub async fn read_file(&self, file: usize, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let span = &self.files[file];
+        let torrent_offset = span.offset + offset;
+        let index = (torrent_offset / self.piece_length) as PieceIndex;
+        if self.parts.contains(&index) {
+            read_at(&self.part_path, torrent_offset, buf).await
+        } else {
+            read_at(&span.path, offset, buf).await
+        }
+    }
+
+    /// changes the priorities of the files, the parts of the pieces in `have` which belong to a
+    /// file which is no longer skipped are moved out of the part file into it.
+    pub async fn set_priorities(
+        &mut self,
+        priorities: Vec<FilePriority>,
+        have: &Bitfield,
+    ) -> std::io::Result<()> {
+        let unskipped: Vec<_> = (0..self.files.len())
+            .filter(|&file| {
+                self.priorities[file] == FilePriority::Skip
+                    && priorities
+                        .get(file)
+                        .is_some_and(|p| *p != FilePriority::Skip)
+            })
+            .collect();
+        self.priorities = priorities;
+
+        let parts: Vec<_> = self.parts.iter().copied().collect();
+        for index in parts {
+            if !have.get(index).is_some_and(|has| *has) {
+                continue;
+            }
+            let segments: Vec<_> = self
+                .segments(index)
+                .filter(|segment| unskipped.contains(&segment.file))
+                .collect();
+            if segments.is_empty() {
+                continue;
+            }
+
+            let piece = self.read_piece(index).await?;
+            for segment in &segments {
+                let data = &piece[segment.piece_offset..segment.piece_offset + segment.length];
+                let file = &self.files[segment.file];
+                write_at(&file.path, segment.file_offset, data).await?;
+            }
+            if self
+                .segments(index)
+                .all(|segment| self.priorities[segment.file] != FilePriority::Skip)
+            {
+                // the part file is left as is, the piece is just read from the files from here on.
+                self.parts.remove(&index);
+            }
+        }
+        Ok(())
+    }
+
+    /// deletes the files of the torrent and its part file, along with the directories they leave
+    /// empty. files which were never created are skipped.
+    pub async fn delete(&self) -> std::io::Result<()> {
+        let paths = self.files.iter().map(|file| &file.path);
+        for path in paths.clone().chain([&self.part_path]) {
+            match fs::remove_file(path).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        for path in paths {
+            let parents = path.ancestors().skip(1);
+            for dir in parents.take_while(|dir| *dir != self.download_dir) {
+                // it's still in use by other files, or went away along with a sibling.
+                if fs::remove_dir(dir).await.is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn piece_offset(&self, index: PieceIndex) -> u64 {
+        index as u64 * self.piece_length
+    }
+
+    // the files the piece spans, in order. empty files don't take up any of it.
+    fn segments(&self, index: PieceIndex) -> impl Iterator<Item = Segment> + '_ {
+        let start = self.piece_offset(index);
+        let end = start + self.piece_len(index) as u64;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(move |(_, file)| file.offset < end && file.offset + file.length > start)
+            .map(move |(file, span)| {
+                let from = span.offset.max(start);
+                let to = (span.offset + span.length).min(end);
+                Segment {
+                    file,
+                    file_offset: from - span.offset,
+                    piece_offset: (from - start) as usize,
+                    length: (to - from) as usize,
+                }
+            })
+    }
+}
+
+async fn write_at(path: &Path, offset: u64, data: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let mut file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.write_all(data).await?;
+    file.flush().await
+}
+
+async fn read_at(path: &Path, offset: u64, data: &mut [u8]) -> std::io::Result<()> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.read_exact(data).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::metainfo::FileInfo;
+
+    pub(crate) fn download_info(lengths: &[usize], priorities: &[FilePriority]) -> DownloadInfo {
+        let files = lengths
+            .iter()
+            .zip(priorities)
+            .enumerate()
+            .map(|(i, (&length, &priority))| FileInfo {
+                path: vec![format!("file{i}")],
+                length,
+                md5sum: None,
+                priority,
+            })
+            .collect();
+        let total: usize = lengths.iter().sum();
+        DownloadInfo::MultiFile {
+            dirname: "torrent".to_string(),
+            files,
+            piece_length: 4,
+            pieces: vec![[0; 20]; total.div_ceil(4)],
+            private: None,
+        }
+    }
+
+    pub(crate) fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("crux-torrent-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_skipped_files_go_to_the_part_file() {
+        use FilePriority::*;
+        let dir = temp_dir();
+        let info_hash = InfoHash::new([1; 20]);
+        // pieces: [0 0 0 0] [0 0 1 1] [1 1 1 1] [2 2]
+        let info = download_info(&[6, 6, 2], &[Normal, Skip, High]);
+        let mut storage = Storage::new(&dir, &info_hash, &info);
+        assert_eq!(storage.piece_priorities(), vec![Normal, Normal, Skip, High]);
+
+        let pieces: Vec<Vec<u8>> = vec![vec![1; 4], vec![2; 4], vec![3; 4], vec![4; 2]];
+        for index in [0, 1, 3] {
+            storage.write_piece(index, &pieces[index]).await.unwrap();
+        }
+        let torrent = dir.join("torrent");
+        assert_eq!(
+            std::fs::read(torrent.join("file0")).unwrap(),
+            [1, 1, 1, 1, 2, 2]
+        );
+        assert!(!torrent.join("file1").exists());
+        assert_eq!(storage.read_piece(1).await.unwrap(), pieces[1]);
+
+        // the shared piece comes out of the part file once the file is wanted.
+        let mut have = Bitfield::repeat(false, 4);
+        for index in [0, 1, 3] {
+            have.set(index, true);
+        }
+        storage
+            .set_priorities(vec![Normal, Low, High], &have)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(torrent.join("file1")).unwrap(), [2, 2]);
+        assert_eq!(storage.piece_priorities(), vec![Normal, Normal, Low, High]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_blocks_are_read_across_files() {
+        use FilePriority::*;
+        let dir = temp_dir();
+        // pieces: [0 0 0 1] [1 1 1 1]
+        let info = download_info(&[3, 5], &[Normal, Normal]);
+        let mut storage = Storage::new(&dir, &InfoHash::new([1; 20]), &info);
+        storage.write_piece(0, &[1, 2, 3, 4]).await.unwrap();
+        storage.write_piece(1, &[5, 6, 7, 8]).await.unwrap();
+
+        let mut block = [0; 3];
+        storage.read_block(0, 1, &mut block).await.unwrap();
+        assert_eq!(block, [2, 3, 4]);
+        let mut block = [0; 2];
+        storage.read_block(1, 2, &mut block).await.unwrap();
+        assert_eq!(block, [7, 8]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,11 +1,13 @@
 use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Serialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, Hash)]
 #[serde(transparent)]
 #[repr(transparent)]
 pub struct InfoHash([u8; Self::INFO_HASH_SIZE]);
 impl InfoHash {
-    const INFO_HASH_SIZE: usize = sha1_smol::DIGEST_LENGTH;
+    pub const INFO_HASH_SIZE: usize = sha1_smol::DIGEST_LENGTH;
 }
 
 impl InfoHash {
@@ -18,3 +20,60 @@ impl AsRef<[u8; Self::INFO_HASH_SIZE]> for InfoHash {
         &self.0
     }
 }
+
+/// formatted as 40 lowercase hex digits, the way it's usually shown to users.
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("info hash must be 40 hex digits")]
+pub struct ParseInfoHashError;
+
+impl FromStr for InfoHash {
+    type Err = ParseInfoHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0; Self::INFO_HASH_SIZE];
+        hex::decode_to_slice(s, &mut bytes).map_err(|_| ParseInfoHashError)?;
+        Ok(Self(bytes))
+    }
+}
+
+/// (de)serializes an info hash as a hex string rather than raw bytes, for use with
+/// `#[serde(with = "...")]` in human readable formats like json.
+pub mod as_hex {
+    use super::InfoHash;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        info_hash: &InfoHash,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(info_hash)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<InfoHash, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_roundtrip() {
+        let info_hash = InfoHash::new(
+            *b"\x01\x23\x45\x67\x89\xab\xcd\xef\x00\x11\x22\x33\x44\x55\x66\x77\x88\x99\xaa\xff",
+        );
+        let hex = info_hash.to_string();
+        assert_eq!(hex, "0123456789abcdef00112233445566778899aaff");
+        assert_eq!(hex.parse::<InfoHash>().unwrap(), info_hash);
+        assert_eq!(hex.to_uppercase().parse::<InfoHash>().unwrap(), info_hash);
+        assert!(hex[2..].parse::<InfoHash>().is_err());
+    }
+}
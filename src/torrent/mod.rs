@@ -1,7 +1,9 @@
+//! identifiers and piece bookkeeping types shared by every layer.
 mod bitfield;
 mod info_hash;
 mod peer_id;
 
 pub use bitfield::Bitfield;
-pub use info_hash::InfoHash;
+pub(crate) use info_hash::as_hex;
+pub use info_hash::{InfoHash, ParseInfoHashError};
 pub use peer_id::PeerId;
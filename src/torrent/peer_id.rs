@@ -1,7 +1,7 @@
 use rand::distributions::{Alphanumeric, DistString};
 use serde::Serialize;
 
-#[derive(Debug, Clone, Serialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, Hash)]
 #[serde(transparent)]
 #[repr(transparent)]
 pub struct PeerId([u8; Self::PEER_ID_SIZE]);
@@ -29,6 +29,11 @@ impl PeerId {
         PeerId(peer_id)
     }
 
+    /// a peer id as received from a peer, which can be anything.
+    pub fn from_bytes(bytes: [u8; Self::PEER_ID_SIZE]) -> Self {
+        PeerId(bytes)
+    }
+
     pub fn random() -> Self {
         let mut rng = rand::thread_rng();
         let suffix = Alphanumeric.sample_string(&mut rng, Self::SUFFIX_LEN);
@@ -41,3 +46,68 @@ impl PeerId {
         )
     }
 }
+
+impl PeerId {
+    // the two letter codes of azureus style peer ids, for clients which are likely to show up.
+    const CLIENT_CODES: &'static [(&'static [u8; 2], &'static str)] = &[
+        (b"AZ", "Vuze"),
+        (b"BC", "BitComet"),
+        (b"BI", "BiglyBT"),
+        (b"BT", "BitTorrent"),
+        (b"CX", "crux-torrent"),
+        (b"DE", "Deluge"),
+        (b"FD", "Free Download Manager"),
+        (b"KT", "KTorrent"),
+        (b"LT", "libtorrent"),
+        (b"lt", "libtorrent"),
+        (b"qB", "qBittorrent"),
+        (b"RT", "rTorrent"),
+        (b"TR", "Transmission"),
+        (b"UT", "µTorrent"),
+        (b"WW", "WebTorrent"),
+        (b"XL", "Xunlei"),
+    ];
+
+    /// the client which made up the peer id, for azureus style ids like `-qB4250-`. the two
+    /// letter code is shown as is for clients which aren't known.
+    pub fn client_name(&self) -> Option<String> {
+        let [b'-', code @ .., b'-'] = &self.0[..8] else {
+            return None;
+        };
+        let (name, version) = code.split_at(2);
+        if !name.iter().all(u8::is_ascii_alphanumeric)
+            || !version.iter().all(u8::is_ascii_alphanumeric)
+        {
+            return None;
+        }
+
+        let name = Self::CLIENT_CODES
+            .iter()
+            .find(|(known, _)| known[..] == *name)
+            .map(|(_, client)| client.to_string())
+            .unwrap_or_else(|| String::from_utf8_lossy(name).into_owned());
+        // trailing zeroes are left out, but always with a major and minor version.
+        let mut version: Vec<_> = version.iter().map(|&b| (b as char).to_string()).collect();
+        while version.len() > 2 && version.last().is_some_and(|v| v == "0") {
+            version.pop();
+        }
+        Some(format!("{name} {}", version.join(".")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(b"-qB4250-abcdefghijkl", Some("qBittorrent 4.2.5"))]
+    #[case(b"-TR4000-abcdefghijkl", Some("Transmission 4.0"))]
+    #[case(b"-ZZ1230-abcdefghijkl", Some("ZZ 1.2.3"))]
+    #[case(b"M7-2-2--abcdefghijkl", None)]
+    #[case(b"\0\0\0\0\0\0\0\0abcdefghijkl", None)]
+    fn test_client_name(#[case] peer_id: &[u8; 20], #[case] expected: Option<&str>) {
+        let peer_id = PeerId::from_bytes(*peer_id);
+        assert_eq!(peer_id.client_name().as_deref(), expected);
+    }
+}
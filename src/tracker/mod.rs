@@ -1,14 +1,67 @@
+//! announcing to trackers to find peers for a torrent.
 pub mod request;
 pub mod response;
 
 use crate::metainfo::url::{HttpUrl, UdpUrl};
-use reqwest::Client as HttpClient;
+use reqwest::{Client as HttpClient, StatusCode};
+use std::future::Future;
+use std::time::Duration;
 use tokio::net::UdpSocket;
 
 use request::TrackerRequest;
 
 use self::response::{TrackerResponse, TrackerResponseResult};
 
+#[derive(Debug, thiserror::Error)]
+pub enum TrackerError {
+    #[error("tracker refused the announce: {0}")]
+    Failure(String),
+    #[error("tracker responded with http status {0}")]
+    Status(StatusCode),
+    #[error("failed to parse tracker response: {0}")]
+    Parse(#[from] serde_bencode::Error),
+    #[error("tracker request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("{0} trackers aren't supported yet")]
+    Unsupported(&'static str),
+    #[error("torrent has no tracker")]
+    NoTracker,
+}
+
+impl TrackerError {
+    /// whether announcing again later could go through, a tracker which refused us or sent
+    /// garbage isn't going to change its mind.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Failure(_) | Self::Parse(_) | Self::Unsupported(_) | Self::NoTracker => false,
+            Self::Status(status) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            Self::Http(err) => err.is_timeout() || err.is_connect(),
+        }
+    }
+}
+
+/// how announces which fail are retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackerConfig {
+    /// attempts at an announce, including the first one, before giving up on the tracker.
+    pub announce_attempts: u32,
+    /// wait between attempts.
+    pub retry_delay: Duration,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            announce_attempts: 3,
+            retry_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+// TODO: only read once udp announces are implemented.
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct UdpTracker<'a> {
     client: &'a UdpSocket,
@@ -21,6 +74,15 @@ pub struct HttpTracker<'a> {
     announce_url: HttpUrl,
 }
 
+impl<'a> UdpTracker<'a> {
+    pub fn new(client: &'a UdpSocket, announce_url: UdpUrl) -> Self {
+        Self {
+            client,
+            announce_url,
+        }
+    }
+}
+
 impl<'a> HttpTracker<'a> {
     pub fn new(client: &'a HttpClient, announce_url: HttpUrl) -> Self {
         Self {
@@ -31,21 +93,29 @@ impl<'a> HttpTracker<'a> {
 }
 
 pub trait Announce {
-    async fn announce(self, request: &TrackerRequest) -> anyhow::Result<TrackerResponse>;
+    fn announce(
+        self,
+        request: &TrackerRequest,
+    ) -> impl Future<Output = Result<TrackerResponse, TrackerError>> + Send;
 }
 
 impl<'a> Announce for HttpTracker<'a> {
-    async fn announce(self, request: &TrackerRequest) -> anyhow::Result<TrackerResponse> {
+    async fn announce(self, request: &TrackerRequest) -> Result<TrackerResponse, TrackerError> {
         let mut request_url = self.announce_url.into_inner();
         request_url.set_query(Some(&request.to_url_query()));
-        let response = self.client.get(request_url).send().await?.bytes().await?;
+        let response = self.client.get(request_url).send().await?;
+        if !response.status().is_success() {
+            return Err(TrackerError::Status(response.status()));
+        }
+        let response = response.bytes().await?;
         let response: TrackerResponseResult = serde_bencode::from_bytes(&response)?;
         response.into()
     }
 }
 
 impl<'a> Announce for UdpTracker<'a> {
-    async fn announce(self, request: &TrackerRequest) -> anyhow::Result<TrackerResponse> {
-        todo!()
+    // TODO: BEP: https://www.bittorrent.org/beps/bep_0015.html
+    async fn announce(self, _request: &TrackerRequest) -> Result<TrackerResponse, TrackerError> {
+        Err(TrackerError::Unsupported("udp"))
     }
 }
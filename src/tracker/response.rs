@@ -1,3 +1,4 @@
+use super::TrackerError;
 use serde::Deserialize;
 use std::net::SocketAddrV4;
 
@@ -22,14 +23,12 @@ pub enum TrackerResponseResult {
     },
 }
 
-impl From<TrackerResponseResult> for anyhow::Result<TrackerResponse> {
+impl From<TrackerResponseResult> for Result<TrackerResponse, TrackerError> {
     fn from(value: TrackerResponseResult) -> Self {
         type TR = TrackerResponseResult;
         match value {
             TR::Success(tracker_response) => Ok(tracker_response),
-            TR::Failure { failure_reason } => {
-                anyhow::bail!(format!("{} (Tracker)", failure_reason))
-            }
+            TR::Failure { failure_reason } => Err(TrackerError::Failure(failure_reason)),
         }
     }
 }
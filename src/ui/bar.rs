@@ -0,0 +1,73 @@
+//! a single line of progress, for when there's no terminal to draw the full ui on.
+use super::{format_bytes, format_eta, format_rate, Totals};
+use crux_torrent::Session;
+use std::io::{IsTerminal, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub struct ProgressBar {
+    session: Arc<Session>,
+    // whether the line can be rewritten in place, otherwise a new line is printed now and then.
+    interactive: bool,
+}
+
+impl ProgressBar {
+    const WIDTH: usize = 24;
+
+    pub fn new(session: Arc<Session>) -> Self {
+        Self {
+            session,
+            interactive: std::io::stderr().is_terminal(),
+        }
+    }
+
+    /// keeps redrawing the line, until it's dropped.
+    pub async fn run(&self) {
+        let period = match self.interactive {
+            true => Duration::from_secs(1),
+            false => Duration::from_secs(10),
+        };
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.draw();
+        }
+    }
+
+    /// draws the line one last time, and moves on past it.
+    pub fn finish(&self) {
+        self.draw();
+        if self.interactive {
+            eprintln!();
+        }
+    }
+
+    fn draw(&self) {
+        let line = render(&Totals::new(&self.session.torrents()));
+        let mut stderr = std::io::stderr().lock();
+        let _ = match self.interactive {
+            // carriage return and clear the line, so a shorter line doesn't leave junk behind.
+            true => write!(stderr, "\r\x1b[2K{line}"),
+            false => writeln!(stderr, "{line}"),
+        };
+        let _ = stderr.flush();
+    }
+}
+
+fn render(totals: &Totals) -> String {
+    let filled = ((totals.ratio() * ProgressBar::WIDTH as f64) as usize).min(ProgressBar::WIDTH);
+    format!(
+        "[{}{}] {:5.1}%  {} / {}  ↓ {}  ↑ {}  eta {}  {} peers  {}/{} done",
+        "#".repeat(filled),
+        "-".repeat(ProgressBar::WIDTH - filled),
+        totals.ratio() * 100.0,
+        format_bytes(totals.bytes_done),
+        format_bytes(totals.total_length),
+        format_rate(totals.download_rate),
+        format_rate(totals.upload_rate),
+        format_eta(totals.eta()),
+        totals.peers,
+        totals.complete,
+        totals.torrents,
+    )
+}
@@ -0,0 +1,141 @@
+//! showing how the download is going in the terminal, either as a full screen ui or as a single
+//! line which keeps being rewritten.
+pub mod bar;
+pub mod tui;
+
+use crux_torrent::{TorrentState, TorrentStatus};
+use std::time::Duration;
+
+/// progress of every torrent of the session added up.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Totals {
+    pub torrents: usize,
+    pub complete: usize,
+    pub total_length: u64,
+    pub bytes_done: u64,
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    pub peers: usize,
+}
+
+impl Totals {
+    pub fn new(torrents: &[TorrentStatus]) -> Self {
+        torrents.iter().fold(
+            Self {
+                torrents: torrents.len(),
+                ..Self::default()
+            },
+            |mut totals, status| {
+                totals.complete += (status.state == TorrentState::Complete) as usize;
+                totals.total_length += status.wanted_length;
+                totals.bytes_done += wanted_done(status);
+                totals.download_rate += status.download_rate;
+                totals.upload_rate += status.upload_rate;
+                totals.peers += status.peers;
+                totals
+            },
+        )
+    }
+
+    pub fn ratio(&self) -> f64 {
+        ratio(self.bytes_done, self.total_length)
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        eta(
+            self.total_length.saturating_sub(self.bytes_done),
+            self.download_rate,
+        )
+    }
+}
+
+/// how much of the wanted files of a torrent is done, between 0 and 1.
+pub fn progress(status: &TorrentStatus) -> f64 {
+    ratio(wanted_done(status), status.wanted_length)
+}
+
+// pieces shared with skipped files count towards the bytes done, but aren't wanted as a whole.
+fn wanted_done(status: &TorrentStatus) -> u64 {
+    status.bytes_done.min(status.wanted_length)
+}
+
+pub fn ratio(done: u64, total: u64) -> f64 {
+    match total {
+        0 => 1.0,
+        total => done as f64 / total as f64,
+    }
+}
+
+/// time left at the current rate, which is none when nothing is coming in.
+pub fn eta(left: u64, rate: u64) -> Option<Duration> {
+    match (left, rate) {
+        (0, _) => Some(Duration::ZERO),
+        (_, 0) => None,
+        (left, rate) => Some(Duration::from_secs(left.div_ceil(rate))),
+    }
+}
+
+/// a byte count in binary units, e.g `1.5 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+pub fn format_rate(rate: u64) -> String {
+    format!("{}/s", format_bytes(rate))
+}
+
+pub fn format_eta(eta: Option<Duration>) -> String {
+    let Some(eta) = eta else {
+        return "∞".to_string();
+    };
+    let secs = eta.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m {s:02}s"),
+        (h, m, _) if h < 24 => format!("{h}h {m:02}m"),
+        (h, _, _) => format!("{}d {:02}h", h / 24, h % 24),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(0, "0 B")]
+    #[case(1023, "1023 B")]
+    #[case(1536, "1.5 KiB")]
+    #[case(5 << 30, "5.0 GiB")]
+    fn test_format_bytes(#[case] bytes: u64, #[case] expected: &str) {
+        assert_eq!(format_bytes(bytes), expected);
+    }
+
+    #[rstest]
+    #[case(100, 0, None)]
+    #[case(0, 0, Some(0))]
+    #[case(101, 10, Some(11))]
+    fn test_eta(#[case] left: u64, #[case] rate: u64, #[case] expected: Option<u64>) {
+        assert_eq!(eta(left, rate), expected.map(Duration::from_secs));
+    }
+
+    #[rstest]
+    #[case(None, "∞")]
+    #[case(Some(42), "42s")]
+    #[case(Some(185), "3m 05s")]
+    #[case(Some(3720), "1h 02m")]
+    #[case(Some(90000), "1d 01h")]
+    fn test_format_eta(#[case] secs: Option<u64>, #[case] expected: &str) {
+        assert_eq!(format_eta(secs.map(Duration::from_secs)), expected);
+    }
+}
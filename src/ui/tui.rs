@@ -0,0 +1,347 @@
+//! the full screen ui: every torrent of the session, and the peers, files, pieces and trackers
+//! of the one which is selected.
+use super::{format_bytes, format_eta, format_rate, progress, ratio, Totals};
+use crux_torrent::metainfo::FilePriority;
+use crux_torrent::{InfoHash, Session, TorrentState, TorrentStatus};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Cell, Gauge, Paragraph, Row, Table, TableState, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// how often the screen is redrawn when no keys are pressed.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// takes over the terminal until `q` is pressed or `finished` is set, it blocks so it should be
+/// run off the runtime. returns whether the user quit.
+pub fn run(session: &Session, finished: &AtomicBool) -> std::io::Result<bool> {
+    let mut terminal = ratatui::init();
+    let result = App::default().run(&mut terminal, session, finished);
+    ratatui::restore();
+    result
+}
+
+#[derive(Debug, Default)]
+struct App {
+    torrents: TableState,
+}
+
+impl App {
+    fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        session: &Session,
+        finished: &AtomicBool,
+    ) -> std::io::Result<bool> {
+        self.torrents.select(Some(0));
+        while !finished.load(Ordering::Relaxed) {
+            let torrents = session.torrents();
+            terminal.draw(|frame| self.draw(frame, session, &torrents))?;
+
+            if !event::poll(REFRESH_INTERVAL)? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let selected = self.selected(&torrents);
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(true),
+                KeyCode::Down | KeyCode::Char('j') => self.torrents.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.torrents.select_previous(),
+                KeyCode::Char('p') => {
+                    if let Some(status) = selected {
+                        // the torrent may have gone away in the meantime, which is fine.
+                        let _ = match status.state {
+                            TorrentState::Paused => session.resume(&status.info_hash),
+                            _ => session.pause(&status.info_hash),
+                        };
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(false)
+    }
+
+    fn selected<'a>(&self, torrents: &'a [TorrentStatus]) -> Option<&'a TorrentStatus> {
+        let index = self
+            .torrents
+            .selected()?
+            .min(torrents.len().checked_sub(1)?);
+        torrents.get(index)
+    }
+
+    fn draw(&mut self, frame: &mut Frame, session: &Session, torrents: &[TorrentStatus]) {
+        let torrents_height = (torrents.len() as u16 + 3).min(10);
+        let [header, list, details, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(torrents_height),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        frame.render_widget(header_line(&Totals::new(torrents)), header);
+        self.draw_torrents(frame, list, torrents);
+        if let Some(status) = self.selected(torrents) {
+            draw_details(frame, details, session, status);
+        }
+        frame.render_widget(
+            Line::from("q quit  ↑/↓ select  p pause/resume").dark_gray(),
+            footer,
+        );
+    }
+
+    fn draw_torrents(&mut self, frame: &mut Frame, area: Rect, torrents: &[TorrentStatus]) {
+        let rows = torrents.iter().map(|status| {
+            Row::new([
+                Cell::from(status.name.clone()),
+                Cell::from(state_label(status.state)).style(state_style(status.state)),
+                Cell::from(format!("{:.1}%", progress(status) * 100.0)),
+                Cell::from(format_rate(status.download_rate)),
+                Cell::from(format_rate(status.upload_rate)),
+                Cell::from(status.peers.to_string()),
+                Cell::from(format_eta(torrent_eta(status))),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(11),
+                Constraint::Length(7),
+                Constraint::Length(12),
+                Constraint::Length(12),
+                Constraint::Length(5),
+                Constraint::Length(8),
+            ],
+        )
+        .header(
+            Row::new(["name", "state", "done", "down", "up", "peers", "eta"])
+                .style(Style::new().add_modifier(Modifier::BOLD)),
+        )
+        .highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .block(Block::bordered().title("torrents"));
+        frame.render_stateful_widget(table, area, &mut self.torrents);
+    }
+}
+
+fn header_line(totals: &Totals) -> Line<'static> {
+    Line::from(vec![
+        Span::from(format!(
+            "{}/{} torrents done  ",
+            totals.complete, totals.torrents
+        ))
+        .bold(),
+        Span::from(format!(
+            "{:.1}% of {}  ",
+            totals.ratio() * 100.0,
+            format_bytes(totals.total_length)
+        )),
+        Span::from(format!("↓ {}  ", format_rate(totals.download_rate))).green(),
+        Span::from(format!("↑ {}  ", format_rate(totals.upload_rate))).cyan(),
+        Span::from(format!("eta {}  ", format_eta(totals.eta()))),
+        Span::from(format!("{} peers", totals.peers)),
+    ])
+}
+
+fn draw_details(frame: &mut Frame, area: Rect, session: &Session, status: &TorrentStatus) {
+    let info_hash = &status.info_hash;
+    let trackers = session.trackers(info_hash).unwrap_or_default();
+    let [gauge, lists, pieces, trackers_area] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Min(5),
+        Constraint::Length(6),
+        Constraint::Length(trackers.len().clamp(1, 4) as u16 + 2),
+    ])
+    .areas(area);
+    let [peers, files] =
+        Layout::horizontal([Constraint::Percentage(55), Constraint::Percentage(45)]).areas(lists);
+
+    let label = match &status.error {
+        Some(error) => format!("failed: {error}"),
+        None => format!(
+            "{} / {}  ({}/{} pieces)",
+            format_bytes(status.bytes_done.min(status.wanted_length)),
+            format_bytes(status.wanted_length),
+            status.pieces_done,
+            status.num_pieces
+        ),
+    };
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title(status.name.clone()))
+            .gauge_style(Style::new().fg(Color::Green))
+            .ratio(progress(status).clamp(0.0, 1.0))
+            .label(label),
+        gauge,
+    );
+
+    draw_peers(frame, peers, session, info_hash);
+    draw_files(frame, files, session, info_hash);
+    draw_pieces(frame, pieces, session, info_hash);
+    draw_trackers(frame, trackers_area, &trackers);
+}
+
+fn draw_peers(frame: &mut Frame, area: Rect, session: &Session, info_hash: &InfoHash) {
+    let mut peers = session.peers(info_hash).unwrap_or_default();
+    peers.sort_by_key(|peer| std::cmp::Reverse(peer.downloaded));
+    let title = format!("peers ({})", peers.len());
+    let rows = peers.into_iter().map(|peer| {
+        let flags = match (peer.peer_interested, peer.snubbed) {
+            (true, true) => "is",
+            (true, false) => "i",
+            (false, true) => "s",
+            (false, false) => "",
+        };
+        Row::new([
+            peer.addr.to_string(),
+            peer.client.unwrap_or_else(|| "?".to_string()),
+            format_bytes(peer.downloaded),
+            format_bytes(peer.uploaded),
+            flags.to_string(),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(21),
+            Constraint::Fill(1),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(5),
+        ],
+    )
+    .header(
+        Row::new(["address", "client", "down", "up", "flags"])
+            .style(Style::new().add_modifier(Modifier::BOLD)),
+    )
+    .block(Block::bordered().title(title));
+    frame.render_widget(table, area);
+}
+
+fn draw_files(frame: &mut Frame, area: Rect, session: &Session, info_hash: &InfoHash) {
+    let files = session.files(info_hash).unwrap_or_default();
+    let rows = files.into_iter().map(|file| {
+        let row = Row::new([
+            format!("{:.1}%", ratio(file.bytes_done, file.length) * 100.0),
+            format_bytes(file.length),
+            priority_label(file.priority).to_string(),
+            file.path,
+        ]);
+        match file.priority {
+            FilePriority::Skip => row.style(Style::new().add_modifier(Modifier::DIM)),
+            _ => row,
+        }
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(6),
+            Constraint::Length(10),
+            Constraint::Length(6),
+            Constraint::Fill(1),
+        ],
+    )
+    .block(Block::bordered().title("files"));
+    frame.render_widget(table, area);
+}
+
+// every cell stands for a run of pieces when there are more pieces than cells.
+fn draw_pieces(frame: &mut Frame, area: Rect, session: &Session, info_hash: &InfoHash) {
+    let block = Block::bordered().title("pieces");
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let pieces = session.pieces(info_hash).unwrap_or_default();
+    let cells = inner.width as usize * inner.height as usize;
+    if pieces.is_empty() || cells == 0 {
+        return;
+    }
+    let per_cell = pieces.len().div_ceil(cells);
+    let spans: Vec<Span> = pieces
+        .chunks(per_cell)
+        .map(|chunk| {
+            let done = chunk.iter().filter(|have| **have).count();
+            match done {
+                0 => Span::from("·").dark_gray(),
+                done if done == chunk.len() => Span::from("█").green(),
+                _ => Span::from("▒").yellow(),
+            }
+        })
+        .collect();
+    frame.render_widget(
+        Paragraph::new(Line::from(spans)).wrap(Wrap { trim: false }),
+        inner,
+    );
+}
+
+fn draw_trackers(frame: &mut Frame, area: Rect, trackers: &[crux_torrent::session::TrackerInfo]) {
+    let rows = trackers.iter().map(|tracker| {
+        let status = match (&tracker.error, tracker.interval) {
+            (Some(error), _) => Span::from(error.clone()).red(),
+            (None, Some(interval)) => Span::from(format!("ok, every {interval}s")).green(),
+            (None, None) => Span::from("ok").green(),
+        };
+        Row::new([
+            Cell::from(tracker.url.clone()),
+            Cell::from(tracker.peers.to_string()),
+            Cell::from(status),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Percentage(50),
+            Constraint::Length(5),
+            Constraint::Fill(1),
+        ],
+    )
+    .block(Block::bordered().title("trackers"));
+    frame.render_widget(table, area);
+}
+
+fn torrent_eta(status: &TorrentStatus) -> Option<Duration> {
+    super::eta(
+        status.wanted_length.saturating_sub(status.bytes_done),
+        status.download_rate,
+    )
+}
+
+fn priority_label(priority: FilePriority) -> &'static str {
+    match priority {
+        FilePriority::Skip => "skip",
+        FilePriority::Low => "low",
+        FilePriority::Normal => "normal",
+        FilePriority::High => "high",
+    }
+}
+
+fn state_label(state: TorrentState) -> &'static str {
+    match state {
+        TorrentState::Queued => "queued",
+        TorrentState::Announcing => "announcing",
+        TorrentState::Downloading => "downloading",
+        TorrentState::Paused => "paused",
+        TorrentState::Complete => "complete",
+        TorrentState::Failed => "failed",
+        _ => "unknown",
+    }
+}
+
+fn state_style(state: TorrentState) -> Style {
+    match state {
+        TorrentState::Downloading => Style::new().fg(Color::Green),
+        TorrentState::Complete => Style::new().fg(Color::Cyan),
+        TorrentState::Failed => Style::new().fg(Color::Red),
+        TorrentState::Paused | TorrentState::Queued => Style::new().fg(Color::DarkGray),
+        _ => Style::new(),
+    }
+}
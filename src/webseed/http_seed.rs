@@ -0,0 +1,63 @@
+//! the requests of BEP 17 seeds, which take the piece in the query and answer a 503 with the
+//! number of seconds to wait before asking again.
+use crate::peers::{PieceIndex, PieceLength};
+use crate::torrent::InfoHash;
+use reqwest::header::{self, HeaderMap};
+use reqwest::Url;
+use std::time::Duration;
+
+/// asks for the whole piece, as `?info_hash=..&piece=..&ranges=..` after whatever query the seed
+/// already has. the ranges are within the piece, and inclusive.
+pub(super) fn piece_url(
+    base: &Url,
+    info_hash: &InfoHash,
+    index: PieceIndex,
+    length: PieceLength,
+) -> Url {
+    let query = format!(
+        "info_hash={}&piece={index}&ranges=0-{}",
+        urlencoding::encode_binary(&info_hash.as_ref()[..]),
+        length - 1
+    );
+    let query = match base.query() {
+        Some(existing) if !existing.is_empty() => format!("{existing}&{query}"),
+        _ => query,
+    };
+    let mut url = base.clone();
+    url.set_query(Some(&query));
+    url
+}
+
+pub(super) fn retry_after_body(body: &str) -> Option<Duration> {
+    body.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// only the number of seconds is understood, not the date form of the header.
+pub(super) fn retry_after_header(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?;
+    retry_after_body(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("http://a.test/seed.php", "http://a.test/seed.php?info_hash=%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB&piece=3&ranges=0-16383")]
+    #[case("http://a.test/seed?id=7", "http://a.test/seed?id=7&info_hash=%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB&piece=3&ranges=0-16383")]
+    fn test_piece_url(#[case] base: &str, #[case] expected: &str) {
+        let info_hash = InfoHash::new([0xab; 20]);
+        let url = piece_url(&Url::parse(base).unwrap(), &info_hash, 3, 16384);
+        assert_eq!(url.as_str(), expected);
+    }
+
+    #[rstest]
+    #[case("30", Some(Duration::from_secs(30)))]
+    #[case(" 5\n", Some(Duration::from_secs(5)))]
+    #[case("busy", None)]
+    #[case("", None)]
+    fn test_retry_after(#[case] body: &str, #[case] expected: Option<Duration>) {
+        assert_eq!(retry_after_body(body), expected);
+    }
+}
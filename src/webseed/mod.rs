@@ -0,0 +1,661 @@
+//! web seeding, i.e downloading pieces over http rather than from peers. a web seed stands in for
+//! a peer which has every piece, so the engine hands it pieces through the same picker as the
+//! peers. there are two kinds, plain http servers which host the files of the torrent (BEP 19),
+//! where pieces are fetched with range requests, one per file they span, and scripts which serve
+//! the pieces by index (BEP 17). ftp servers, which BEP 19 allows as well, aren't supported, see
+//! [`Metainfo::web_seeds`](crate::metainfo::Metainfo::web_seeds).
+mod http_seed;
+
+use crate::metainfo::DownloadInfo;
+use crate::peers::{
+    PeerAlerts, PeerCommands, PeerStats, PieceIndex, PieceRequestInfo, WebSeedAlert,
+};
+use crate::prelude::*;
+use crate::rate_limit::RateLimiter;
+use crate::torrent::InfoHash;
+use crate::tracker::request::Requestable;
+use reqwest::{header, StatusCode, Url};
+use sha1_smol::Sha1;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebSeedError {
+    #[error("web seed request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("web seed responded with http status {0}")]
+    Status(StatusCode),
+    #[error("web seed sent {received} bytes out of {expected}")]
+    Truncated { expected: u64, received: u64 },
+    #[error("piece {0} from the web seed failed the hash check")]
+    HashMismatch(PieceIndex),
+    #[error("web seed is busy, retrying in {0:?}")]
+    Busy(Duration),
+}
+
+/// how a web seed hands out the pieces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSeedKind {
+    /// a server with the files of the torrent (BEP 19), from the `url-list` of the metainfo.
+    UrlList,
+    /// a script which serves pieces by index (BEP 17), from the `httpseeds` of the metainfo.
+    HttpSeed,
+}
+
+#[derive(Debug)]
+enum Source {
+    UrlList(Vec<FileUrl>),
+    HttpSeed(InfoHash),
+}
+
+/// a file of the torrent, where the seed has it and where it's laid out in the pieces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileUrl {
+    url: Url,
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Debug)]
+pub struct WebSeed {
+    url: Url,
+    source: Source,
+    piece_length: u64,
+    client: reqwest::Client,
+    limiter: RateLimiter,
+    alerts_tx: mpsc::Sender<PeerAlerts>,
+    stats: Arc<PeerStats>,
+    min_backoff: Duration,
+}
+
+impl WebSeed {
+    // the first wait after a failure, it doubles with every failure in a row.
+    const MIN_BACKOFF: Duration = Duration::from_secs(5);
+    const MAX_BACKOFF: Duration = Duration::from_secs(300);
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+    const COMMANDS_BUFFER: usize = 16;
+
+    /// the seed draws from `limiter` for everything it downloads, like a peer connection would.
+    pub fn new(
+        kind: WebSeedKind,
+        url: Url,
+        download_info: &DownloadInfo,
+        client: reqwest::Client,
+        limiter: RateLimiter,
+        alerts_tx: mpsc::Sender<PeerAlerts>,
+    ) -> Self {
+        let source = match kind {
+            WebSeedKind::UrlList => Source::UrlList(file_urls(&url, download_info)),
+            WebSeedKind::HttpSeed => Source::HttpSeed(
+                download_info
+                    .get_info_hash()
+                    .expect("info hash was already computed when the torrent was added"),
+            ),
+        };
+        Self {
+            source,
+            url,
+            piece_length: download_info.piece_length() as u64,
+            client,
+            limiter,
+            alerts_tx,
+            stats: Arc::new(PeerStats::default()),
+            min_backoff: Self::MIN_BACKOFF,
+        }
+    }
+
+    /// downloads the pieces the engine asks for until it shuts the seed down or goes away.
+    /// whenever the seed fails it's taken out for a while, and then offered to the engine again.
+    #[instrument(level = "info", name = "web seed", skip_all, fields(url = %self.url))]
+    pub async fn run(self) {
+        let mut failures = 0;
+        loop {
+            let (commands_tx, commands_rx) = mpsc::channel(Self::COMMANDS_BUFFER);
+            let ready = WebSeedAlert::Ready {
+                commands_tx,
+                stats: self.stats.clone(),
+            };
+            if !self.alert(ready).await {
+                return;
+            }
+
+            let err = match self.serve(commands_rx, &mut failures).await {
+                Ok(()) => return,
+                Err(err) => err,
+            };
+            let backoff = match err {
+                // the seed asked to be left alone for a while, which isn't a failure as such.
+                WebSeedError::Busy(wait) => wait.min(Self::MAX_BACKOFF),
+                _ => {
+                    failures += 1;
+                    self.min_backoff
+                        .saturating_mul(1 << (failures - 1).min(16))
+                        .min(Self::MAX_BACKOFF)
+                }
+            };
+            warn!(%err, failures, ?backoff, "web seed failed, backing off");
+            if !self.alert(WebSeedAlert::Down).await {
+                return;
+            }
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    // returns once the engine is done with the seed, or with the first error.
+    async fn serve(
+        &self,
+        mut commands_rx: mpsc::Receiver<PeerCommands>,
+        failures: &mut u32,
+    ) -> Result<(), WebSeedError> {
+        while let Some(command) = commands_rx.recv().await {
+            let info = match command {
+                PeerCommands::DownloadPiece(info) => info,
+                PeerCommands::Shutdown => break,
+                // there's no choking or interest with web seeds.
+                _ => continue,
+            };
+
+            debug!(index = info.index, "fetching piece from web seed");
+            let piece = self.fetch_piece(&info).await?;
+            if Sha1::from(&piece).digest().bytes() != info.hash {
+                let failed = WebSeedAlert::PieceFailed {
+                    piece_index: info.index,
+                };
+                self.alert(failed).await;
+                return Err(WebSeedError::HashMismatch(info.index));
+            }
+            *failures = 0;
+            let done = WebSeedAlert::DonePiece {
+                piece_index: info.index,
+                piece,
+            };
+            if !self.alert(done).await {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn fetch_piece(&self, info: &PieceRequestInfo) -> Result<Vec<u8>, WebSeedError> {
+        let files = match &self.source {
+            Source::UrlList(files) => files,
+            Source::HttpSeed(info_hash) => {
+                return self.fetch_http_seed_piece(info_hash, info).await
+            }
+        };
+
+        let start = info.index as u64 * self.piece_length;
+        let end = start + info.length as u64;
+        let mut piece = Vec::with_capacity(info.length as usize);
+        for file in files {
+            let from = file.offset.max(start);
+            let to = (file.offset + file.length).min(end);
+            if from < to {
+                let range = from - file.offset..to - file.offset;
+                self.fetch_range(&file.url, range, &mut piece).await?;
+            }
+        }
+        Ok(piece)
+    }
+
+    // appends the range of the file to `buf`.
+    async fn fetch_range(
+        &self,
+        url: &Url,
+        range: Range<u64>,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), WebSeedError> {
+        let response = self
+            .client
+            .get(url.clone())
+            .header(
+                header::RANGE,
+                format!("bytes={}-{}", range.start, range.end - 1),
+            )
+            .timeout(Self::REQUEST_TIMEOUT)
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => {}
+            // servers which ignore ranges send the whole file, which is fine as long as the range
+            // starts at the beginning, the rest isn't read.
+            StatusCode::OK if range.start == 0 => {}
+            StatusCode::SERVICE_UNAVAILABLE => {
+                let wait = http_seed::retry_after_header(response.headers());
+                return Err(unavailable(wait));
+            }
+            status => return Err(WebSeedError::Status(status)),
+        }
+        self.read_body(response, range.end - range.start, buf).await
+    }
+
+    async fn fetch_http_seed_piece(
+        &self,
+        info_hash: &InfoHash,
+        info: &PieceRequestInfo,
+    ) -> Result<Vec<u8>, WebSeedError> {
+        let url = http_seed::piece_url(&self.url, info_hash, info.index, info.length);
+        let response = self
+            .client
+            .get(url)
+            .timeout(Self::REQUEST_TIMEOUT)
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::OK => {}
+            // the body says how many seconds to wait before asking again.
+            StatusCode::SERVICE_UNAVAILABLE => {
+                let header = http_seed::retry_after_header(response.headers());
+                let body = response.text().await.unwrap_or_default();
+                let wait = http_seed::retry_after_body(&body).or(header);
+                return Err(unavailable(wait));
+            }
+            status => return Err(WebSeedError::Status(status)),
+        }
+        let mut piece = Vec::with_capacity(info.length as usize);
+        self.read_body(response, info.length as u64, &mut piece)
+            .await?;
+        Ok(piece)
+    }
+
+    // appends the first `expected` bytes of the body to `buf`, anything after them isn't read.
+    async fn read_body(
+        &self,
+        mut response: reqwest::Response,
+        expected: u64,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), WebSeedError> {
+        let mut received = 0;
+        while received < expected {
+            let Some(chunk) = response.chunk().await? else {
+                break;
+            };
+            self.throttle(chunk.len()).await;
+            self.stats.record_downloaded(chunk.len());
+            let len = chunk.len().min((expected - received) as usize);
+            buf.extend_from_slice(&chunk[..len]);
+            received += len as u64;
+        }
+        if received < expected {
+            return Err(WebSeedError::Truncated { expected, received });
+        }
+        Ok(())
+    }
+
+    // waits for the limiter to have room, the chunk has already been received by then so it's
+    // only the next one which is held back.
+    async fn throttle(&self, nbytes: usize) {
+        while let Err(wait) = self.limiter.available() {
+            tokio::time::sleep(wait).await;
+        }
+        self.limiter.consume(nbytes);
+    }
+
+    // returns false once the engine is gone.
+    async fn alert(&self, alert: WebSeedAlert) -> bool {
+        let alert = PeerAlerts::WebSeed {
+            url: self.url.clone(),
+            alert,
+        };
+        self.alerts_tx.send(alert).await.is_ok()
+    }
+}
+
+// a seed which is down is only waited out if it says for how long, otherwise it's a failure.
+fn unavailable(wait: Option<Duration>) -> WebSeedError {
+    wait.map_or(
+        WebSeedError::Status(StatusCode::SERVICE_UNAVAILABLE),
+        WebSeedError::Busy,
+    )
+}
+
+/// where each file of the torrent is on the seed. the url of a single file torrent is the file
+/// itself, unless it ends with a slash. otherwise the name of the torrent and the path of the file
+/// are appended to it.
+fn file_urls(base: &Url, download_info: &DownloadInfo) -> Vec<FileUrl> {
+    let single_file = matches!(download_info, DownloadInfo::SingleFile { .. });
+    let mut offset = 0;
+    download_info
+        .files()
+        .into_iter()
+        .map(|(path, length)| {
+            let url = if single_file && !base.path().ends_with('/') {
+                base.clone()
+            } else {
+                let mut url = base.clone();
+                url.path_segments_mut()
+                    .expect("web seeds are http urls, which have a path")
+                    .pop_if_empty()
+                    .extend(path.iter().map(|part| part.to_string_lossy()));
+                url
+            };
+            let file = FileUrl {
+                url,
+                offset,
+                length: length as u64,
+            };
+            offset += length as u64;
+            file
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_server::parse_range;
+    use crate::metainfo::FilePriority;
+    use crate::metainfo::Metainfo;
+    use crate::storage::tests::{download_info, temp_dir};
+    use crate::{Session, SessionConfig, SessionEvent};
+    use axum::extract::{Path, RawQuery, State};
+    use axum::http::{HeaderMap, StatusCode as AxumStatus};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::get;
+    use axum::Router;
+    use rstest::rstest;
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+    use tokio::sync::broadcast;
+
+    fn single_file(name: &str) -> DownloadInfo {
+        DownloadInfo::SingleFile {
+            filename: name.to_string(),
+            length: 10,
+            md5sum: None,
+            piece_length: 4,
+            pieces: vec![[0; 20]; 3],
+            private: None,
+        }
+    }
+
+    #[rstest]
+    #[case("http://a.test/movie.mkv", "movie.mkv", "http://a.test/movie.mkv")]
+    #[case("http://a.test/files/", "movie.mkv", "http://a.test/files/movie.mkv")]
+    #[case(
+        "http://a.test/files/",
+        "a movie?.mkv",
+        "http://a.test/files/a%20movie%3F.mkv"
+    )]
+    fn test_single_file_url(#[case] base: &str, #[case] name: &str, #[case] expected: &str) {
+        let files = file_urls(&Url::parse(base).unwrap(), &single_file(name));
+        assert_eq!(files[0].url.as_str(), expected);
+    }
+
+    #[rstest]
+    #[case("http://a.test/mirror")]
+    #[case("http://a.test/mirror/")]
+    fn test_multi_file_urls(#[case] base: &str) {
+        let info = download_info(&[6, 6], &[FilePriority::Normal; 2]);
+        let files = file_urls(&Url::parse(base).unwrap(), &info);
+        let expected = [
+            ("http://a.test/mirror/torrent/file0", 0),
+            ("http://a.test/mirror/torrent/file1", 6),
+        ];
+        for (file, (url, offset)) in files.iter().zip(expected) {
+            assert_eq!(
+                (file.url.as_str(), file.offset, file.length),
+                (url, offset, 6)
+            );
+        }
+    }
+
+    #[derive(Clone)]
+    struct Mirror {
+        files: Arc<HashMap<String, Vec<u8>>>,
+        // requests which are answered with an error before the mirror starts working.
+        failures_left: Arc<AtomicUsize>,
+    }
+
+    async fn serve_file(
+        State(mirror): State<Mirror>,
+        Path(path): Path<String>,
+        headers: HeaderMap,
+    ) -> Response {
+        let failing = mirror
+            .failures_left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        if failing.is_ok() {
+            return AxumStatus::SERVICE_UNAVAILABLE.into_response();
+        }
+        let Some(data) = mirror.files.get(&path) else {
+            return AxumStatus::NOT_FOUND.into_response();
+        };
+        let value = headers["range"].to_str().unwrap();
+        match parse_range(value, data.len() as u64) {
+            Ok(range) => (
+                AxumStatus::PARTIAL_CONTENT,
+                data[range.start as usize..range.end as usize].to_vec(),
+            )
+                .into_response(),
+            Err(_) => AxumStatus::RANGE_NOT_SATISFIABLE.into_response(),
+        }
+    }
+
+    // a BEP 17 script with the whole torrent under `seed`, pieces are 4 bytes long. it's busy
+    // for as long as the mirror is failing, and asks to be retried right away.
+    async fn serve_piece(State(mirror): State<Mirror>, RawQuery(query): RawQuery) -> Response {
+        let failing = mirror
+            .failures_left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        if failing.is_ok() {
+            return (AxumStatus::SERVICE_UNAVAILABLE, "0").into_response();
+        }
+        let query = query.unwrap();
+        let params: HashMap<_, _> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        assert!(params.contains_key("info_hash"));
+        let offset = params["piece"].parse::<usize>().unwrap() * 4;
+        let (first, last) = params["ranges"].split_once('-').unwrap();
+        let (first, last): (usize, usize) = (first.parse().unwrap(), last.parse().unwrap());
+        mirror.files["seed"][offset + first..=offset + last]
+            .to_vec()
+            .into_response()
+    }
+
+    async fn serve_mirror(files: HashMap<String, Vec<u8>>, failures: usize) -> String {
+        let mirror = Mirror {
+            files: Arc::new(files),
+            failures_left: Arc::new(AtomicUsize::new(failures)),
+        };
+        let app = Router::new()
+            .route("/seed", get(serve_piece))
+            .route("/*path", get(serve_file))
+            .with_state(mirror);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    // hands every piece of `data` to the seed the way the engine would, returns how many times
+    // the seed went down.
+    async fn download(
+        seed: WebSeed,
+        mut alerts_rx: mpsc::Receiver<PeerAlerts>,
+        data: &[u8],
+    ) -> usize {
+        tokio::spawn(seed.run());
+        let pieces: Vec<_> = data
+            .chunks(4)
+            .enumerate()
+            .map(|(index, piece)| {
+                PieceRequestInfo::new(index, 4, Sha1::from(piece).digest().bytes())
+            })
+            .collect();
+        let mut done = HashMap::new();
+        let mut downs = 0;
+        while done.len() < pieces.len() {
+            let Some(PeerAlerts::WebSeed { alert, .. }) = alerts_rx.recv().await else {
+                panic!("expected an alert from the web seed");
+            };
+            match alert {
+                WebSeedAlert::Ready { commands_tx, .. } => {
+                    for info in pieces.iter().filter(|info| !done.contains_key(&info.index)) {
+                        let command = PeerCommands::DownloadPiece(info.clone());
+                        commands_tx.send(command).await.unwrap();
+                    }
+                }
+                WebSeedAlert::DonePiece { piece_index, piece } => {
+                    done.insert(piece_index, piece);
+                }
+                WebSeedAlert::Down => downs += 1,
+                WebSeedAlert::PieceFailed { .. } => panic!("the mirror has the right data"),
+            }
+        }
+        for (index, piece) in data.chunks(4).enumerate() {
+            assert_eq!(done[&index], piece);
+        }
+        downs
+    }
+
+    #[tokio::test]
+    async fn test_downloads_pieces_across_files() {
+        // pieces: [0 0 0 0] [0 0 1 1] [1 1 1 1]
+        let info = download_info(&[6, 6], &[FilePriority::Normal; 2]);
+        let data: Vec<u8> = (0..12).collect();
+        let files = HashMap::from([
+            ("mirror/torrent/file0".to_string(), data[..6].to_vec()),
+            ("mirror/torrent/file1".to_string(), data[6..].to_vec()),
+        ]);
+        let url = format!("{}/mirror", serve_mirror(files, 1).await);
+
+        let (alerts_tx, alerts_rx) = mpsc::channel(16);
+        let mut seed = WebSeed::new(
+            WebSeedKind::UrlList,
+            Url::parse(&url).unwrap(),
+            &info,
+            reqwest::Client::new(),
+            RateLimiter::new(),
+            alerts_tx,
+        );
+        seed.min_backoff = Duration::from_millis(10);
+        let stats = seed.stats.clone();
+        // the first request fails, so the pieces have to be handed out again after the retry.
+        assert_eq!(download(seed, alerts_rx, &data).await, 1);
+        assert_eq!(stats.downloaded(), 12);
+    }
+
+    #[tokio::test]
+    async fn test_http_seed_retries_when_busy() {
+        let info = download_info(&[6, 6], &[FilePriority::Normal; 2]);
+        let data: Vec<u8> = (0..12).collect();
+        let url = serve_mirror(HashMap::from([("seed".to_string(), data.clone())]), 2).await;
+
+        let (alerts_tx, alerts_rx) = mpsc::channel(16);
+        let mut seed = WebSeed::new(
+            WebSeedKind::HttpSeed,
+            Url::parse(&format!("{url}/seed")).unwrap(),
+            &info,
+            reqwest::Client::new(),
+            RateLimiter::new(),
+            alerts_tx,
+        );
+        // the seed says when to come back, a failure would be backed off for far longer.
+        seed.min_backoff = Duration::from_secs(3600);
+        let downs = tokio::time::timeout(Duration::from_secs(10), download(seed, alerts_rx, &data));
+        assert_eq!(downs.await.unwrap(), 2);
+    }
+
+    async fn wait_for_completion(events: &mut broadcast::Receiver<SessionEvent>) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match events.recv().await.unwrap() {
+                    SessionEvent::TorrentCompleted { .. } => break,
+                    SessionEvent::TorrentFailed { error, .. } => panic!("torrent failed: {error}"),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_downloads_from_web_seed() {
+        let data: Vec<u8> = (0..10).collect();
+        let url = serve_mirror(HashMap::from([("files/test".to_string(), data.clone())]), 0).await;
+        let hashes: Vec<u8> = data
+            .chunks(4)
+            .flat_map(|piece| Sha1::from(piece).digest().bytes())
+            .collect();
+        // the udp tracker isn't supported, so the web seed is all there is.
+        let mut torrent = b"d8:announce26:udp://127.0.0.1:1/announce".to_vec();
+        torrent.extend_from_slice(b"4:infod6:lengthi10e4:name4:test12:piece lengthi4e6:pieces60:");
+        torrent.extend_from_slice(&hashes);
+        torrent.extend_from_slice(format!("e8:url-list{}:{url}/files/e", url.len() + 7).as_bytes());
+
+        let dir = temp_dir();
+        let session = Session::new(SessionConfig {
+            port: 0,
+            download_dir: dir.clone(),
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap();
+        let mut events = session.subscribe();
+        session
+            .add_torrent(Metainfo::from_bytes(&torrent).unwrap())
+            .unwrap();
+        wait_for_completion(&mut events).await;
+        assert_eq!(std::fs::read(dir.join("test")).unwrap(), data);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_downloads_unskipped_file_after_completion() {
+        let (a, b): (Vec<u8>, Vec<u8>) = ((0..4).collect(), (4..10).collect());
+        let url = serve_mirror(
+            HashMap::from([
+                ("files/dir/a".to_string(), a.clone()),
+                ("files/dir/b".to_string(), b.clone()),
+            ]),
+            0,
+        )
+        .await;
+        let hashes: Vec<u8> = [a.clone(), b.clone()]
+            .concat()
+            .chunks(4)
+            .flat_map(|piece| Sha1::from(piece).digest().bytes())
+            .collect();
+        let mut torrent = b"d8:announce26:udp://127.0.0.1:1/announce4:infod5:filesl".to_vec();
+        torrent.extend_from_slice(b"d6:lengthi4e4:pathl1:aeed6:lengthi6e4:pathl1:bee");
+        torrent.extend_from_slice(b"e4:name3:dir12:piece lengthi4e6:pieces60:");
+        torrent.extend_from_slice(&hashes);
+        torrent.extend_from_slice(format!("e8:url-list{}:{url}/files/e", url.len() + 7).as_bytes());
+        // the first file is only the first piece.
+        let mut metainfo = Metainfo::from_bytes(&torrent).unwrap();
+        if let DownloadInfo::MultiFile { files, .. } = &mut metainfo.file_info {
+            files[0].priority = FilePriority::Skip;
+        }
+
+        let dir = temp_dir();
+        let session = Session::new(SessionConfig {
+            port: 0,
+            download_dir: dir.clone(),
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap();
+        let mut events = session.subscribe();
+        let info_hash = session.add_torrent(metainfo).unwrap();
+        wait_for_completion(&mut events).await;
+        assert_eq!(std::fs::read(dir.join("dir/b")).unwrap(), b);
+        assert_eq!(session.files(&info_hash).unwrap()[0].bytes_done, 0);
+
+        session
+            .set_file_priority(&info_hash, 0, FilePriority::Normal)
+            .unwrap();
+        wait_for_completion(&mut events).await;
+        assert_eq!(std::fs::read(dir.join("dir/a")).unwrap(), a);
+        assert_eq!(session.files(&info_hash).unwrap()[0].bytes_done, 4);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}